@@ -0,0 +1,65 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+use clap::Clap;
+
+#[derive(Clap)]
+#[clap(
+    version = "0.6",
+    author = "Hoping White",
+    about = "Control a running trojan server through its admin address"
+)]
+struct Opts {
+    #[clap(
+        short = "a",
+        long,
+        default_value = "127.0.0.1:9443",
+        help = "admin address of the server"
+    )]
+    admin_addr: String,
+    #[clap(
+        short = "s",
+        long,
+        help = "admin unix socket of the server, used instead of admin address"
+    )]
+    admin_socket: Option<String>,
+    #[clap(help = "command to run, status, list, kill <id>, pause or resume")]
+    command: Vec<String>,
+}
+
+fn main() {
+    let opts = Opts::parse();
+    let command = format!("{}\n", opts.command.join(" "));
+    #[cfg(unix)]
+    {
+        if let Some(path) = &opts.admin_socket {
+            match std::os::unix::net::UnixStream::connect(path) {
+                Ok(stream) => run(stream, command.as_str()),
+                Err(err) => {
+                    eprintln!("connect to {} failed:{}", path, err);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+    }
+    match TcpStream::connect(opts.admin_addr.as_str()) {
+        Ok(stream) => run(stream, command.as_str()),
+        Err(err) => {
+            eprintln!("connect to {} failed:{}", opts.admin_addr, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run<S: Read + Write>(mut stream: S, command: &str) {
+    let mut response = String::new();
+    if let Err(err) = stream
+        .write_all(command.as_bytes())
+        .and_then(|_| stream.read_to_string(&mut response))
+    {
+        eprintln!("admin command failed:{}", err);
+        std::process::exit(1);
+    }
+    print!("{}", response);
+}
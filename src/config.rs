@@ -1,18 +1,172 @@
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 
 use clap::Clap;
 use crypto::digest::Digest;
 use crypto::sha2::Sha224;
+use crypto::util::fixed_time_eq;
+use rustls::ClientConfig;
 use trust_dns_resolver::Resolver;
+use webpki::{DNSName, DNSNameRef};
+
+use crate::daemon::{Notifier, PidFile};
+use crate::proto::MAX_DATAGRAM_SIZE;
+use crate::resolver::{other_family, select_address, Inflight};
+#[cfg(feature = "netem")]
+use crate::server::Netem;
+use crate::server::{Limits, MAX_INDEX, MIN_INDEX};
+use crate::stats::Stats;
+use crate::sys;
+use crate::sys::Signals;
+
+/// user of the password given by -p
+pub const DEFAULT_USER: &str = "default";
 
 pub struct DnsEntry {
-    pub address: IpAddr,
+    pub addresses: Vec<IpAddr>,
     pub expired_time: Instant,
 }
 
+/// length of a password hash, sha224 in hex
+const HASH_LEN: usize = 56;
+
+/// a password accepted by the server and the user it identifies
+pub struct User {
+    pub name: String,
+    hash: String,
+}
+
+/// which packet to drop when a udp queue is full
+#[derive(Copy, Clone)]
+pub enum DropPolicy {
+    Oldest,
+    Newest,
+}
+
+impl Default for DropPolicy {
+    fn default() -> Self {
+        DropPolicy::Oldest
+    }
+}
+
+/// which udp replies are passed back to the client, like nat filtering
+#[derive(Copy, Clone, PartialEq)]
+pub enum ReplyFilter {
+    /// from any source
+    Any,
+    /// from hosts the client sent packets to
+    Address,
+    /// from exactly the addresses and ports the client sent packets to
+    Endpoint,
+}
+
+impl Default for ReplyFilter {
+    fn default() -> Self {
+        ReplyFilter::Any
+    }
+}
+
+/// what to do with a connection exceeding the upload ratio limit
+#[derive(Copy, Clone, PartialEq)]
+pub enum RatioAction {
+    Log,
+    Close,
+}
+
+impl Default for RatioAction {
+    fn default() -> Self {
+        RatioAction::Log
+    }
+}
+
+/// what to do with a new connection when max connections are reached
+#[derive(Copy, Clone, PartialEq)]
+pub enum OverloadPolicy {
+    Refuse,
+    Oldest,
+    Idlest,
+}
+
+impl Default for OverloadPolicy {
+    fn default() -> Self {
+        OverloadPolicy::Refuse
+    }
+}
+
+/// what to do with the target when the client resets the connection
+#[derive(Copy, Clone, PartialEq)]
+pub enum ResetAction {
+    Shutdown,
+    Reset,
+}
+
+impl Default for ResetAction {
+    fn default() -> Self {
+        ResetAction::Shutdown
+    }
+}
+
+/// how the client is told its target can not be connected
+#[derive(Copy, Clone, PartialEq)]
+pub enum UnreachableAction {
+    Close,
+    Reset,
+}
+
+impl Default for UnreachableAction {
+    fn default() -> Self {
+        UnreachableAction::Close
+    }
+}
+
+/// what to do with tls connections sending application data before the handshake finished
+#[derive(Copy, Clone, PartialEq)]
+pub enum EarlyDataAction {
+    Alert,
+    Close,
+    Reset,
+}
+
+impl Default for EarlyDataAction {
+    fn default() -> Self {
+        EarlyDataAction::Alert
+    }
+}
+
+/// when the traffic counters start a new accounting period by themselves
+#[derive(Copy, Clone, PartialEq)]
+pub enum ResetSchedule {
+    Never,
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+impl Default for ResetSchedule {
+    fn default() -> Self {
+        ResetSchedule::Never
+    }
+}
+
+/// what to do with tls connections sending neither a trojan request nor http
+#[derive(Copy, Clone, PartialEq)]
+pub enum PayloadAction {
+    Fallback,
+    Close,
+    Reset,
+    Sink,
+}
+
+impl Default for PayloadAction {
+    fn default() -> Self {
+        PayloadAction::Fallback
+    }
+}
+
 #[derive(Clap)]
 #[clap(
     version = "0.6",
@@ -25,109 +179,831 @@ pub struct Opts {
     #[clap(short, long, help = "log file path")]
     pub log_file: Option<String>,
     #[clap(
-        short = "a",
         long,
-        help = "listen address for server, format like 0.0.0.0:443"
+        help = "write process id to this file, removed on exit. with --run-as its directory has \
+                to be writable by that user, like a /run subdirectory owned by it"
+    )]
+    pub pid_file: Option<String>,
+    #[clap(
+        short = "a",
+        long,
+        help = "listen address for server, format like 0.0.0.0:443"
+    )]
+    pub local_addr: String,
+    #[clap(short, long, help = "passwords for negotiation")]
+    password: String,
+    #[clap(
+        short = "L",
+        long,
+        default_value = "2",
+        help = "log level, 0 for trace, 1 for debug, 2 for info, 3 for warning, 4 for error, 5 for off"
+    )]
+    pub log_level: u8,
+    #[clap(short, long, default_value = "1", help = "set marker used by tproxy")]
+    pub marker: u8,
+    #[clap(
+        short,
+        long,
+        default_value = "60",
+        help = "time in seconds before closing an inactive udp connection"
+    )]
+    pub udp_idle_timeout: u64,
+    #[clap(
+        short,
+        long,
+        default_value = "600",
+        help = "time in seconds before closing an inactive tcp connection"
+    )]
+    pub tcp_idle_timeout: u64,
+    #[clap(
+        long,
+        default_value = "1450",
+        help = "max udp payload size relayed, larger packets are dropped to avoid ip fragmentation"
+    )]
+    pub udp_max_datagram: usize,
+    #[clap(
+        long,
+        default_value = "1024",
+        help = "max events handled per poll wakeup, more takes fewer polls under load"
+    )]
+    pub poll_events: usize,
+    #[clap(
+        long,
+        global = true,
+        default_value = "1450",
+        help = "max bytes read from a tcp target, or a tcp client in proxy mode, at a time"
+    )]
+    pub recv_buffer_size: usize,
+    #[clap(
+        long,
+        global = true,
+        default_value = "1048576",
+        help = "bytes queued for a peer above which the other side is not read"
+    )]
+    pub send_buffer_high_water: usize,
+    #[cfg(feature = "netem")]
+    #[clap(
+        long,
+        default_value = "0",
+        help = "[testing only] latency in milliseconds added to data sent to tcp targets"
+    )]
+    pub sim_latency: u64,
+    #[cfg(feature = "netem")]
+    #[clap(
+        long,
+        default_value = "0",
+        help = "[testing only] percentage of udp packets dropped on the relay path"
+    )]
+    pub sim_loss: f64,
+    #[cfg(feature = "netem")]
+    #[clap(
+        long,
+        default_value = "1",
+        help = "[testing only] random seed of packet loss, 0 for a time based seed"
+    )]
+    pub sim_seed: u64,
+    #[clap(skip)]
+    dns_cache_duration: Duration,
+    #[clap(skip)]
+    sha_pass: String,
+    /// accepted password hashes, the one of -p comes first
+    #[clap(skip)]
+    users: Vec<User>,
+    /// index into users by password hash, looked up with the hash a request starts with
+    #[clap(skip)]
+    user_hashes: HashMap<[u8; HASH_LEN], usize>,
+    #[clap(skip)]
+    pub pass_len: usize,
+    #[clap(skip)]
+    pub back_addr: Option<SocketAddr>,
+    #[clap(skip)]
+    pub dns_cache: HashMap<String, DnsEntry>,
+    /// shared by all workers, so the pending query limit caps the whole server
+    #[clap(skip)]
+    pub dns_inflight: Arc<Inflight>,
+    #[clap(skip)]
+    pub static_hosts: HashMap<String, Vec<IpAddr>>,
+    /// server names of targets reached over tls, by target like example.com:443
+    #[clap(skip)]
+    pub target_tls: HashMap<String, DNSName>,
+    #[clap(skip)]
+    pub target_tls_config: Option<Arc<ClientConfig>>,
+    #[clap(skip)]
+    pub udp_header_len: usize,
+    #[clap(skip)]
+    pub empty_addr: Option<SocketAddr>,
+    #[clap(skip)]
+    pub udp_idle_duration: Duration,
+    #[clap(skip)]
+    pub tcp_idle_duration: Duration,
+    /// connections are closed this long after accept however active they are
+    #[clap(skip)]
+    pub max_connection_lifetime: Option<Duration>,
+    /// a client shut down is closed this long after, even if data or close_notify is not flushed
+    #[clap(skip)]
+    pub close_grace: Option<Duration>,
+    /// tls handshakes taking longer than this are logged
+    #[clap(skip)]
+    pub slow_handshake: Option<Duration>,
+    #[clap(skip)]
+    pub statsd_addr: Option<SocketAddr>,
+    #[clap(skip)]
+    pub otlp_addr: Option<SocketAddr>,
+    #[clap(skip)]
+    pub statsd_interval: Duration,
+    /// shared by all workers
+    #[clap(skip)]
+    pub stats: Arc<Stats>,
+    /// counts of the connection limits, shared by all workers
+    #[clap(skip)]
+    pub limits: Arc<Limits>,
+    #[clap(skip)]
+    pub udp_drop_policy: DropPolicy,
+    #[clap(skip)]
+    pub udp_reply_filter: ReplyFilter,
+    #[clap(skip)]
+    pub tls_handshake_duration: Duration,
+    #[clap(skip)]
+    pub upload_ratio_action: RatioAction,
+    #[clap(skip)]
+    pub block_self_connect: bool,
+    #[clap(skip)]
+    pub overload_policy: OverloadPolicy,
+    /// connection indexes are in min_index..=max_index, each worker has a range of its own
+    #[clap(skip)]
+    pub min_index: usize,
+    #[clap(skip)]
+    pub max_index: usize,
+    #[clap(skip)]
+    pub client_reset_action: ResetAction,
+    #[clap(skip)]
+    pub unknown_payload_action: PayloadAction,
+    #[clap(skip)]
+    pub unreachable_action: UnreachableAction,
+    #[clap(skip)]
+    pub early_data_action: EarlyDataAction,
+    #[clap(skip)]
+    pub traffic_reset: ResetSchedule,
+    #[clap(skip)]
+    pub sink_addr: Option<SocketAddr>,
+    #[clap(skip)]
+    pub admin_socket_mode: u32,
+    /// extensions required in client hello, empty if not checked
+    #[clap(skip)]
+    pub hello_extensions: Vec<u16>,
+    #[clap(skip)]
+    pub bound_addrs: Vec<SocketAddr>,
+    /// addresses of the local interfaces, taken on startup if a wildcard address is bound
+    #[clap(skip)]
+    pub interface_addrs: Vec<IpAddr>,
+    #[clap(skip)]
+    pub notifier: Option<Notifier>,
+    /// written on startup by main and removed when the server exits
+    #[clap(skip)]
+    pub pid: Option<PidFile>,
+    /// SIGTERM and SIGINT, the server drains connections and exits on them, and SIGHUP which
+    /// reloads the certificate
+    #[clap(skip)]
+    pub signals: Option<Signals>,
+    #[clap(skip)]
+    pub backup_nodes: Vec<(String, u16)>,
+    #[cfg(feature = "netem")]
+    #[clap(skip)]
+    pub netem: Option<Netem>,
+}
+
+#[derive(Clap)]
+pub enum Mode {
+    #[clap(name = "proxy", about = "run in proxy mode")]
+    Proxy(ProxyArgs),
+    #[clap(name = "server", about = "run in server mode")]
+    Server(ServerArgs),
+}
+
+#[derive(Clap)]
+pub struct ProxyArgs {
+    #[clap(short = "H", long, help = "trojan server hostname")]
+    pub hostname: String,
+    #[clap(short = "o", long, default_value = "443", help = "trojan server port")]
+    pub port: u16,
+    #[clap(
+        short = "P",
+        long,
+        default_value = "0",
+        help = "pool size, 0 for disable"
+    )]
+    pub pool_size: usize,
+    #[clap(
+        long,
+        help = "listen address for http CONNECT proxy requests, format like 127.0.0.1:8080"
+    )]
+    pub http_addr: Option<String>,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "times to retry on another server connection when tls handshake to server fails, 0 for no retry"
+    )]
+    pub upstream_retries: usize,
+    #[clap(
+        long,
+        default_value = "100",
+        help = "delay in milliseconds before the first retry, doubled for each following one"
+    )]
+    pub upstream_retry_backoff: u64,
+    #[clap(
+        long,
+        help = "backup trojan server in host:port format, can be given multiple times, tried in the given order when the ones before are down"
+    )]
+    pub backup_node: Vec<String>,
+    #[clap(
+        long,
+        default_value = "30",
+        help = "time in seconds a failed trojan server is skipped before it is tried again"
+    )]
+    pub node_down_time: u64,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "seconds after which the server closes each connection, the server has to allow client deadline, 0 for none"
+    )]
+    pub request_deadline: u32,
+}
+
+#[derive(Clap)]
+pub struct ServerArgs {
+    #[clap(
+        short,
+        long,
+        help = "certificate file path, This should contain PEM-format certificates in the right order (the first certificate should certify KEYFILE, the last should be a root CA"
+    )]
+    pub cert: String,
+    #[clap(
+        short,
+        long,
+        help = "private key file path,  This should be a RSA private key or PKCS8-encoded private key, in PEM format."
+    )]
+    pub key: String,
+    #[clap(
+        short,
+        long,
+        default_value = "127.0.0.1:80",
+        help = "http backend server address"
+    )]
+    pub remote_addr: String,
+    #[clap(
+        short,
+        long,
+        default_value = "300",
+        help = "time in seconds for dns query cache"
+    )]
+    dns_cache_time: u64,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "max dns queries of different domains running at a time, 0 for no limit"
+    )]
+    max_pending_dns: usize,
+    #[clap(
+        long,
+        default_value = "wait",
+        possible_values = &["wait", "refuse"],
+        help = "on a new domain to resolve with max pending dns queries running, wait in the dns \
+                queue or refuse the connection. connections are refused when the queue is full too"
+    )]
+    dns_overflow_policy: String,
+    #[clap(
+        long,
+        default_value = "1024",
+        help = "max dns queries waiting for a running one to finish"
+    )]
+    dns_queue_size: usize,
+    #[clap(
+        long,
+        help = "static address of a target host like a hosts file entry, format like example.com=10.0.0.1, can be given multiple times"
+    )]
+    pub static_host: Vec<String>,
+    #[clap(
+        short = "n",
+        long,
+        default_values = &["h2", "http/1.1"],
+        help = "alpn protocols offered in order of preference, can be given multiple times, none \
+                for no alpn. h2 should be offered only if the fallback speaks it too, give \
+                http/1.1 alone otherwise"
+    )]
+    pub alpn: Vec<String>,
+    #[clap(
+        long,
+        help = "target reached over tls, format like example.com:443 or 10.0.0.1:443=example.com \
+                with the server name to send and verify, can be given multiple times"
+    )]
+    pub target_tls: Vec<String>,
+    #[clap(
+        long,
+        help = "another accepted password and the user it identifies in logs and stats, format \
+                like alice=password, can be given multiple times. -p is the password of user default"
+    )]
+    pub user: Vec<String>,
+    #[clap(
+        long,
+        help = "ca certificates in pem for verifying tls targets, default is the webpki roots"
+    )]
+    pub target_tls_ca: Option<String>,
+    #[clap(
+        long,
+        help = "client certificate chain in pem presented to tls targets"
+    )]
+    pub target_tls_cert: Option<String>,
+    #[clap(
+        long,
+        help = "private key of client certificate presented to tls targets"
+    )]
+    pub target_tls_key: Option<String>,
+    #[clap(
+        long,
+        help = "statsd server address for pushing metrics, format like 127.0.0.1:8125"
+    )]
+    pub statsd_addr: Option<String>,
+    #[clap(
+        long,
+        default_value = "10",
+        help = "time in seconds between two statsd pushes"
+    )]
+    statsd_interval: u64,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "time in seconds between two throughput logs, traffic of each connection is logged at debug level, 0 for never"
+    )]
+    pub stats_log_interval: u64,
+    #[clap(long, default_value = "trojan", help = "prefix of statsd metric names")]
+    pub statsd_prefix: String,
+    #[clap(
+        long,
+        help = "opentelemetry collector address for exporting each connection as a span with \
+                otlp over http, format like 127.0.0.1:4318"
+    )]
+    pub otlp_addr: Option<String>,
+    #[clap(
+        long,
+        default_value = "trojan",
+        help = "service name of exported spans"
+    )]
+    pub otlp_service_name: String,
+    #[clap(
+        long,
+        help = "dogstatsd tags attached to every metric, format like key:value"
+    )]
+    pub statsd_tags: Vec<String>,
+    #[clap(
+        long,
+        help = "file the traffic of the accounting period is persisted to, signed with the stats key"
+    )]
+    pub stats_file: Option<String>,
+    #[clap(long, help = "file holding the hmac key for signing the stats file")]
+    pub stats_key_file: Option<String>,
+    #[clap(
+        long,
+        default_value = "60",
+        help = "time in seconds between two saves of the stats file"
+    )]
+    pub stats_checkpoint_interval: u64,
+    #[clap(
+        long,
+        default_value = "never",
+        possible_values = &["never", "daily", "weekly", "monthly"],
+        help = "reset traffic counters at the start of every utc day, week from monday or month"
+    )]
+    traffic_reset: String,
+    #[clap(
+        long,
+        help = "file the traffic of every period ended by scheduled reset is appended to"
+    )]
+    pub traffic_log: Option<String>,
+    #[clap(
+        long,
+        default_value = "64",
+        help = "max udp packets queued per session while tls side is busy, 0 for unlimited"
+    )]
+    pub udp_queue_size: usize,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "max udp packets per second each session sends to targets, excess ones are \
+                dropped, 0 for no limit"
+    )]
+    pub udp_packet_rate: u32,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "udp packets a session may send at once above the packet rate, 0 for one second \
+                worth of packets"
+    )]
+    pub udp_packet_burst: u32,
+    #[clap(
+        long,
+        default_value = "oldest",
+        possible_values = &["oldest", "newest"],
+        help = "which packet to drop when the udp queue is full"
+    )]
+    udp_drop_policy: String,
+    #[clap(
+        long,
+        default_value = "any",
+        possible_values = &["any", "address", "endpoint"],
+        help = "udp replies passed to the client, from any source, or only from addresses or \
+                address and port pairs the client sent packets to"
+    )]
+    udp_reply_filter: String,
+    #[clap(
+        long,
+        help = "admin address for status and control commands, format like 127.0.0.1:9443"
+    )]
+    pub admin_addr: Option<String>,
+    #[clap(
+        long,
+        help = "unix socket path for admin commands instead of admin address, only reachable locally"
+    )]
+    pub admin_socket: Option<String>,
+    #[clap(
+        long,
+        help = "address serving prometheus metrics on /metrics, format like 127.0.0.1:9100"
+    )]
+    pub metrics_addr: Option<String>,
+    #[clap(
+        long,
+        default_value = "600",
+        help = "file permissions of admin socket in octal"
+    )]
+    admin_socket_mode: String,
+    #[clap(
+        long,
+        help = "owner of admin socket, format like user, user:group or :group, names or numeric ids"
+    )]
+    pub admin_socket_owner: Option<String>,
+    #[clap(
+        long,
+        help = "user to run as once listeners are bound, format like user or user:group, names or \
+                numeric ids, the group defaults to the primary group of the user"
+    )]
+    pub run_as: Option<String>,
+    #[clap(
+        long,
+        help = "directory to chroot into once listeners are bound and certificate loaded, files \
+                read later like the certificate on SIGHUP and /etc/resolv.conf are looked up in it"
+    )]
+    pub chroot: Option<String>,
+    #[clap(
+        long,
+        help = "network namespace to enter once listeners are bound, like /var/run/netns/name, \
+                connections made later, to targets and dns servers, go out from it"
+    )]
+    pub netns: Option<String>,
+    #[clap(
+        long,
+        help = "process events of handshaking connections before bulk data ones in each poll"
+    )]
+    pub prioritize_handshakes: bool,
+    #[clap(
+        long,
+        help = "assign connection ids in random order instead of sequentially, so ids in logs do \
+                not tell how many connections came between two"
+    )]
+    pub random_index: bool,
+    #[clap(
+        long,
+        help = "connect dual stack targets using the same address family as the client"
+    )]
+    pub prefer_client_family: bool,
+    #[clap(
+        long,
+        help = "connect dual stack targets again over the other address family if they close before sending anything"
+    )]
+    pub retry_other_family: bool,
+    #[clap(
+        long,
+        help = "send a PROXY protocol v2 header with the client address to tcp targets before any data"
+    )]
+    pub send_proxy_protocol: bool,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "max tls bytes read from a client per event before serving others, 0 for the send \
+                buffer high water"
+    )]
+    pub read_budget: usize,
+    #[clap(
+        long,
+        default_value = "30",
+        help = "time in seconds for a client to finish tls handshake, 0 for no limit"
+    )]
+    tls_handshake_timeout: u64,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "log tls handshakes taking longer than this many milliseconds, 0 for no log"
+    )]
+    slow_handshake: u64,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "max tls handshakes in progress, new connections wait in the backlog above it, 0 for no limit"
+    )]
+    pub max_handshakes: usize,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "max connections accepted per second, more wait in the backlog, 0 for no limit"
+    )]
+    pub accept_rate: u32,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "connections accepted at once above the accept rate, 0 for one second worth of \
+                connections"
+    )]
+    pub accept_burst: u32,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "max connections, new ones above it are handled by overload policy, 0 for no limit"
+    )]
+    pub max_connections: usize,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "max connections from one client address, new ones above it are closed at once, 0 for no limit"
+    )]
+    pub max_conns_per_ip: usize,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "largest connection index, which bounds concurrent connections, 0 for the largest the token space allows"
+    )]
+    pub max_index: usize,
+    #[clap(
+        long,
+        default_value = "1",
+        help = "threads serving connections, each accepts on a listener of its own bound with \
+                SO_REUSEPORT. signals, admin and metrics are served by the first one, connection \
+                limits apply to all of them together"
+    )]
+    pub workers: usize,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "seconds after which the server drains connections and exits for a restart, 0 for never"
+    )]
+    pub max_uptime: u64,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "connections accepted after which the server drains connections and exits for a restart, 0 for no limit"
+    )]
+    pub max_served: u64,
+    #[clap(
+        long,
+        default_value = "30",
+        help = "seconds to wait for connections to finish when draining, the rest are closed"
+    )]
+    pub drain_timeout: u64,
+    #[clap(
+        long,
+        default_value = "75",
+        help = "exit code after draining for a restart, for the supervisor to tell it from a failure"
+    )]
+    pub restart_exit_code: i32,
+    #[clap(
+        long,
+        default_value = "30",
+        help = "seconds to wait for connections to finish after SIGTERM or SIGINT, the rest are shut down"
+    )]
+    pub shutdown_timeout: u64,
+    #[clap(
+        long,
+        default_value = "refuse",
+        possible_values = &["refuse", "oldest", "idlest"],
+        help = "on max connections, refuse the new connection, or close the oldest or the longest idle one to make room"
+    )]
+    overload_policy: String,
+    #[clap(
+        long,
+        default_value = "shutdown",
+        possible_values = &["shutdown", "reset"],
+        help = "on a reset from client, send data already received to the target before closing it, or reset the target at once"
+    )]
+    client_reset_action: String,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "max udp associations of all connections, new ones are refused above it, 0 for no limit"
+    )]
+    pub max_udp_sessions: usize,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "source port shared by all tcp connections to targets with SO_REUSEADDR and SO_REUSEPORT, 0 for a random port each"
+    )]
+    pub outbound_port: u16,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "ip ttl or ipv6 hop limit of tcp and udp packets to targets, 0 for system default"
+    )]
+    pub outbound_ttl: u8,
+    #[clap(
+        long,
+        default_value = "1024",
+        help = "receive buffers of idle or closed tcp targets kept for others, 0 to free them"
+    )]
+    pub recv_buffer_pool: usize,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "bytes queued for a tcp target a paused client is read again below, 0 for half \
+                the high water"
+    )]
+    pub send_buffer_low_water: usize,
+    #[clap(
+        long,
+        help = "accept requests with the deadline extension, connections are closed when their deadline is reached"
+    )]
+    pub allow_client_deadline: bool,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "SO_RCVBUF in bytes for client and target sockets, 0 for system default"
     )]
-    pub local_addr: String,
-    #[clap(short, long, help = "passwords for negotiation")]
-    password: String,
+    pub socket_recv_buffer: usize,
     #[clap(
-        short = "L",
         long,
-        default_value = "2",
-        help = "log level, 0 for trace, 1 for debug, 2 for info, 3 for warning, 4 for error, 5 for off"
+        default_value = "0",
+        help = "SO_SNDBUF in bytes for client and target sockets, 0 for system default"
     )]
-    pub log_level: u8,
-    #[clap(short, long, default_value = "1", help = "set marker used by tproxy")]
-    pub marker: u8,
+    pub socket_send_buffer: usize,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "max ratio of bytes uploaded to bytes downloaded on a tcp connection, 0 for no limit"
+    )]
+    pub upload_ratio_limit: f64,
+    #[clap(
+        long,
+        default_value = "10485760",
+        help = "bytes uploaded before the upload ratio limit is checked"
+    )]
+    pub upload_ratio_min_bytes: u64,
+    #[clap(
+        long,
+        default_value = "log",
+        possible_values = &["log", "close"],
+        help = "action on connections exceeding the upload ratio limit"
+    )]
+    upload_ratio_action: String,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "min bytes per second a tcp connection has to transfer in both directions, checked \
+                over each grace period, 0 for no limit. quiet interactive sessions are closed too"
+    )]
+    pub min_transfer_rate: usize,
+    #[clap(
+        long,
+        default_value = "30",
+        help = "seconds before the min transfer rate is first checked, also the length of each rate window"
+    )]
+    pub min_rate_grace: u64,
+    #[clap(
+        long,
+        help = "allow trojan requests targeting loopback or the listen addresses of this server"
+    )]
+    allow_self_connect: bool,
+    #[clap(
+        long,
+        help = "allow link-local ipv6 targets, the scope is given like fe80::1%eth0 in domain targets"
+    )]
+    pub allow_link_local: bool,
+    #[clap(
+        long,
+        help = "log command and target of each trojan request, targets are privacy sensitive"
+    )]
+    pub log_requests: bool,
+    #[clap(
+        long,
+        help = "log accepted connections already closed by the client as warnings instead of debug, \
+                these are common under port scanning"
+    )]
+    pub log_dead_accepts: bool,
+    #[clap(
+        long,
+        help = "send tcp keepalive probes on client and target connections, so ones silently \
+                dropped by a nat are closed before the idle timeout"
+    )]
+    pub tcp_keepalive: bool,
     #[clap(
-        short,
         long,
         default_value = "60",
-        help = "time in seconds before closing an inactive udp connection"
+        help = "seconds without traffic before the first keepalive probe"
     )]
-    pub udp_idle_timeout: u64,
+    pub keepalive_idle: u32,
+    #[clap(long, default_value = "10", help = "seconds between keepalive probes")]
+    pub keepalive_interval: u32,
     #[clap(
-        short,
         long,
-        default_value = "600",
-        help = "time in seconds before closing an inactive tcp connection"
+        default_value = "30",
+        help = "warn at startup if the certificate expires within this many days"
     )]
-    pub tcp_idle_timeout: u64,
-    #[clap(skip)]
-    dns_cache_duration: Duration,
-    #[clap(skip)]
-    sha_pass: String,
-    #[clap(skip)]
-    pub pass_len: usize,
-    #[clap(skip)]
-    pub back_addr: Option<SocketAddr>,
-    #[clap(skip)]
-    pub dns_cache: HashMap<String, DnsEntry>,
-    #[clap(skip)]
-    pub udp_header_len: usize,
-    #[clap(skip)]
-    pub empty_addr: Option<SocketAddr>,
-    #[clap(skip)]
-    pub udp_idle_duration: Duration,
-    #[clap(skip)]
-    pub tcp_idle_duration: Duration,
-}
-
-#[derive(Clap)]
-pub enum Mode {
-    #[clap(name = "proxy", about = "run in proxy mode")]
-    Proxy(ProxyArgs),
-    #[clap(name = "server", about = "run in server mode")]
-    Server(ServerArgs),
-}
-
-#[derive(Clap)]
-pub struct ProxyArgs {
-    #[clap(short = "H", long, help = "trojan server hostname")]
-    pub hostname: String,
-    #[clap(short = "o", long, default_value = "443", help = "trojan server port")]
-    pub port: u16,
+    pub cert_min_remaining: u64,
+    #[clap(
+        long,
+        help = "check revocation status of the certificate via ocsp at startup"
+    )]
+    pub cert_revocation_check: bool,
+    #[clap(
+        long,
+        help = "refuse to start if the certificate is expired or revoked"
+    )]
+    pub refuse_bad_cert: bool,
+    #[clap(
+        long,
+        default_value = "fallback",
+        possible_values = &["fallback", "close", "reset", "sink"],
+        help = "action on connections sending neither a trojan request nor http after tls handshake"
+    )]
+    unknown_payload_action: String,
     #[clap(
-        short = "P",
         long,
         default_value = "0",
-        help = "pool size, 0 for disable"
+        help = "seconds to wait for a tcp target to accept the connection, 0 for the system timeout"
     )]
-    pub pool_size: usize,
-}
-
-#[derive(Clap)]
-pub struct ServerArgs {
+    pub target_connect_timeout: u64,
     #[clap(
-        short,
         long,
-        help = "certificate file path, This should contain PEM-format certificates in the right order (the first certificate should certify KEYFILE, the last should be a root CA"
+        default_value = "0",
+        help = "seconds after which a connection is closed even if it is still transferring, 0 for no limit"
     )]
-    pub cert: String,
+    max_connection_lifetime: u64,
     #[clap(
-        short,
         long,
-        help = "private key file path,  This should be a RSA private key or PKCS8-encoded private key, in PEM format."
+        default_value = "5",
+        help = "seconds to flush data and close_notify to a client being closed, 0 to wait up to \
+                the idle timeout"
     )]
-    pub key: String,
+    close_grace: u64,
     #[clap(
-        short,
         long,
-        default_value = "127.0.0.1:80",
-        help = "http backend server address"
+        default_value = "close",
+        possible_values = &["close", "reset"],
+        help = "when a tcp target refuses or times out connecting, close the client connection, or reset it"
     )]
-    pub remote_addr: String,
+    unreachable_action: String,
     #[clap(
-        short,
         long,
-        default_value = "300",
-        help = "time in seconds for dns query cache"
+        default_value = "alert",
+        possible_values = &["alert", "close", "reset"],
+        help = "on application data before tls handshake finished, send the tls alert and close, close \
+                without the alert, or reset. data right after the client finished message is not early and kept"
     )]
-    dns_cache_time: u64,
-    #[clap(short = "n", long, help = "alpn protocol supported")]
-    pub alpn: Vec<String>,
+    early_data_action: String,
+    #[clap(
+        long,
+        help = "address receiving unknown payloads with sink action, format like 127.0.0.1:9000"
+    )]
+    unknown_payload_sink: Option<String>,
+    #[clap(
+        long,
+        help = "pass connections to the fallback backend if their tls client hello lacks extensions browsers send"
+    )]
+    pub client_hello_check: bool,
+    #[clap(
+        long,
+        default_value = "0,10,13,43,51",
+        help = "tls extension types a client hello must have with client hello check, separated by comma"
+    )]
+    client_hello_extensions: String,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "hold a first packet shorter than this while it looks like the start of a trojan request, 0 to decide on the first read"
+    )]
+    pub first_packet_wait: usize,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "max connections closed for timeout each second, 0 for no limit"
+    )]
+    pub timeout_check_batch: usize,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "log when bytes waiting to be sent to a tcp target exceed this soft limit, 0 to disable"
+    )]
+    pub send_buffer_warn: usize,
+    #[clap(
+        long,
+        default_value = "65536",
+        help = "capacity a drained tcp target send buffer keeps for reuse, larger buffers are freed"
+    )]
+    pub send_buffer_retain: usize,
 }
 
 impl Opts {
@@ -138,6 +1014,44 @@ impl Opts {
         }
     }
 
+    /// bytes queued for a tcp target below which reading a paused client goes on
+    pub fn tcp_low_water(&self) -> usize {
+        match self.server_args().send_buffer_low_water {
+            0 => self.send_buffer_high_water / 2,
+            size => size,
+        }
+    }
+
+    /// opts of the workers after the first one, which is `self`, made by `setup` from the same
+    /// arguments. all workers share the stats, limits and dns queries and have a part of the
+    /// connection indexes each
+    pub fn split_workers(&mut self, setup: impl Fn() -> Opts) -> Vec<Opts> {
+        let workers = self.server_args().workers;
+        let others = (1..workers)
+            .map(|worker| {
+                let mut other = setup();
+                other.stats = self.stats.clone();
+                other.limits = self.limits.clone();
+                other.dns_inflight = self.dns_inflight.clone();
+                other.notifier = None;
+                other.split_index(worker, workers);
+                other
+            })
+            .collect();
+        self.split_index(0, workers);
+        others
+    }
+
+    /// take part `worker` of `workers` of the connection indexes, so connections of different
+    /// workers never share one
+    fn split_index(&mut self, worker: usize, workers: usize) {
+        let share = (self.max_index - MIN_INDEX + 1) / workers;
+        self.min_index = MIN_INDEX + share * worker;
+        if worker + 1 < workers {
+            self.max_index = self.min_index + share - 1;
+        }
+    }
+
     pub fn proxy_args(&self) -> &ProxyArgs {
         match self.mode {
             Mode::Proxy(ref args) => args,
@@ -151,6 +1065,217 @@ impl Opts {
                 let back_addr: SocketAddr = args.remote_addr.parse().unwrap();
                 self.back_addr = Some(back_addr);
                 self.dns_cache_duration = Duration::new(args.dns_cache_time, 0);
+                let queue_size = if args.dns_overflow_policy == "refuse" {
+                    0
+                } else {
+                    args.dns_queue_size
+                };
+                self.dns_inflight =
+                    Arc::new(Inflight::with_limit(args.max_pending_dns, queue_size));
+                for entry in &args.static_host {
+                    match parse_static_host(entry) {
+                        Some((host, ip)) => self.static_hosts.entry(host).or_default().push(ip),
+                        None => panic!(
+                            "invalid static host {}, format like example.com=10.0.0.1",
+                            entry
+                        ),
+                    }
+                }
+                for entry in &args.user {
+                    let user = match parse_user(entry) {
+                        Some(user) => user,
+                        None => panic!(
+                            "invalid user {}, format like alice=password, names are made of \
+                             letters, digits, '-', '_' and '.'",
+                            entry
+                        ),
+                    };
+                    if user.name == DEFAULT_USER || self.users.iter().any(|u| u.name == user.name) {
+                        panic!("user {} given more than once", user.name);
+                    }
+                    log::info!("user {} sha224 = {}", user.name, user.hash);
+                    self.users.push(user);
+                }
+                for entry in &args.target_tls {
+                    match parse_target_tls(entry) {
+                        Some((target, sni)) => {
+                            self.target_tls.insert(target, sni);
+                        }
+                        None => panic!(
+                            "invalid tls target {}, format like example.com:443 or \
+                             10.0.0.1:443=example.com",
+                            entry
+                        ),
+                    }
+                }
+                if args.target_tls_cert.is_some() != args.target_tls_key.is_some() {
+                    panic!("--target-tls-cert and --target-tls-key must be given together");
+                }
+                if let Some(addr) = &args.statsd_addr {
+                    self.statsd_addr = Some(addr.parse().unwrap());
+                }
+                self.statsd_interval = Duration::new(args.statsd_interval, 0);
+                if let Some(addr) = &args.otlp_addr {
+                    self.otlp_addr = Some(addr.parse().unwrap());
+                }
+                self.udp_drop_policy = match args.udp_drop_policy.as_str() {
+                    "newest" => DropPolicy::Newest,
+                    _ => DropPolicy::Oldest,
+                };
+                self.udp_reply_filter = match args.udp_reply_filter.as_str() {
+                    "address" => ReplyFilter::Address,
+                    "endpoint" => ReplyFilter::Endpoint,
+                    _ => ReplyFilter::Any,
+                };
+                self.tls_handshake_duration = Duration::new(args.tls_handshake_timeout, 0);
+                if args.max_connection_lifetime > 0 {
+                    self.max_connection_lifetime =
+                        Some(Duration::from_secs(args.max_connection_lifetime));
+                }
+                if args.slow_handshake > 0 {
+                    self.slow_handshake = Some(Duration::from_millis(args.slow_handshake));
+                }
+                if args.close_grace > 0 {
+                    self.close_grace = Some(Duration::from_secs(args.close_grace));
+                }
+                self.upload_ratio_action = match args.upload_ratio_action.as_str() {
+                    "close" => RatioAction::Close,
+                    _ => RatioAction::Log,
+                };
+                self.block_self_connect = !args.allow_self_connect;
+                self.min_index = MIN_INDEX;
+                self.max_index = match args.max_index {
+                    0 => MAX_INDEX,
+                    // tokens are index * CHANNEL_CNT + channel, larger ones overflow
+                    max if (MIN_INDEX..=MAX_INDEX).contains(&max) => max,
+                    max => panic!(
+                        "max index {} out of range {}..={}",
+                        max, MIN_INDEX, MAX_INDEX
+                    ),
+                };
+                if args.workers == 0 || args.workers > self.max_index - MIN_INDEX + 1 {
+                    panic!(
+                        "workers {} out of range 1..={}, one index at least for each",
+                        args.workers,
+                        self.max_index - MIN_INDEX + 1
+                    );
+                }
+                self.overload_policy = match args.overload_policy.as_str() {
+                    "oldest" => OverloadPolicy::Oldest,
+                    "idlest" => OverloadPolicy::Idlest,
+                    _ => OverloadPolicy::Refuse,
+                };
+                self.client_reset_action = match args.client_reset_action.as_str() {
+                    "reset" => ResetAction::Reset,
+                    _ => ResetAction::Shutdown,
+                };
+                self.unreachable_action = match args.unreachable_action.as_str() {
+                    "reset" => UnreachableAction::Reset,
+                    _ => UnreachableAction::Close,
+                };
+                self.early_data_action = match args.early_data_action.as_str() {
+                    "close" => EarlyDataAction::Close,
+                    "reset" => EarlyDataAction::Reset,
+                    _ => EarlyDataAction::Alert,
+                };
+                self.traffic_reset = match args.traffic_reset.as_str() {
+                    "daily" => ResetSchedule::Daily,
+                    "weekly" => ResetSchedule::Weekly,
+                    "monthly" => ResetSchedule::Monthly,
+                    _ => ResetSchedule::Never,
+                };
+                self.unknown_payload_action = match args.unknown_payload_action.as_str() {
+                    "close" => PayloadAction::Close,
+                    "reset" => PayloadAction::Reset,
+                    "sink" => PayloadAction::Sink,
+                    _ => PayloadAction::Fallback,
+                };
+                if let Some(addr) = &args.unknown_payload_sink {
+                    self.sink_addr = Some(addr.parse().unwrap());
+                }
+                if args.client_hello_check {
+                    self.hello_extensions = args
+                        .client_hello_extensions
+                        .split(',')
+                        .map(|extension| extension.trim().parse().unwrap())
+                        .collect();
+                }
+                if args.admin_addr.is_some() && args.admin_socket.is_some() {
+                    panic!("--admin-addr and --admin-socket can not be used together");
+                }
+                self.admin_socket_mode = u32::from_str_radix(args.admin_socket_mode.as_str(), 8)
+                    .ok()
+                    .filter(|mode| *mode <= 0o7777)
+                    .unwrap_or_else(|| {
+                        panic!("invalid admin socket mode {}", args.admin_socket_mode)
+                    });
+                if self.unknown_payload_action == PayloadAction::Sink && self.sink_addr.is_none() {
+                    panic!("unknown payload sink action requires --unknown-payload-sink");
+                }
+                // the kernel silently caps buffer sizes to its maximum
+                if let Some((recv_max, send_max)) = sys::max_buffer_size() {
+                    if args.socket_recv_buffer > recv_max {
+                        log::warn!(
+                            "socket recv buffer {} exceeds system max {}, raise net.core.rmem_max",
+                            args.socket_recv_buffer,
+                            recv_max
+                        );
+                    }
+                    if args.socket_send_buffer > send_max {
+                        log::warn!(
+                            "socket send buffer {} exceeds system max {}, raise net.core.wmem_max",
+                            args.socket_send_buffer,
+                            send_max
+                        );
+                    }
+                }
+                if args.tcp_keepalive {
+                    if args.keepalive_idle == 0 || args.keepalive_interval == 0 {
+                        panic!("keepalive idle and interval must be at least 1 second");
+                    }
+                    if !sys::KEEPALIVE_TIMES {
+                        log::warn!(
+                            "keepalive idle and interval can not be set on this platform, \
+                             system defaults are used"
+                        );
+                    }
+                }
+                if self.tcp_low_water() >= self.send_buffer_high_water {
+                    panic!(
+                        "send buffer low water {} must be below high water {}",
+                        self.tcp_low_water(),
+                        self.send_buffer_high_water
+                    );
+                }
+                if args.outbound_port != 0 {
+                    log::warn!(
+                        "tcp connections to targets share source port {}, only one of them can \
+                         connect to the same target address at a time, including ones in TIME_WAIT",
+                        args.outbound_port
+                    );
+                }
+                self.bound_addrs.push(self.local_addr.parse().unwrap());
+                if let Some(addr) = &args.admin_addr {
+                    self.bound_addrs.push(addr.parse().unwrap());
+                }
+                if let Some(addr) = &args.metrics_addr {
+                    self.bound_addrs.push(addr.parse().unwrap());
+                }
+                if self.block_self_connect
+                    && self
+                        .bound_addrs
+                        .iter()
+                        .any(|addr| addr.ip().is_unspecified())
+                {
+                    match sys::interface_addrs() {
+                        Ok(addrs) => self.interface_addrs = addrs,
+                        Err(err) => log::warn!(
+                            "list interface addresses failed:{}, self connect through them is \
+                             not rejected",
+                            err
+                        ),
+                    }
+                }
             }
             Mode::Proxy(ref args) => {
                 let mut hostname = args.hostname.clone();
@@ -179,6 +1304,12 @@ impl Opts {
                 }
 
                 log::info!("server address is {}", self.back_addr.as_ref().unwrap());
+                for node in &args.backup_node {
+                    match split_host_port(node) {
+                        Some(node) => self.backup_nodes.push(node),
+                        None => panic!("invalid backup node {}, format like example.com:443", node),
+                    }
+                }
             }
         }
         let empty_addr = if self.back_addr.as_ref().unwrap().is_ipv4() {
@@ -187,16 +1318,35 @@ impl Opts {
             SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)
         };
         self.empty_addr.replace(empty_addr);
+        self.notifier = Notifier::from_env();
         self.udp_idle_duration = Duration::new(self.udp_idle_timeout, 0);
         self.tcp_idle_duration = Duration::new(self.tcp_idle_timeout, 0);
+        if self.udp_max_datagram > MAX_DATAGRAM_SIZE {
+            panic!(
+                "udp max datagram {} is larger than {}",
+                self.udp_max_datagram, MAX_DATAGRAM_SIZE
+            );
+        }
+        if self.poll_events == 0 {
+            panic!("poll events must be at least 1");
+        }
+        if self.recv_buffer_size == 0 {
+            panic!("recv buffer size must be at least 1");
+        }
+        if self.send_buffer_high_water == 0 {
+            panic!("send buffer high water must be at least 1");
+        }
+        #[cfg(feature = "netem")]
+        {
+            if self.sim_latency > 0 || self.sim_loss > 0.0 {
+                self.netem = Some(Netem::new(self.sim_latency, self.sim_loss, self.sim_seed));
+            }
+        }
         self.digest_pass();
     }
 
     fn digest_pass(&mut self) {
-        let mut encoder = Sha224::new();
-        encoder.reset();
-        encoder.input(self.password.as_bytes());
-        let result = encoder.result_str();
+        let result = sha224(self.password.as_str());
         self.pass_len = result.len();
         log::info!(
             "sha224({}) = {}, length = {}",
@@ -204,12 +1354,34 @@ impl Opts {
             result,
             self.pass_len
         );
+        self.users.insert(
+            0,
+            User {
+                name: DEFAULT_USER.to_string(),
+                hash: result.clone(),
+            },
+        );
         self.sha_pass = result;
+        for (index, user) in self.users.iter().enumerate() {
+            let hash = <[u8; HASH_LEN]>::try_from(user.hash.as_bytes()).unwrap();
+            if let Some(other) = self.user_hashes.insert(hash, index) {
+                panic!(
+                    "user {} has the same password as user {}",
+                    user.name, self.users[other].name
+                );
+            }
+        }
     }
 
-    pub fn check_pass(&self, pass: &str) -> Option<&String> {
-        if self.sha_pass.eq(pass) {
-            Some(&self.password)
+    /// user whose password hash is `pass`, None if no password matches. the table hashes keys
+    /// with a randomly seeded siphash, so a request can not choose which stored hashes it is
+    /// compared with, and the time taken tells nothing about which of them is close. the hit is
+    /// compared in full once more, so its time does not depend on the bytes either
+    pub fn check_pass(&self, pass: &[u8]) -> Option<&str> {
+        let hash = <[u8; HASH_LEN]>::try_from(pass).ok()?;
+        let user = &self.users[*self.user_hashes.get(&hash)?];
+        if fixed_time_eq(user.hash.as_bytes(), pass) {
+            Some(user.name.as_str())
         } else {
             None
         }
@@ -219,23 +1391,46 @@ impl Opts {
         &self.sha_pass
     }
 
-    pub fn update_dns(&mut self, domain: String, address: IpAddr) {
-        log::trace!("update dns cache, {} = {}", domain, address);
+    /// whether connecting to `addr` would loop back into this server
+    pub fn is_self_connect(&self, addr: &SocketAddr) -> bool {
+        is_self_addr(addr, &self.bound_addrs, &self.interface_addrs)
+    }
+
+    /// reason to reject a link-local target, None if the target is fine
+    pub fn link_local_error(&self, addr: &SocketAddr) -> Option<&'static str> {
+        link_local_error(addr, self.server_args().allow_link_local)
+    }
+
+    pub fn update_dns(&mut self, domain: String, addresses: Vec<IpAddr>) {
+        log::trace!("update dns cache, {} = {:?}", domain, addresses);
         let expired_time = Instant::now() + self.dns_cache_duration;
         self.dns_cache.insert(
             domain,
             DnsEntry {
-                address,
+                addresses,
                 expired_time,
             },
         );
     }
 
-    pub fn query_dns(&mut self, domain: &str) -> Option<IpAddr> {
+    /// an address of `domain` in static hosts or dns cache of the other family than `ip`
+    pub fn query_other_family(&self, domain: &str, ip: IpAddr) -> Option<IpAddr> {
+        if let Some(addresses) = self.static_hosts.get(&domain.to_ascii_lowercase()) {
+            return other_family(addresses.as_slice(), ip);
+        }
+        let entry = self.dns_cache.get(domain)?;
+        other_family(entry.addresses.as_slice(), ip)
+    }
+
+    pub fn query_dns(&mut self, domain: &str, prefer_ipv6: bool) -> Option<IpAddr> {
+        if let Some(addresses) = self.static_hosts.get(&domain.to_ascii_lowercase()) {
+            log::debug!("found {} = {:?} in static hosts", domain, addresses);
+            return select_address(addresses.as_slice(), prefer_ipv6);
+        }
         if let Some(entry) = self.dns_cache.get(domain) {
-            log::debug!("found {} = {} in dns cache", domain, entry.address);
+            log::debug!("found {} = {:?} in dns cache", domain, entry.addresses);
             if entry.expired_time > Instant::now() {
-                return Some(entry.address);
+                return select_address(entry.addresses.as_slice(), prefer_ipv6);
             } else {
                 log::info!("domain {} expired, remove from cache", domain);
                 let _ = self.dns_cache.remove(domain);
@@ -245,6 +1440,105 @@ impl Opts {
     }
 }
 
+/// an address bound to a wildcard is reached through any of `interface_addrs` on its port
+fn is_self_addr(addr: &SocketAddr, bound_addrs: &[SocketAddr], interface_addrs: &[IpAddr]) -> bool {
+    let ip = unmapped(addr.ip());
+    ip.is_loopback()
+        || ip.is_unspecified()
+        || bound_addrs.iter().any(|bound| {
+            let bound_ip = unmapped(bound.ip());
+            bound.port() == addr.port()
+                && (bound_ip == ip
+                    || (bound_ip.is_unspecified()
+                        && interface_addrs.iter().any(|local| unmapped(*local) == ip)))
+        })
+}
+
+/// ipv4-mapped ipv6 address, ::ffff:a.b.c.d, as the ipv4 one
+fn unmapped(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) if ip.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => {
+            IpAddr::V4(ip.to_ipv4().unwrap())
+        }
+        ip => ip,
+    }
+}
+
+/// link-local addresses are only routable with a scope id, which binary trojan addresses lack
+fn link_local_error(addr: &SocketAddr, allow: bool) -> Option<&'static str> {
+    match addr {
+        SocketAddr::V6(v6) if v6.ip().segments()[0] & 0xffc0 == 0xfe80 => {
+            if !allow {
+                Some("link-local target is not allowed")
+            } else if v6.scope_id() == 0 {
+                Some("link-local target has no scope id")
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// split `host:port`, ipv6 hosts are in brackets like `[::1]:443`
+fn split_host_port(node: &str) -> Option<(String, u16)> {
+    let pos = node.rfind(':')?;
+    let port = node[pos + 1..].parse().ok()?;
+    let host = node[..pos].trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        None
+    } else {
+        Some((host.to_string(), port))
+    }
+}
+
+/// split `host=ip`, host names are case insensitive
+fn parse_static_host(entry: &str) -> Option<(String, IpAddr)> {
+    let mut parts = entry.splitn(2, '=');
+    let host = parts.next()?.trim();
+    let ip = parts.next()?.trim().parse().ok()?;
+    if host.is_empty() {
+        None
+    } else {
+        Some((host.to_ascii_lowercase(), ip))
+    }
+}
+
+fn sha224(password: &str) -> String {
+    let mut encoder = Sha224::new();
+    encoder.input(password.as_bytes());
+    encoder.result_str()
+}
+
+/// user and password hash of an entry like alice=password
+fn parse_user(entry: &str) -> Option<User> {
+    let mut parts = entry.splitn(2, '=');
+    let name = parts.next()?.trim();
+    let password = parts.next()?;
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.';
+    if name.is_empty() || !name.chars().all(valid) || password.is_empty() {
+        return None;
+    }
+    Some(User {
+        name: name.to_string(),
+        hash: sha224(password),
+    })
+}
+
+/// target and server name of a tls target, the server name defaults to the target host
+fn parse_target_tls(entry: &str) -> Option<(String, DNSName)> {
+    let mut parts = entry.splitn(2, '=');
+    let target = parts.next()?.trim().to_ascii_lowercase();
+    let pos = target.rfind(':')?;
+    target[pos + 1..].parse::<u16>().ok()?;
+    let sni = match parts.next() {
+        Some(sni) => sni.trim(),
+        None => &target[..pos],
+    };
+    let sni = DNSNameRef::try_from_ascii_str(sni).ok()?.to_owned();
+    Some((target, sni))
+}
+
 pub fn setup_logger(logfile: &Option<String>, level: u8) {
     let level = match level {
         0x00 => log::LevelFilter::Trace,
@@ -280,3 +1574,177 @@ pub fn setup_logger(logfile: &Option<String>, level: u8) {
     }
     builder.apply().unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddrV6;
+
+    use super::*;
+    use crate::proto::{MAX_BUFFER_SIZE, MAX_PACKET_SIZE};
+
+    #[test]
+    fn self_connect() {
+        let bound: Vec<SocketAddr> = vec!["192.168.1.2:443".parse().unwrap()];
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        assert!(is_self_addr(&addr("192.168.1.2:443"), &bound, &[]));
+        assert!(is_self_addr(&addr("[::ffff:192.168.1.2]:443"), &bound, &[]));
+        assert!(is_self_addr(&addr("127.0.0.1:80"), &bound, &[]));
+        assert!(is_self_addr(&addr("[::1]:443"), &bound, &[]));
+        assert!(is_self_addr(&addr("[::ffff:127.0.0.1]:443"), &bound, &[]));
+        assert!(is_self_addr(&addr("0.0.0.0:443"), &bound, &[]));
+        assert!(!is_self_addr(&addr("192.168.1.2:80"), &bound, &[]));
+        assert!(!is_self_addr(&addr("8.8.8.8:443"), &bound, &[]));
+        // a wildcard is reached through the interface addresses
+        let bound: Vec<SocketAddr> = vec![addr("[::]:443")];
+        let interfaces: Vec<IpAddr> = vec!["10.0.0.2".parse().unwrap(), "fd00::2".parse().unwrap()];
+        assert!(is_self_addr(&addr("10.0.0.2:443"), &bound, &interfaces));
+        assert!(is_self_addr(
+            &addr("[::ffff:10.0.0.2]:443"),
+            &bound,
+            &interfaces
+        ));
+        assert!(is_self_addr(&addr("[fd00::2]:443"), &bound, &interfaces));
+        assert!(!is_self_addr(&addr("10.0.0.2:80"), &bound, &interfaces));
+        assert!(!is_self_addr(&addr("10.0.0.3:443"), &bound, &interfaces));
+    }
+
+    #[test]
+    fn link_local() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        let scoped = SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().unwrap(), 443, 0, 2));
+        assert!(link_local_error(&addr("[fe80::1]:443"), false).is_some());
+        assert!(link_local_error(&scoped, false).is_some());
+        assert!(link_local_error(&addr("[fe80::1]:443"), true).is_some());
+        assert!(link_local_error(&scoped, true).is_none());
+        assert!(link_local_error(&addr("[febf::1]:443"), false).is_some());
+        assert!(link_local_error(&addr("[fec0::1]:443"), false).is_none());
+        assert!(link_local_error(&addr("[2001:db8::1]:443"), false).is_none());
+        assert!(link_local_error(&addr("169.254.1.1:443"), false).is_none());
+    }
+
+    #[test]
+    fn host_port() {
+        let node = |host: &str, port| Some((host.to_string(), port));
+        assert_eq!(split_host_port("example.com:443"), node("example.com", 443));
+        assert_eq!(split_host_port("10.0.0.1:8443"), node("10.0.0.1", 8443));
+        assert_eq!(
+            split_host_port("[2001:db8::1]:443"),
+            node("2001:db8::1", 443)
+        );
+        assert_eq!(split_host_port("example.com"), None);
+        assert_eq!(split_host_port(":443"), None);
+        assert_eq!(split_host_port("example.com:port"), None);
+    }
+
+    #[test]
+    fn static_host() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(
+            parse_static_host("Example.com=10.0.0.1"),
+            Some(("example.com".to_string(), ip("10.0.0.1")))
+        );
+        assert_eq!(
+            parse_static_host("example.com=2001:db8::1"),
+            Some(("example.com".to_string(), ip("2001:db8::1")))
+        );
+        assert_eq!(parse_static_host("example.com"), None);
+        assert_eq!(parse_static_host("=10.0.0.1"), None);
+        assert_eq!(parse_static_host("example.com=host"), None);
+    }
+
+    #[test]
+    fn user() {
+        let user = parse_user("alice=pass=word").unwrap();
+        assert_eq!(user.name, "alice");
+        assert_eq!(user.hash, sha224("pass=word"));
+        assert!(parse_user("alice").is_none());
+        assert!(parse_user("=password").is_none());
+        assert!(parse_user("alice=").is_none());
+        assert!(parse_user("a\"b=password").is_none());
+    }
+
+    #[test]
+    fn buffer_sizes() {
+        let args = [
+            "trojan",
+            "-a",
+            "127.0.0.1:443",
+            "-p",
+            "pw",
+            "server",
+            "-c",
+            "cert",
+            "-k",
+            "key",
+        ];
+        let opts = Opts::parse_from(args.iter());
+        assert_eq!(opts.recv_buffer_size, MAX_PACKET_SIZE);
+        assert_eq!(opts.send_buffer_high_water, MAX_BUFFER_SIZE);
+        // given before the mode for the proxy too, or after it like the other server options
+        let mut args = args.to_vec();
+        args.splice(1..1, ["--recv-buffer-size", "16384"].iter().copied());
+        args.extend_from_slice(&["--send-buffer-high-water", "4096"]);
+        let opts = Opts::parse_from(args.iter());
+        assert_eq!(opts.recv_buffer_size, 16384);
+        assert_eq!(opts.send_buffer_high_water, 4096);
+    }
+
+    #[test]
+    fn workers_split_indexes() {
+        let parse = || {
+            let args = [
+                "trojan",
+                "-a",
+                "127.0.0.1:443",
+                "-p",
+                "pw",
+                "server",
+                "-c",
+                "cert",
+                "-k",
+                "key",
+                "--workers",
+                "3",
+                "--max-index",
+                "13",
+            ];
+            let mut opts = Opts::parse_from(args.iter());
+            opts.setup();
+            opts
+        };
+        let mut opts = parse();
+        let workers = opts.split_workers(parse);
+        assert_eq!(workers.len(), 2);
+        assert_eq!((opts.min_index, opts.max_index), (MIN_INDEX, MIN_INDEX + 2));
+        assert_eq!((workers[0].min_index, workers[0].max_index), (7, 9));
+        assert_eq!((workers[1].min_index, workers[1].max_index), (10, 13));
+        assert!(workers.iter().all(|worker| {
+            Arc::ptr_eq(&worker.stats, &opts.stats)
+                && Arc::ptr_eq(&worker.limits, &opts.limits)
+                && Arc::ptr_eq(&worker.dns_inflight, &opts.dns_inflight)
+        }));
+    }
+
+    #[test]
+    fn check_pass() {
+        let args = [
+            "trojan",
+            "-a",
+            "127.0.0.1:443",
+            "-p",
+            "pw",
+            "server",
+            "-c",
+            "cert",
+            "-k",
+            "key",
+        ];
+        let mut opts = Opts::parse_from(args.iter());
+        opts.users.push(parse_user("alice=secret").unwrap());
+        opts.digest_pass();
+        assert_eq!(opts.check_pass(sha224("pw").as_bytes()), Some(DEFAULT_USER));
+        assert_eq!(opts.check_pass(sha224("secret").as_bytes()), Some("alice"));
+        assert_eq!(opts.check_pass(sha224("other").as_bytes()), None);
+        assert_eq!(opts.check_pass(b"short"), None);
+    }
+}
@@ -0,0 +1,150 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::sys;
+
+/// holds the pid file written on startup, the file is removed when dropped. its directory is
+/// kept open, so the file is still found after a chroot
+pub struct PidFile {
+    path: PathBuf,
+    dir: File,
+}
+
+impl PidFile {
+    pub fn create(path: &str) -> std::io::Result<PidFile> {
+        let path = PathBuf::from(path);
+        let dir = path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        let dir = sys::open_dir(dir)?;
+        std::fs::write(&path, format!("{}\n", std::process::id()))?;
+        log::info!("pid written to {:?}", path);
+        Ok(PidFile { path, dir })
+    }
+
+    /// whether the file can be removed by the process as it is now
+    pub fn removable(&self) -> bool {
+        sys::dir_writable(&self.dir)
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(err) = sys::remove_in(&self.dir, &self.path) {
+            log::warn!("remove pid file {:?} failed:{}", self.path, err);
+        }
+    }
+}
+
+/// systemd style readiness and watchdog notification, only when started with NOTIFY_SOCKET
+pub struct Notifier {
+    path: String,
+    socket: sys::NotifySocket,
+    watchdog_interval: Option<Duration>,
+    last_watchdog: Instant,
+}
+
+impl Notifier {
+    pub fn from_env() -> Option<Notifier> {
+        let socket = std::env::var("NOTIFY_SOCKET").ok()?;
+        if socket.is_empty() {
+            return None;
+        }
+        // WATCHDOG_PID is set when the watchdog is meant for another process
+        let for_me = std::env::var("WATCHDOG_PID")
+            .map(|pid| pid == std::process::id().to_string())
+            .unwrap_or(true);
+        // ping twice in each watchdog period as systemd suggests
+        let watchdog_interval = std::env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse::<u64>().ok())
+            .filter(|usec| for_me && *usec > 0)
+            .map(|usec| Duration::from_micros(usec / 2));
+        log::info!(
+            "notify socket {}, watchdog interval {:?}",
+            socket,
+            watchdog_interval
+        );
+        Notifier::connect(socket, watchdog_interval)
+            .map_err(|err| log::warn!("connect notify socket failed:{}", err))
+            .ok()
+    }
+
+    /// connected before any chroot, the socket path may not be reachable after
+    pub fn connect(path: String, watchdog_interval: Option<Duration>) -> std::io::Result<Notifier> {
+        Ok(Notifier {
+            socket: sys::notify_socket(path.as_str())?,
+            path,
+            watchdog_interval,
+            last_watchdog: Instant::now(),
+        })
+    }
+
+    pub fn ready(&self) {
+        self.send("READY=1");
+    }
+
+    pub fn stopping(&self) {
+        self.send("STOPPING=1");
+    }
+
+    pub fn watchdog(&mut self, now: Instant) {
+        if let Some(interval) = self.watchdog_interval {
+            if now - self.last_watchdog >= interval {
+                self.send("WATCHDOG=1");
+                self.last_watchdog = now;
+            }
+        }
+    }
+
+    fn send(&self, state: &str) {
+        if let Err(err) = sys::notify(&self.socket, state) {
+            log::warn!("notify {} to {} failed:{}", state, self.path, err);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::net::UnixDatagram;
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("trojan-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn pid_file_removed_on_drop() {
+        let path = temp_path("pid");
+        let pid_file = PidFile::create(path.to_str().unwrap()).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content.trim(), std::process::id().to_string());
+        assert!(pid_file.removable());
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn notify_ready_and_watchdog() {
+        let path = temp_path("notify");
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        let mut notifier = Notifier::connect(
+            path.to_str().unwrap().to_string(),
+            Some(Duration::from_secs(1)),
+        )
+        .unwrap();
+        let mut buffer = [0u8; 64];
+        notifier.ready();
+        let size = socket.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], b"READY=1");
+
+        notifier.watchdog(Instant::now() + Duration::from_secs(2));
+        let size = socket.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], b"WATCHDOG=1");
+        let _ = std::fs::remove_file(&path);
+    }
+}
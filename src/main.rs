@@ -2,23 +2,34 @@ use clap::derive::IntoApp;
 use clap::{App, AppSettings, FromArgMatches};
 
 use crate::config::{Mode, Opts};
+use crate::daemon::PidFile;
+use crate::sys::{Signals, RELOAD_SIGNAL};
 
 mod config;
+mod daemon;
 mod proto;
 mod proxy;
 mod resolver;
 mod server;
+mod stats;
 mod sys;
 mod tcp_util;
+#[cfg(test)]
+mod test_support;
 mod tls_conn;
 
 fn main() {
     let mut app: App = <Opts as IntoApp>::into_app();
     app.set(AppSettings::AllowExternalSubcommands);
-    let mut opts = <Opts as FromArgMatches>::from_arg_matches(&app.get_matches());
+    let matches = app.get_matches();
+    let mut opts = <Opts as FromArgMatches>::from_arg_matches(&matches);
 
     config::setup_logger(&opts.log_file, opts.log_level);
     opts.setup();
+    opts.pid = opts
+        .pid_file
+        .as_ref()
+        .map(|path| PidFile::create(path).unwrap());
     match opts.mode {
         Mode::Proxy(_) => {
             log::warn!("trojan started in proxy mode");
@@ -26,7 +37,18 @@ fn main() {
         }
         Mode::Server(_) => {
             log::warn!("trojan started in server mode");
-            server::run(&mut opts);
+            match Signals::new(&[libc::SIGTERM, libc::SIGINT, RELOAD_SIGNAL]) {
+                Ok(signals) => opts.signals = Some(signals),
+                Err(err) => log::error!("handle SIGTERM, SIGINT and SIGHUP failed:{}", err),
+            }
+            let workers = opts.split_workers(|| {
+                let mut worker = <Opts as FromArgMatches>::from_arg_matches(&matches);
+                worker.setup();
+                worker
+            });
+            let code = server::run(&mut opts, workers);
+            drop(opts.pid.take());
+            std::process::exit(code);
         }
     }
 }
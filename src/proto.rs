@@ -1,25 +1,62 @@
+use std::fmt::{Display, Formatter};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 
 use bytes::{BufMut, BytesMut};
 
 use crate::config::Opts;
+use crate::sys;
 
 /// protocol code for CONNECT command
 pub const CONNECT: u8 = 0x01;
 /// protocol code for UDP_ASSOCIATE command
 pub const UDP_ASSOCIATE: u8 = 0x03;
+/// command flag of the deadline extension, seconds as u32 follow the CRLF after address
+pub const DEADLINE_FLAG: u8 = 0x80;
 /// max packet size for udp, MTU = 1500 minus IP head size
 pub const MAX_PACKET_SIZE: usize = 1450;
+/// max udp payload size over ipv4
+pub const MAX_DATAGRAM_SIZE: usize = 65507;
 /// buffer size for connections
 pub const MAX_BUFFER_SIZE: usize = 1024 * 1024;
 /// protocol code for IPV4 type
 const IPV4: u8 = 0x01;
 /// protocol code for DOMAIN type
-const DOMAIN: u8 = 0x03;
+pub const DOMAIN: u8 = 0x03;
 /// protocol code for IPV6 type
 const IPV6: u8 = 0x04;
+/// signature starting a PROXY protocol v2 header
+const PROXY_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+/// request line prefixes of http/1 methods and the http/2 preface
+const HTTP_METHODS: &[&[u8]] = &[
+    b"GET ",
+    b"POST ",
+    b"HEAD ",
+    b"PUT ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"CONNECT ",
+    b"PATCH ",
+    b"TRACE ",
+    b"PRI * HTTP/2",
+];
+
+/// data may still become a trojan request once more bytes arrive, that is a prefix of the
+/// hex password hash, CRLF and a valid command
+pub fn maybe_request(buffer: &[u8], pass_len: usize) -> bool {
+    let hash = &buffer[..buffer.len().min(pass_len)];
+    if !hash.iter().all(u8::is_ascii_hexdigit) {
+        return false;
+    }
+    let rest = &buffer[hash.len()..];
+    rest.iter().zip(b"\r\n").all(|(c, expected)| c == expected)
+        && rest.get(2).map_or(true, |cmd| {
+            let cmd = *cmd & !DEADLINE_FLAG;
+            cmd == CONNECT || cmd == UDP_ASSOCIATE
+        })
+}
 
 /// Trojan Socks5 address enum
+#[derive(Debug)]
 pub enum Sock5Address {
     Socket(SocketAddr),
     // IP address
@@ -32,11 +69,21 @@ pub enum Sock5Address {
 pub struct TrojanRequest<'a> {
     pub command: u8,
     pub address: Sock5Address,
+    /// seconds after which the client wants the connection closed
+    pub deadline: Option<u32>,
+    /// target domain, kept when the address was taken from static hosts or dns cache
+    pub domain: Option<String>,
+    /// user whose password the request carries
+    pub user: String,
     pub payload: &'a [u8],
 }
 
 impl<'a> TrojanRequest<'a> {
-    pub fn parse(mut buffer: &'a [u8], opts: &mut Opts) -> Option<TrojanRequest<'a>> {
+    pub fn parse(
+        mut buffer: &'a [u8],
+        opts: &mut Opts,
+        prefer_ipv6: bool,
+    ) -> Option<TrojanRequest<'a>> {
         if buffer.len() < opts.pass_len {
             log::debug!(
                 "data length:{} is too short for a trojan request",
@@ -45,13 +92,16 @@ impl<'a> TrojanRequest<'a> {
             return None;
         }
 
-        let pass = String::from_utf8_lossy(&buffer[..opts.pass_len]);
-        if let Some(orig) = opts.check_pass(&pass) {
-            log::debug!("request using password:{}", &orig);
-        } else {
-            log::debug!("request didn't find matched password");
-            return None;
-        }
+        let user = match opts.check_pass(&buffer[..opts.pass_len]) {
+            Some(user) => {
+                log::debug!("request password of user {} matched", user);
+                user.to_string()
+            }
+            None => {
+                log::debug!("request didn't find matched password");
+                return None;
+            }
+        };
 
         buffer = &buffer[opts.pass_len..];
         if buffer.len() < 2 || buffer[0] != b'\r' || buffer[1] != b'\n' {
@@ -68,7 +118,13 @@ impl<'a> TrojanRequest<'a> {
             log::error!("unknown protocol, invalid size");
             return None;
         }
-        if buffer[0] != CONNECT && buffer[0] != UDP_ASSOCIATE {
+        let extended = buffer[0] & DEADLINE_FLAG != 0 && opts.server_args().allow_client_deadline;
+        let command = if extended {
+            buffer[0] & !DEADLINE_FLAG
+        } else {
+            buffer[0]
+        };
+        if command != CONNECT && command != UDP_ASSOCIATE {
             log::error!(
                 "unknown protocol, expected valid command, found:{}",
                 buffer[0]
@@ -76,19 +132,39 @@ impl<'a> TrojanRequest<'a> {
             return None;
         }
 
-        let command = buffer[0];
         let atyp = buffer[1];
         buffer = &buffer[2..];
-        if let Some((size, address)) = parse_address(atyp, buffer, opts) {
+        if let Some((size, address)) = parse_address(atyp, buffer, opts, prefer_ipv6) {
+            let domain = match &address {
+                Sock5Address::Socket(_) if atyp == DOMAIN => {
+                    Some(String::from_utf8_lossy(&buffer[1..size - 2]).into())
+                }
+                _ => None,
+            };
             buffer = &buffer[size..];
             if buffer[0] != b'\r' || buffer[1] != b'\n' {
                 log::error!("unknown protocol, expected CRLF after address");
                 return None;
             }
+            buffer = &buffer[2..];
+            let deadline = if extended {
+                if buffer.len() < 4 {
+                    log::error!("unknown protocol, invalid deadline");
+                    return None;
+                }
+                let deadline = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]);
+                buffer = &buffer[4..];
+                Some(deadline)
+            } else {
+                None
+            };
             Some(TrojanRequest {
                 command,
                 address,
-                payload: &buffer[2..],
+                deadline,
+                domain,
+                user,
+                payload: buffer,
             })
         } else {
             None
@@ -96,17 +172,35 @@ impl<'a> TrojanRequest<'a> {
     }
 
     pub fn generate(buffer: &mut BytesMut, cmd: u8, addr: &SocketAddr, opts: &Opts) {
+        TrojanRequest::generate_address(buffer, cmd, &Sock5Address::Socket(*addr), opts);
+    }
+
+    /// like `generate`, domain targets are resolved by the server
+    pub fn generate_address(buffer: &mut BytesMut, cmd: u8, address: &Sock5Address, opts: &Opts) {
+        let deadline = opts.proxy_args().request_deadline;
         buffer.extend_from_slice(opts.get_pass().as_bytes());
         buffer.put_u8(b'\r');
         buffer.put_u8(b'\n');
-        buffer.put_u8(cmd);
-        Sock5Address::generate(buffer, addr);
+        if deadline > 0 {
+            buffer.put_u8(cmd | DEADLINE_FLAG);
+        } else {
+            buffer.put_u8(cmd);
+        }
+        address.write(buffer);
         buffer.put_u8(b'\r');
         buffer.put_u8(b'\n');
+        if deadline > 0 {
+            buffer.put_u32(deadline);
+        }
     }
 }
 
-fn parse_address(atyp: u8, buffer: &[u8], opts: &mut Opts) -> Option<(usize, Sock5Address)> {
+fn parse_address(
+    atyp: u8,
+    buffer: &[u8],
+    opts: &mut Opts,
+    prefer_ipv6: bool,
+) -> Option<(usize, Sock5Address)> {
     match atyp {
         IPV4 => {
             log::debug!("ipv4 address found");
@@ -130,9 +224,9 @@ fn parse_address(atyp: u8, buffer: &[u8], opts: &mut Opts) -> Option<(usize, Soc
             }
             let domain: String = String::from_utf8_lossy(&buffer[1..length + 1]).into();
             let port = to_u16(&buffer[length + 1..]);
-            if let Ok(ip) = domain.parse::<IpAddr>() {
-                Some((length + 3, Sock5Address::Socket(SocketAddr::new(ip, port))))
-            } else if let Some(ip) = opts.query_dns(&domain) {
+            if let Some(addr) = parse_ip(&domain, port) {
+                Some((length + 3, Sock5Address::Socket(addr)))
+            } else if let Some(ip) = opts.query_dns(&domain, prefer_ipv6) {
                 Some((length + 3, Sock5Address::Socket(SocketAddr::new(ip, port))))
             } else {
                 log::debug!("domain found:{}:{}", domain, port);
@@ -177,6 +271,8 @@ pub struct UdpAssociate<'a> {
 
 pub enum UdpParseResult<'a> {
     Packet(UdpAssociate<'a>),
+    /// packet larger than `udp_max_datagram`, should be skipped
+    Oversized(UdpAssociate<'a>),
     InvalidProtocol,
     Continued,
 }
@@ -189,16 +285,12 @@ impl<'a> UdpAssociate<'a> {
         }
         let atyp = buffer[0];
         buffer = &buffer[1..];
-        if let Some((size, addr)) = parse_address(atyp, buffer, opts) {
+        if let Some((size, addr)) = parse_address(atyp, buffer, opts, false) {
             buffer = &buffer[size..];
             if buffer.len() < 4 {
                 return UdpParseResult::Continued;
             }
             let length = to_u16(buffer) as usize;
-            if length > MAX_PACKET_SIZE {
-                log::error!("udp packet size:{} is too long", length);
-                return UdpParseResult::InvalidProtocol;
-            }
             if buffer.len() < length + 4 {
                 return UdpParseResult::Continued;
             }
@@ -207,11 +299,18 @@ impl<'a> UdpAssociate<'a> {
                 return UdpParseResult::InvalidProtocol;
             }
             match addr {
-                Sock5Address::Socket(address) => UdpParseResult::Packet(UdpAssociate {
-                    address,
-                    length,
-                    payload: &buffer[4..],
-                }),
+                Sock5Address::Socket(address) => {
+                    let packet = UdpAssociate {
+                        address,
+                        length,
+                        payload: &buffer[4..],
+                    };
+                    if length > opts.udp_max_datagram {
+                        UdpParseResult::Oversized(packet)
+                    } else {
+                        UdpParseResult::Packet(packet)
+                    }
+                }
                 _ => {
                     log::warn!("udp packet only accept ip address");
                     UdpParseResult::InvalidProtocol
@@ -230,6 +329,124 @@ impl<'a> UdpAssociate<'a> {
     }
 }
 
+/// ip address in a domain target, ipv6 may come with a scope like fe80::1%eth0 or fe80::1%2,
+/// an unknown interface leaves scope id 0 and the target is rejected later
+fn parse_ip(text: &str, port: u16) -> Option<SocketAddr> {
+    if let Ok(ip) = text.parse::<IpAddr>() {
+        return Some(SocketAddr::new(ip, port));
+    }
+    let pos = text.find('%')?;
+    let ip = text[..pos].parse::<Ipv6Addr>().ok()?;
+    let scope = &text[pos + 1..];
+    let scope_id = scope
+        .parse::<u32>()
+        .ok()
+        .or_else(|| sys::if_index(scope))
+        .unwrap_or_else(|| {
+            log::warn!("unknown interface {} in target {}", scope, text);
+            0
+        });
+    Some(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id)))
+}
+
+/// whether data received after tls handshake looks like the start of an http request
+pub fn is_http_request(buffer: &[u8]) -> bool {
+    HTTP_METHODS.iter().any(|method| buffer.starts_with(method))
+}
+
+/// extension types of a tls client hello in the first record, None if it is not one
+pub fn hello_extensions(buffer: &[u8]) -> Option<Vec<u16>> {
+    if buffer.len() < 5 || buffer[0] != 0x16 {
+        return None;
+    }
+    let record = buffer.get(5..5 + to_u16(&buffer[3..]) as usize)?;
+    if record.first() != Some(&0x01) {
+        return None;
+    }
+    // handshake header, client version and random
+    let mut pos = 38;
+    pos += 1 + *record.get(pos)? as usize;
+    pos += 2 + to_u16(record.get(pos..pos + 2)?) as usize;
+    pos += 1 + *record.get(pos)? as usize;
+    let mut extensions = Vec::new();
+    if pos == record.len() {
+        return Some(extensions);
+    }
+    let end = pos + 2 + to_u16(record.get(pos..pos + 2)?) as usize;
+    if end > record.len() {
+        return None;
+    }
+    pos += 2;
+    while pos + 4 <= end {
+        extensions.push(to_u16(&record[pos..]));
+        pos += 4 + to_u16(&record[pos + 2..]) as usize;
+    }
+    Some(extensions)
+}
+
+/// target of an http proxy request header like `CONNECT host:port HTTP/1.1`,
+/// None if it is not a valid CONNECT request
+pub fn parse_http_connect(header: &[u8]) -> Option<Sock5Address> {
+    let line = std::str::from_utf8(header).ok()?.lines().next()?;
+    let mut parts = line.split(' ');
+    let (method, authority, version) = (parts.next()?, parts.next()?, parts.next()?);
+    if method != "CONNECT" || !version.starts_with("HTTP/1.") {
+        return None;
+    }
+    if let Ok(address) = authority.parse::<SocketAddr>() {
+        return Some(Sock5Address::Socket(address));
+    }
+    let pos = authority.rfind(':')?;
+    let (host, port) = (&authority[..pos], authority[pos + 1..].parse::<u16>().ok()?);
+    if host.is_empty() || host.len() > 255 || host.contains(&['[', ']'][..]) {
+        return None;
+    }
+    Some(Sock5Address::Domain(host.to_string(), port))
+}
+
+/// PROXY protocol v2 header telling a target the client `src` connected to `dst`, addresses of
+/// different families are both sent as ipv6. the alpn protocol negotiated with the client
+/// follows the addresses if there is one
+pub fn proxy_header(
+    buffer: &mut BytesMut,
+    src: &SocketAddr,
+    dst: &SocketAddr,
+    alpn: Option<&[u8]>,
+) {
+    buffer.extend_from_slice(PROXY_SIGNATURE);
+    // version 2, PROXY command
+    buffer.put_u8(0x21);
+    // type, length and value of PP2_TYPE_ALPN
+    let tlv_len = alpn.map_or(0, |alpn| 3 + alpn.len()) as u16;
+    match (src, dst) {
+        (SocketAddr::V4(src), SocketAddr::V4(dst)) => {
+            // TCP over IPv4
+            buffer.put_u8(0x11);
+            buffer.put_u16(12 + tlv_len);
+            buffer.extend_from_slice(&src.ip().octets()[..]);
+            buffer.extend_from_slice(&dst.ip().octets()[..]);
+        }
+        _ => {
+            let ipv6 = |addr: &SocketAddr| match addr.ip() {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            // TCP over IPv6
+            buffer.put_u8(0x21);
+            buffer.put_u16(36 + tlv_len);
+            buffer.extend_from_slice(&ipv6(src).octets()[..]);
+            buffer.extend_from_slice(&ipv6(dst).octets()[..]);
+        }
+    }
+    buffer.put_u16(src.port());
+    buffer.put_u16(dst.port());
+    if let Some(alpn) = alpn {
+        buffer.put_u8(0x01);
+        buffer.put_u16(alpn.len() as u16);
+        buffer.extend_from_slice(alpn);
+    }
+}
+
 fn to_u16(buffer: &[u8]) -> u16 {
     (buffer[0] as u16) << 8 | buffer[1] as u16
 }
@@ -250,4 +467,168 @@ impl Sock5Address {
         };
         buffer.put_u16(port);
     }
+
+    pub fn write(&self, buffer: &mut BytesMut) {
+        match self {
+            Sock5Address::Socket(address) => Sock5Address::generate(buffer, address),
+            Sock5Address::Domain(domain, port) => {
+                buffer.put_u8(DOMAIN);
+                buffer.put_u8(domain.len() as u8);
+                buffer.extend_from_slice(domain.as_bytes());
+                buffer.put_u16(*port);
+            }
+            Sock5Address::None => {}
+        }
+    }
+}
+
+impl Display for Sock5Address {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Sock5Address::Socket(address) => write!(f, "{}", address),
+            Sock5Address::Domain(domain, port) => write!(f, "{}:{}", domain, port),
+            Sock5Address::None => write!(f, "-"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scoped_ipv6() {
+        let scope_id = |text: &str| match parse_ip(text, 80) {
+            Some(SocketAddr::V6(v6)) => Some(v6.scope_id()),
+            _ => None,
+        };
+        assert_eq!(scope_id("fe80::1"), Some(0));
+        assert_eq!(scope_id("fe80::1%3"), Some(3));
+        assert_eq!(scope_id("fe80::1%lo"), sys::if_index("lo"));
+        assert_eq!(scope_id("fe80::1%no-such-interface"), Some(0));
+        assert_eq!(scope_id("127.0.0.1%1"), None);
+        assert_eq!(scope_id("example.com"), None);
+    }
+
+    #[test]
+    fn address_display() {
+        let socket = Sock5Address::Socket("[::1]:443".parse().unwrap());
+        assert_eq!(socket.to_string(), "[::1]:443");
+        let domain = Sock5Address::Domain("example.com".to_string(), 80);
+        assert_eq!(domain.to_string(), "example.com:80");
+        assert_eq!(Sock5Address::None.to_string(), "-");
+    }
+
+    #[test]
+    fn partial_request() {
+        let hash = "5755991b19ac5a6a159c53ae02466466c78f67b9f3008719affa2da4";
+        assert!(maybe_request(b"", 56));
+        assert!(maybe_request(&hash.as_bytes()[..20], 56));
+        assert!(maybe_request(format!("{}\r", hash).as_bytes(), 56));
+        assert!(maybe_request(
+            format!("{}\r\n\x01\x03", hash).as_bytes(),
+            56
+        ));
+        assert!(!maybe_request(b"GET / HTTP/1.1", 56));
+        assert!(!maybe_request(b"\x16\x03\x01", 56));
+        assert!(!maybe_request(format!("{}\n", hash).as_bytes(), 56));
+        assert!(!maybe_request(format!("{}\r\n\x02", hash).as_bytes(), 56));
+        let flagged = [
+            format!("{}\r\n", hash).as_bytes(),
+            &[CONNECT | DEADLINE_FLAG],
+        ]
+        .concat();
+        assert!(maybe_request(flagged.as_slice(), 56));
+    }
+
+    #[test]
+    fn http_connect() {
+        let target = |header: &[u8]| parse_http_connect(header).map(|address| address.to_string());
+        let some = |target: &str| Some(target.to_string());
+        assert_eq!(
+            target(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443"),
+            some("example.com:443")
+        );
+        assert_eq!(target(b"CONNECT 10.0.0.1:80 HTTP/1.0"), some("10.0.0.1:80"));
+        assert!(matches!(
+            parse_http_connect(b"CONNECT [::1]:443 HTTP/1.1"),
+            Some(Sock5Address::Socket(addr)) if addr == "[::1]:443".parse().unwrap()
+        ));
+        // missing port
+        assert_eq!(target(b"CONNECT example.com HTTP/1.1"), None);
+        assert_eq!(target(b"CONNECT example.com: HTTP/1.1"), None);
+        assert_eq!(target(b"CONNECT [::1] HTTP/1.1"), None);
+        // not CONNECT
+        assert_eq!(target(b"GET http://example.com/ HTTP/1.1"), None);
+        assert_eq!(target(b"connect example.com:443 HTTP/1.1"), None);
+        // truncated
+        assert_eq!(target(b"CONNECT example.com:443"), None);
+        assert_eq!(target(b"CONNECT"), None);
+        assert_eq!(target(b""), None);
+        // oversized host
+        let header = format!("CONNECT {}:443 HTTP/1.1", "a".repeat(255));
+        assert!(target(header.as_bytes()).is_some());
+        let header = format!("CONNECT {}:443 HTTP/1.1", "a".repeat(256));
+        assert_eq!(target(header.as_bytes()), None);
+    }
+
+    #[test]
+    fn proxy_headers() {
+        let mut buffer = BytesMut::new();
+        let src = "10.0.0.1:1234".parse().unwrap();
+        let dst = "10.0.0.2:443".parse().unwrap();
+        proxy_header(&mut buffer, &src, &dst, None);
+        assert_eq!(&buffer[..12], PROXY_SIGNATURE);
+        assert_eq!(
+            &buffer[12..],
+            &[0x21, 0x11, 0, 12, 10, 0, 0, 1, 10, 0, 0, 2, 0x04, 0xd2, 0x01, 0xbb]
+        );
+
+        let mut buffer = BytesMut::new();
+        proxy_header(&mut buffer, &src, &dst, Some(b"h2"));
+        assert_eq!(&buffer[14..16], &[0, 17]);
+        assert_eq!(&buffer[28..], &[0x01, 0, 2, b'h', b'2']);
+
+        let mut buffer = BytesMut::new();
+        proxy_header(
+            &mut buffer,
+            &src,
+            &"[2001:db8::1]:443".parse().unwrap(),
+            None,
+        );
+        assert_eq!(buffer.len(), 16 + 36);
+        assert_eq!(&buffer[12..16], &[0x21, 0x21, 0, 36]);
+        let mapped = Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped();
+        assert_eq!(&buffer[16..32], &mapped.octets()[..]);
+        let dst: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        assert_eq!(&buffer[32..48], &dst.octets()[..]);
+        assert_eq!(&buffer[48..], &[0x04, 0xd2, 0x01, 0xbb]);
+    }
+
+    #[test]
+    fn client_hello() {
+        use std::sync::Arc;
+
+        use rustls::{ClientConfig, ClientSession, Session};
+        use webpki::DNSNameRef;
+
+        let hostname = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let mut session = ClientSession::new(&Arc::new(ClientConfig::new()), hostname);
+        let mut hello = Vec::new();
+        session.write_tls(&mut hello).unwrap();
+        let extensions = hello_extensions(hello.as_slice()).unwrap();
+        for extension in &[0, 10, 13, 43, 51] {
+            assert!(extensions.contains(extension));
+        }
+
+        // tls 1.2 hello with one cipher suite and no extensions
+        let mut minimal = vec![
+            0x16, 0x03, 0x01, 0x00, 0x2d, 0x01, 0x00, 0x00, 0x29, 0x03, 0x03,
+        ];
+        minimal.extend_from_slice(&[0u8; 32]);
+        minimal.extend_from_slice(&[0x00, 0x00, 0x02, 0xc0, 0x2f, 0x01, 0x00]);
+        assert_eq!(hello_extensions(minimal.as_slice()), Some(Vec::new()));
+        assert_eq!(hello_extensions(&minimal[..20]), None);
+        assert_eq!(hello_extensions(b"GET / HTTP/1.1\r\n"), None);
+    }
 }
@@ -1,10 +1,11 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use mio::net::TcpStream;
 use mio::{Event, Poll, PollOpt, Ready, Token};
 use rustls::{ClientConfig, ClientSession};
-use webpki::DNSName;
+use webpki::{DNSName, DNSNameRef};
 
 use crate::config::Opts;
 use crate::proxy::{next_index, CHANNEL_CNT, CHANNEL_IDLE, MIN_INDEX, RESOLVER};
@@ -12,42 +13,89 @@ use crate::resolver::EventedResolver;
 use crate::sys;
 use crate::tls_conn::TlsConn;
 
+/// a trojan server, the one given by hostname comes first, then backups in preference order
+struct Node {
+    domain: String,
+    port: u16,
+    addr: Option<SocketAddr>,
+    hostname: DNSName,
+    /// skipped until this time after a failure
+    down_until: Option<Instant>,
+}
+
+impl Node {
+    fn up(&self, now: Instant) -> bool {
+        self.addr.is_some() && !matches!(self.down_until, Some(time) if time > now)
+    }
+}
+
 pub struct IdlePool {
-    pool: Vec<TlsConn<ClientSession>>,
+    /// idle connections with the node they connect to
+    pool: Vec<(usize, TlsConn<ClientSession>)>,
     next_index: usize,
     size: usize,
-    addr: SocketAddr,
-    domain: String,
-    port: u16,
+    nodes: Vec<Node>,
+    down_time: Duration,
     marker: u8,
+    /// high water of server connections handed out
+    high_water: usize,
     config: Arc<ClientConfig>,
-    resolver: Option<EventedResolver>,
-    hostname: DNSName,
+    /// node being resolved and its resolver
+    resolver: Option<(usize, EventedResolver)>,
 }
 
 impl IdlePool {
     pub fn new(opts: &Opts, config: Arc<ClientConfig>, hostname: DNSName) -> IdlePool {
+        let mut nodes = vec![Node {
+            domain: opts.proxy_args().hostname.clone(),
+            port: opts.proxy_args().port,
+            addr: opts.back_addr,
+            hostname: hostname.clone(),
+            down_until: None,
+        }];
+        for (domain, port) in &opts.backup_nodes {
+            let addr = domain
+                .parse::<IpAddr>()
+                .ok()
+                .map(|ip| SocketAddr::new(ip, *port));
+            // ip addresses are not valid server names, verify with the primary one instead
+            let hostname = DNSNameRef::try_from_ascii_str(domain)
+                .map(|name| name.to_owned())
+                .unwrap_or_else(|_| hostname.clone());
+            nodes.push(Node {
+                domain: domain.clone(),
+                port: *port,
+                addr,
+                hostname,
+                down_until: None,
+            });
+        }
         IdlePool {
             size: opts.proxy_args().pool_size + 1,
-            addr: opts.back_addr.unwrap(),
+            nodes,
+            down_time: Duration::new(opts.proxy_args().node_down_time, 0),
             marker: opts.marker,
-            port: opts.proxy_args().port,
-            domain: opts.proxy_args().hostname.clone(),
+            high_water: opts.send_buffer_high_water,
             pool: Vec::new(),
             next_index: MIN_INDEX,
             resolver: None,
             config,
-            hostname,
         }
     }
 
     pub fn init(&mut self, poll: &Poll) {
+        self.resolve_next(poll);
         if self.size > 1 {
             self.alloc(poll);
         }
     }
 
     pub fn get(&mut self, poll: &Poll) -> Option<TlsConn<ClientSession>> {
+        self.get_node(poll).map(|(_, conn)| conn)
+    }
+
+    /// a server connection and the node it connects to
+    pub fn get_node(&mut self, poll: &Poll) -> Option<(usize, TlsConn<ClientSession>)> {
         self.alloc(poll);
         self.pool.pop()
     }
@@ -55,19 +103,57 @@ impl IdlePool {
     fn alloc(&mut self, poll: &Poll) {
         let size = self.pool.len();
         for _ in size..self.size {
-            let conn = self.new_conn();
-            if let Some(mut conn) = conn {
+            let node = match self.pick(Instant::now()) {
+                Some(node) => node,
+                None => {
+                    log::error!("no trojan server address available");
+                    self.resolve_next(poll);
+                    return;
+                }
+            };
+            if let Some(mut conn) = self.new_conn(node) {
                 if conn.register(poll) {
-                    self.pool.push(conn);
+                    self.pool.push((node, conn));
                 }
             } else {
-                self.update_dns(poll);
+                self.node_failed(node, poll);
+                self.update_dns(node, poll);
             }
         }
     }
 
-    fn new_conn(&mut self) -> Option<TlsConn<ClientSession>> {
-        let server = match TcpStream::connect(&self.addr) {
+    /// first node which is up, or the one coming back soonest if all of them are down
+    fn pick(&self, now: Instant) -> Option<usize> {
+        self.nodes.iter().position(|node| node.up(now)).or_else(|| {
+            (0..self.nodes.len())
+                .filter(|i| self.nodes[*i].addr.is_some())
+                .min_by_key(|i| self.nodes[*i].down_until)
+        })
+    }
+
+    /// mark a node down after connecting or tls handshake to it failed, idle connections of it
+    /// are dropped. returns whether there is another node to fail over to.
+    pub fn node_failed(&mut self, node: usize, poll: &Poll) -> bool {
+        let now = Instant::now();
+        if self.nodes[node].up(now) {
+            log::warn!(
+                "trojan server {}:{} is down, skipped for {:?}",
+                self.nodes[node].domain,
+                self.nodes[node].port,
+                self.down_time
+            );
+            self.nodes[node].down_until = Some(now + self.down_time);
+            for (_, conn) in self.pool.iter_mut().filter(|(i, _)| *i == node) {
+                conn.close_now(poll);
+            }
+            self.pool.retain(|(i, _)| *i != node);
+        }
+        self.nodes.iter().any(|node| node.up(now))
+    }
+
+    fn new_conn(&mut self, node: usize) -> Option<TlsConn<ClientSession>> {
+        let node = &self.nodes[node];
+        let server = match TcpStream::connect(node.addr.as_ref().unwrap()) {
             Ok(server) => {
                 if let Err(err) = sys::set_mark(&server, self.marker) {
                     log::error!("set mark failed:{}", err);
@@ -80,55 +166,70 @@ impl IdlePool {
                 }
             }
             Err(err) => {
-                log::error!("connection to server failed:{}", err);
+                log::error!("connection to server {} failed:{}", node.domain, err);
                 None
             }
         };
         if let Some(server) = server {
-            let session = ClientSession::new(&self.config, self.hostname.as_ref());
+            let session = ClientSession::new(&self.config, node.hostname.as_ref());
             let index = next_index(&mut self.next_index);
-            let conn = TlsConn::new(
+            let mut conn = TlsConn::new(
                 index,
                 Token(index * CHANNEL_CNT + CHANNEL_IDLE),
                 session,
                 server,
             );
+            conn.set_high_water(self.high_water);
             Some(conn)
         } else {
             None
         }
     }
 
-    fn update_dns(&mut self, poll: &Poll) {
-        let resolver = EventedResolver::new(self.domain.clone());
-        self.resolver.replace(resolver);
+    /// resolve the first node without an address yet
+    fn resolve_next(&mut self, poll: &Poll) {
+        if let Some(node) = self.nodes.iter().position(|node| node.addr.is_none()) {
+            self.update_dns(node, poll);
+        }
+    }
+
+    fn update_dns(&mut self, node: usize, poll: &Poll) {
+        if self.resolver.is_some() || self.nodes[node].domain.parse::<IpAddr>().is_ok() {
+            return;
+        }
+        let resolver = EventedResolver::new(self.nodes[node].domain.clone());
         if let Err(err) = poll.register(
-            self.resolver.as_ref().unwrap(),
+            &resolver,
             Token(RESOLVER),
             Ready::readable(),
             PollOpt::level(),
         ) {
             log::error!("idle_pool register resolver failed:{}", err);
-            let _ = self.resolver.take();
+            return;
         }
+        self.resolver.replace((node, resolver));
     }
 
     pub fn resolve(&mut self, poll: &Poll) {
-        if let Some(address) = self.resolver.as_ref().unwrap().address() {
-            log::debug!("idle_pool got resolve result {} = {}", self.domain, address);
-            let addr = SocketAddr::new(address, self.port);
-            self.addr = addr;
+        let (index, resolver) = self.resolver.take().unwrap();
+        let _ = poll.deregister(&resolver);
+        let node = &mut self.nodes[index];
+        if let Some(address) = resolver.address(false) {
+            log::debug!("idle_pool got resolve result {} = {}", node.domain, address);
+            let resolved = node.addr.is_none();
+            node.addr = Some(SocketAddr::new(address, node.port));
+            if resolved {
+                self.resolve_next(poll);
+            }
         } else {
-            log::error!("idle_pool resolve host:{} failed", self.domain);
+            log::error!("idle_pool resolve host:{} failed", node.domain);
         }
-        let _ = poll.deregister(self.resolver.as_ref().unwrap());
-        let _ = self.resolver.take();
     }
 
     pub fn ready(&mut self, event: &Event, poll: &Poll) {
         let mut found = false;
         for i in 0..self.pool.len() {
-            let conn = self.pool.get_mut(i).unwrap();
+            let (_, conn) = self.pool.get_mut(i).unwrap();
             if conn.token() == event.token() {
                 if event.readiness().is_readable() && conn.do_read().is_some() {
                     log::error!("found data in https handshake phase");
@@ -139,7 +240,10 @@ impl IdlePool {
                 conn.reregister(poll, true);
                 conn.check_close(poll);
                 if conn.closed() {
-                    self.pool.remove(i);
+                    let (node, conn) = self.pool.remove(i);
+                    if conn.is_handshaking() {
+                        self.node_failed(node, poll);
+                    }
                 }
                 found = true;
                 break;
@@ -26,6 +26,7 @@ const MAX_INDEX: usize = std::usize::MAX / CHANNEL_CNT;
 const TCP_LISTENER: usize = 1;
 const UDP_LISTENER: usize = 2;
 const RESOLVER: usize = 3;
+const HTTP_LISTENER: usize = 4;
 const CHANNEL_CNT: usize = 4;
 const CHANNEL_IDLE: usize = 0;
 const CHANNEL_UDP: usize = 1;
@@ -85,6 +86,15 @@ pub fn new_socket(addr: SocketAddr, is_udp: bool) -> Option<Socket> {
 }
 
 pub fn run(opts: &mut Opts) {
+    let mut config = ClientConfig::new();
+    config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    serve(opts, Arc::new(config));
+}
+
+/// serve clients through the trojan servers, whose certificates are verified with `config`
+pub fn serve(opts: &mut Opts, config: Arc<ClientConfig>) {
     let addr: SocketAddr = opts.local_addr.parse().unwrap();
     let tcp_listener = TcpListener::from_std(new_socket(addr, false).unwrap().into_tcp_listener()).unwrap();
     let udp_listener = UdpSocket::from_socket(new_socket(addr, true).unwrap().into_udp_socket()).unwrap();
@@ -112,24 +122,36 @@ pub fn run(opts: &mut Opts) {
     let hostname = DNSNameRef::try_from_ascii(opts.proxy_args().hostname.as_bytes())
         .unwrap()
         .to_owned();
-    let mut config = ClientConfig::new();
-    config
-        .root_store
-        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
-    let config = Arc::new(config);
 
-    let mut tcp_server = TcpServer::new(tcp_listener);
+    let http_listener = opts.proxy_args().http_addr.as_ref().map(|addr| {
+        let addr = addr.parse().unwrap();
+        let listener = TcpListener::bind(&addr).unwrap();
+        poll.register(
+            &listener,
+            Token(HTTP_LISTENER),
+            Ready::readable(),
+            PollOpt::edge(),
+        )
+        .unwrap();
+        log::warn!("http proxy listening on {}", addr);
+        listener
+    });
+    let mut tcp_server = TcpServer::new(tcp_listener, http_listener);
     let mut udp_server = UdpServer::new(udp_listener);
 
-    let mut events = Events::with_capacity(1024);
+    let mut events = Events::with_capacity(opts.poll_events);
     let mut last_check_time = Instant::now();
     let check_duration = Duration::new(1, 0);
 
     let mut pool = IdlePool::new(opts, config, hostname);
     pool.init(&poll);
+    if let Some(notifier) = &opts.notifier {
+        notifier.ready();
+    }
 
     loop {
-        let nevent = poll.poll(&mut events, Some(check_duration)).unwrap();
+        let timeout = tcp_server.retry_timeout(Instant::now(), check_duration);
+        let nevent = poll.poll(&mut events, Some(timeout)).unwrap();
         log::trace!("poll got {} events", nevent);
         for event in &events {
             log::trace!("dispatch token:{}", event.token().0);
@@ -143,6 +165,9 @@ pub fn run(opts: &mut Opts) {
                 Token(RESOLVER) => {
                     pool.resolve(&poll);
                 }
+                Token(HTTP_LISTENER) => {
+                    tcp_server.accept_http(opts, &poll, &mut pool);
+                }
                 Token(i) if i % CHANNEL_CNT == CHANNEL_IDLE => {
                     pool.ready(&event, &poll);
                 }
@@ -150,14 +175,18 @@ pub fn run(opts: &mut Opts) {
                     udp_server.ready(&event, opts, &poll, &mut udp_cache);
                 }
                 _ => {
-                    tcp_server.ready(&event, &poll);
+                    tcp_server.ready(&event, opts, &poll, &mut pool);
                 }
             }
         }
         let now = Instant::now();
+        tcp_server.check_retry(now, opts, &poll, &mut pool);
         if now - last_check_time > opts.udp_idle_duration {
             udp_cache.check_timeout();
             last_check_time = now;
         }
+        if let Some(notifier) = opts.notifier.as_mut() {
+            notifier.watchdog(now);
+        }
     }
 }
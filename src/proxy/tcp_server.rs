@@ -1,8 +1,7 @@
-use std::collections::HashMap;
-use std::io::ErrorKind;
+use std::collections::{HashMap, HashSet};
+use std::io::{ErrorKind, Read};
 use std::net::Shutdown;
-use std::net::SocketAddr;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use bytes::BytesMut;
 use mio::net::{TcpListener, TcpStream};
@@ -10,36 +9,55 @@ use mio::{Event, Poll, PollOpt, Ready, Token};
 use rustls::ClientSession;
 
 use crate::config::Opts;
-use crate::proto::{TrojanRequest, CONNECT, MAX_BUFFER_SIZE, MAX_PACKET_SIZE};
+use crate::proto::{parse_http_connect, Sock5Address, TrojanRequest, CONNECT};
 use crate::proxy::idle_pool::IdlePool;
 use crate::proxy::{next_index, CHANNEL_CLIENT, CHANNEL_CNT, CHANNEL_TCP, MIN_INDEX};
 use crate::sys;
 use crate::tcp_util;
 use crate::tls_conn::{ConnStatus, TlsConn};
 
+/// max size of the request header from http proxy clients
+const MAX_HTTP_HEADER: usize = 8192;
+
 pub struct TcpServer {
     tcp_listener: TcpListener,
+    http_listener: Option<TcpListener>,
     conns: HashMap<usize, Connection>,
+    /// connections waiting to retry on another server connection
+    retrying: HashSet<usize>,
     next_id: usize,
 }
 
 struct Connection {
     index: usize,
-    dst_addr: SocketAddr,
+    dst_addr: Sock5Address,
+    /// header read from an http proxy client, tunnel starts after a complete CONNECT request
+    http_header: Option<Vec<u8>>,
     client: TcpStream,
     recv_buffer: Vec<u8>,
     send_buffer: BytesMut,
+    /// the server is not read while send_buffer holds this many bytes
+    high_water: usize,
     client_readiness: Ready,
     status: ConnStatus,
     client_time: Instant,
     server_conn: TlsConn<ClientSession>,
+    /// trojan server node of server_conn
+    node: usize,
+    bytes_read: u64,
+    bytes_sent: u64,
+    /// retries done after tls handshake to server failed
+    retries: usize,
+    retry_at: Option<Instant>,
 }
 
 impl TcpServer {
-    pub fn new(tcp_listener: TcpListener) -> TcpServer {
+    pub fn new(tcp_listener: TcpListener, http_listener: Option<TcpListener>) -> TcpServer {
         TcpServer {
             tcp_listener,
+            http_listener,
             conns: HashMap::new(),
+            retrying: HashSet::new(),
             next_id: MIN_INDEX,
         }
     }
@@ -58,19 +76,8 @@ impl TcpServer {
                     match sys::get_oridst_addr(&client) {
                         Ok(dst_addr) => {
                             log::info!("got new connection from:{} to:{}", src_addr, dst_addr);
-                            if let Some(mut conn) = pool.get(poll) {
-                                let index = next_index(&mut self.next_id);
-                                conn.reset_index(index, Token(index * CHANNEL_CNT + CHANNEL_TCP));
-                                let mut conn = Connection::new(index, conn, dst_addr, client);
-                                if conn.setup(opts, poll) {
-                                    self.conns.insert(conn.index(), conn);
-                                } else {
-                                    conn.shutdown(poll);
-                                    continue;
-                                }
-                            } else {
-                                log::error!("alloc new connection failed")
-                            }
+                            let dst_addr = Sock5Address::Socket(dst_addr);
+                            self.new_connection(client, dst_addr, opts, poll, pool);
                         }
                         Err(err) => {
                             log::error!("get original destination address failed:{}", err);
@@ -89,13 +96,94 @@ impl TcpServer {
         }
     }
 
-    pub fn ready(&mut self, event: &Event, poll: &Poll) {
+    pub fn accept_http(&mut self, opts: &mut Opts, poll: &Poll, pool: &mut IdlePool) {
+        loop {
+            match self.http_listener.as_ref().unwrap().accept() {
+                Ok((client, src_addr)) => {
+                    if let Err(err) = client.set_nodelay(true) {
+                        log::error!("set nodelay failed:{}", err);
+                        continue;
+                    }
+                    log::info!("got new http proxy connection from:{}", src_addr);
+                    self.new_connection(client, Sock5Address::None, opts, poll, pool);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(err) => {
+                    log::error!("accept http failed:{}", err);
+                    continue;
+                }
+            }
+        }
+    }
+
+    /// `dst_addr` is None for http proxy clients, it is known after the CONNECT request
+    fn new_connection(
+        &mut self,
+        client: TcpStream,
+        dst_addr: Sock5Address,
+        opts: &mut Opts,
+        poll: &Poll,
+        pool: &mut IdlePool,
+    ) {
+        if let Some((node, mut conn)) = pool.get_node(poll) {
+            let index = next_index(&mut self.next_id);
+            conn.reset_index(index, Token(index * CHANNEL_CNT + CHANNEL_TCP));
+            let mut conn = Connection::new(index, conn, node, dst_addr, client, opts);
+            if conn.setup(opts, poll) {
+                self.conns.insert(conn.index(), conn);
+            } else {
+                conn.shutdown(poll);
+            }
+        } else {
+            log::error!("alloc new connection failed")
+        }
+    }
+
+    pub fn ready(&mut self, event: &Event, opts: &mut Opts, poll: &Poll, pool: &mut IdlePool) {
         let index = Connection::token2index(event.token());
         if let Some(conn) = self.conns.get_mut(&index) {
-            conn.ready(event, poll);
+            conn.ready(event, opts, poll, pool);
             if conn.destroyed() {
                 log::debug!("connection:{} removed from list", index);
                 self.conns.remove(&index);
+            } else if conn.retry_at.is_some() {
+                self.retrying.insert(index);
+            }
+        }
+    }
+
+    /// time until the earliest retry is due, at most `max`
+    pub fn retry_timeout(&self, now: Instant, max: Duration) -> Duration {
+        self.retrying
+            .iter()
+            .filter_map(|index| self.conns.get(index)?.retry_at)
+            .map(|time| time.saturating_duration_since(now))
+            .fold(max, Duration::min)
+    }
+
+    /// move connections with a due retry to new server connections
+    pub fn check_retry(&mut self, now: Instant, opts: &mut Opts, poll: &Poll, pool: &mut IdlePool) {
+        let due: Vec<usize> = self
+            .retrying
+            .iter()
+            .copied()
+            .filter(|index| {
+                let retry_at = self.conns.get(index).and_then(|conn| conn.retry_at);
+                !matches!(retry_at, Some(time) if time > now)
+            })
+            .collect();
+        for index in due {
+            self.retrying.remove(&index);
+            if let Some(conn) = self.conns.get_mut(&index) {
+                conn.retry(opts, poll, pool);
+                if conn.destroyed() {
+                    log::debug!("connection:{} removed from list", index);
+                    self.conns.remove(&index);
+                } else if conn.retry_at.is_some() {
+                    self.retrying.insert(index);
+                }
             }
         }
     }
@@ -105,19 +193,33 @@ impl Connection {
     fn new(
         index: usize,
         server_conn: TlsConn<ClientSession>,
-        dst_addr: SocketAddr,
+        node: usize,
+        dst_addr: Sock5Address,
         client: TcpStream,
+        opts: &Opts,
     ) -> Connection {
+        let http_header = if let Sock5Address::None = dst_addr {
+            Some(Vec::new())
+        } else {
+            None
+        };
         Connection {
             index,
             dst_addr,
+            http_header,
             client,
             server_conn,
+            node,
             client_readiness: Ready::empty(),
             status: ConnStatus::Established,
             send_buffer: BytesMut::new(),
-            recv_buffer: vec![0u8; MAX_PACKET_SIZE],
+            high_water: opts.send_buffer_high_water,
+            recv_buffer: vec![0u8; opts.recv_buffer_size],
             client_time: Instant::now(),
+            bytes_read: 0,
+            bytes_sent: 0,
+            retries: 0,
+            retry_at: None,
         }
     }
 
@@ -135,10 +237,11 @@ impl Connection {
 
     fn setup(&mut self, opts: &mut Opts, poll: &Poll) -> bool {
         self.server_conn.setup(poll);
-        let mut request = BytesMut::new();
+        if opts.proxy_args().upstream_retries > 0 || !opts.backup_nodes.is_empty() {
+            self.server_conn.record_replay();
+        }
         self.client_readiness = Ready::readable();
-        TrojanRequest::generate(&mut request, CONNECT, &self.dst_addr, opts);
-        if !self.server_conn.write_session(request.as_ref()) {
+        if self.http_header.is_none() && !self.send_request(&[], opts) {
             false
         } else if let Err(err) = poll.register(
             &self.client,
@@ -153,6 +256,13 @@ impl Connection {
         }
     }
 
+    fn send_request(&mut self, payload: &[u8], opts: &Opts) -> bool {
+        let mut request = BytesMut::new();
+        TrojanRequest::generate_address(&mut request, CONNECT, &self.dst_addr, opts);
+        request.extend_from_slice(payload);
+        self.server_conn.write_session(request.as_ref())
+    }
+
     fn index(&self) -> usize {
         self.index
     }
@@ -161,11 +271,15 @@ impl Connection {
         token.0 / CHANNEL_CNT
     }
 
-    fn ready(&mut self, event: &Event, poll: &Poll) {
+    fn ready(&mut self, event: &Event, opts: &mut Opts, poll: &Poll, pool: &mut IdlePool) {
         match event.token().0 % CHANNEL_CNT {
             CHANNEL_CLIENT => {
                 if event.readiness().is_readable() {
-                    self.try_read_client();
+                    if self.http_header.is_some() {
+                        self.try_read_http(opts);
+                    } else {
+                        self.try_read_client();
+                    }
                 }
                 if event.readiness().is_writable() {
                     self.try_send_client(&[]);
@@ -193,12 +307,85 @@ impl Connection {
         if self.closed() && !self.server_conn.closed() {
             self.server_conn.shutdown(poll);
         } else if !self.closed() && self.server_conn.closed() {
-            self.shutdown(poll);
+            let failover = self.server_conn.is_handshaking() && pool.node_failed(self.node, poll);
+            if !self.schedule_retry(opts, poll, failover) {
+                self.shutdown(poll);
+            }
+        }
+    }
+
+    /// server connection closed before tls handshake finished, it is retried on another one
+    /// at once when failing over to another node, otherwise after a backoff.
+    /// returns false if there is nothing to retry or retries are used up.
+    fn schedule_retry(&mut self, opts: &Opts, poll: &Poll, failover: bool) -> bool {
+        if self.retry_at.is_some() {
+            return true;
+        }
+        let args = opts.proxy_args();
+        if !self.server_conn.has_replay() {
+            return false;
+        }
+        if failover {
+            log::warn!(
+                "connection:{} tls handshake to server failed, fail over to next server",
+                self.index
+            );
+            self.retry_at = Some(Instant::now());
+        } else if self.retries < args.upstream_retries {
+            let backoff = Duration::from_millis(args.upstream_retry_backoff << self.retries);
+            self.retries += 1;
+            log::warn!(
+                "connection:{} tls handshake to server failed, retry {} in {:?}",
+                self.index,
+                self.retries,
+                backoff
+            );
+            self.retry_at = Some(Instant::now() + backoff);
+        } else {
+            return false;
+        }
+        // stop reading client until there is a new server connection
+        self.reregister(poll);
+        true
+    }
+
+    fn retry(&mut self, opts: &mut Opts, poll: &Poll, pool: &mut IdlePool) {
+        self.retry_at = None;
+        let (node, mut server_conn) = match pool.get_node(poll) {
+            Some(server_conn) => server_conn,
+            None => {
+                log::error!(
+                    "connection:{} alloc server connection for retry failed",
+                    self.index
+                );
+                if !self.schedule_retry(opts, poll, false) {
+                    self.shutdown(poll);
+                }
+                return;
+            }
+        };
+        let replay = self.server_conn.take_replay().unwrap_or_default();
+        log::info!(
+            "connection:{} retry with {} bytes on a new server connection",
+            self.index,
+            replay.len()
+        );
+        server_conn.reset_index(self.index, Token(self.index * CHANNEL_CNT + CHANNEL_TCP));
+        server_conn.record_replay();
+        self.server_conn = server_conn;
+        self.node = node;
+        self.server_conn.setup(poll);
+        if !self.server_conn.write_session(replay.as_slice()) {
+            self.status = ConnStatus::Closing;
+            self.check_close(poll);
+            return;
         }
+        self.reregister(poll);
+        self.try_send_server();
     }
 
     fn readable(&self) -> bool {
-        self.send_buffer.len() < MAX_BUFFER_SIZE
+        self.send_buffer.len() < self.high_water
     }
 
     fn shutdown(&mut self, poll: &Poll) {
@@ -235,10 +422,12 @@ impl Connection {
         self.status = ConnStatus::Closed;
         let secs = self.client_time.elapsed().as_secs();
         log::warn!(
-            "connection:{} closed, target address {:?}, {} seconds",
+            "connection:{} closed, target address {:?}, {} seconds, read {} bytes, sent {} bytes",
             self.index(),
             self.dst_addr,
-            secs
+            secs,
+            self.bytes_read,
+            self.bytes_sent
         );
     }
 
@@ -258,11 +447,12 @@ impl Connection {
                     self.client_readiness.remove(Ready::writable());
                     changed = true;
                 }
-                if self.server_conn.writable() && !self.client_readiness.is_readable() {
+                let readable = self.server_conn.writable() && self.retry_at.is_none();
+                if readable && !self.client_readiness.is_readable() {
                     self.client_readiness.insert(Ready::readable());
                     changed = true;
                 }
-                if !self.server_conn.writable() && self.client_readiness.is_readable() {
+                if !readable && self.client_readiness.is_readable() {
                     self.client_readiness.remove(Ready::readable());
                     changed = true;
                 }
@@ -297,6 +487,7 @@ impl Connection {
             &self.client,
             &mut self.recv_buffer,
             &mut self.server_conn,
+            &mut self.bytes_read,
         ) {
             self.status = ConnStatus::Closing;
         }
@@ -304,6 +495,63 @@ impl Connection {
         self.try_send_server();
     }
 
+    /// read http proxy request header, reply and start the tunnel once it is complete
+    fn try_read_http(&mut self, opts: &mut Opts) {
+        if let ConnStatus::Shutdown = self.status {
+            return;
+        }
+        let header = self.http_header.as_mut().unwrap();
+        loop {
+            match (&self.client).read(self.recv_buffer.as_mut_slice()) {
+                Ok(0) => {
+                    log::warn!("connection:{} http client closed", self.index);
+                    self.status = ConnStatus::Closing;
+                    return;
+                }
+                Ok(size) => header.extend_from_slice(&self.recv_buffer[..size]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    log::warn!("connection:{} read http client failed:{}", self.index, err);
+                    self.status = ConnStatus::Closing;
+                    return;
+                }
+            }
+        }
+        let pos = match header.windows(4).position(|window| window == b"\r\n\r\n") {
+            Some(pos) => pos,
+            None if header.len() > MAX_HTTP_HEADER => {
+                log::warn!("connection:{} http request header too large", self.index);
+                self.reject_http(b"HTTP/1.1 431 Request Header Fields Too Large\r\n\r\n");
+                return;
+            }
+            None => return,
+        };
+        let address = match parse_http_connect(&header[..pos]) {
+            Some(address) => address,
+            None => {
+                log::warn!("connection:{} got invalid http CONNECT request", self.index);
+                self.reject_http(b"HTTP/1.1 405 Method Not Allowed\r\nAllow: CONNECT\r\n\r\n");
+                return;
+            }
+        };
+        log::info!("connection:{} http CONNECT to {:?}", self.index, address);
+        let header = self.http_header.take().unwrap();
+        self.dst_addr = address;
+        if !self.send_request(&header[pos + 4..], opts) {
+            self.status = ConnStatus::Closing;
+            return;
+        }
+        self.bytes_read += (header.len() - pos - 4) as u64;
+        self.try_send_client(b"HTTP/1.1 200 Connection Established\r\n\r\n");
+        self.try_send_server();
+    }
+
+    /// send error response and close the client after it is sent
+    fn reject_http(&mut self, response: &[u8]) {
+        self.status = ConnStatus::Shutdown;
+        self.try_send_client(response);
+    }
+
     fn try_send_client(&mut self, buffer: &[u8]) {
         if self.send_buffer.is_empty() {
             self.do_send_client(buffer);
@@ -315,7 +563,13 @@ impl Connection {
     }
 
     fn do_send_client(&mut self, data: &[u8]) {
-        if !tcp_util::tcp_send(self.index, &self.client, &mut self.send_buffer, data) {
+        if !tcp_util::tcp_send(
+            self.index,
+            &self.client,
+            &mut self.send_buffer,
+            data,
+            &mut self.bytes_sent,
+        ) {
             self.status = ConnStatus::Closing;
             return;
         }
@@ -337,3 +591,96 @@ impl Connection {
         self.server_conn.do_send();
     }
 }
+
+#[cfg(all(test, feature = "test-support"))]
+mod client_tests {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::Arc;
+    use std::thread::{sleep, spawn};
+    use std::time::Duration;
+
+    use clap::Clap;
+
+    use crate::config::Opts;
+    use crate::proxy;
+    use crate::test_support::*;
+
+    /// proxy to `server` with an http listener, returns the http address
+    fn start_http_proxy(server: SocketAddr) -> SocketAddr {
+        let (local_addr, http_addr) = (free_addr().to_string(), free_addr());
+        let (port, http) = (server.port().to_string(), http_addr.to_string());
+        let args = [
+            "trojan",
+            "-a",
+            local_addr.as_str(),
+            "-p",
+            PASSWORD,
+            "proxy",
+            "-H",
+            "localhost",
+            "-o",
+            port.as_str(),
+            "--http-addr",
+            http.as_str(),
+        ];
+        let mut opts = Opts::parse_from(args.iter());
+        opts.setup();
+        opts.back_addr = Some(server);
+        spawn(move || proxy::serve(&mut opts, Arc::new(client_config())));
+        for _ in 0..100 {
+            if TcpStream::connect(http_addr).is_ok() {
+                return http_addr;
+            }
+            sleep(Duration::from_millis(10));
+        }
+        panic!("http proxy at {} not started", http_addr);
+    }
+
+    /// response header of the proxy, read up to the empty line
+    fn response_header(stream: &mut TcpStream) -> String {
+        let mut header = Vec::new();
+        let mut byte = [0u8; 1];
+        while !header.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap() == 1 {
+            header.push(byte[0]);
+        }
+        String::from_utf8(header).unwrap()
+    }
+
+    #[test]
+    fn http_connect_tunnel() {
+        let server = start_server(&["--allow-self-connect"]);
+        let echo = start_echo();
+        let proxy = start_http_proxy(server);
+        let mut client = TcpStream::connect(proxy).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        // data right after the header goes along with the trojan request
+        write!(
+            client,
+            "CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\nhello",
+            echo, echo
+        )
+        .unwrap();
+        assert_eq!(
+            response_header(&mut client),
+            "HTTP/1.1 200 Connection Established\r\n\r\n"
+        );
+        let mut buffer = [0u8; 5];
+        client.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"hello");
+
+        let mut client = TcpStream::connect(proxy).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let header = format!(
+            "CONNECT {} HTTP/1.1\r\nX-Padding: {}",
+            echo,
+            "a".repeat(8192)
+        );
+        client.write_all(header.as_bytes()).unwrap();
+        assert!(response_header(&mut client).starts_with("HTTP/1.1 431 "));
+    }
+}
@@ -11,7 +11,7 @@ use rustls::ClientSession;
 
 use crate::config::Opts;
 use crate::proto::{
-    MAX_BUFFER_SIZE, MAX_PACKET_SIZE, TrojanRequest, UDP_ASSOCIATE, UdpAssociate, UdpParseResult,
+    MAX_PACKET_SIZE, TrojanRequest, UDP_ASSOCIATE, UdpAssociate, UdpParseResult,
 };
 use crate::proxy::{CHANNEL_CNT, CHANNEL_UDP, MIN_INDEX, next_index};
 use crate::proxy::idle_pool::IdlePool;
@@ -31,14 +31,16 @@ struct Connection {
     index: usize,
     src_addr: SocketAddr,
     send_buffer: BytesMut,
+    /// the server is not read while send_buffer holds this many bytes
+    high_water: usize,
     recv_buffer: BytesMut,
     server_conn: TlsConn<ClientSession>,
     status: ConnStatus,
     client_time: Instant,
     socket: Rc<UdpSocket>,
     dst_addr: SocketAddr,
-    bytes_read: usize,
-    bytes_sent: usize,
+    bytes_read: u64,
+    bytes_sent: u64,
 }
 
 impl UdpServer {
@@ -98,6 +100,7 @@ impl UdpServer {
                                         conn,
                                         src_addr,
                                         socket,
+                                        opts,
                                     );
                                     if conn.setup(opts, poll) {
                                         let _ = self.conns.insert(index, conn);
@@ -163,6 +166,7 @@ impl Connection {
         server_conn: TlsConn<ClientSession>,
         src_addr: SocketAddr,
         socket: Rc<UdpSocket>,
+        opts: &Opts,
     ) -> Connection {
         let dst_addr = socket.local_addr().unwrap();
         Connection {
@@ -172,6 +176,7 @@ impl Connection {
             socket,
             dst_addr,
             send_buffer: BytesMut::new(),
+            high_water: opts.send_buffer_high_water,
             recv_buffer: BytesMut::new(),
             status: ConnStatus::Established,
             client_time: Instant::now(),
@@ -220,7 +225,7 @@ impl Connection {
             );
             return;
         }
-        self.bytes_read += payload.len();
+        self.bytes_read += payload.len() as u64;
         self.recv_buffer.clear();
         UdpAssociate::generate(&mut self.recv_buffer, dst_addr, payload.len() as u16);
         if !self.server_conn.write_session(self.recv_buffer.as_ref())
@@ -261,7 +266,7 @@ impl Connection {
     }
 
     fn readable(&self) -> bool {
-        self.send_buffer.len() < MAX_BUFFER_SIZE
+        self.send_buffer.len() < self.high_water
     }
 
     fn check_close(&mut self, poll: &Poll) {
@@ -321,7 +326,7 @@ impl Connection {
         }
         match self.socket.send_to(data, &self.src_addr) {
             Ok(size) => {
-                self.bytes_sent += size;
+                self.bytes_sent += size as u64;
                 log::debug!(
                     "send {} bytes upd data from {} to {}",
                     size,
@@ -355,6 +360,10 @@ impl Connection {
                     self.do_send_udp(packet.address, payload, udp_cache);
                     buffer = &packet.payload[packet.length..];
                 }
+                UdpParseResult::Oversized(packet) => {
+                    log::warn!("connection:{} drop {} bytes oversized udp packet", self.index(), packet.length);
+                    buffer = &packet.payload[packet.length..];
+                }
                 UdpParseResult::InvalidProtocol => {
                     log::error!("connection:{} got invalid protocol", self.index());
                     self.status = ConnStatus::Closing;
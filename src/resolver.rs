@@ -1,55 +1,209 @@
+use std::collections::{HashMap, VecDeque};
 use std::io::Error;
 use std::net::IpAddr;
 use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
 
-use mio::{Evented, Poll, PollOpt, Ready, Registration, Token};
+use mio::{Evented, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
 use trust_dns_resolver::Resolver;
 
+type LookupFn = fn(&str) -> Vec<IpAddr>;
+type Lookups = Arc<Mutex<Table>>;
+
+/// result of one dns query, shared by all resolvers waiting for the same domain
+#[derive(Default)]
+struct Lookup {
+    done: bool,
+    addresses: Vec<IpAddr>,
+    waiters: Vec<SetReadiness>,
+}
+
+/// queries running or queued, by domain
+#[derive(Default)]
+struct Table {
+    lookups: HashMap<String, Arc<Mutex<Lookup>>>,
+    running: usize,
+    queue: VecDeque<(String, Arc<Mutex<Lookup>>, LookupFn)>,
+}
+
+/// Dns queries on the way, concurrent resolvers of the same domain share one query.
+#[derive(Default)]
+pub struct Inflight {
+    lookups: Lookups,
+    /// max queries running at a time, 0 for no limit
+    max_running: usize,
+    /// max queries waiting for a running one to finish
+    max_queued: usize,
+}
+
 pub struct EventedResolver {
     registration: Registration,
-    address: Arc<Mutex<Option<IpAddr>>>,
+    lookup: Arc<Mutex<Lookup>>,
     handle: Option<JoinHandle<()>>,
 }
 
+fn system_lookup(domain: &str) -> Vec<IpAddr> {
+    if let Ok(resolver) = Resolver::from_system_conf() {
+        if let Ok(response) = resolver.lookup_ip(domain) {
+            return response.iter().collect();
+        }
+    }
+    Vec::new()
+}
+
+fn notify(set_readiness: &SetReadiness) {
+    if let Err(err) = set_readiness.set_readiness(Ready::readable()) {
+        log::error!("set readiness failed:{}", err);
+    }
+}
+
+/// run the query in a new thread, finishing it starts the first queued one
+fn start(
+    domain: String,
+    lookup: Arc<Mutex<Lookup>>,
+    lookup_fn: LookupFn,
+    lookups: Option<Lookups>,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let addresses = lookup_fn(domain.as_str());
+        // remove from inflight first, so later resolvers do a fresh query
+        if let Some(lookups) = lookups {
+            let mut table = lookups.lock().unwrap();
+            table.lookups.remove(&domain);
+            table.running -= 1;
+            if let Some((domain, lookup, lookup_fn)) = table.queue.pop_front() {
+                table.running += 1;
+                start(domain, lookup, lookup_fn, Some(lookups.clone()));
+            }
+        }
+        let mut state = lookup.lock().unwrap();
+        state.addresses = addresses;
+        state.done = true;
+        for set_readiness in state.waiters.drain(..) {
+            notify(&set_readiness);
+        }
+    })
+}
+
+impl Inflight {
+    /// limit queries of different domains running at a time, a query over the limit waits in a
+    /// queue of at most `max_queued`
+    pub fn with_limit(max_running: usize, max_queued: usize) -> Inflight {
+        Inflight {
+            max_running,
+            max_queued,
+            ..Default::default()
+        }
+    }
+
+    /// None if both running queries and the queue are full
+    pub fn resolve(&self, domain: String) -> Option<EventedResolver> {
+        self.resolve_with(domain, system_lookup)
+    }
+
+    fn resolve_with(&self, mut domain: String, lookup_fn: LookupFn) -> Option<EventedResolver> {
+        if !domain.ends_with('.') {
+            domain.push('.');
+        }
+        let mut table = self.lookups.lock().unwrap();
+        let (registration, set_readiness) = Registration::new2();
+        if let Some(lookup) = table.lookups.get(&domain) {
+            log::debug!("dns query of {} is on the way, wait for it", domain);
+            let mut state = lookup.lock().unwrap();
+            if state.done {
+                notify(&set_readiness);
+            } else {
+                state.waiters.push(set_readiness);
+            }
+            return Some(EventedResolver {
+                registration,
+                lookup: lookup.clone(),
+                handle: None,
+            });
+        }
+        let lookup = Arc::new(Mutex::new(Lookup::default()));
+        lookup.lock().unwrap().waiters.push(set_readiness);
+        let mut handle = None;
+        if self.max_running == 0 || table.running < self.max_running {
+            table.running += 1;
+            handle = Some(start(
+                domain.clone(),
+                lookup.clone(),
+                lookup_fn,
+                Some(self.lookups.clone()),
+            ));
+        } else if table.queue.len() < self.max_queued {
+            log::warn!(
+                "{} dns queries running, query of {} queued",
+                table.running,
+                domain
+            );
+            table
+                .queue
+                .push_back((domain.clone(), lookup.clone(), lookup_fn));
+        } else {
+            log::warn!(
+                "{} dns queries running and {} queued, query of {} refused",
+                table.running,
+                table.queue.len(),
+                domain
+            );
+            return None;
+        }
+        table.lookups.insert(domain, lookup.clone());
+        Some(EventedResolver {
+            registration,
+            lookup,
+            handle,
+        })
+    }
+}
+
 impl EventedResolver {
     pub fn new(mut domain: String) -> EventedResolver {
         if !domain.ends_with('.') {
             domain.push('.');
         }
         let (registration, set_readiness) = Registration::new2();
-        let address = Arc::new(Mutex::new(None));
-        let address2 = address.clone();
-        let handle = std::thread::spawn(move || {
-            if let Ok(resolver) = Resolver::from_system_conf() {
-                if let Ok(response) = resolver.lookup_ip(domain.as_str()) {
-                    let mut address = address2.lock().unwrap();
-                    for addr in response.iter() {
-                        if address.is_none() || addr.is_ipv4() {
-                            address.replace(addr);
-                        }
-                        if address.as_ref().unwrap().is_ipv4() {
-                            break;
-                        }
-                    }
-                }
-            }
-            if let Err(err) = set_readiness.set_readiness(Ready::readable()) {
-                log::error!("set readiness failed:{}", err);
-            }
-        });
+        let lookup = Arc::new(Mutex::new(Lookup::default()));
+        lookup.lock().unwrap().waiters.push(set_readiness);
+        let handle = start(domain, lookup.clone(), system_lookup, None);
         EventedResolver {
             registration,
-            address,
+            lookup,
             handle: Some(handle),
         }
     }
 
-    pub fn address(&self) -> Option<IpAddr> {
-        *self.address.lock().unwrap()
+    pub fn address(&self, prefer_ipv6: bool) -> Option<IpAddr> {
+        select_address(
+            self.lookup.lock().unwrap().addresses.as_slice(),
+            prefer_ipv6,
+        )
+    }
+
+    pub fn addresses(&self) -> Vec<IpAddr> {
+        self.lookup.lock().unwrap().addresses.clone()
     }
 }
 
+/// pick the first address of preferred family, any address if not found
+pub fn select_address(addresses: &[IpAddr], prefer_ipv6: bool) -> Option<IpAddr> {
+    addresses
+        .iter()
+        .find(|addr| addr.is_ipv6() == prefer_ipv6)
+        .or_else(|| addresses.first())
+        .copied()
+}
+
+/// the first address of the other family than `ip`
+pub fn other_family(addresses: &[IpAddr], ip: IpAddr) -> Option<IpAddr> {
+    addresses
+        .iter()
+        .find(|addr| addr.is_ipv6() != ip.is_ipv6())
+        .copied()
+}
+
 impl Evented for EventedResolver {
     fn register(
         &self,
@@ -80,6 +234,97 @@ impl Evented for EventedResolver {
 impl Drop for EventedResolver {
     fn drop(&mut self) {
         //FIXME is this necessary?
-        let _ = self.handle.take().unwrap().join();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use mio::Events;
+
+    use super::*;
+
+    static QUERIES: AtomicUsize = AtomicUsize::new(0);
+
+    fn slow_lookup(_: &str) -> Vec<IpAddr> {
+        QUERIES.fetch_add(1, Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(100));
+        vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]
+    }
+
+    #[test]
+    fn concurrent_resolves_share_one_query() {
+        let inflight = Inflight::default();
+        let poll = Poll::new().unwrap();
+        let resolvers: Vec<_> = (0..10)
+            .map(|i| {
+                let resolver = inflight
+                    .resolve_with("example.com".to_string(), slow_lookup)
+                    .unwrap();
+                poll.register(&resolver, Token(i), Ready::readable(), PollOpt::edge())
+                    .unwrap();
+                resolver
+            })
+            .collect();
+        let mut events = Events::with_capacity(16);
+        let mut ready = 0;
+        while ready < resolvers.len() {
+            ready += poll
+                .poll(&mut events, Some(Duration::from_secs(5)))
+                .unwrap();
+        }
+        assert_eq!(QUERIES.load(Ordering::SeqCst), 1);
+        for resolver in &resolvers {
+            assert_eq!(resolver.address(false), Some("10.0.0.1".parse().unwrap()));
+        }
+        assert!(inflight.lookups.lock().unwrap().lookups.is_empty());
+    }
+
+    fn stalled_lookup(_: &str) -> Vec<IpAddr> {
+        std::thread::sleep(Duration::from_millis(200));
+        vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))]
+    }
+
+    #[test]
+    fn pending_queries_bounded() {
+        let inflight = Inflight::with_limit(1, 1);
+        let poll = Poll::new().unwrap();
+        let running = inflight
+            .resolve_with("a.example.com".to_string(), stalled_lookup)
+            .unwrap();
+        let queued = inflight
+            .resolve_with("b.example.com".to_string(), stalled_lookup)
+            .unwrap();
+        assert!(inflight
+            .resolve_with("c.example.com".to_string(), stalled_lookup)
+            .is_none());
+        // joining a query on the way is not limited
+        let shared = inflight
+            .resolve_with("b.example.com".to_string(), stalled_lookup)
+            .unwrap();
+        let resolvers = [running, queued, shared];
+        for (i, resolver) in resolvers.iter().enumerate() {
+            poll.register(resolver, Token(i), Ready::readable(), PollOpt::edge())
+                .unwrap();
+        }
+        let mut events = Events::with_capacity(16);
+        let mut ready = 0;
+        while ready < resolvers.len() {
+            ready += poll
+                .poll(&mut events, Some(Duration::from_secs(5)))
+                .unwrap();
+        }
+        for resolver in &resolvers {
+            assert_eq!(resolver.address(false), Some("10.0.0.2".parse().unwrap()));
+        }
+        let table = inflight.lookups.lock().unwrap();
+        assert_eq!(table.running, 0);
+        assert!(table.lookups.is_empty());
     }
 }
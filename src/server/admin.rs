@@ -0,0 +1,627 @@
+use std::io::{ErrorKind, Read, Result, Write};
+use std::net::Shutdown;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::TryRecvError;
+use std::time::{Duration, Instant};
+
+use mio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use mio::unix::EventedFd;
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+use mio_extras::channel::{channel, Receiver};
+
+use crate::config::Opts;
+use crate::server::tls_server::ServerStats;
+use crate::server::{broadcast, Command, TlsServer, Worker, ADMIN_CLIENT};
+use crate::stats::Traffic;
+
+/// workers answering an admin command later than this are left out of the response
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Line based admin endpoint, each client sends one command and gets the response before closing.
+pub struct Admin {
+    listener: AdminListener,
+    clients: Vec<AdminClient>,
+}
+
+/// admin endpoint listens on a tcp address or a unix socket path
+pub enum AdminListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+enum AdminStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+struct AdminClient {
+    stream: AdminStream,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    /// command waiting for the replies of the other workers
+    pending: Option<Pending>,
+    closed: bool,
+}
+
+/// answer of a worker to an admin command sent by the first one
+pub enum Reply {
+    Text(String),
+    Stats(ServerStats),
+}
+
+/// command run by the first worker and sent to the other ones, their replies arrive on
+/// `receiver`, registered with the admin client token
+struct Pending {
+    command: String,
+    /// response of the first worker
+    reply: Reply,
+    replies: Vec<Reply>,
+    receiver: Receiver<Reply>,
+    deadline: Instant,
+}
+
+impl Admin {
+    pub fn new(listener: AdminListener) -> Admin {
+        Admin {
+            listener,
+            clients: Vec::new(),
+        }
+    }
+
+    pub fn listener(&self) -> &AdminListener {
+        &self.listener
+    }
+
+    pub fn accept(&mut self, poll: &Poll) {
+        loop {
+            match self.listener.accept() {
+                Ok(stream) => {
+                    if let Err(err) = poll.register(
+                        &stream,
+                        Token(ADMIN_CLIENT),
+                        Ready::readable(),
+                        PollOpt::edge(),
+                    ) {
+                        log::error!("register admin client failed:{}", err);
+                        continue;
+                    }
+                    self.clients.push(AdminClient {
+                        stream,
+                        input: Vec::new(),
+                        output: Vec::new(),
+                        pending: None,
+                        closed: false,
+                    });
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(err) => {
+                    log::error!("admin accept failed:{}", err);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// all admin clients and the replies they wait for share one token, so every client is
+    /// checked on each event, and once its deadline passed
+    pub fn ready(
+        &mut self,
+        poll: &Poll,
+        server: &mut TlsServer,
+        workers: &[Worker],
+        opts: &mut Opts,
+    ) {
+        let now = Instant::now();
+        for client in &mut self.clients {
+            client.do_read();
+            if client.pending.is_none() {
+                if let Some(command) = client.command() {
+                    log::info!("admin command:{}", command);
+                    client.run(command, server, workers, poll, opts);
+                }
+            }
+            client.check_replies(now);
+            if !client.output.is_empty() {
+                client.do_send(poll);
+            }
+            if client.closed {
+                let _ = poll.deregister(&client.stream);
+                let _ = client.stream.shutdown(Shutdown::Both);
+            }
+        }
+        self.clients.retain(|client| !client.closed);
+    }
+
+    /// time the replies of the other workers are waited for until
+    pub fn deadline(&self) -> Option<Instant> {
+        self.clients
+            .iter()
+            .filter_map(|client| client.pending.as_ref().map(|pending| pending.deadline))
+            .min()
+    }
+}
+
+impl AdminClient {
+    fn do_read(&mut self) {
+        let mut buffer = [0u8; 256];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => {
+                    self.closed = self.output.is_empty() && self.pending.is_none();
+                    break;
+                }
+                Ok(size) => self.input.extend_from_slice(&buffer[..size]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    log::warn!("read from admin client failed:{}", err);
+                    self.closed = true;
+                    break;
+                }
+            }
+        }
+    }
+
+    fn command(&mut self) -> Option<String> {
+        let pos = self.input.iter().position(|c| *c == b'\n')?;
+        let line = String::from_utf8_lossy(&self.input[..pos])
+            .trim()
+            .to_string();
+        self.input.clear();
+        Some(line)
+    }
+
+    /// run `command` on the first worker, and send it to the other ones too if it concerns
+    /// connections, each worker has connections of its own. their replies are waited for
+    /// without blocking the loop
+    fn run(
+        &mut self,
+        command: String,
+        server: &mut TlsServer,
+        workers: &[Worker],
+        poll: &Poll,
+        opts: &mut Opts,
+    ) {
+        let args: Vec<&str> = command.split_whitespace().collect();
+        let forwarded = matches!(
+            args.as_slice(),
+            ["stats"] | ["status"] | ["list"] | ["pause"] | ["resume"] | ["kill", _]
+        );
+        if !forwarded || workers.is_empty() {
+            let response = execute(&command, server, poll, opts);
+            self.output.extend_from_slice(response.as_bytes());
+            return;
+        }
+        let (sender, receiver) = channel();
+        if let Err(err) = poll.register(
+            &receiver,
+            Token(ADMIN_CLIENT),
+            Ready::readable(),
+            PollOpt::edge(),
+        ) {
+            log::error!("register admin replies failed:{}", err);
+            self.closed = true;
+            return;
+        }
+        broadcast(workers, Command::Admin(command.clone(), sender));
+        self.pending = Some(Pending {
+            reply: reply(&command, server, poll, opts),
+            command,
+            replies: Vec::new(),
+            receiver,
+            deadline: Instant::now() + REPLY_TIMEOUT,
+        });
+    }
+
+    /// take the replies arrived, each worker drops its sender once answered or exited, so the
+    /// response is written as soon as all of them are done or the deadline passed
+    fn check_replies(&mut self, now: Instant) {
+        let pending = match self.pending.as_mut() {
+            Some(pending) => pending,
+            None => return,
+        };
+        loop {
+            match pending.receiver.try_recv() {
+                Ok(reply) => pending.replies.push(reply),
+                Err(TryRecvError::Empty) if now < pending.deadline => return,
+                Err(TryRecvError::Empty) => {
+                    log::warn!("admin command not answered by every worker in time");
+                    break;
+                }
+                Err(TryRecvError::Disconnected) => break,
+            }
+        }
+        let response = self.pending.take().unwrap().response();
+        self.output.extend_from_slice(response.as_bytes());
+    }
+
+    fn do_send(&mut self, poll: &Poll) {
+        loop {
+            if self.output.is_empty() {
+                self.closed = true;
+                return;
+            }
+            match self.stream.write(self.output.as_slice()) {
+                Ok(size) => {
+                    let _ = self.output.drain(..size);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    log::warn!("write to admin client failed:{}", err);
+                    self.closed = true;
+                    return;
+                }
+            }
+        }
+        if let Err(err) = poll.reregister(
+            &self.stream,
+            Token(ADMIN_CLIENT),
+            Ready::writable(),
+            PollOpt::edge(),
+        ) {
+            log::warn!("reregister admin client failed:{}", err);
+            self.closed = true;
+        }
+    }
+}
+
+impl AdminListener {
+    fn accept(&self) -> Result<AdminStream> {
+        match self {
+            AdminListener::Tcp(listener) => {
+                let (stream, addr) = listener.accept()?;
+                log::info!("admin client connected from {}", addr);
+                Ok(AdminStream::Tcp(stream))
+            }
+            #[cfg(unix)]
+            AdminListener::Unix(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(true)?;
+                log::info!("admin client connected on unix socket");
+                Ok(AdminStream::Unix(stream))
+            }
+        }
+    }
+}
+
+impl Evented for AdminListener {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<()> {
+        match self {
+            AdminListener::Tcp(listener) => listener.register(poll, token, interest, opts),
+            #[cfg(unix)]
+            AdminListener::Unix(listener) => {
+                EventedFd(&listener.as_raw_fd()).register(poll, token, interest, opts)
+            }
+        }
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<()> {
+        match self {
+            AdminListener::Tcp(listener) => listener.reregister(poll, token, interest, opts),
+            #[cfg(unix)]
+            AdminListener::Unix(listener) => {
+                EventedFd(&listener.as_raw_fd()).reregister(poll, token, interest, opts)
+            }
+        }
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<()> {
+        match self {
+            AdminListener::Tcp(listener) => listener.deregister(poll),
+            #[cfg(unix)]
+            AdminListener::Unix(listener) => EventedFd(&listener.as_raw_fd()).deregister(poll),
+        }
+    }
+}
+
+impl AdminStream {
+    fn shutdown(&self, how: Shutdown) -> Result<()> {
+        match self {
+            AdminStream::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            AdminStream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+impl Read for AdminStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            AdminStream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            AdminStream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for AdminStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            AdminStream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            AdminStream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            AdminStream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            AdminStream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Evented for AdminStream {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<()> {
+        match self {
+            AdminStream::Tcp(stream) => stream.register(poll, token, interest, opts),
+            #[cfg(unix)]
+            AdminStream::Unix(stream) => {
+                EventedFd(&stream.as_raw_fd()).register(poll, token, interest, opts)
+            }
+        }
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<()> {
+        match self {
+            AdminStream::Tcp(stream) => stream.reregister(poll, token, interest, opts),
+            #[cfg(unix)]
+            AdminStream::Unix(stream) => {
+                EventedFd(&stream.as_raw_fd()).reregister(poll, token, interest, opts)
+            }
+        }
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<()> {
+        match self {
+            AdminStream::Tcp(stream) => stream.deregister(poll),
+            #[cfg(unix)]
+            AdminStream::Unix(stream) => EventedFd(&stream.as_raw_fd()).deregister(poll),
+        }
+    }
+}
+
+/// answer of a worker to a command sent by the first one
+pub fn reply(command: &str, server: &mut TlsServer, poll: &Poll, opts: &mut Opts) -> Reply {
+    if command.trim() == "stats" {
+        Reply::Stats(server.stats())
+    } else {
+        Reply::Text(execute(command, server, poll, opts))
+    }
+}
+
+impl Pending {
+    fn response(self) -> String {
+        match self.reply {
+            Reply::Stats(mut stats) => {
+                for reply in self.replies {
+                    if let Reply::Stats(other) = reply {
+                        stats.merge(other);
+                    }
+                }
+                format!("{}\n", stats.to_json())
+            }
+            Reply::Text(response) => {
+                let replies = self
+                    .replies
+                    .into_iter()
+                    .filter_map(|reply| match reply {
+                        Reply::Text(text) => Some(text),
+                        Reply::Stats(_) => None,
+                    })
+                    .collect();
+                let args: Vec<&str> = self.command.split_whitespace().collect();
+                merge(&args, response, replies)
+            }
+        }
+    }
+}
+
+/// response of the first worker combined with the replies of the other ones
+fn merge(args: &[&str], response: String, replies: Vec<String>) -> String {
+    let mut responses = std::iter::once(&response).chain(&replies);
+    match args {
+        ["list"] => {
+            let mut lines: Vec<&str> = responses.flat_map(|text| text.lines()).collect();
+            lines.sort_by_key(|line| line_index(line));
+            lines.iter().map(|line| format!("{}\n", line)).collect()
+        }
+        ["status"] => {
+            let (mut lines, mut total, mut notes) = (Vec::new(), 0, Vec::new());
+            for line in responses.flat_map(|text| text.lines()) {
+                let count = line
+                    .strip_prefix("total ")
+                    .and_then(|line| line.strip_suffix(" connections"))
+                    .and_then(|count| count.parse::<usize>().ok());
+                if let Some(count) = count {
+                    total += count;
+                } else if line_index(line).is_some() {
+                    lines.push(line);
+                } else if !notes.contains(&line) {
+                    // the same state is reported by each worker, e.g. accept paused
+                    notes.push(line);
+                }
+            }
+            lines.sort_by_key(|line| line_index(line));
+            let mut status: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+            status.push_str(&format!("total {} connections\n", total));
+            for note in notes {
+                status.push_str(&format!("{}\n", note));
+            }
+            status
+        }
+        // the connection is found by one worker at most
+        ["kill", _] => responses
+            .find(|text| text.ends_with(" killed\n"))
+            .unwrap_or(&response)
+            .clone(),
+        // workers pause and resume along with the first one
+        _ => response,
+    }
+}
+
+/// connection index a line of list or status starts with
+fn line_index(line: &str) -> Option<usize> {
+    line.split(' ').next()?.parse().ok()
+}
+
+/// run `command` on the connections of one worker
+pub fn execute(command: &str, server: &mut TlsServer, poll: &Poll, opts: &mut Opts) -> String {
+    let args: Vec<&str> = command.split_whitespace().collect();
+    match args.as_slice() {
+        ["status"] => server.status(opts),
+        ["list"] => server.list(),
+        ["stats"] => format!("{}\n", server.stats().to_json()),
+        ["pause"] if server.pause(poll) => "accept paused\n".to_string(),
+        ["pause"] => "accept not paused, already paused or failed\n".to_string(),
+        ["resume"] if server.resume(poll) => "accept resumed\n".to_string(),
+        ["resume"] => "accept not resumed, not paused or failed\n".to_string(),
+        ["shutdown"] if server.begin_shutdown(poll) => "shutting down\n".to_string(),
+        ["shutdown"] => "already shutting down\n".to_string(),
+        ["traffic"] => traffic(server.traffic_snapshot(opts)),
+        ["traffic", "reset"] => traffic(server.reset_traffic(opts)),
+        ["kill", index] => match index.parse() {
+            Ok(index) if server.kill(index, poll, opts) => format!("connection {} killed\n", index),
+            Ok(index) => format!("connection {} not found\n", index),
+            Err(_) => format!("invalid connection id:{}\n", index),
+        },
+        _ => format!("unknown command:{}\n", command),
+    }
+}
+
+fn traffic(traffic: Traffic) -> String {
+    format!(
+        "read {} bytes, sent {} bytes\n",
+        traffic.bytes_read, traffic.bytes_sent
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use crate::test_support::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn admin_on_unix_socket() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("trojan-admin-{}.sock", free_addr().port()));
+        let path = path.to_str().unwrap();
+        start_server(&["--admin-socket", path, "--admin-socket-mode", "660"]);
+        sleep(Duration::from_millis(100));
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o660);
+        assert_eq!(admin_unix(path, "pause"), "accept paused\n");
+        assert!(admin_unix(path, "status").ends_with("accept paused\n"));
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+#[cfg(all(test, feature = "test-support"))]
+mod client_tests {
+    use std::net::TcpStream;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_support::*;
+
+    #[test]
+    fn pause_and_resume_accept() {
+        let admin_addr = free_addr().to_string();
+        let server = start_server(&["--allow-self-connect", "--admin-addr", admin_addr.as_str()]);
+        let target = start_echo();
+        let mut active = TrojanClient::connect(server, PASSWORD, &target).unwrap();
+
+        assert_eq!(admin(&admin_addr, "pause"), "accept paused\n");
+        assert!(admin(&admin_addr, "pause").starts_with("accept not paused"));
+        // connection waits in the backlog, the one already accepted keeps working
+        let _pending = TcpStream::connect(server).unwrap();
+        active.write_all(b"still alive").unwrap();
+        let mut buffer = [0u8; 11];
+        active.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"still alive");
+        sleep(Duration::from_millis(100));
+        let status = admin(&admin_addr, "status");
+        assert!(status.contains("total 1 connections\naccept paused\n"));
+
+        assert_eq!(admin(&admin_addr, "resume"), "accept resumed\n");
+        sleep(Duration::from_millis(100));
+        let status = admin(&admin_addr, "status");
+        assert!(status.ends_with("total 2 connections\n"));
+    }
+
+    #[test]
+    fn stats_of_each_connection() {
+        let admin_addr = free_addr().to_string();
+        let server = start_server(&["--allow-self-connect", "--admin-addr", admin_addr.as_str()]);
+        let target = start_echo();
+        let mut client = TrojanClient::connect(server, PASSWORD, &target).unwrap();
+        let mut buffer = [0u8; 5];
+        client.write_all(b"hello").unwrap();
+        client.read_exact(&mut buffer).unwrap();
+        sleep(Duration::from_millis(100));
+        let stats = admin(&admin_addr, "stats");
+        assert!(stats.starts_with("{\"bytes_read\":5,\"bytes_sent\":5,\"active\":1,"));
+        assert!(stats.ends_with(",\"bytes_read\":5,\"bytes_sent\":5}]}\n"));
+    }
+
+    #[test]
+    fn users_told_apart_in_stats() {
+        let admin_addr = free_addr().to_string();
+        let server = start_server(&[
+            "--allow-self-connect",
+            "--admin-addr",
+            admin_addr.as_str(),
+            "--user",
+            "alice=secret",
+        ]);
+        let target = start_echo();
+        let mut default = TrojanClient::connect(server, PASSWORD, &target).unwrap();
+        let mut alice = TrojanClient::connect(server, "secret", &target).unwrap();
+        let mut buffer = [0u8; 7];
+        default.write_all(b"default").unwrap();
+        default.read_exact(&mut buffer).unwrap();
+        alice.write_all(b"alice").unwrap();
+        alice.read_exact(&mut buffer[..5]).unwrap();
+        sleep(Duration::from_millis(100));
+        let stats = admin(&admin_addr, "stats");
+        assert!(stats.contains(",\"user\":\"default\",\"bytes_read\":7,"));
+        assert!(stats.contains(",\"user\":\"alice\",\"bytes_read\":5,"));
+    }
+
+    #[test]
+    fn traffic_snapshot_and_reset() {
+        let admin_addr = free_addr().to_string();
+        let server = start_server(&["--allow-self-connect", "--admin-addr", admin_addr.as_str()]);
+        let target = start_echo();
+        let mut client = TrojanClient::connect(server, PASSWORD, &target).unwrap();
+        let mut buffer = [0u8; 5];
+        client.write_all(b"first").unwrap();
+        client.read_exact(&mut buffer).unwrap();
+        sleep(Duration::from_millis(100));
+        let expected = "read 5 bytes, sent 5 bytes\n";
+        assert_eq!(admin(&admin_addr, "traffic"), expected);
+        assert_eq!(admin(&admin_addr, "traffic reset"), expected);
+        assert_eq!(
+            admin(&admin_addr, "traffic"),
+            "read 0 bytes, sent 0 bytes\n"
+        );
+
+        client.write_all(b"again").unwrap();
+        client.read_exact(&mut buffer).unwrap();
+        sleep(Duration::from_millis(100));
+        assert_eq!(admin(&admin_addr, "traffic reset"), expected);
+    }
+}
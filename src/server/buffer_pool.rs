@@ -0,0 +1,62 @@
+use std::sync::{Arc, Mutex};
+
+/// Receive buffers of idle or closed tcp targets kept for reads of others, so each read does not
+/// allocate one. at most `cap` idle buffers are kept, the rest are freed.
+pub struct BufferPool {
+    free: Mutex<Vec<Vec<u8>>>,
+    size: usize,
+    cap: usize,
+}
+
+impl BufferPool {
+    pub fn new(size: usize, cap: usize) -> Arc<BufferPool> {
+        Arc::new(BufferPool {
+            free: Mutex::new(Vec::new()),
+            size,
+            cap,
+        })
+    }
+
+    /// a buffer of `size` bytes, reused if one is idle
+    pub fn take(&self) -> Vec<u8> {
+        self.free
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| vec![0u8; self.size])
+    }
+
+    /// give back a buffer from `take`, freed if the pool is full
+    pub fn put(&self, buffer: Vec<u8>) {
+        if buffer.len() != self.size {
+            return;
+        }
+        let mut free = self.free.lock().unwrap();
+        if free.len() < self.cap {
+            free.push(buffer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BufferPool;
+
+    #[test]
+    fn reuse_up_to_cap() {
+        let pool = BufferPool::new(16, 2);
+        let buffers: Vec<Vec<u8>> = (0..3).map(|_| pool.take()).collect();
+        assert!(buffers.iter().all(|buffer| buffer.len() == 16));
+        let ptr = buffers[0].as_ptr();
+        for buffer in buffers {
+            pool.put(buffer);
+        }
+        assert_eq!(pool.free.lock().unwrap().len(), 2);
+        pool.put(Vec::new());
+        assert_eq!(pool.free.lock().unwrap().len(), 2);
+        pool.take();
+        let reused = pool.take();
+        assert_eq!(reused.as_ptr(), ptr);
+        assert_eq!(pool.free.lock().unwrap().len(), 0);
+    }
+}
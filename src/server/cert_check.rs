@@ -0,0 +1,375 @@
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use rustls::Certificate;
+
+use crate::config::Opts;
+
+const OID_AIA: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x01];
+const OID_OCSP: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01];
+const OID_SHA1: &[u8] = &[0x2b, 0x0e, 0x03, 0x02, 0x1a];
+const OCSP_TIMEOUT: Duration = Duration::from_secs(5);
+
+enum Revocation {
+    Good,
+    Revoked,
+    Unknown,
+}
+
+/// the fields of a x509 certificate needed by the startup check
+struct CertInfo<'a> {
+    serial: &'a [u8],
+    subject: &'a [u8],
+    public_key: &'a [u8],
+    not_after: DateTime<Utc>,
+    ocsp_url: Option<String>,
+}
+
+/// a minimal der reader, just enough to walk a certificate and an ocsp response
+struct Der<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Der<'a> {
+    fn new(data: &'a [u8]) -> Der<'a> {
+        Der { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.data.first().copied()
+    }
+
+    /// returns tag, content and the whole encoded element
+    fn read_any(&mut self) -> Option<(u8, &'a [u8], &'a [u8])> {
+        let tag = *self.data.first()?;
+        let first = *self.data.get(1)? as usize;
+        let (len, offset) = if first < 0x80 {
+            (first, 2)
+        } else {
+            let cnt = first & 0x7f;
+            if cnt == 0 || cnt > 4 {
+                return None;
+            }
+            let mut len = 0usize;
+            for byte in self.data.get(2..2 + cnt)? {
+                len = (len << 8) | *byte as usize;
+            }
+            (len, 2 + cnt)
+        };
+        let raw = self.data.get(..offset + len)?;
+        self.data = &self.data[offset + len..];
+        Some((tag, &raw[offset..], raw))
+    }
+
+    fn read(&mut self, tag: u8) -> Option<&'a [u8]> {
+        match self.read_any()? {
+            (t, content, _) if t == tag => Some(content),
+            _ => None,
+        }
+    }
+
+    fn read_raw(&mut self, tag: u8) -> Option<&'a [u8]> {
+        match self.read_any()? {
+            (t, _, raw) if t == tag => Some(raw),
+            _ => None,
+        }
+    }
+
+    fn skip_if(&mut self, tag: u8) -> Option<()> {
+        if self.peek() == Some(tag) {
+            self.read_any()?;
+        }
+        Some(())
+    }
+}
+
+fn encode(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut data = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        data.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len
+            .to_be_bytes()
+            .iter()
+            .copied()
+            .skip_while(|byte| *byte == 0)
+            .collect();
+        data.push(0x80 | bytes.len() as u8);
+        data.extend_from_slice(bytes.as_slice());
+    }
+    data.extend_from_slice(content);
+    data
+}
+
+fn parse_time(tag: u8, content: &[u8]) -> Option<DateTime<Utc>> {
+    let text = std::str::from_utf8(content).ok()?;
+    let text = match tag {
+        // UTCTime, years before 50 belong to this century
+        0x17 if text.len() == 13 => {
+            let century = if &text[..2] < "50" { "20" } else { "19" };
+            format!("{}{}", century, text)
+        }
+        0x18 => text.to_string(),
+        _ => return None,
+    };
+    let time = NaiveDateTime::parse_from_str(text.as_str(), "%Y%m%d%H%M%SZ").ok()?;
+    Some(DateTime::from_utc(time, Utc))
+}
+
+fn parse_ocsp_url(extensions: &[u8]) -> Option<String> {
+    let mut extensions = Der::new(Der::new(extensions).read(0x30)?);
+    while !extensions.is_empty() {
+        let mut extension = Der::new(extensions.read(0x30)?);
+        if extension.read(0x06)? != OID_AIA {
+            continue;
+        }
+        extension.skip_if(0x01)?;
+        let mut descriptions = Der::new(Der::new(extension.read(0x04)?).read(0x30)?);
+        while !descriptions.is_empty() {
+            let mut description = Der::new(descriptions.read(0x30)?);
+            if description.read(0x06)? == OID_OCSP {
+                if let Some(url) = description.read(0x86) {
+                    return String::from_utf8(url.to_vec()).ok();
+                }
+            }
+        }
+    }
+    None
+}
+
+fn parse_cert(der: &[u8]) -> Option<CertInfo<'_>> {
+    let mut cert = Der::new(Der::new(der).read(0x30)?);
+    let mut tbs = Der::new(cert.read(0x30)?);
+    tbs.skip_if(0xa0)?;
+    let serial = tbs.read(0x02)?;
+    tbs.read(0x30)?;
+    tbs.read(0x30)?;
+    let mut validity = Der::new(tbs.read(0x30)?);
+    validity.read_any()?;
+    let (tag, content, _) = validity.read_any()?;
+    let not_after = parse_time(tag, content)?;
+    let subject = tbs.read_raw(0x30)?;
+    let mut key_info = Der::new(tbs.read(0x30)?);
+    key_info.read(0x30)?;
+    // skip the unused bits byte of the bit string
+    let public_key = key_info.read(0x03)?.get(1..)?;
+    let mut ocsp_url = None;
+    while !tbs.is_empty() {
+        if let (0xa3, extensions, _) = tbs.read_any()? {
+            ocsp_url = parse_ocsp_url(extensions);
+        }
+    }
+    Some(CertInfo {
+        serial,
+        subject,
+        public_key,
+        not_after,
+        ocsp_url,
+    })
+}
+
+fn sha1(data: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    hasher.input(data);
+    let mut result = vec![0u8; hasher.output_bytes()];
+    hasher.result(result.as_mut_slice());
+    result
+}
+
+fn ocsp_request(cert: &CertInfo, issuer: &CertInfo) -> Vec<u8> {
+    let mut algorithm = encode(0x06, OID_SHA1);
+    algorithm.extend_from_slice(&[0x05, 0x00]);
+    let mut cert_id = encode(0x30, algorithm.as_slice());
+    cert_id.extend(encode(0x04, sha1(issuer.subject).as_slice()));
+    cert_id.extend(encode(0x04, sha1(issuer.public_key).as_slice()));
+    cert_id.extend(encode(0x02, cert.serial));
+    let cert_id = encode(0x30, cert_id.as_slice());
+    let request = encode(0x30, cert_id.as_slice());
+    let request_list = encode(0x30, request.as_slice());
+    let tbs_request = encode(0x30, request_list.as_slice());
+    encode(0x30, tbs_request.as_slice())
+}
+
+/// post request to a plain http responder, returns body of a 200 response
+fn http_post(url: &str, body: &[u8]) -> Result<Vec<u8>, String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("unsupported ocsp url {}", url))?;
+    let (host, path) = match rest.find('/') {
+        Some(pos) => (&rest[..pos], &rest[pos..]),
+        None => (rest, "/"),
+    };
+    let addr_text = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+    let addr = addr_text
+        .to_socket_addrs()
+        .map_err(|err| format!("resolve {} failed:{}", host, err))?
+        .next()
+        .ok_or_else(|| format!("no address for {}", host))?;
+    let mut stream =
+        TcpStream::connect_timeout(&addr, OCSP_TIMEOUT).map_err(|err| err.to_string())?;
+    let _ = stream.set_read_timeout(Some(OCSP_TIMEOUT));
+    let _ = stream.set_write_timeout(Some(OCSP_TIMEOUT));
+    let header = format!(
+        "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/ocsp-request\r\nContent-Length: {}\r\n\r\n",
+        path,
+        host,
+        body.len()
+    );
+    stream
+        .write_all(header.as_bytes())
+        .and_then(|_| stream.write_all(body))
+        .map_err(|err| err.to_string())?;
+    let mut response = Vec::new();
+    stream
+        .read_to_end(&mut response)
+        .map_err(|err| err.to_string())?;
+    let pos = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or("incomplete http response")?;
+    let status = String::from_utf8_lossy(&response[..pos]);
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(format!(
+            "responder returned {}",
+            status.lines().next().unwrap_or("")
+        ));
+    }
+    Ok(response[pos + 4..].to_vec())
+}
+
+/// picks the status of `serial` from an ocsp response, signature of the response is not verified
+fn parse_ocsp_response(data: &[u8], serial: &[u8]) -> Option<Revocation> {
+    let mut response = Der::new(Der::new(data).read(0x30)?);
+    if response.read(0x0a)? != [0] {
+        return None;
+    }
+    let mut bytes = Der::new(Der::new(response.read(0xa0)?).read(0x30)?);
+    bytes.read(0x06)?;
+    let basic = Der::new(bytes.read(0x04)?).read(0x30)?;
+    let mut data = Der::new(Der::new(basic).read(0x30)?);
+    data.skip_if(0xa0)?;
+    data.read_any()?;
+    data.read(0x18)?;
+    let mut responses = Der::new(data.read(0x30)?);
+    while !responses.is_empty() {
+        let mut single = Der::new(responses.read(0x30)?);
+        let mut cert_id = Der::new(single.read(0x30)?);
+        cert_id.read(0x30)?;
+        cert_id.read(0x04)?;
+        cert_id.read(0x04)?;
+        if cert_id.read(0x02)? != serial {
+            continue;
+        }
+        return match single.peek()? {
+            0x80 => Some(Revocation::Good),
+            0xa1 => Some(Revocation::Revoked),
+            _ => Some(Revocation::Unknown),
+        };
+    }
+    None
+}
+
+fn check_revocation(cert: &CertInfo, chain: &[Certificate]) -> Option<Revocation> {
+    let url = match &cert.ocsp_url {
+        Some(url) => url,
+        None => {
+            log::warn!("certificate has no ocsp responder, revocation check skipped");
+            return None;
+        }
+    };
+    let issuer = match chain.get(1).and_then(|der| parse_cert(der.0.as_slice())) {
+        Some(issuer) => issuer,
+        None => {
+            log::warn!("issuer certificate not found in chain, revocation check skipped");
+            return None;
+        }
+    };
+    let response = match http_post(url.as_str(), ocsp_request(cert, &issuer).as_slice()) {
+        Ok(response) => response,
+        Err(err) => {
+            log::warn!("ocsp query to {} failed:{}", url, err);
+            return None;
+        }
+    };
+    let status = parse_ocsp_response(response.as_slice(), cert.serial);
+    if status.is_none() {
+        log::warn!("invalid ocsp response from {}", url);
+    }
+    status
+}
+
+/// validates expiry and optionally revocation of the leaf certificate,
+/// returns false if the server should refuse to start
+pub fn check(opts: &Opts, chain: &[Certificate]) -> bool {
+    let args = opts.server_args();
+    let cert = match chain.first().and_then(|der| parse_cert(der.0.as_slice())) {
+        Some(cert) => cert,
+        None => {
+            log::warn!("parse certificate failed, certificate check skipped");
+            return true;
+        }
+    };
+    let mut ok = true;
+    let remaining = cert.not_after - Utc::now();
+    if remaining.num_seconds() <= 0 {
+        log::error!("certificate expired at {}", cert.not_after);
+        ok = false;
+    } else if remaining.num_days() < args.cert_min_remaining as i64 {
+        log::warn!(
+            "certificate expires at {}, only {} days remaining",
+            cert.not_after,
+            remaining.num_days()
+        );
+    } else {
+        log::info!("certificate expires at {}", cert.not_after);
+    }
+    if args.cert_revocation_check {
+        match check_revocation(&cert, chain) {
+            Some(Revocation::Revoked) => {
+                log::error!("certificate is revoked");
+                ok = false;
+            }
+            Some(Revocation::Unknown) => log::warn!("certificate is unknown to ocsp responder"),
+            Some(Revocation::Good) => log::info!("certificate is not revoked"),
+            None => {}
+        }
+    }
+    ok || !args.refuse_bad_cert
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls::internal::pemfile::certs;
+
+    use super::*;
+
+    #[test]
+    fn parse_test_cert() {
+        let pem = include_bytes!("../../tests/certs/cert.pem");
+        let chain = certs(&mut &pem[..]).unwrap();
+        let cert = parse_cert(chain[0].0.as_slice()).unwrap();
+        assert!(cert.not_after > Utc::now());
+        assert!(cert.ocsp_url.is_none());
+    }
+
+    #[test]
+    fn der_length() {
+        let content = vec![0u8; 300];
+        let data = encode(0x04, content.as_slice());
+        assert_eq!(&data[..4], &[0x04, 0x82, 0x01, 0x2c]);
+        assert_eq!(Der::new(data.as_slice()).read(0x04).unwrap().len(), 300);
+    }
+}
@@ -0,0 +1,212 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::time::{Duration, Instant};
+
+use crypto::hmac::Hmac;
+use crypto::mac::{Mac, MacResult};
+use crypto::sha2::Sha256;
+
+use crate::config::Opts;
+use crate::stats::{Stats, Traffic};
+
+const HEADER: &str = "trojan traffic v1\n";
+
+/// Periodically persists the traffic counters of the accounting period with an HMAC over them,
+/// so a stats file changed by anything but the server is rejected when loaded on restart.
+pub struct TrafficCheckpoint {
+    path: String,
+    key: Vec<u8>,
+    interval: Duration,
+    last_time: Instant,
+}
+
+impl TrafficCheckpoint {
+    /// load the persisted counters into `stats`, a missing file starts from zero
+    pub fn new(opts: &Opts) -> Option<TrafficCheckpoint> {
+        let args = opts.server_args();
+        let path = args.stats_file.clone()?;
+        let key_file = args
+            .stats_key_file
+            .as_ref()
+            .expect("--stats-file requires --stats-key-file");
+        let key = fs::read(key_file)
+            .unwrap_or_else(|err| panic!("read stats key {} failed:{}", key_file, err));
+        if key.is_empty() {
+            panic!("stats key {} is empty", key_file);
+        }
+        let checkpoint = TrafficCheckpoint {
+            path,
+            key,
+            interval: Duration::from_secs(args.stats_checkpoint_interval),
+            last_time: Instant::now(),
+        };
+        match fs::read_to_string(&checkpoint.path) {
+            Ok(content) => match checkpoint.verify(content.as_str()) {
+                Ok(traffic) => {
+                    log::info!(
+                        "traffic restored from {}, read {} bytes, sent {} bytes",
+                        checkpoint.path,
+                        traffic.bytes_read,
+                        traffic.bytes_sent
+                    );
+                    opts.stats.restore_traffic(traffic);
+                }
+                Err(err) => {
+                    // keep the rejected file for inspection instead of overwriting it
+                    let rejected = format!("{}.rejected", checkpoint.path);
+                    log::error!(
+                        "traffic in {} rejected:{}, moved to {}, counting from zero",
+                        checkpoint.path,
+                        err,
+                        rejected
+                    );
+                    if let Err(err) = fs::rename(&checkpoint.path, &rejected) {
+                        log::error!("move {} failed:{}", checkpoint.path, err);
+                    }
+                }
+            },
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                log::info!("no traffic at {}, counting from zero", checkpoint.path);
+            }
+            Err(err) => panic!("read stats file {} failed:{}", checkpoint.path, err),
+        }
+        Some(checkpoint)
+    }
+
+    pub fn check(&mut self, now: Instant, stats: &Stats) {
+        if now - self.last_time < self.interval {
+            return;
+        }
+        self.last_time = now;
+        self.save(stats);
+    }
+
+    pub fn save(&self, stats: &Stats) {
+        let traffic = stats.traffic();
+        let mut content = format!(
+            "{}bytes_read {}\nbytes_sent {}\n",
+            HEADER, traffic.bytes_read, traffic.bytes_sent
+        );
+        let code = self.sign(content.as_str());
+        content.push_str("hmac ");
+        for byte in code.code() {
+            let _ = write!(content, "{:02x}", byte);
+        }
+        content.push('\n');
+        // a crash while writing leaves the last checkpoint intact
+        let tmp = format!("{}.tmp", self.path);
+        if let Err(err) = fs::write(&tmp, content).and_then(|_| fs::rename(&tmp, &self.path)) {
+            log::error!("save traffic to {} failed:{}", self.path, err);
+        }
+    }
+
+    fn sign(&self, message: &str) -> MacResult {
+        let mut hmac = Hmac::new(Sha256::new(), self.key.as_slice());
+        hmac.input(message.as_bytes());
+        hmac.result()
+    }
+
+    fn verify(&self, content: &str) -> Result<Traffic> {
+        let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_string());
+        let pos = content
+            .rfind("hmac ")
+            .ok_or_else(|| invalid("hmac missing"))?;
+        let (message, code) = content.split_at(pos);
+        let code = code["hmac ".len()..].trim();
+        if code.len() != 64 || !code.is_ascii() {
+            return Err(invalid("malformed hmac"));
+        }
+        let mut bytes = Vec::with_capacity(32);
+        for i in (0..code.len()).step_by(2) {
+            let byte =
+                u8::from_str_radix(&code[i..i + 2], 16).map_err(|_| invalid("malformed hmac"))?;
+            bytes.push(byte);
+        }
+        // MacResult compares in constant time
+        if self.sign(message) != MacResult::new(bytes.as_slice()) {
+            return Err(invalid("hmac mismatch"));
+        }
+        let body = message
+            .strip_prefix(HEADER)
+            .ok_or_else(|| invalid("unknown format"))?;
+        let mut traffic = Traffic::default();
+        for line in body.lines() {
+            let mut fields = line.split_whitespace();
+            let name = fields.next();
+            let value = fields.next().and_then(|value| value.parse().ok());
+            match (name, value) {
+                (Some("bytes_read"), Some(value)) => traffic.bytes_read = value,
+                (Some("bytes_sent"), Some(value)) => traffic.bytes_sent = value,
+                _ => return Err(invalid("malformed counter")),
+            }
+        }
+        Ok(traffic)
+    }
+}
+
+#[cfg(all(test, feature = "test-support"))]
+mod client_tests {
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use crate::test_support::*;
+
+    #[test]
+    fn tampered_stats_rejected() {
+        let dir = std::env::temp_dir().join(format!("trojan-stats-{}", free_addr().port()));
+        fs::create_dir_all(&dir).unwrap();
+        let key = dir.join("key");
+        fs::write(&key, b"billing secret").unwrap();
+        let start = |admin_addr: &str, stats: &std::path::Path| {
+            let args = vec![
+                "--allow-self-connect".to_string(),
+                "--admin-addr".to_string(),
+                admin_addr.to_string(),
+                "--stats-file".to_string(),
+                stats.to_str().unwrap().to_string(),
+                "--stats-key-file".to_string(),
+                key.to_str().unwrap().to_string(),
+                "--stats-checkpoint-interval".to_string(),
+                "1".to_string(),
+            ];
+            let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+            start_server(args.as_slice())
+        };
+
+        let stats = dir.join("traffic");
+        let admin_addr = free_addr().to_string();
+        let server = start(&admin_addr, &stats);
+        let target = start_echo();
+        let mut client = TrojanClient::connect(server, PASSWORD, &target).unwrap();
+        let mut buffer = [0u8; 5];
+        client.write_all(b"hello").unwrap();
+        client.read_exact(&mut buffer).unwrap();
+        sleep(Duration::from_millis(2500));
+        let content = fs::read_to_string(&stats).unwrap();
+        assert!(content.contains("bytes_read 5\nbytes_sent 5\nhmac "));
+
+        // a restarted server goes on from the persisted counters
+        let restored = dir.join("restored");
+        fs::write(&restored, content.as_str()).unwrap();
+        let admin_addr = free_addr().to_string();
+        start(&admin_addr, &restored);
+        assert_eq!(
+            admin(&admin_addr, "traffic"),
+            "read 5 bytes, sent 5 bytes\n"
+        );
+
+        let tampered = dir.join("tampered");
+        fs::write(&tampered, content.replace("bytes_read 5", "bytes_read 1")).unwrap();
+        let admin_addr = free_addr().to_string();
+        start(&admin_addr, &tampered);
+        assert_eq!(
+            admin(&admin_addr, "traffic"),
+            "read 0 bytes, sent 0 bytes\n"
+        );
+        assert!(dir.join("tampered.rejected").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
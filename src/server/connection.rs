@@ -1,18 +1,25 @@
 use std::io::Write;
-use std::net::SocketAddr;
-use std::time::Instant;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
-use mio::net::{TcpStream, UdpSocket};
+use mio::net::TcpStream;
 use mio::{Event, Poll, PollOpt, Ready, Token};
 use rustls::ServerSession;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 
-use crate::config::Opts;
-use crate::proto::{Sock5Address, TrojanRequest, CONNECT};
-use crate::resolver::EventedResolver;
+use crate::config::{Opts, PayloadAction, ResetAction, UnreachableAction};
+use crate::proto::{
+    hello_extensions, is_http_request, maybe_request, Sock5Address, TrojanRequest, CONNECT,
+    UDP_ASSOCIATE,
+};
+use crate::resolver::{other_family, EventedResolver};
+use crate::server::buffer_pool::BufferPool;
+use crate::server::otlp::Span;
 use crate::server::tcp_backend::TcpBackend;
-use crate::server::tls_server::Backend;
-use crate::server::udp_backend::UdpBackend;
-use crate::server::{CHANNEL_BACKEND, CHANNEL_CNT, CHANNEL_PROXY};
+use crate::server::tls_server::{set_keepalive, Backend};
+use crate::server::udp_backend::{self, UdpBackend};
+use crate::server::{Channel, Counter};
 use crate::sys;
 use crate::tls_conn::TlsConn;
 
@@ -25,69 +32,328 @@ enum Status {
 
 pub struct Connection {
     index: usize,
+    peer_addr: SocketAddr,
+    prefer_ipv6: bool,
     proxy: TlsConn<ServerSession>,
     resolver: Option<EventedResolver>,
     status: Status,
     sock5_addr: Sock5Address,
     command: u8,
     last_active_time: Instant,
+    accept_time: Instant,
+    /// when tls handshake finished, the target was set up, and its first byte was read
+    tls_time: Option<Instant>,
+    target_time: Option<Instant>,
+    first_byte_time: Option<Instant>,
+    /// close time requested by the client with the deadline extension
+    client_deadline: Option<Instant>,
+    /// deadline this connection is pushed into the timeout heap with
+    scheduled: Option<Instant>,
     backend: Option<Box<dyn Backend>>,
     closing: bool,
     target_addr: Option<SocketAddr>,
     data: Vec<u8>,
+    /// target address of the other family, tried if the target closes before replying
+    alternate_addr: Option<SocketAddr>,
+    /// data sent to the target while it may still be connected again
+    replay: Vec<u8>,
+    /// user whose password the request carried, None until then or for the fallback
+    user: Option<String>,
+    /// receive buffers of tcp targets are taken from here
+    buffers: Arc<BufferPool>,
 }
 
+/// max data kept for connecting the target again over the other family
+const REPLAY_LIMIT: usize = 16 * 1024;
+
 impl Connection {
-    pub fn new(index: usize, proxy: TlsConn<ServerSession>) -> Connection {
+    pub fn new(
+        index: usize,
+        peer_addr: SocketAddr,
+        proxy: TlsConn<ServerSession>,
+        buffers: Arc<BufferPool>,
+    ) -> Connection {
         Connection {
             index,
+            peer_addr,
+            prefer_ipv6: false,
             proxy,
             resolver: None,
             status: Status::HandShake,
             command: 0,
             sock5_addr: Sock5Address::None,
             last_active_time: Instant::now(),
+            accept_time: Instant::now(),
+            tls_time: None,
+            target_time: None,
+            first_byte_time: None,
+            client_deadline: None,
+            scheduled: None,
             backend: None,
             closing: false,
             target_addr: None,
             data: Vec::new(),
+            alternate_addr: None,
+            replay: Vec::new(),
+            user: None,
+            buffers,
         }
     }
 
-    pub fn timeout(&self, recent_active_time: Instant) -> bool {
+    pub fn timeout(&self, recent_active_time: Instant, opts: &Opts) -> bool {
+        if matches!(self.client_deadline, Some(deadline) if deadline <= recent_active_time) {
+            log::warn!("connection:{} reached deadline from client", self.index);
+            return true;
+        }
+        if self.proxy.is_handshaking() {
+            let limit = opts.tls_handshake_duration;
+            if limit.as_secs() != 0 && recent_active_time - self.accept_time > limit {
+                log::warn!(
+                    "connection:{} from {} not finish tls handshake in {:?}",
+                    self.index,
+                    self.peer_addr,
+                    limit
+                );
+                opts.stats.add_handshake_timeout();
+                return true;
+            }
+        }
+        if matches!(self.proxy.close_deadline(), Some(deadline) if deadline <= recent_active_time) {
+            log::warn!(
+                "connection:{} from {} not flushed in close grace",
+                self.index,
+                self.peer_addr
+            );
+            return true;
+        }
+        if let Some(lifetime) = opts.max_connection_lifetime {
+            if recent_active_time - self.accept_time > lifetime {
+                log::warn!(
+                    "connection:{} from {} reached max lifetime {:?}",
+                    self.index,
+                    self.peer_addr,
+                    lifetime
+                );
+                return true;
+            }
+        }
         if let Some(backend) = &self.backend {
-            backend.timeout(self.last_active_time, recent_active_time)
+            backend.timeout(self.last_active_time(), recent_active_time)
         } else {
             false
         }
     }
 
-    pub fn close_now(&mut self, poll: &Poll) {
-        self.proxy.shutdown(poll);
-        if let Some(backend) = self.backend.as_mut() {
-            backend.shutdown(poll);
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    pub fn accept_time(&self) -> Instant {
+        self.accept_time
+    }
+
+    /// last time data moved if the backend tracks it, otherwise the last event
+    pub fn last_active_time(&self) -> Instant {
+        self.backend
+            .as_ref()
+            .and_then(|backend| backend.last_active())
+            .unwrap_or(self.last_active_time)
+    }
+
+    /// earliest time `timeout` may become true, None if it never times out in current state
+    pub fn deadline(&self, opts: &Opts) -> Option<Instant> {
+        let limit = opts.tls_handshake_duration;
+        let handshake = if self.proxy.is_handshaking() && limit.as_secs() != 0 {
+            Some(self.accept_time + limit)
+        } else {
+            None
+        };
+        let idle = self
+            .backend
+            .as_ref()
+            .map(|backend| self.last_active_time() + backend.get_timeout());
+        let connect = self
+            .backend
+            .as_ref()
+            .and_then(|backend| backend.connect_deadline());
+        let lifetime = opts
+            .max_connection_lifetime
+            .map(|lifetime| self.accept_time + lifetime);
+        let close = self.proxy.close_deadline();
+        [
+            handshake,
+            idle,
+            connect,
+            self.client_deadline,
+            lifetime,
+            close,
+        ]
+        .iter()
+        .flatten()
+        .min()
+        .copied()
+    }
+
+    pub fn scheduled(&self) -> Option<Instant> {
+        self.scheduled
+    }
+
+    pub fn set_scheduled(&mut self, deadline: Option<Instant>) {
+        self.scheduled = deadline;
+    }
+
+    pub fn status(&self) -> String {
+        if let Some(backend) = &self.backend {
+            let target = backend
+                .target()
+                .map_or_else(|| "-".to_string(), |addr| addr.to_string());
+            format!("{} {} {}", self.index, backend.peer_addr(), target)
+        } else {
+            format!("{} {} -", self.index, self.peer_addr)
         }
     }
 
-    fn proxy_token(&self, token: Token) -> bool {
-        token.0 % CHANNEL_CNT == CHANNEL_PROXY
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
     }
 
-    pub fn ready(&mut self, poll: &Poll, event: &Event, opts: &mut Opts) {
-        self.last_active_time = Instant::now();
+    /// bytes read from and sent to target, zero before the target is set up
+    pub fn traffic(&self) -> (u64, u64) {
+        self.backend
+            .as_ref()
+            .map_or((0, 0), |backend| backend.traffic())
+    }
 
-        if self.proxy_token(event.token()) {
-            if event.readiness().is_readable() {
-                self.try_read_proxy(opts, poll);
-            }
-            if event.readiness().is_writable() {
-                self.try_send_proxy();
+    /// status with traffic and idle time
+    pub fn detail(&self) -> String {
+        let (read, sent) = self.traffic();
+        let queue = self
+            .backend
+            .as_ref()
+            .map_or(0, |backend| backend.queue_depth());
+        format!(
+            "{} read:{} sent:{} queue:{} idle:{}s",
+            self.status(),
+            read,
+            sent,
+            queue,
+            self.last_active_time().elapsed().as_secs()
+        )
+    }
+
+    /// lifetime and traffic of this connection for tracing
+    pub fn span(&self) -> Span {
+        let (bytes_read, bytes_sent) = self.traffic();
+        let end = SystemTime::now();
+        Span {
+            start: end - self.accept_time.elapsed(),
+            end,
+            client: self.peer_addr,
+            target: self.sock5_addr.to_string(),
+            user: self.user.clone(),
+            bytes_read,
+            bytes_sent,
+        }
+    }
+
+    fn mark_phases(&mut self, now: Instant, opts: &Opts) {
+        if self.tls_time.is_none() && !self.proxy.is_handshaking() {
+            self.tls_time = Some(now);
+            let duration = now - self.accept_time;
+            opts.stats.add_handshake(duration);
+            if matches!(opts.slow_handshake, Some(slow) if duration > slow) {
+                log::warn!(
+                    "connection:{} from {} slow tls handshake in {:?}",
+                    self.index,
+                    self.peer_addr,
+                    duration
+                );
             }
+        }
+        if self.target_time.is_none() && self.backend.is_some() {
+            self.target_time = Some(now);
+        }
+        if self.first_byte_time.is_none()
+            && matches!(&self.backend, Some(backend) if backend.traffic().0 > 0)
+        {
+            self.first_byte_time = Some(now);
+        }
+    }
+
+    /// time from accept to tls handshake done, then to the target set up after the trojan
+    /// request, then to the first byte from target, then transferring until now
+    pub fn phases(&self) -> String {
+        let span = |from: Option<Instant>, to: Option<Instant>| match (from, to) {
+            (Some(from), Some(to)) => format!("{:?}", to - from),
+            _ => "-".to_string(),
+        };
+        format!(
+            "tls handshake {}, request {}, first target byte {}, transfer {}",
+            span(Some(self.accept_time), self.tls_time),
+            span(self.tls_time, self.target_time),
+            span(self.target_time, self.first_byte_time),
+            span(self.first_byte_time, Some(Instant::now()))
+        )
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// tls handshake not finished yet, the part costing cpu
+    pub fn tls_handshaking(&self) -> bool {
+        self.proxy.is_handshaking()
+    }
+
+    /// udp backend is set up, counted in `Counter::UdpSessions`
+    pub fn udp_associated(&self) -> bool {
+        matches!(self.status, Status::UDPForward)
+    }
+
+    /// tls or trojan handshake not finished yet
+    pub fn handshaking(&self) -> bool {
+        match self.status {
+            Status::HandShake | Status::DnsWait => true,
+            _ => self.proxy.is_handshaking(),
+        }
+    }
+
+    pub fn close_now(&mut self, poll: &Poll, opts: &Opts) {
+        if self.unreachable_reset(opts) {
+            self.proxy.reset(poll);
         } else {
-            match self.status {
+            self.proxy.shutdown(poll);
+        }
+        if let Some(backend) = self.backend.as_mut() {
+            backend.shutdown(poll);
+        }
+    }
+
+    pub fn ready(&mut self, poll: &Poll, event: &Event, opts: &mut Opts) {
+        let now = Instant::now();
+        self.last_active_time = now;
+
+        match Channel::of(event.token()).1 {
+            Channel::Proxy => {
+                if event.readiness().is_readable() {
+                    self.try_read_proxy(opts, poll);
+                }
+                if event.readiness().is_writable() {
+                    self.try_send_proxy();
+                    if let Some(backend) = self.backend.as_mut() {
+                        backend.resume(&mut self.proxy, opts);
+                    }
+                }
+            }
+            Channel::Backend => match self.status {
                 Status::UDPForward | Status::TCPForward => {
                     if let Some(backend) = self.backend.as_mut() {
                         backend.ready(event, opts, &mut self.proxy);
+                        if self.alternate_addr.is_some() && backend.traffic().0 > 0 {
+                            // the target replied, it is kept for good
+                            self.alternate_addr = None;
+                            self.replay = Vec::new();
+                        }
                     } else {
                         log::error!("connection:{} has invalid status", self.index);
                     }
@@ -96,30 +362,98 @@ impl Connection {
                     self.try_resolve(opts, poll);
                 }
                 _ => {}
-            }
+            },
         }
+        self.mark_phases(now, opts);
 
         // handshake failed, no dns query on the way, close now.
         if self.closing && self.resolver.is_none() {
-            self.proxy.shutdown(poll);
+            if !self.proxy.closed() {
+                self.proxy.shutdown(poll);
+            }
             return;
         }
 
         self.proxy.reregister(poll, self.proxy_readable());
         self.proxy.check_close(poll);
+        let mut retry = false;
         if let Some(backend) = &mut self.backend {
             backend.reregister(poll, self.proxy.writable());
             backend.check_close(poll);
             if self.proxy.closed() && !backend.closed() {
+                if self.proxy.take_peer_reset() {
+                    log::warn!(
+                        "connection:{} from {} reset by client, close target",
+                        self.index,
+                        self.peer_addr
+                    );
+                    opts.stats.add_client_reset();
+                    if opts.client_reset_action == ResetAction::Reset {
+                        backend.reset(poll);
+                    }
+                }
                 //proxy is closing, backend is ok, register backend with write only
                 backend.shutdown(poll);
             } else if backend.closed() && !self.proxy.closed() {
-                //backend is closing, proxy is ok, register proxy with write only
-                self.proxy.shutdown(poll);
+                if self.alternate_addr.is_some() {
+                    retry = true;
+                } else if backend.unreachable()
+                    && opts.unreachable_action == UnreachableAction::Reset
+                {
+                    self.proxy.reset(poll);
+                } else {
+                    //backend is closing, proxy is ok, register proxy with write only
+                    self.proxy.shutdown(poll);
+                }
+            }
+        }
+        if retry {
+            self.retry_other_family(opts, poll);
+        }
+    }
+
+    /// the target closed before sending anything, connect its address of the other family and
+    /// send it the data the closed one got
+    fn retry_other_family(&mut self, opts: &mut Opts, poll: &Poll) {
+        let addr = self.alternate_addr.take().unwrap();
+        log::warn!(
+            "connection:{} target {} closed before replying, retry {}",
+            self.index,
+            self.target_addr.unwrap(),
+            addr
+        );
+        self.backend = None;
+        self.target_addr = Some(addr);
+        self.data = std::mem::take(&mut self.replay);
+        if self.try_setup_tcp_target(opts, poll) {
+            if let Some(backend) = &mut self.backend {
+                backend.reregister(poll, self.proxy.writable());
             }
+        } else if !self.proxy.closed() {
+            self.proxy.shutdown(poll);
+        }
+    }
+
+    /// keep data sent to the target while it may still be connected again
+    fn keep_for_retry(&mut self, data: &[u8]) {
+        if self.replay.len() + data.len() > REPLAY_LIMIT {
+            log::debug!(
+                "connection:{} sent over {} bytes, no retry over other family",
+                self.index,
+                REPLAY_LIMIT
+            );
+            self.alternate_addr = None;
+            self.replay = Vec::new();
+        } else {
+            self.replay.extend_from_slice(data);
         }
     }
 
+    fn unreachable_reset(&self, opts: &Opts) -> bool {
+        opts.unreachable_action == UnreachableAction::Reset
+            && matches!(&self.backend, Some(backend) if backend.unreachable())
+    }
+
     fn proxy_readable(&self) -> bool {
         if let Some(backend) = &self.backend {
             backend.writable()
@@ -130,20 +464,26 @@ impl Connection {
 
     fn try_resolve(&mut self, opts: &mut Opts, poll: &Poll) {
         if let Sock5Address::Domain(domain, port) = &self.sock5_addr {
-            if let Some(address) = self.resolver.as_ref().unwrap().address() {
+            let resolver = self.resolver.as_ref().unwrap();
+            if let Some(address) = resolver.address(self.prefer_ipv6) {
                 log::debug!(
                     "connection:{} got resolve result {} = {}",
                     self.index,
                     domain,
                     address
                 );
-                opts.update_dns(domain.clone(), address);
+                opts.update_dns(domain.clone(), resolver.addresses());
                 let addr = SocketAddr::new(address, *port);
                 self.target_addr.replace(addr);
+                if opts.server_args().retry_other_family {
+                    self.alternate_addr = other_family(resolver.addresses().as_slice(), address)
+                        .map(|ip| SocketAddr::new(ip, *port));
+                }
                 self.dispatch(&[], opts, poll);
             } else {
                 log::error!("connection:{} resolve host:{} failed", self.index, domain);
                 self.closing = true;
+                opts.stats.add_error();
             }
         } else {
             log::error!("connection:{} got bug, not a resolver status", self.index);
@@ -162,22 +502,57 @@ impl Connection {
         }
     }
 
-    pub fn setup(&mut self, poll: &Poll, _: &Opts) -> bool {
+    pub fn setup(&mut self, poll: &Poll, opts: &Opts) -> bool {
+        // one read queues at most about the high water for the target, however fast the client
+        let budget = match opts.server_args().read_budget {
+            0 => opts.send_buffer_high_water,
+            budget => budget.min(opts.send_buffer_high_water),
+        };
+        self.proxy.set_read_budget(budget);
+        self.proxy.set_early_data_action(opts.early_data_action);
+        self.proxy.set_close_grace(opts.close_grace);
+        self.proxy.set_high_water(opts.send_buffer_high_water);
+        if !opts.hello_extensions.is_empty() {
+            self.proxy.record_hello();
+        }
         self.proxy.register(poll)
     }
 
+    /// registration of the client or target socket with poll failed
+    pub fn register_failed(&self) -> bool {
+        self.proxy.register_failed()
+            || matches!(&self.backend, Some(backend) if backend.register_failed())
+    }
+
     fn try_handshake(&mut self, buffer: &mut &[u8], opts: &mut Opts, poll: &Poll) -> bool {
-        if let Some(request) = TrojanRequest::parse(buffer, opts) {
+        self.prefer_ipv6 = opts.server_args().prefer_client_family && is_ipv6(&self.peer_addr);
+        if self.atypical_hello(opts) {
+            // probers can not tell a trojan server from the fallback with such handshakes
+            self.command = CONNECT;
+            self.sock5_addr = Sock5Address::None;
+        } else if let Some(request) = TrojanRequest::parse(buffer, opts, self.prefer_ipv6) {
             self.command = request.command;
             self.sock5_addr = request.address;
+            self.user = Some(request.user);
+            if let (Sock5Address::Socket(address), Some(domain)) =
+                (&self.sock5_addr, request.domain)
+            {
+                if request.command == CONNECT && opts.server_args().retry_other_family {
+                    self.alternate_addr = opts
+                        .query_other_family(domain.as_str(), address.ip())
+                        .map(|ip| SocketAddr::new(ip, address.port()));
+                }
+            }
             *buffer = request.payload;
-        } else {
-            log::debug!(
-                "connection:{} does not get a trojan request, pass through",
-                self.index
-            );
-            self.command = CONNECT;
-            self.sock5_addr = Sock5Address::None;
+            if let Some(seconds) = request.deadline {
+                log::info!("connection:{} closes in {} seconds", self.index, seconds);
+                self.client_deadline = Some(Instant::now() + Duration::from_secs(seconds.into()));
+            }
+            if opts.server_args().log_requests {
+                self.log_request();
+            }
+        } else if self.wait_request(buffer, opts) || !self.try_unknown_payload(buffer, opts, poll) {
+            return false;
         }
         match &self.sock5_addr {
             Sock5Address::Domain(domain, _) => {
@@ -186,7 +561,20 @@ impl Connection {
                     return true;
                 }
                 log::debug!("connection:{} has to resolve {}", self.index, domain);
-                let resolver = EventedResolver::new(domain.clone());
+                let resolver = match opts.dns_inflight.resolve(domain.clone()) {
+                    Some(resolver) => resolver,
+                    None => {
+                        log::warn!(
+                            "connection:{} from {} refused, too many dns queries",
+                            self.index,
+                            self.peer_addr
+                        );
+                        self.closing = true;
+                        opts.stats.add_error();
+                        return false;
+                    }
+                };
+                opts.stats.add_dns_lookup();
                 if let Err(err) = poll.register(
                     &resolver,
                     self.target_token(),
@@ -194,6 +582,8 @@ impl Connection {
                     PollOpt::level(),
                 ) {
                     self.closing = true;
+                    opts.stats.add_error();
+                    opts.stats.add_register_failure();
                     log::error!("connection:{} register resolver failed:{}", self.index, err);
                     return false;
                 }
@@ -208,23 +598,128 @@ impl Connection {
                 self.target_addr.replace(*address);
             }
             Sock5Address::None => {
+                // the sink may already be picked for an unknown payload
+                let address = *self.target_addr.get_or_insert(opts.back_addr.unwrap());
                 log::debug!(
                     "connection:{} got default target address:{}",
                     self.index,
-                    opts.back_addr.as_ref().unwrap()
+                    address
                 );
-                self.target_addr = opts.back_addr;
             }
         }
         true
     }
 
-    fn dispatch(&mut self, mut buffer: &[u8], opts: &mut Opts, poll: &Poll) {
+    /// decoded request for troubleshooting, the password is checked already and never logged
+    fn log_request(&self) {
+        let command = match self.command {
+            CONNECT => "connect",
+            UDP_ASSOCIATE => "udp associate",
+            _ => "unknown",
+        };
+        log::info!(
+            "connection:{} of user {} from {} requests {} {}",
+            self.index,
+            self.user.as_deref().unwrap_or("-"),
+            self.peer_addr,
+            command,
+            self.sock5_addr
+        );
+    }
+
+    /// client hello recorded by tls conn lacks extensions browsers always send
+    fn atypical_hello(&mut self, opts: &Opts) -> bool {
+        let hello = match self.proxy.take_hello() {
+            Some(hello) => hello,
+            None => return false,
+        };
+        let missing: Vec<u16> = match hello_extensions(hello.as_slice()) {
+            Some(extensions) => opts
+                .hello_extensions
+                .iter()
+                .filter(|extension| !extensions.contains(extension))
+                .copied()
+                .collect(),
+            None => opts.hello_extensions.clone(),
+        };
+        if missing.is_empty() {
+            return false;
+        }
+        log::warn!(
+            "connection:{} from {} client hello lacks extensions {:?}, pass to fallback",
+            self.index,
+            self.peer_addr,
+            missing
+        );
+        true
+    }
+
+    /// hold a short first packet in `data` while it may still become a trojan request
+    fn wait_request(&mut self, buffer: &[u8], opts: &Opts) -> bool {
+        if buffer.len() >= opts.server_args().first_packet_wait
+            || !maybe_request(buffer, opts.pass_len)
+        {
+            return false;
+        }
+        log::debug!(
+            "connection:{} holds {} bytes of a partial request",
+            self.index,
+            buffer.len()
+        );
+        self.data.extend_from_slice(buffer);
+        true
+    }
+
+    /// data after tls handshake is not a trojan request, pass http through to the fallback
+    /// backend, anything else is most likely a probe and handled by `unknown_payload_action`
+    fn try_unknown_payload(&mut self, buffer: &[u8], opts: &mut Opts, poll: &Poll) -> bool {
+        self.command = CONNECT;
+        self.sock5_addr = Sock5Address::None;
+        if opts.unknown_payload_action == PayloadAction::Fallback || is_http_request(buffer) {
+            log::debug!(
+                "connection:{} does not get a trojan request, pass through",
+                self.index
+            );
+            return true;
+        }
+        log::warn!(
+            "connection:{} from {} sent {} bytes unknown payload",
+            self.index,
+            self.peer_addr,
+            buffer.len()
+        );
+        match opts.unknown_payload_action {
+            PayloadAction::Close => {
+                self.closing = true;
+                false
+            }
+            PayloadAction::Reset => {
+                self.proxy.reset(poll);
+                self.closing = true;
+                false
+            }
+            _ => {
+                self.target_addr = opts.sink_addr;
+                true
+            }
+        }
+    }
+
+    fn dispatch(&mut self, buffer: &[u8], opts: &mut Opts, poll: &Poll) {
         log::debug!(
             "connection:{} dispatch {} bytes request data",
             self.index,
             buffer.len()
         );
+        // continue a partial request held by `wait_request`
+        let pending;
+        let mut buffer = if matches!(self.status, Status::HandShake) && !self.data.is_empty() {
+            self.data.extend_from_slice(buffer);
+            pending = std::mem::take(&mut self.data);
+            pending.as_slice()
+        } else {
+            buffer
+        };
         loop {
             match self.status {
                 Status::HandShake => {
@@ -240,6 +735,7 @@ impl Connection {
                         if let Err(err) = self.data.write(buffer) {
                             log::warn!("connection:{} cache data failed {}", self.index, err);
                             self.closing = true;
+                            opts.stats.add_error();
                             return;
                         }
 
@@ -259,6 +755,9 @@ impl Connection {
                     }
                 }
                 _ => {
+                    if self.alternate_addr.is_some() && self.command == CONNECT {
+                        self.keep_for_retry(buffer);
+                    }
                     if let Some(backend) = self.backend.as_mut() {
                         backend.dispatch(buffer, opts);
                     } else {
@@ -271,39 +770,123 @@ impl Connection {
     }
 
     fn try_setup_tcp_target(&mut self, opts: &mut Opts, poll: &Poll) -> bool {
+        // fallback address is configured by ourselves, only check the requested ones
+        if opts.block_self_connect
+            && !matches!(self.sock5_addr, Sock5Address::None)
+            && opts.is_self_connect(self.target_addr.as_ref().unwrap())
+        {
+            log::warn!(
+                "connection:{} from {} requests self connect to {}, rejected",
+                self.index,
+                self.peer_addr,
+                self.target_addr.unwrap()
+            );
+            self.closing = true;
+            opts.stats.add_error();
+            return false;
+        }
+        if let Some(reason) = opts.link_local_error(self.target_addr.as_ref().unwrap()) {
+            log::warn!(
+                "connection:{} from {} requests {}, {}, rejected",
+                self.index,
+                self.peer_addr,
+                self.target_addr.unwrap(),
+                reason
+            );
+            self.closing = true;
+            opts.stats.add_error();
+            return false;
+        }
         log::debug!(
             "connection:{} make a target connection to {}",
             self.index,
             self.target_addr.unwrap()
         );
-        match TcpStream::connect(self.target_addr.as_ref().unwrap()) {
+        let args = opts.server_args();
+        match connect_target(
+            self.target_addr.as_ref().unwrap(),
+            args.outbound_port,
+            args.outbound_ttl,
+        ) {
             Ok(tcp_target) => {
                 if let Err(err) = sys::set_mark(&tcp_target, opts.marker) {
                     log::error!("connection:{} set mark failed:{}", self.index, err);
                     self.closing = true;
+                    opts.stats.add_error();
                     return false;
                 } else if let Err(err) = poll.register(
                     &tcp_target,
                     self.target_token(),
-                    Ready::readable(),
+                    // writable tells connect finished
+                    Ready::readable() | Ready::writable(),
                     PollOpt::edge(),
                 ) {
                     log::error!("connection:{} register target failed:{}", self.index, err);
                     self.closing = true;
+                    opts.stats.add_error();
+                    opts.stats.add_register_failure();
                     return false;
                 } else if let Err(err) = tcp_target.set_nodelay(true) {
                     log::error!("connection:{} set nodelay failed:{}", self.index, err);
                     self.closing = true;
+                    opts.stats.add_error();
+                    return false;
+                } else if let Err(err) = set_keepalive(&tcp_target, opts) {
+                    log::error!("connection:{} set keepalive failed:{}", self.index, err);
+                    self.closing = true;
+                    opts.stats.add_error();
+                    return false;
+                } else if let Err(err) = sys::set_buffer_size(
+                    &tcp_target,
+                    opts.server_args().socket_recv_buffer,
+                    opts.server_args().socket_send_buffer,
+                ) {
+                    log::error!("connection:{} set buffer size failed:{}", self.index, err);
+                    self.closing = true;
+                    opts.stats.add_error();
                     return false;
                 }
                 let mut backend = TcpBackend::new(
                     tcp_target,
                     self.index,
                     self.target_token(),
-                    opts.tcp_idle_duration,
+                    opts,
+                    self.peer_addr,
+                    self.target_addr.unwrap(),
+                    self.buffers.clone(),
                 );
+                if opts.server_args().send_proxy_protocol {
+                    match self.proxy.local_addr() {
+                        Ok(local_addr) => {
+                            // the protocol was agreed on for the fallback, not tunneled targets
+                            let alpn = match self.sock5_addr {
+                                Sock5Address::None => self.proxy.alpn_protocol(),
+                                _ => None,
+                            };
+                            backend.send_proxy_header(&local_addr, alpn)
+                        }
+                        Err(err) => {
+                            log::error!(
+                                "connection:{} get local address for proxy protocol failed:{}",
+                                self.index,
+                                err
+                            );
+                            self.closing = true;
+                            opts.stats.add_error();
+                            return false;
+                        }
+                    }
+                }
+                let target = self.sock5_addr.to_string().to_ascii_lowercase();
+                if let Some(sni) = opts.target_tls.get(&target) {
+                    backend.start_tls(opts.target_tls_config.as_ref().unwrap(), sni.as_ref());
+                }
                 if !self.data.is_empty() {
                     backend.dispatch(self.data.as_slice(), opts);
+                    if self.alternate_addr.is_some() {
+                        let data = std::mem::take(&mut self.data);
+                        self.keep_for_retry(data.as_slice());
+                    }
                     self.data.clear();
                     self.data.shrink_to_fit();
                 }
@@ -311,7 +894,16 @@ impl Connection {
             }
             Err(err) => {
                 log::warn!("connection:{} connect to target failed:{}", self.index, err);
+                if let Some(addr) = self.alternate_addr.take() {
+                    log::warn!("connection:{} retry {}", self.index, addr);
+                    self.target_addr = Some(addr);
+                    return self.try_setup_tcp_target(opts, poll);
+                }
                 self.closing = true;
+                opts.stats.add_error();
+                if opts.unreachable_action == UnreachableAction::Reset {
+                    self.proxy.reset(poll);
+                }
                 return false;
             }
         }
@@ -320,16 +912,50 @@ impl Connection {
 
     fn try_setup_udp_target(&mut self, opts: &mut Opts, poll: &Poll) -> bool {
         log::debug!("connection:{} got udp connection", self.index);
-        match UdpSocket::bind(opts.empty_addr.as_ref().unwrap()) {
+        let limit = opts.server_args().max_udp_sessions;
+        let session = match opts.limits.take(Counter::UdpSessions, limit) {
+            Some(session) => session,
+            None => {
+                log::warn!(
+                    "connection:{} udp associate refused, {} sessions reach the global limit",
+                    self.index,
+                    limit
+                );
+                self.closing = true;
+                return false;
+            }
+        };
+        match udp_backend::bind(opts) {
             Err(err) => {
                 log::error!("connection:{} bind udp socket failed:{}", self.index, err);
                 self.closing = true;
+                opts.stats.add_error();
                 return false;
             }
             Ok(udp_target) => {
+                let ttl = opts.server_args().outbound_ttl;
                 if let Err(err) = sys::set_mark(&udp_target, opts.marker) {
                     log::error!("connection:{} set mark failed:{}", self.index, err);
                     self.closing = true;
+                    opts.stats.add_error();
+                    return false;
+                } else if ttl != 0 {
+                    let ipv6 = matches!(udp_target.local_addr(), Ok(addr) if addr.is_ipv6());
+                    if let Err(err) = sys::set_ttl(&udp_target, ipv6, ttl) {
+                        log::error!("connection:{} set ttl failed:{}", self.index, err);
+                        self.closing = true;
+                        opts.stats.add_error();
+                        return false;
+                    }
+                }
+                if let Err(err) = sys::set_buffer_size(
+                    &udp_target,
+                    opts.server_args().socket_recv_buffer,
+                    opts.server_args().socket_send_buffer,
+                ) {
+                    log::error!("connection:{} set buffer size failed:{}", self.index, err);
+                    self.closing = true;
+                    opts.stats.add_error();
                     return false;
                 }
                 if let Err(err) = poll.register(
@@ -344,15 +970,19 @@ impl Connection {
                         err
                     );
                     self.closing = true;
+                    opts.stats.add_error();
+                    opts.stats.add_register_failure();
                     return false;
                 }
                 let backend = UdpBackend::new(
                     udp_target,
                     self.index,
+                    self.peer_addr,
                     self.target_token(),
-                    opts.udp_idle_duration,
+                    opts,
                 );
                 self.backend.replace(Box::new(backend));
+                session.keep();
             }
         }
         true
@@ -370,6 +1000,551 @@ impl Connection {
     }
 
     fn target_token(&self) -> Token {
-        Token((self.index * CHANNEL_CNT) + CHANNEL_BACKEND)
+        Channel::Backend.token(self.index)
+    }
+}
+
+/// connect from a fixed source port if `port` is not 0, the port is shared with other targets.
+/// `ttl` is set before connecting if not 0, so syn carries it too
+fn connect_target(addr: &SocketAddr, port: u16, ttl: u8) -> std::io::Result<TcpStream> {
+    if port == 0 && ttl == 0 {
+        return TcpStream::connect(addr);
+    }
+    let (domain, local) = if addr.is_ipv4() {
+        (Domain::ipv4(), IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+    } else {
+        (Domain::ipv6(), IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+    };
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+    if ttl != 0 {
+        sys::set_ttl(&socket, addr.is_ipv6(), ttl)?;
+    }
+    if port != 0 {
+        socket.set_reuse_address(true)?;
+        sys::set_reuse_port(&socket)?;
+        socket.bind(&SockAddr::from(SocketAddr::new(local, port)))?;
+    }
+    TcpStream::connect_stream(socket.into_tcp_stream(), addr)
+}
+
+/// ipv4 clients on a dual stack listener show up as ipv4-mapped ipv6 address
+fn is_ipv6(addr: &SocketAddr) -> bool {
+    match addr {
+        SocketAddr::V4(_) => false,
+        SocketAddr::V6(v6) => {
+            let segments = v6.ip().segments();
+            !(segments[..5].iter().all(|s| *s == 0) && segments[5] == 0xffff)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    use crate::test_support::*;
+
+    #[test]
+    fn stalled_tls_handshake() {
+        let server = start_server(&["--tls-handshake-timeout", "1"]);
+        let mut stream = TcpStream::connect(server).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        // first bytes of a client hello, never finished
+        stream.write_all(&[0x16, 0x03, 0x01]).unwrap();
+        let mut buffer = [0u8; 16];
+        match stream.read(&mut buffer) {
+            Ok(size) => assert_eq!(size, 0),
+            Err(err) => assert_eq!(err.kind(), ErrorKind::ConnectionReset),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "test-support"))]
+mod client_tests {
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+    use std::thread::{sleep, spawn};
+    use std::time::{Duration, Instant};
+
+    use socket2::{Domain, SockAddr, Socket, Type};
+
+    use crate::proto::{CONNECT, DEADLINE_FLAG};
+    use crate::test_support::*;
+
+    const GARBAGE: &[u8] = b"\x00\x01\xfe not trojan, not http \xff";
+
+    fn echoed(client: &mut TrojanClient, data: &[u8]) -> Vec<u8> {
+        client.write_all(data).unwrap();
+        let mut buffer = vec![0u8; data.len()];
+        client.read_exact(buffer.as_mut_slice()).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn unknown_payload_close() {
+        let server = start_server(&["--unknown-payload-action", "close"]);
+        let mut client = TrojanClient::raw(server).unwrap();
+        client.write_all(GARBAGE).unwrap();
+        assert!(close_notified(&mut client));
+    }
+
+    #[test]
+    fn unknown_payload_reset() {
+        let server = start_server(&["--unknown-payload-action", "reset"]);
+        let mut client = TrojanClient::raw(server).unwrap();
+        client.write_all(GARBAGE).unwrap();
+        let mut buffer = [0u8; 16];
+        let err = client.read(&mut buffer).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn unknown_payload_sink() {
+        let sink = start_echo().to_string();
+        let server = start_server(&[
+            "--unknown-payload-action",
+            "sink",
+            "--unknown-payload-sink",
+            sink.as_str(),
+        ]);
+        let mut client = TrojanClient::raw(server).unwrap();
+        assert_eq!(echoed(&mut client, GARBAGE), GARBAGE);
+    }
+
+    #[test]
+    fn http_still_falls_back() {
+        let backend = start_echo().to_string();
+        let server = start_server(&["-r", backend.as_str(), "--unknown-payload-action", "close"]);
+        let mut client = TrojanClient::raw(server).unwrap();
+        let request = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        assert_eq!(echoed(&mut client, request), &request[..]);
+    }
+
+    #[test]
+    fn alpn_forwarded_to_fallback() {
+        let backend = start_echo().to_string();
+        let server = start_server(&[
+            "-r",
+            backend.as_str(),
+            "--send-proxy-protocol",
+            "--alpn",
+            "h2",
+            "--alpn",
+            "http/1.1",
+            "--allow-self-connect",
+        ]);
+        let mut client = TrojanClient::raw_alpn(server, &["h2", "http/1.1"]).unwrap();
+        assert_eq!(client.alpn_protocol().unwrap(), b"h2");
+        let request = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        client.write_all(request).unwrap();
+        // the fallback gets a header with the addresses and the negotiated protocol first
+        let mut buffer = vec![0u8; 33 + request.len()];
+        client.read_exact(buffer.as_mut_slice()).unwrap();
+        assert_eq!(&buffer[14..16], &[0, 17]);
+        assert_eq!(&buffer[28..33], &[0x01, 0, 2, b'h', b'2']);
+        assert_eq!(&buffer[33..], &request[..]);
+
+        // tunneled targets get the addresses only
+        let echo = start_echo();
+        let mut client = TrojanClient::raw_alpn(server, &["h2"]).unwrap();
+        client
+            .write_all(&request_header(PASSWORD, CONNECT, &echo))
+            .unwrap();
+        client.write_all(b"ping").unwrap();
+        let mut buffer = [0u8; 32];
+        client.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer[14..16], &[0, 12]);
+        assert_eq!(&buffer[28..], b"ping");
+
+        // h2 is preferred by default, like browsers do, http/1.1 clients still get theirs
+        let server = start_server(&[]);
+        let mut client = TrojanClient::raw_alpn(server, &["h2", "http/1.1"]).unwrap();
+        assert_eq!(client.alpn_protocol().unwrap(), b"h2");
+        let mut client = TrojanClient::raw_alpn(server, &["http/1.1"]).unwrap();
+        assert_eq!(client.alpn_protocol().unwrap(), b"http/1.1");
+
+        let server = start_server(&["--alpn", "none"]);
+        let mut client = TrojanClient::raw_alpn(server, &["h2"]).unwrap();
+        assert_eq!(client.alpn_protocol(), None);
+    }
+
+    #[test]
+    fn valid_request_then_garbage() {
+        let server = start_server(&["--allow-self-connect", "--unknown-payload-action", "close"]);
+        let echo = start_echo();
+        let mut client = TrojanClient::connect(server, PASSWORD, &echo).unwrap();
+        // classification is done once, later data is payload of the tunnel
+        assert_eq!(echoed(&mut client, GARBAGE), GARBAGE);
+    }
+
+    #[test]
+    fn split_request_is_held() {
+        let fallback = start_flood().to_string();
+        let server = start_server(&[
+            "--allow-self-connect",
+            "-r",
+            fallback.as_str(),
+            "--first-packet-wait",
+            "64",
+        ]);
+        let echo = start_echo();
+        let request = request_header(PASSWORD, CONNECT, &echo);
+        let mut client = TrojanClient::raw(server).unwrap();
+        client.write_all(&request[..20]).unwrap();
+        sleep(Duration::from_millis(100));
+        client.write_all(&request[20..]).unwrap();
+        assert_eq!(echoed(&mut client, b"tunneled"), b"tunneled");
+    }
+
+    #[test]
+    fn wrong_password_replayed_to_fallback() {
+        let fallback = start_echo().to_string();
+        let server = start_server(&["-r", fallback.as_str(), "--first-packet-wait", "64"]);
+        let echo = start_echo();
+        let mut request = request_header("wrong password", CONNECT, &echo);
+        request.extend_from_slice(b"GET / HTTP/1.1\r\n\r\n");
+        let mut client = TrojanClient::raw(server).unwrap();
+        // the first part is held as it may still be a trojan request
+        client.write_all(&request[..20]).unwrap();
+        sleep(Duration::from_millis(100));
+        client.write_all(&request[20..]).unwrap();
+        let mut buffer = vec![0u8; request.len()];
+        client.read_exact(buffer.as_mut_slice()).unwrap();
+        assert_eq!(buffer, request);
+    }
+
+    #[test]
+    fn probe_goes_to_fallback_at_once() {
+        let fallback = start_echo().to_string();
+        let server = start_server(&["-r", fallback.as_str(), "--first-packet-wait", "64"]);
+        let mut client = TrojanClient::raw(server).unwrap();
+        assert_eq!(echoed(&mut client, GARBAGE), GARBAGE);
+    }
+
+    #[test]
+    fn simultaneous_close() {
+        let admin_addr = free_addr().to_string();
+        let server = start_server(&["--allow-self-connect", "--admin-addr", admin_addr.as_str()]);
+        // target closes as soon as data arrives, while the client closes right after sending
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = listener.local_addr().unwrap();
+        spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let _ = stream.read(&mut [0u8; 16]);
+            }
+        });
+        for _ in 0..20 {
+            let mut client = TrojanClient::connect(server, PASSWORD, &target).unwrap();
+            client.write_all(b"bye").unwrap();
+            client.shutdown();
+        }
+        sleep(Duration::from_millis(200));
+        assert!(admin(&admin_addr, "status").ends_with("total 0 connections\n"));
+    }
+
+    #[test]
+    fn udp_sessions_over_limit_refused() {
+        let server = start_server(&["--allow-self-connect", "--max-udp-sessions", "1"]);
+        let echo = start_udp_echo("127.0.0.1:0");
+        let mut first = TrojanClient::associate(server, PASSWORD).unwrap();
+        first.send_to(b"first", &echo).unwrap();
+        assert_eq!(first.recv_from().unwrap().1, b"first");
+
+        let mut second = TrojanClient::associate(server, PASSWORD).unwrap();
+        assert!(close_notified(&mut second));
+
+        // closing the first association frees the slot
+        first.shutdown();
+        drop(first);
+        sleep(Duration::from_millis(100));
+        let mut third = TrojanClient::associate(server, PASSWORD).unwrap();
+        third.send_to(b"third", &echo).unwrap();
+        assert_eq!(third.recv_from().unwrap().1, b"third");
+    }
+
+    #[test]
+    fn typical_client_hello_passes() {
+        let server = start_server(&["--allow-self-connect", "--client-hello-check"]);
+        let echo = start_echo();
+        let mut client = TrojanClient::connect(server, PASSWORD, &echo).unwrap();
+        assert_eq!(echoed(&mut client, b"hello"), b"hello");
+    }
+
+    #[test]
+    fn atypical_client_hello_goes_to_fallback() {
+        let fallback = start_echo().to_string();
+        // the test client sends no alpn extension
+        let server = start_server(&[
+            "--allow-self-connect",
+            "-r",
+            fallback.as_str(),
+            "--client-hello-check",
+            "--client-hello-extensions",
+            "0,16",
+        ]);
+        let target = start_flood();
+        let mut client = TrojanClient::raw(server).unwrap();
+        let request = request_header(PASSWORD, CONNECT, &target);
+        assert_eq!(echoed(&mut client, request.as_slice()), request);
+    }
+
+    #[test]
+    fn targets_share_source_port() {
+        let port = free_addr().port().to_string();
+        let server = start_server(&["--allow-self-connect", "--outbound-port", port.as_str()]);
+        // targets answer with the source port they see
+        let targets: Vec<_> = (0..2)
+            .map(|_| {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let target = listener.local_addr().unwrap();
+                spawn(move || {
+                    for mut stream in listener.incoming().flatten() {
+                        let port = stream.peer_addr().unwrap().port();
+                        let _ = stream.write_all(&port.to_be_bytes());
+                        let _ = stream.read(&mut [0u8; 16]);
+                    }
+                });
+                target
+            })
+            .collect();
+        let mut clients: Vec<_> = targets
+            .iter()
+            .map(|target| TrojanClient::connect(server, PASSWORD, target).unwrap())
+            .collect();
+        for client in &mut clients {
+            client.write_all(b"port").unwrap();
+            let mut buffer = [0u8; 2];
+            client.read_exact(&mut buffer).unwrap();
+            assert_eq!(u16::from_be_bytes(buffer).to_string(), port);
+        }
+    }
+
+    #[test]
+    fn ip_literal_targets_skip_dns() {
+        let statsd = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        statsd
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let statsd_addr = statsd.local_addr().unwrap().to_string();
+        let server = start_server(&[
+            "--allow-self-connect",
+            "--statsd-addr",
+            statsd_addr.as_str(),
+            "--statsd-interval",
+            "1",
+        ]);
+        let echo = start_echo();
+        // an ipv4 address, and an ip in a domain address
+        let mut client = TrojanClient::connect(server, PASSWORD, &echo).unwrap();
+        assert_eq!(echoed(&mut client, b"ipv4"), b"ipv4");
+        let mut client = TrojanClient::raw(server).unwrap();
+        let mut request = domain_request_header(PASSWORD, "127.0.0.1", echo.port());
+        request.extend_from_slice(b"text");
+        client.write_all(request.as_slice()).unwrap();
+        let mut buffer = [0u8; 4];
+        client.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"text");
+
+        // every push after both connections were set up
+        let mut buffer = [0u8; 2048];
+        for _ in 0..2 {
+            let size = statsd.recv(&mut buffer).unwrap();
+            let metrics = String::from_utf8_lossy(&buffer[..size]).to_string();
+            assert!(metrics.contains("trojan.dns_lookups:0|c"), "{}", metrics);
+        }
+    }
+
+    #[test]
+    fn client_deadline_closes() {
+        let server = start_server(&["--allow-self-connect", "--allow-client-deadline"]);
+        let echo = start_echo();
+        let mut client = TrojanClient::raw(server).unwrap();
+        let mut request = request_header(PASSWORD, CONNECT | DEADLINE_FLAG, &echo);
+        request.extend_from_slice(&1u32.to_be_bytes());
+        request.extend_from_slice(b"hello");
+        client.write_all(request.as_slice()).unwrap();
+        let mut buffer = [0u8; 5];
+        client.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"hello");
+        // closed by the deadline, while the echo target would keep it open
+        assert!(matches!(client.read(&mut buffer), Ok(0) | Err(_)));
+    }
+
+    /// how the target sees the client resetting its connection
+    fn target_close(args: &[&str]) -> ErrorKind {
+        let server = start_server(args);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = listener.local_addr().unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = [0u8; 16];
+            let _ = stream.read(&mut buffer);
+            let kind = match stream.read(&mut buffer) {
+                Ok(_) => ErrorKind::UnexpectedEof,
+                Err(err) => err.kind(),
+            };
+            sender.send(kind).unwrap();
+        });
+        let mut client = TrojanClient::connect(server, PASSWORD, &target).unwrap();
+        client.write_all(b"data").unwrap();
+        sleep(Duration::from_millis(100));
+        client.reset();
+        receiver.recv_timeout(Duration::from_secs(2)).unwrap()
+    }
+
+    #[test]
+    fn client_reset_closes_target() {
+        let args = ["--allow-self-connect"];
+        assert_eq!(target_close(&args), ErrorKind::UnexpectedEof);
+        let args = ["--allow-self-connect", "--client-reset-action", "reset"];
+        assert_eq!(target_close(&args), ErrorKind::ConnectionReset);
+    }
+
+    /// whether the client connection is reset instead of closed after requesting `target`
+    fn client_reset(args: &[&str], target: &SocketAddr) -> bool {
+        let server = start_server(args);
+        let mut client = TrojanClient::connect(server, PASSWORD, target).unwrap();
+        client.write_all(b"data").unwrap();
+        let mut buffer = [0u8; 16];
+        match client.read(&mut buffer) {
+            Ok(size) => {
+                assert_eq!(size, 0);
+                false
+            }
+            // the client may still be writing tls data when the reset arrives
+            Err(err) => matches!(
+                err.kind(),
+                ErrorKind::ConnectionReset | ErrorKind::BrokenPipe
+            ),
+        }
+    }
+
+    #[test]
+    fn refused_target() {
+        let target = free_addr();
+        let args = ["--allow-self-connect"];
+        assert!(!client_reset(&args, &target));
+        let args = ["--allow-self-connect", "--unreachable-action", "reset"];
+        assert!(client_reset(&args, &target));
+    }
+
+    #[test]
+    fn unreachable_target_times_out() {
+        // syn to a listener with a full backlog is dropped, connecting never finishes
+        let socket = Socket::new(Domain::ipv4(), Type::stream(), None).unwrap();
+        socket
+            .bind(&SockAddr::from(
+                "127.0.0.1:0".parse::<SocketAddr>().unwrap(),
+            ))
+            .unwrap();
+        socket.listen(0).unwrap();
+        let target = socket.local_addr().unwrap().as_inet().unwrap().into();
+        let mut backlog = Vec::new();
+        while let Ok(stream) = TcpStream::connect_timeout(&target, Duration::from_millis(200)) {
+            backlog.push(stream);
+        }
+        let args = [
+            "--allow-self-connect",
+            "--target-connect-timeout",
+            "1",
+            "--unreachable-action",
+            "reset",
+        ];
+        let start = Instant::now();
+        assert!(client_reset(&args, &target));
+        assert!(start.elapsed() < Duration::from_secs(4));
+    }
+
+    #[test]
+    fn static_host_skips_dns() {
+        let echo = start_echo();
+        let host = format!("echo.invalid={}", echo.ip());
+        let server = start_server(&["--allow-self-connect", "--static-host", host.as_str()]);
+        let mut client = TrojanClient::raw(server).unwrap();
+        // .invalid never resolves, the connection only works with the static address
+        let mut request = domain_request_header(PASSWORD, "ECHO.invalid", echo.port());
+        request.extend_from_slice(b"hello");
+        client.write_all(request.as_slice()).unwrap();
+        let mut buffer = [0u8; 5];
+        client.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"hello");
+    }
+
+    #[test]
+    fn refused_target_retried_over_other_family() {
+        // the ipv4 address is tried first, nothing listens there on the port of the echo
+        let echo = start_echo_at("[::1]:0");
+        let request = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.extend_from_slice(&[
+                "--allow-self-connect",
+                "--static-host",
+                "dual.test=127.0.0.1",
+                "--static-host",
+                "dual.test=::1",
+            ]);
+            let server = start_server(args.as_slice());
+            let mut client = TrojanClient::raw(server).unwrap();
+            let mut request = domain_request_header(PASSWORD, "dual.test", echo.port());
+            request.extend_from_slice(b"hello");
+            client.write_all(request.as_slice()).unwrap();
+            let mut buffer = [0u8; 5];
+            client.read_exact(&mut buffer).map(|_| buffer)
+        };
+        assert!(request(&[]).is_err());
+        assert_eq!(&request(&["--retry-other-family"]).unwrap(), b"hello");
+        // now the ipv4 target accepts and closes at once
+        let listener = TcpListener::bind(("127.0.0.1", echo.port())).unwrap();
+        spawn(move || {
+            for stream in listener.incoming() {
+                drop(stream);
+            }
+        });
+        assert!(request(&[]).is_err());
+        assert_eq!(&request(&["--retry-other-family"]).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn close_notify_sent_on_target_close() {
+        let server = start_server(&["--allow-self-connect"]);
+        let target = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target_addr = target.local_addr().unwrap();
+        spawn(move || {
+            let (mut stream, _) = target.accept().unwrap();
+            let mut buffer = [0u8; 4];
+            stream.read_exact(&mut buffer).unwrap();
+            stream.write_all(b"pong").unwrap();
+        });
+        let mut client = TrojanClient::connect(server, PASSWORD, &target_addr).unwrap();
+        client.write_all(b"ping").unwrap();
+        let mut buffer = [0u8; 4];
+        client.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"pong");
+        assert!(close_notified(&mut client));
+    }
+
+    #[test]
+    fn self_connect_rejected() {
+        let server = start_server(&[]);
+        // the listen address, as an ipv4-mapped ipv6 address
+        let mapped = SocketAddr::new(
+            IpAddr::V6(Ipv4Addr::LOCALHOST.to_ipv6_mapped()),
+            server.port(),
+        );
+        let mut client = TrojanClient::connect(server, PASSWORD, &mapped).unwrap();
+        assert!(close_notified(&mut client));
+    }
+
+    /// rustls reports a close_notify as aborted, a bare fin as eof
+    fn close_notified(client: &mut TrojanClient) -> bool {
+        matches!(
+            client.read(&mut [0u8; 16]),
+            Err(err) if err.kind() == ErrorKind::ConnectionAborted
+        )
     }
 }
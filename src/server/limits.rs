@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Counts the connection limits are checked against, shared by all workers so a limit caps
+/// the whole server instead of each worker.
+pub struct Limits {
+    connections: AtomicUsize,
+    handshakes: AtomicUsize,
+    udp_sessions: AtomicUsize,
+    /// live connections of each client address
+    per_ip: Mutex<HashMap<IpAddr, usize>>,
+    /// token bucket of accepted connections and its last refill time
+    accept_tokens: Mutex<(f64, Instant)>,
+}
+
+#[derive(Copy, Clone)]
+pub enum Counter {
+    /// connections in the pool of any worker
+    Connections,
+    /// connections in tls handshake
+    Handshakes,
+    /// connections with a udp backend set up
+    UdpSessions,
+    /// connections from one client address
+    Ip(IpAddr),
+}
+
+/// one unit of a count taken by `Limits::take`, given back on drop unless kept
+pub struct Slot {
+    limits: Arc<Limits>,
+    counter: Counter,
+    kept: bool,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            connections: AtomicUsize::new(0),
+            handshakes: AtomicUsize::new(0),
+            udp_sessions: AtomicUsize::new(0),
+            per_ip: Mutex::new(HashMap::new()),
+            // full on the first refill, whatever the burst is
+            accept_tokens: Mutex::new((f64::INFINITY, Instant::now())),
+        }
+    }
+}
+
+impl Limits {
+    fn atomic(&self, counter: Counter) -> Option<&AtomicUsize> {
+        match counter {
+            Counter::Connections => Some(&self.connections),
+            Counter::Handshakes => Some(&self.handshakes),
+            Counter::UdpSessions => Some(&self.udp_sessions),
+            Counter::Ip(_) => None,
+        }
+    }
+
+    pub fn count(&self, counter: Counter) -> usize {
+        match (self.atomic(counter), counter) {
+            (Some(count), _) => count.load(Ordering::Relaxed),
+            (None, Counter::Ip(ip)) => self.per_ip.lock().unwrap().get(&ip).copied().unwrap_or(0),
+            (None, _) => unreachable!(),
+        }
+    }
+
+    /// add one to `counter` unless it already reached `limit`, 0 for no limit
+    pub fn take(self: &Arc<Self>, counter: Counter, limit: usize) -> Option<Slot> {
+        let below = |count: usize| {
+            if limit == 0 || count < limit {
+                Some(count + 1)
+            } else {
+                None
+            }
+        };
+        let taken = match (self.atomic(counter), counter) {
+            (Some(count), _) => count
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, below)
+                .is_ok(),
+            (None, Counter::Ip(ip)) => {
+                let mut per_ip = self.per_ip.lock().unwrap();
+                let count = per_ip.entry(ip).or_insert(0);
+                match below(*count) {
+                    Some(taken) => {
+                        *count = taken;
+                        true
+                    }
+                    None => false,
+                }
+            }
+            (None, _) => unreachable!(),
+        };
+        if taken {
+            Some(Slot {
+                limits: self.clone(),
+                counter,
+                kept: false,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// give back one unit of `counter` taken by a kept slot
+    pub fn release(&self, counter: Counter) {
+        match (self.atomic(counter), counter) {
+            (Some(count), _) => {
+                count.fetch_sub(1, Ordering::Relaxed);
+            }
+            (None, Counter::Ip(ip)) => {
+                let mut per_ip = self.per_ip.lock().unwrap();
+                if let Some(count) = per_ip.get_mut(&ip) {
+                    *count -= 1;
+                    if *count == 0 {
+                        per_ip.remove(&ip);
+                    }
+                }
+            }
+            (None, _) => unreachable!(),
+        }
+    }
+
+    /// refill the accept token bucket, false if no connection may be accepted now
+    pub fn refill_accept_tokens(&self, rate: u32, burst: u32) -> bool {
+        if rate == 0 {
+            return true;
+        }
+        let rate = rate as f64;
+        let burst = if burst == 0 { rate } else { burst as f64 };
+        let now = Instant::now();
+        let mut bucket = self.accept_tokens.lock().unwrap();
+        let (tokens, refill_time) = &mut *bucket;
+        let elapsed = (now - *refill_time).as_secs_f64();
+        *refill_time = now;
+        *tokens = (*tokens + elapsed * rate).min(burst);
+        *tokens >= 1.0
+    }
+
+    /// charge a connection accepted to the bucket, several workers may take the last token
+    /// at once, the bucket goes below 0 and is paid back by the next refills
+    pub fn take_accept_token(&self) {
+        // never drops below 1 without a limit, the bucket stays infinite
+        self.accept_tokens.lock().unwrap().0 -= 1.0;
+    }
+
+    /// time until the next accept token at `rate`
+    pub fn accept_wait(&self, rate: u32) -> Duration {
+        let tokens = self.accept_tokens.lock().unwrap().0;
+        Duration::from_secs_f64((1.0 - tokens).max(0.0) / rate as f64)
+    }
+}
+
+impl Slot {
+    /// the unit is held by a connection now, given back by `Limits::release` when it goes
+    pub fn keep(mut self) {
+        self.kept = true;
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if !self.kept {
+            self.limits.release(self.counter);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::Arc;
+
+    use super::{Counter, Limits};
+
+    #[test]
+    fn slots_given_back_unless_kept() {
+        let limits = Arc::new(Limits::default());
+        let ip = Counter::Ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        for counter in [Counter::Connections, ip].iter().copied() {
+            let first = limits.take(counter, 2).unwrap();
+            limits.take(counter, 2).unwrap().keep();
+            assert!(limits.take(counter, 2).is_none());
+            drop(first);
+            assert_eq!(limits.count(counter), 1);
+            limits.take(counter, 2).unwrap().keep();
+            limits.release(counter);
+            limits.release(counter);
+            assert_eq!(limits.count(counter), 0);
+            assert!(limits.take(counter, 0).is_some());
+        }
+        assert!(limits.per_ip.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,314 @@
+use std::fmt::Write as _;
+use std::io::{ErrorKind, Read, Write};
+use std::net::Shutdown;
+
+use mio::net::{TcpListener, TcpStream};
+use mio::{Poll, PollOpt, Ready, Token};
+
+use crate::config::Opts;
+use crate::server::METRICS_CLIENT;
+use crate::stats::HANDSHAKE_BUCKETS;
+
+/// requests larger than this are not a scrape, the client is dropped
+const MAX_REQUEST_SIZE: usize = 4096;
+
+/// Prometheus scrape endpoint, answers `GET /metrics` with counters in the text format.
+pub struct Metrics {
+    listener: TcpListener,
+    clients: Vec<MetricsClient>,
+}
+
+struct MetricsClient {
+    stream: TcpStream,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    closed: bool,
+}
+
+impl Metrics {
+    pub fn new(listener: TcpListener) -> Metrics {
+        Metrics {
+            listener,
+            clients: Vec::new(),
+        }
+    }
+
+    pub fn listener(&self) -> &TcpListener {
+        &self.listener
+    }
+
+    pub fn accept(&mut self, poll: &Poll) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(err) = poll.register(
+                        &stream,
+                        Token(METRICS_CLIENT),
+                        Ready::readable(),
+                        PollOpt::edge(),
+                    ) {
+                        log::error!("register metrics client failed:{}", err);
+                        continue;
+                    }
+                    self.clients.push(MetricsClient {
+                        stream,
+                        input: Vec::new(),
+                        output: Vec::new(),
+                        closed: false,
+                    });
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    log::error!("metrics accept failed:{}", err);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// all metrics clients share one token, so every client is checked on each event
+    pub fn ready(&mut self, poll: &Poll, active: usize, opts: &Opts) {
+        for client in &mut self.clients {
+            client.do_read();
+            if let Some(path) = client.request() {
+                let response = respond(path.as_str(), active, opts);
+                client.output.extend_from_slice(response.as_bytes());
+            }
+            if !client.output.is_empty() {
+                client.do_send(poll);
+            }
+            if client.closed {
+                let _ = poll.deregister(&client.stream);
+                let _ = client.stream.shutdown(Shutdown::Both);
+            }
+        }
+        self.clients.retain(|client| !client.closed);
+    }
+}
+
+impl MetricsClient {
+    fn do_read(&mut self) {
+        let mut buffer = [0u8; 1024];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => {
+                    self.closed = self.output.is_empty();
+                    break;
+                }
+                Ok(size) => {
+                    self.input.extend_from_slice(&buffer[..size]);
+                    if self.input.len() > MAX_REQUEST_SIZE {
+                        log::warn!("metrics request too large, close client");
+                        self.closed = true;
+                        break;
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    log::warn!("read from metrics client failed:{}", err);
+                    self.closed = true;
+                    break;
+                }
+            }
+        }
+    }
+
+    /// path of a GET request once its headers are complete, None for other methods
+    fn request(&mut self) -> Option<String> {
+        if self.closed || !self.output.is_empty() {
+            return None;
+        }
+        let end = self.input.windows(4).position(|w| w == b"\r\n\r\n")?;
+        let head = String::from_utf8_lossy(&self.input[..end]).to_string();
+        self.input.clear();
+        let mut parts = head.lines().next().unwrap_or("").split(' ');
+        match (parts.next(), parts.next()) {
+            (Some("GET"), Some(path)) => Some(path.to_string()),
+            _ => Some(String::new()),
+        }
+    }
+
+    fn do_send(&mut self, poll: &Poll) {
+        loop {
+            if self.output.is_empty() {
+                self.closed = true;
+                return;
+            }
+            match self.stream.write(self.output.as_slice()) {
+                Ok(size) => {
+                    let _ = self.output.drain(..size);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    log::warn!("write to metrics client failed:{}", err);
+                    self.closed = true;
+                    return;
+                }
+            }
+        }
+        if let Err(err) = poll.reregister(
+            &self.stream,
+            Token(METRICS_CLIENT),
+            Ready::writable(),
+            PollOpt::edge(),
+        ) {
+            log::warn!("reregister metrics client failed:{}", err);
+            self.closed = true;
+        }
+    }
+}
+
+fn respond(path: &str, active: usize, opts: &Opts) -> String {
+    if path != "/metrics" {
+        return "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            .to_string();
+    }
+    let body = render(active, opts);
+    format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )
+}
+
+/// counters and gauges in the prometheus text exposition format
+fn render(active: usize, opts: &Opts) -> String {
+    let stats = opts.stats.snapshot();
+    let metrics = [
+        (
+            "connections_accepted_total",
+            "counter",
+            "connections accepted by the listener",
+            stats.accepted,
+        ),
+        (
+            "connections_active",
+            "gauge",
+            "connections alive",
+            active as u64,
+        ),
+        (
+            "bytes_read_total",
+            "counter",
+            "bytes read from targets",
+            stats.bytes_read,
+        ),
+        (
+            "bytes_sent_total",
+            "counter",
+            "bytes sent to targets",
+            stats.bytes_sent,
+        ),
+        (
+            "timeouts_total",
+            "counter",
+            "connections closed by a timeout or deadline",
+            stats.timeouts,
+        ),
+        (
+            "handshake_timeouts_total",
+            "counter",
+            "connections closed for not finishing tls handshake in time",
+            stats.handshake_timeouts,
+        ),
+        (
+            "accept_errors_total",
+            "counter",
+            "failures accepting or setting up new connections",
+            stats.accept_errors,
+        ),
+        (
+            "errors_total",
+            "counter",
+            "failures on accepting, resolving or connecting",
+            stats.errors,
+        ),
+        (
+            "register_failures_total",
+            "counter",
+            "sockets failed to register with poll",
+            stats.register_failures,
+        ),
+    ];
+    let mut body = String::new();
+    for (name, kind, help, value) in metrics.iter() {
+        let _ = write!(
+            body,
+            "# HELP trojan_{0} {1}\n# TYPE trojan_{0} {2}\ntrojan_{0} {3}\n",
+            name, help, kind, value
+        );
+    }
+    body.push_str(
+        "# HELP trojan_tls_handshake_seconds time from accept to tls handshake done\n\
+         # TYPE trojan_tls_handshake_seconds histogram\n",
+    );
+    let mut count = 0;
+    for (bound, bucket) in HANDSHAKE_BUCKETS.iter().zip(&stats.handshake_buckets) {
+        count += bucket;
+        let _ = writeln!(
+            body,
+            "trojan_tls_handshake_seconds_bucket{{le=\"{}\"}} {}",
+            *bound as f64 / 1000.0,
+            count
+        );
+    }
+    count += stats.handshake_buckets[HANDSHAKE_BUCKETS.len()];
+    let _ = write!(
+        body,
+        "trojan_tls_handshake_seconds_bucket{{le=\"+Inf\"}} {0}\n\
+         trojan_tls_handshake_seconds_sum {1}\n\
+         trojan_tls_handshake_seconds_count {0}\n",
+        count,
+        stats.handshake_micros as f64 / 1_000_000.0
+    );
+    body
+}
+
+#[cfg(all(test, feature = "test-support"))]
+mod client_tests {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    use crate::test_support::*;
+
+    fn get(addr: &str, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, addr).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn metrics_scraped() {
+        let metrics_addr = free_addr().to_string();
+        let server = start_server(&[
+            "--allow-self-connect",
+            "--metrics-addr",
+            metrics_addr.as_str(),
+        ]);
+        let echo = start_echo();
+        let mut client = TrojanClient::connect(server, PASSWORD, &echo).unwrap();
+        let mut buffer = [0u8; 4];
+        client.write_all(b"ping").unwrap();
+        client.read_exact(&mut buffer).unwrap();
+
+        let response = get(metrics_addr.as_str(), "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("# TYPE trojan_connections_active gauge\n"));
+        assert!(response.contains("\ntrojan_connections_active 1\n"));
+        assert!(response.contains("\ntrojan_bytes_read_total 4\n"));
+        assert!(response.contains("\ntrojan_bytes_sent_total 4\n"));
+        assert!(response.contains("# TYPE trojan_tls_handshake_seconds histogram\n"));
+        assert!(response.contains("\ntrojan_tls_handshake_seconds_bucket{le=\"+Inf\"} 1\n"));
+        assert!(response.contains("\ntrojan_tls_handshake_seconds_count 1\n"));
+
+        let response = get(metrics_addr.as_str(), "/");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -1,98 +1,928 @@
 use std::fs::File;
 use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::thread::JoinHandle;
 use std::time::{Duration, Instant};
 
+use chrono::Utc;
 use mio::net::TcpListener;
-use mio::{Events, Poll, PollOpt, Ready, Token};
+use mio::{Event, Events, Poll, PollOpt, Ready, Token};
+use mio_extras::channel::{channel, Receiver, Sender};
 use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
-use rustls::{KeyLogFile, NoClientAuth, ServerConfig};
+use rustls::{
+    Certificate, ClientConfig, KeyLogFile, NoClientAuth, PrivateKey, ServerConfig, SignatureScheme,
+};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 
+pub use limits::{Counter, Limits};
+#[cfg(feature = "netem")]
+pub use netem::Netem;
 pub use tls_server::TlsServer;
 
 use crate::config::Opts;
+use crate::server::admin::{Admin, AdminListener};
+use crate::server::buffer_pool::BufferPool;
+use crate::server::checkpoint::TrafficCheckpoint;
+use crate::server::metrics::Metrics;
+use crate::server::otlp::SpanExporter;
+use crate::server::schedule::TrafficSchedule;
+use crate::server::statsd::StatsdEmitter;
+use crate::stats::StatsSnapshot;
 
+mod admin;
+mod buffer_pool;
+mod cert_check;
+mod checkpoint;
 mod connection;
+mod limits;
+mod metrics;
+#[cfg(feature = "netem")]
+mod netem;
+mod otlp;
+mod schedule;
+mod statsd;
 mod tcp_backend;
 mod tls_server;
 mod udp_backend;
 
-const MIN_INDEX: usize = 2;
-const MAX_INDEX: usize = std::usize::MAX / CHANNEL_CNT;
-const CHANNEL_CNT: usize = 2;
-const CHANNEL_PROXY: usize = 0;
-const CHANNEL_BACKEND: usize = 1;
+pub(crate) const MIN_INDEX: usize = 4;
+/// the top token stays below usize::MAX, which mio keeps for itself
+pub(crate) const MAX_INDEX: usize = (std::usize::MAX - CHANNEL_CNT) / CHANNEL_CNT;
+const CHANNEL_CNT: usize = Channel::ALL.len();
 const LISTENER: usize = 1;
+const ADMIN: usize = 2;
+const ADMIN_CLIENT: usize = 3;
+const SIGNAL: usize = 4;
+const METRICS: usize = 5;
+const METRICS_CLIENT: usize = 6;
+/// commands from the first worker, polled by the other ones
+const COMMANDS: usize = 7;
+/// time for connections shut down at the drain deadline to flush, then they are closed
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+/// poll registration failures within one check interval reported as a resource problem
+const REGISTER_FAILURE_SPIKE: u64 = 16;
+#[cfg(feature = "netem")]
+const NETEM: usize = 0;
+
+/// max uptime or max connections served is reached, time to drain and restart
+fn restart_due(opts: &Opts, started: Instant, now: Instant) -> bool {
+    let args = opts.server_args();
+    if args.max_uptime > 0 && now - started >= Duration::from_secs(args.max_uptime) {
+        log::warn!("up for {:?}, drain for restart", now - started);
+        return true;
+    }
+    let served = opts.stats.accepted.load(Ordering::Relaxed);
+    if args.max_served > 0 && served >= args.max_served {
+        log::warn!("{} connections served, drain for restart", served);
+        return true;
+    }
+    false
+}
+
+/// log throughput since `last` was taken `elapsed` ago, and traffic of each connection
+fn log_stats(server: &TlsServer, opts: &Opts, elapsed: Duration, last: &StatsSnapshot) {
+    let current = opts.stats.snapshot();
+    let seconds = elapsed.as_secs_f64();
+    log::info!(
+        "throughput read {:.0} bytes/s, sent {:.0} bytes/s, {} connections",
+        (current.bytes_read - last.bytes_read) as f64 / seconds,
+        (current.bytes_sent - last.bytes_sent) as f64 / seconds,
+        opts.limits.count(Counter::Connections)
+    );
+    log::debug!("stats {}", server.stats().to_json());
+}
+
+/// admin listener on the configured tcp address or unix socket, None if admin is disabled
+fn bind_admin(opts: &Opts) -> Option<AdminListener> {
+    let args = opts.server_args();
+    if let Some(path) = &args.admin_socket {
+        #[cfg(unix)]
+        {
+            let listener = crate::sys::bind_unix(
+                path.as_str(),
+                opts.admin_socket_mode,
+                args.admin_socket_owner.as_deref(),
+            )
+            .unwrap_or_else(|err| panic!("bind admin socket {} failed:{}", path, err));
+            log::warn!(
+                "admin listening on {} with mode {:o}",
+                path,
+                opts.admin_socket_mode
+            );
+            return Some(AdminListener::Unix(listener));
+        }
+        #[cfg(not(unix))]
+        panic!("admin socket {} not supported in windows", path);
+    }
+    let addr = args.admin_addr.as_ref()?.parse().unwrap();
+    let listener = TcpListener::bind(&addr).unwrap();
+    log::warn!("admin listening on {}", addr);
+    Some(AdminListener::Tcp(listener))
+}
+
+fn load_private_key(path: &str) -> PrivateKey {
+    read_private_key(path).unwrap_or_else(|err| panic!("{}", err))
+}
+
+fn read_private_key(path: &str) -> Result<PrivateKey, String> {
+    let open = || {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|err| format!("open private key {} failed:{}", path, err))
+    };
+    let keys = pkcs8_private_keys(&mut open()?).unwrap_or_default();
+    if let Some(key) = keys.get(0) {
+        log::info!("pkcs8 private key found");
+        return Ok(key.clone());
+    }
+    let keys = rsa_private_keys(&mut open()?).unwrap_or_default();
+    if let Some(key) = keys.get(0) {
+        log::info!("rsa private key found");
+        Ok(key.clone())
+    } else {
+        Err(format!("no private key found in {}", path))
+    }
+}
+
+/// whether a signature made with `key` fails verification by the public key in `cert`, so a
+/// renewed certificate paired with the old key is caught before serving. keys or certificates
+/// that can not be checked here are left to rustls
+fn key_mismatch(cert: &Certificate, key: &PrivateKey) -> bool {
+    let schemes = [
+        (
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            &webpki::ECDSA_P256_SHA256,
+        ),
+        (
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            &webpki::ECDSA_P384_SHA384,
+        ),
+        (SignatureScheme::ED25519, &webpki::ED25519),
+        (
+            SignatureScheme::RSA_PKCS1_SHA256,
+            &webpki::RSA_PKCS1_2048_8192_SHA256,
+        ),
+    ];
+    let offered: Vec<_> = schemes.iter().map(|(scheme, _)| *scheme).collect();
+    let signer = match rustls::sign::any_supported_type(key)
+        .ok()
+        .and_then(|key| key.choose_scheme(offered.as_slice()))
+    {
+        Some(signer) => signer,
+        None => return false,
+    };
+    let message = b"trojan certificate and key check";
+    let signature = match signer.sign(message) {
+        Ok(signature) => signature,
+        Err(_) => return true,
+    };
+    let algorithm = schemes
+        .iter()
+        .find(|(scheme, _)| *scheme == signer.get_scheme())
+        .map(|(_, algorithm)| *algorithm);
+    match (webpki::EndEntityCert::from(cert.0.as_slice()), algorithm) {
+        (Ok(cert), Some(algorithm)) => cert
+            .verify_signature(algorithm, message, signature.as_slice())
+            .is_err(),
+        _ => false,
+    }
+}
+
+/// client config for targets reached over tls, None if there is no such target
+fn init_target_config(opts: &Opts) -> Option<Arc<ClientConfig>> {
+    if opts.target_tls.is_empty() {
+        return None;
+    }
+    let args = opts.server_args();
+    let mut config = ClientConfig::new();
+    if let Some(path) = &args.target_tls_ca {
+        let mut buff_reader = BufReader::new(File::open(path).unwrap());
+        let (added, _) = config.root_store.add_pem_file(&mut buff_reader).unwrap();
+        if added == 0 {
+            panic!("no ca certificate found in {}", path);
+        }
+    } else {
+        config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    }
+    if let (Some(cert), Some(key)) = (&args.target_tls_cert, &args.target_tls_key) {
+        let mut buff_reader = BufReader::new(File::open(cert).unwrap());
+        let cert_chain = certs(&mut buff_reader).unwrap();
+        config
+            .set_single_client_cert(cert_chain, load_private_key(key.as_str()))
+            .unwrap();
+    }
+    Some(Arc::new(config))
+}
 
 fn init_config(opts: &Opts) -> Arc<ServerConfig> {
+    load_config(opts).unwrap_or_else(|err| panic!("{}, refuse to start", err))
+}
+
+/// read the certificate and key again for new connections, the current ones are kept on failure
+fn reload_config(server: &mut TlsServer, workers: &[Worker], opts: &Opts) {
+    match load_config(opts) {
+        Ok(config) => {
+            broadcast(workers, Command::Reload(config.clone()));
+            server.reload_config(config);
+            log::warn!("certificate reloaded, used by new connections");
+        }
+        Err(err) => log::error!("{}, keep serving the previous certificate", err),
+    }
+}
+
+/// tls config with the certificate and key read from their files, an error if either of them
+/// can not be served
+fn load_config(opts: &Opts) -> Result<Arc<ServerConfig>, String> {
+    let args = opts.server_args();
     let mut config = ServerConfig::new(NoClientAuth::new());
     config.key_log = Arc::new(KeyLogFile::new());
-    let cert_file = File::open(opts.server_args().cert.clone()).unwrap();
+    let cert_file = File::open(args.cert.as_str())
+        .map_err(|err| format!("open certificate {} failed:{}", args.cert, err))?;
     let mut buff_reader = BufReader::new(cert_file);
-    let cert_chain = certs(&mut buff_reader).unwrap();
-    let key_der = {
-        let key_file = File::open(opts.server_args().key.clone()).unwrap();
-        let mut buff_reader = BufReader::new(key_file);
-        let keys = pkcs8_private_keys(&mut buff_reader).unwrap();
-        if let Some(key) = keys.get(0) {
-            log::info!("pkcs8 private key found");
-            key.clone()
-        } else {
-            let key_file = File::open(opts.server_args().key.clone()).unwrap();
-            let mut buff_reader = BufReader::new(key_file);
-            let keys = rsa_private_keys(&mut buff_reader).unwrap();
-            if let Some(key) = keys.get(0) {
-                log::info!("rsa private key found");
-                key.clone()
-            } else {
-                panic!("no private key found");
-            }
-        }
-    };
-    config.set_single_cert(cert_chain, key_der).unwrap();
+    let cert_chain = certs(&mut buff_reader).unwrap_or_default();
+    if cert_chain.is_empty() {
+        return Err(format!("no certificate found in {}", args.cert));
+    }
+    if !cert_check::check(opts, cert_chain.as_slice()) {
+        return Err("certificate check failed".to_string());
+    }
+    let key_der = read_private_key(args.key.as_str())?;
+    if key_mismatch(&cert_chain[0], &key_der) {
+        return Err(format!(
+            "private key {} does not match certificate {}",
+            args.key, args.cert
+        ));
+    }
+    config
+        .set_single_cert(cert_chain, key_der)
+        .map_err(|err| format!("set certificate failed:{}", err))?;
     let mut protocols: Vec<Vec<u8>> = Vec::new();
-    for protocol in &opts.server_args().alpn {
+    for protocol in args.alpn.iter().filter(|protocol| *protocol != "none") {
         protocols.push(protocol.as_str().into());
     }
     if !protocols.is_empty() {
         config.set_protocols(&protocols);
     }
-    Arc::new(config)
+    Ok(Arc::new(config))
 }
 
-pub fn run(opts: &mut Opts) {
-    let config = init_config(opts);
-    let poll = Poll::new().unwrap();
-    let addr = opts.local_addr.parse().unwrap();
-    let listener = TcpListener::bind(&addr).unwrap();
+/// report a spike of poll registration failures since `last` was counted, returns the count now
+fn check_register_failures(opts: &Opts, last: u64) -> u64 {
+    let current = opts.stats.register_failures.load(Ordering::Relaxed);
+    if current - last >= REGISTER_FAILURE_SPIKE {
+        log::error!(
+            "{} sockets failed to register with poll in the last check, \
+             file descriptors or kernel memory may be running out",
+            current - last
+        );
+    }
+    current
+}
+
+/// the listener is broken, existing connections are drained and the server exits with an error
+fn accept_failed(server: &mut TlsServer, poll: &Poll, err: &std::io::Error) -> i32 {
+    log::error!("accept failed:{}, stop accepting", err);
+    server.begin_shutdown(poll);
+    1
+}
+
+/// sockets of one connection, each is polled with token `index * CHANNEL_CNT + channel`
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Channel {
+    /// tls connection with the client
+    Proxy,
+    /// target socket, or dns resolver before the target is known
+    Backend,
+}
+
+impl Channel {
+    /// every channel in the order of their values, a new one is added to both
+    const ALL: [Channel; 2] = [Channel::Proxy, Channel::Backend];
+
+    pub fn token(self, index: usize) -> Token {
+        Token(index * CHANNEL_CNT + self as usize)
+    }
+
+    /// connection index and channel of a connection token
+    pub fn of(token: Token) -> (usize, Channel) {
+        (token.0 / CHANNEL_CNT, Channel::ALL[token.0 % CHANNEL_CNT])
+    }
+}
+
+/// control plane events go first, then connections in handshake, bulk data at last
+fn event_priority(token: Token, server: &TlsServer) -> u8 {
+    match token.0 {
+        LISTENER | ADMIN | ADMIN_CLIENT | SIGNAL | METRICS | METRICS_CLIENT | COMMANDS => 0,
+        #[cfg(feature = "netem")]
+        NETEM => 0,
+        _ if server.handshaking(token) => 1,
+        _ => 2,
+    }
+}
+
+/// order a batch of events by `event_priority`, events of the same priority keep their order
+fn prioritize(batch: &mut [Event], server: &TlsServer) {
+    batch.sort_by_key(|event| event_priority(event.token(), server));
+}
+
+/// sent by the first worker, which handles signals and the admin, to the other ones
+#[derive(Clone)]
+enum Command {
+    /// stop accepting, connections left after the timeout are shut down
+    Drain(Duration),
+    /// shut down connections still draining at once
+    StopDraining,
+    /// tls config for new connections
+    Reload(Arc<ServerConfig>),
+    /// admin command on the connections of the worker, answered with its reply
+    Admin(String, Sender<admin::Reply>),
+}
+
+/// a thread serving connections accepted on a listener of its own
+struct Worker {
+    commands: Sender<Command>,
+    handle: JoinHandle<i32>,
+}
+
+impl Worker {
+    fn spawn(
+        id: usize,
+        mut opts: Opts,
+        listener: TcpListener,
+        reserve: Option<File>,
+        config: Arc<ServerConfig>,
+    ) -> Worker {
+        let (commands, receiver) = channel();
+        let handle = std::thread::Builder::new()
+            .name(format!("worker-{}", id))
+            .spawn(move || {
+                let poll = Poll::new().unwrap();
+                register_listener(&poll, &listener);
+                poll.register(
+                    &receiver,
+                    Token(COMMANDS),
+                    Ready::readable(),
+                    PollOpt::edge(),
+                )
+                .unwrap();
+                #[cfg(feature = "netem")]
+                register_netem(&poll, &opts);
+                let server = new_server(&opts, listener, reserve, config);
+                let control = Control {
+                    commands: Some(receiver),
+                    ..Default::default()
+                };
+                serve(&mut opts, &poll, server, control)
+            })
+            .unwrap();
+        Worker { commands, handle }
+    }
+}
+
+/// parts of the event loop only the first worker has, empty in the other ones
+#[derive(Default)]
+struct Control {
+    admin: Option<Admin>,
+    metrics: Option<Metrics>,
+    statsd: Option<StatsdEmitter>,
+    checkpoint: Option<TrafficCheckpoint>,
+    schedule: Option<TrafficSchedule>,
+    /// the other workers, commanded by the first one
+    workers: Vec<Worker>,
+    /// commands from the first worker, None in the first worker itself
+    commands: Option<Receiver<Command>>,
+}
+
+fn broadcast(workers: &[Worker], command: Command) {
+    for worker in workers {
+        let _ = worker.commands.send(command.clone());
+    }
+}
+
+/// listener on `addr`, bound with SO_REUSEPORT if workers share the address, in which case the
+/// kernel spreads new connections over them
+fn bind_listener(addr: &SocketAddr, reuse_port: bool) -> std::io::Result<TcpListener> {
+    if !reuse_port {
+        return TcpListener::bind(addr);
+    }
+    let domain = if addr.is_ipv4() {
+        Domain::ipv4()
+    } else {
+        Domain::ipv6()
+    };
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+    socket.set_reuse_address(true)?;
+    crate::sys::set_reuse_port(&socket)?;
+    socket.bind(&SockAddr::from(*addr))?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into_tcp_listener())
+}
+
+fn register_listener(poll: &Poll, listener: &TcpListener) {
     poll.register(
-        &listener,
+        listener,
         Token(LISTENER),
         Ready::readable(),
         PollOpt::edge(),
     )
     .unwrap();
-    let mut server = TlsServer::new(listener, config);
-    let mut events = Events::with_capacity(1024);
+}
+
+#[cfg(feature = "netem")]
+fn register_netem(poll: &Poll, opts: &Opts) {
+    if let Some(netem) = &opts.netem {
+        poll.register(
+            netem.timer(),
+            Token(NETEM),
+            Ready::readable(),
+            PollOpt::edge(),
+        )
+        .unwrap();
+    }
+}
+
+/// shut down connections left at `deadline`, and close them once flushed or FLUSH_TIMEOUT
+/// after. returns true once all are closed
+fn drained(
+    server: &mut TlsServer,
+    poll: &Poll,
+    opts: &mut Opts,
+    deadline: Instant,
+    close_until: &mut Option<Instant>,
+) -> bool {
+    let now = Instant::now();
+    let active = server.connection_count();
+    if active > 0 && close_until.is_none() && now >= deadline {
+        log::warn!("drain timeout, shut down {} connections", active);
+        server.shutdown_all(poll, opts);
+        *close_until = Some(now + FLUSH_TIMEOUT);
+    }
+    let active = server.connection_count();
+    if active == 0 || matches!(*close_until, Some(close_until) if now >= close_until) {
+        log::warn!("drained, close {} connections and exit", active);
+        server.close_all(poll, opts);
+        return true;
+    }
+    false
+}
+
+/// runs the first worker and spawns the others, returns the exit code once all of them are
+/// drained for a restart or a shutdown
+pub fn run(opts: &mut Opts, workers: Vec<Opts>) -> i32 {
+    let config = init_config(opts);
+    opts.target_tls_config = init_target_config(opts);
+    let poll = Poll::new().unwrap();
+    let addr = opts.local_addr.parse().unwrap();
+    let reuse_port = !workers.is_empty();
+    let listener = bind_listener(&addr, reuse_port)
+        .unwrap_or_else(|err| panic!("bind {} failed:{}", addr, err));
+    register_listener(&poll, &listener);
+    #[cfg(feature = "netem")]
+    register_netem(&poll, opts);
+    let addr = listener.local_addr().unwrap();
+    let listeners: Vec<TcpListener> = workers
+        .iter()
+        .map(|_| {
+            bind_listener(&addr, true)
+                .unwrap_or_else(|err| panic!("bind {} for worker failed:{}", addr, err))
+        })
+        .collect();
+    // reserved before a chroot hides /dev/null, one for each worker
+    let reserve = crate::sys::reserve_fd();
+    let reserves: Vec<Option<File>> = workers.iter().map(|_| crate::sys::reserve_fd()).collect();
+    let admin = bind_admin(opts).map(|listener| {
+        let admin = Admin::new(listener);
+        poll.register(
+            admin.listener(),
+            Token(ADMIN),
+            Ready::readable(),
+            PollOpt::edge(),
+        )
+        .unwrap();
+        admin
+    });
+    let metrics = opts.server_args().metrics_addr.as_ref().map(|addr| {
+        let addr = addr.parse().unwrap();
+        let listener = TcpListener::bind(&addr)
+            .unwrap_or_else(|err| panic!("bind metrics address {} failed:{}", addr, err));
+        log::warn!("metrics listening on {}", addr);
+        let metrics = Metrics::new(listener);
+        poll.register(
+            metrics.listener(),
+            Token(METRICS),
+            Ready::readable(),
+            PollOpt::edge(),
+        )
+        .unwrap();
+        metrics
+    });
+    let run_as = opts.server_args().run_as.as_ref().map(|owner| {
+        let ids = crate::sys::lookup_user(owner.as_str())
+            .unwrap_or_else(|err| panic!("run as {} failed:{}", owner, err));
+        (owner, ids)
+    });
+    if let Some(path) = &opts.server_args().netns {
+        crate::sys::enter_netns(path.as_str())
+            .unwrap_or_else(|err| panic!("enter network namespace {} failed:{}", path, err));
+        log::warn!("listeners bound, entered network namespace {}", path);
+    }
+    if let Some(dir) = &opts.server_args().chroot {
+        crate::sys::chroot(dir.as_str())
+            .unwrap_or_else(|err| panic!("chroot to {} failed:{}", dir, err));
+        log::warn!("listeners bound, confined to {}", dir);
+    }
+    if let Some((owner, (uid, gid))) = run_as {
+        crate::sys::drop_privileges(uid, gid)
+            .unwrap_or_else(|err| panic!("run as {} failed:{}", owner, err));
+        log::warn!("listeners bound, running as {}", owner);
+        if let (Some(path), Some(pid)) = (&opts.pid_file, &opts.pid) {
+            if !pid.removable() {
+                log::warn!(
+                    "directory of pid file {} not writable by {}, the file is left on exit",
+                    path,
+                    owner
+                );
+            }
+        }
+    }
+    if let Some(signals) = &opts.signals {
+        poll.register(signals, Token(SIGNAL), Ready::readable(), PollOpt::edge())
+            .unwrap();
+    }
+    // spawned after confinement, so threads start in the network namespace entered
+    let workers: Vec<Worker> = workers
+        .into_iter()
+        .zip(listeners.into_iter().zip(reserves))
+        .enumerate()
+        .map(|(id, (mut worker, (listener, reserve)))| {
+            worker.target_tls_config = opts.target_tls_config.clone();
+            Worker::spawn(id + 1, worker, listener, reserve, config.clone())
+        })
+        .collect();
+    if !workers.is_empty() {
+        log::warn!("{} workers accepting on {}", workers.len() + 1, addr);
+    }
+    if let Some(notifier) = &opts.notifier {
+        notifier.ready();
+    }
+    let server = new_server(opts, listener, reserve, config);
+    let control = Control {
+        admin,
+        metrics,
+        statsd: StatsdEmitter::new(opts),
+        checkpoint: TrafficCheckpoint::new(opts),
+        schedule: TrafficSchedule::new(opts),
+        workers,
+        commands: None,
+    };
+    serve(opts, &poll, server, control)
+}
+
+/// server of one worker with a buffer pool and span exporter of its own
+fn new_server(
+    opts: &Opts,
+    listener: TcpListener,
+    reserve: Option<File>,
+    config: Arc<ServerConfig>,
+) -> TlsServer {
+    let buffers = BufferPool::new(opts.recv_buffer_size, opts.server_args().recv_buffer_pool);
+    let mut server = TlsServer::new(listener, reserve, config, buffers);
+    if let Some(exporter) = SpanExporter::new(opts) {
+        server.set_span_exporter(exporter);
+    }
+    server
+}
+
+/// event loop of a worker, the first one also serves the admin and metrics, handles signals and
+/// commands the other ones. returns the exit code once all of them are drained
+fn serve(opts: &mut Opts, poll: &Poll, mut server: TlsServer, mut control: Control) -> i32 {
+    let first = control.commands.is_none();
+    let mut events = Events::with_capacity(opts.poll_events);
+    let mut batch: Vec<Event> = Vec::with_capacity(opts.poll_events);
+    let prioritize_handshakes = opts.server_args().prioritize_handshakes;
     let mut last_check_time = Instant::now();
     let check_duration = Duration::new(1, 0);
+    let started = last_check_time;
+    let stats_log_interval = Duration::from_secs(opts.server_args().stats_log_interval);
+    let mut last_stats_log = (started, opts.stats.snapshot());
+    let mut register_failures = 0;
+    // accept is stopped until this time, then remaining connections are shut down
+    let mut drain_until = None;
+    // connections shut down at the drain deadline are closed after this time
+    let mut close_until = None;
+    let mut exit_code = 0;
     loop {
-        let nevent = poll.poll(&mut events, Some(check_duration)).unwrap();
+        let mut timeout = match server.accept_wait(opts) {
+            Some(wait) => wait.min(check_duration),
+            None => check_duration,
+        };
+        let admin_deadline = control.admin.as_ref().and_then(Admin::deadline);
+        if let Some(deadline) = admin_deadline {
+            timeout = timeout.min(deadline.saturating_duration_since(Instant::now()));
+        }
+        let nevent = poll.poll(&mut events, Some(timeout)).unwrap();
         log::trace!("poll got {} events", nevent);
-        for event in &events {
+        opts.stats.add_poll();
+        batch.clear();
+        batch.extend(events.iter());
+        if prioritize_handshakes {
+            prioritize(&mut batch, &server);
+        }
+        for event in &batch {
             match event.token() {
                 Token(LISTENER) => {
-                    server.accept(&poll, opts);
+                    if let Err(err) = server.accept(poll, opts) {
+                        exit_code = accept_failed(&mut server, poll, &err);
+                    }
+                }
+                Token(ADMIN) => {
+                    control.admin.as_mut().unwrap().accept(poll);
+                }
+                Token(ADMIN_CLIENT) => {
+                    control.admin.as_mut().unwrap().ready(
+                        poll,
+                        &mut server,
+                        &control.workers,
+                        opts,
+                    );
+                }
+                Token(METRICS) => {
+                    control.metrics.as_mut().unwrap().accept(poll);
+                }
+                Token(METRICS_CLIENT) => {
+                    let active = opts.limits.count(Counter::Connections);
+                    control.metrics.as_mut().unwrap().ready(poll, active, opts);
+                }
+                Token(SIGNAL) => {
+                    for signal in opts.signals.as_ref().unwrap().pending() {
+                        if signal == crate::sys::RELOAD_SIGNAL {
+                            reload_config(&mut server, &control.workers, opts);
+                            continue;
+                        }
+                        exit_code = 0;
+                        if server.begin_shutdown(poll) {
+                            log::warn!("signal {} received, drain connections", signal);
+                        } else if drain_until.is_some() {
+                            log::warn!("signal {} received again, stop draining", signal);
+                            drain_until = Some(Instant::now());
+                            broadcast(&control.workers, Command::StopDraining);
+                        }
+                    }
+                }
+                // only wakes up the loop, commands are taken below
+                Token(COMMANDS) => {}
+                #[cfg(feature = "netem")]
+                Token(NETEM) => {
+                    let tokens = opts.netem.as_mut().unwrap().expired();
+                    for token in tokens {
+                        server.do_conn_event(poll, &Event::new(Ready::writable(), token), opts);
+                    }
                 }
                 _ => {
-                    server.do_conn_event(&poll, &event, opts);
+                    server.do_conn_event(poll, event, opts);
                 }
             }
         }
         let now = Instant::now();
+        if matches!(admin_deadline, Some(deadline) if now >= deadline) {
+            // workers not answered in time are left out of the response
+            let admin = control.admin.as_mut().unwrap();
+            admin.ready(poll, &mut server, &control.workers, opts);
+        }
         if now - last_check_time > check_duration {
-            server.check_timeout(now, &poll);
+            server.check_timeout(now, poll, opts);
+            if let Err(err) = server.accept_starved(poll, opts) {
+                exit_code = accept_failed(&mut server, poll, &err);
+            }
             last_check_time = now;
+            if first {
+                register_failures = check_register_failures(opts, register_failures);
+                if stats_log_interval.as_secs() > 0 && now - last_stats_log.0 >= stats_log_interval
+                {
+                    log_stats(&server, opts, now - last_stats_log.0, &last_stats_log.1);
+                    last_stats_log = (now, opts.stats.snapshot());
+                }
+                if drain_until.is_none()
+                    && !server.shutting_down()
+                    && restart_due(opts, started, now)
+                {
+                    server.begin_shutdown(poll);
+                    let timeout = Duration::from_secs(opts.server_args().drain_timeout);
+                    drain_until = Some(now + timeout);
+                    broadcast(&control.workers, Command::Drain(timeout));
+                    exit_code = opts.server_args().restart_exit_code;
+                }
+            }
+            if let Some(statsd) = control.statsd.as_mut() {
+                statsd.check(now, &opts.stats, opts.limits.count(Counter::Connections));
+            }
+            if let Some(schedule) = control.schedule.as_mut() {
+                // persist the new period at once, a restart must not restore the ended one
+                if schedule.check(Utc::now(), &opts.stats) {
+                    if let Some(checkpoint) = &control.checkpoint {
+                        checkpoint.save(&opts.stats);
+                    }
+                }
+            }
+            if let Some(checkpoint) = control.checkpoint.as_mut() {
+                checkpoint.check(now, &opts.stats);
+            }
+            if let Some(notifier) = opts.notifier.as_mut() {
+                notifier.watchdog(now);
+            }
+        }
+        if let Some(commands) = &control.commands {
+            while let Ok(command) = commands.try_recv() {
+                match command {
+                    Command::Drain(timeout) => {
+                        server.begin_shutdown(poll);
+                        drain_until = Some(now + timeout);
+                    }
+                    Command::StopDraining => drain_until = Some(now),
+                    Command::Reload(config) => server.reload_config(config),
+                    Command::Admin(command, reply) => {
+                        let _ = reply.send(admin::reply(&command, &mut server, poll, opts));
+                    }
+                }
+            }
+        }
+        if drain_until.is_none() && server.shutting_down() {
+            // shut down by a signal or the admin, or the listener broke
+            let timeout = Duration::from_secs(opts.server_args().shutdown_timeout);
+            drain_until = Some(now + timeout);
+            broadcast(&control.workers, Command::Drain(timeout));
+        }
+        if let Some(deadline) = drain_until {
+            if drained(&mut server, poll, opts, deadline, &mut close_until) {
+                for worker in control.workers.drain(..) {
+                    let code = worker.handle.join().unwrap_or(1);
+                    if exit_code == 0 {
+                        exit_code = code;
+                    }
+                }
+                if let Some(checkpoint) = &control.checkpoint {
+                    checkpoint.save(&opts.stats);
+                }
+                if let Some(notifier) = &opts.notifier {
+                    notifier.stopping();
+                }
+                return exit_code;
+            }
+        }
+        if let Err(err) = server.accept_deferred(poll, opts) {
+            exit_code = accept_failed(&mut server, poll, &err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_tokens() {
+        for (value, channel) in Channel::ALL.iter().enumerate() {
+            assert_eq!(*channel as usize, value);
+            for index in &[MIN_INDEX, MIN_INDEX + 1, MAX_INDEX] {
+                assert_eq!(Channel::of(channel.token(*index)), (*index, *channel));
+            }
+        }
+        // tokens of connections never take ones of the listeners and signals, nor the one of mio
+        assert!(Channel::Proxy.token(MIN_INDEX) > Token(COMMANDS));
+        let top = Channel::ALL[CHANNEL_CNT - 1].token(MAX_INDEX);
+        assert!(top < Token(usize::MAX));
+    }
+
+    #[test]
+    fn handshakes_before_data() {
+        use mio::Ready;
+
+        use crate::test_support::{free_addr, server_opts};
+
+        let addr = free_addr();
+        let mut opts = server_opts(addr, &[]);
+        let listener = bind_listener(&addr, false).unwrap();
+        let poll = Poll::new().unwrap();
+        let mut server = new_server(&opts, listener, None, init_config(&opts));
+        // the client never says hello, the connection stays in handshake
+        let _client = std::net::TcpStream::connect(addr).unwrap();
+        let mut handshake = None;
+        for _ in 0..100 {
+            server.accept(&poll, &mut opts).unwrap();
+            handshake = (MIN_INDEX..MIN_INDEX + 8)
+                .map(|index| Channel::Proxy.token(index))
+                .find(|token| server.handshaking(*token));
+            if handshake.is_some() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
         }
+        let handshake = handshake.unwrap();
+        let data: Vec<Token> = (MIN_INDEX + 8..MIN_INDEX + 11)
+            .map(|index| Channel::Backend.token(index))
+            .collect();
+        let mut batch: Vec<Event> = data
+            .iter()
+            .chain(&[handshake, Token(COMMANDS)])
+            .map(|token| Event::new(Ready::readable(), *token))
+            .collect();
+        prioritize(&mut batch, &server);
+        let order: Vec<Token> = batch.iter().map(Event::token).collect();
+        assert_eq!(order[..2], [Token(COMMANDS), handshake]);
+        assert_eq!(order[2..], data[..]);
+        assert_eq!(event_priority(Token(COMMANDS), &server), 0);
+        assert_eq!(event_priority(data[0], &server), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn chroot_keeps_notify_and_reserve() {
+        use std::os::unix::net::UnixDatagram;
+
+        use crate::daemon::Notifier;
+        use crate::test_support::{admin, free_addr, server_opts};
+
+        if unsafe { libc::getuid() } != 0 {
+            return;
+        }
+        let temp = |name: &str| {
+            std::env::temp_dir().join(format!("trojan-test-{}-{}", std::process::id(), name))
+        };
+        let dir = temp("jail");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = temp("notify");
+        let _ = std::fs::remove_file(&path);
+        let manager = UnixDatagram::bind(&path).unwrap();
+        manager
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let admin_addr = free_addr().to_string();
+        let args = [
+            "--chroot",
+            dir.to_str().unwrap(),
+            "--admin-addr",
+            admin_addr.as_str(),
+            "--max-uptime",
+            "2",
+        ];
+        let mut opts = server_opts(free_addr(), &args);
+        opts.notifier = Some(Notifier::connect(path.to_str().unwrap().to_string(), None).unwrap());
+        // the test process keeps its root, the server is confined in a child
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            let code = run(&mut opts, Vec::new());
+            unsafe { libc::_exit(code) };
+        }
+        let mut buffer = [0u8; 64];
+        let size = manager.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], b"READY=1");
+        let status = admin(&admin_addr, "status");
+        assert!(
+            !status.contains("no file descriptor in reserve"),
+            "{}",
+            status
+        );
+        let size = manager.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], b"STOPPING=1");
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        std::fs::remove_dir_all(dir).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(libc::WIFEXITED(status));
+    }
+
+    #[test]
+    fn config_loaded() {
+        use clap::Clap;
+
+        let load = |cert: &str, key: &str| {
+            let certs = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs/");
+            let cert = format!("{}{}", certs, cert);
+            let key = format!("{}{}", certs, key);
+            let args = [
+                "trojan",
+                "-a",
+                "127.0.0.1:443",
+                "-p",
+                "pw",
+                "server",
+                "-c",
+                cert.as_str(),
+                "-k",
+                key.as_str(),
+            ];
+            load_config(&Opts::parse_from(args.iter())).map(|_| ())
+        };
+        assert!(load("cert.pem", "key.pem").is_ok());
+        assert!(load("target-cert.pem", "target-key.pem").is_ok());
+        assert!(load("cert.pem", "target-key.pem")
+            .unwrap_err()
+            .contains("does not match"));
+        assert!(load("no-such-cert.pem", "key.pem").is_err());
+        assert!(load("target-ca.pem", "no-such-key.pem").is_err());
     }
 }
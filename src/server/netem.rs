@@ -0,0 +1,73 @@
+//! Artificial latency and packet loss on the relay path, for testing only.
+//!
+//! Enabled with the `netem` cargo feature, never build a production binary with it.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use mio::Token;
+use mio_extras::timer::Timer;
+
+pub struct Netem {
+    latency: Duration,
+    loss: f64,
+    seed: u64,
+    timer: Timer<Token>,
+}
+
+impl Netem {
+    pub fn new(latency: u64, loss: f64, seed: u64) -> Netem {
+        log::warn!(
+            "network simulation enabled, latency:{}ms, loss:{}%",
+            latency,
+            loss
+        );
+        let seed = if seed == 0 {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or(1)
+                | 1
+        } else {
+            seed
+        };
+        Netem {
+            latency: Duration::from_millis(latency),
+            loss: loss / 100.0,
+            seed,
+            timer: Timer::default(),
+        }
+    }
+
+    pub fn latency(&self) -> Duration {
+        self.latency
+    }
+
+    pub fn timer(&self) -> &Timer<Token> {
+        &self.timer
+    }
+
+    /// wake up `token` with a writable event after the simulated latency
+    pub fn schedule(&mut self, token: Token) {
+        let _ = self.timer.set_timeout(self.latency, token);
+    }
+
+    /// tokens whose delayed data is due
+    pub fn expired(&mut self) -> Vec<Token> {
+        let mut tokens = Vec::new();
+        while let Some(token) = self.timer.poll() {
+            tokens.push(token);
+        }
+        tokens
+    }
+
+    /// whether current packet should be dropped
+    pub fn lose(&mut self) -> bool {
+        if self.loss <= 0.0 {
+            return false;
+        }
+        // xorshift64, good enough for simulation and reproducible with a fixed seed
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        ((self.seed >> 11) as f64 / (1u64 << 53) as f64) < self.loss
+    }
+}
@@ -0,0 +1,274 @@
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::Opts;
+
+/// spans waiting for the exporter thread, new ones are dropped when it is full
+const QUEUE_SIZE: usize = 4096;
+/// max spans in one export request
+const BATCH_SIZE: usize = 256;
+
+/// A finished connection, exported as one span
+pub struct Span {
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub client: SocketAddr,
+    pub target: String,
+    /// None for connections never matching a user, like ones passed to the fallback
+    pub user: Option<String>,
+    pub bytes_read: u64,
+    pub bytes_sent: u64,
+}
+
+/// Sends connection spans to an OpenTelemetry collector with OTLP/HTTP in json encoding.
+/// requests are made from a separate thread, so a slow collector never blocks the event loop.
+pub struct SpanExporter {
+    sender: SyncSender<Span>,
+    dropped: usize,
+}
+
+impl SpanExporter {
+    pub fn new(opts: &Opts) -> Option<SpanExporter> {
+        let addr = opts.otlp_addr?;
+        let (sender, receiver) = sync_channel(QUEUE_SIZE);
+        let worker = Worker {
+            addr,
+            service: opts.server_args().otlp_service_name.clone(),
+            ids: IdGenerator::new(),
+            body: String::new(),
+        };
+        if let Err(err) = thread::Builder::new()
+            .name("otlp".to_string())
+            .spawn(move || worker.run(receiver))
+        {
+            log::error!("start otlp exporter failed:{}", err);
+            return None;
+        }
+        log::info!("connection spans will be sent to {}", addr);
+        Some(SpanExporter { sender, dropped: 0 })
+    }
+
+    pub fn export(&mut self, span: Span) {
+        match self.sender.try_send(span) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.dropped += 1;
+                if self.dropped.is_power_of_two() {
+                    log::warn!("otlp exporter is behind, {} spans dropped", self.dropped);
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                log::error!("otlp exporter stopped, span dropped");
+            }
+        }
+    }
+}
+
+struct Worker {
+    addr: SocketAddr,
+    service: String,
+    ids: IdGenerator,
+    body: String,
+}
+
+impl Worker {
+    fn run(mut self, receiver: Receiver<Span>) {
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+        while let Ok(span) = receiver.recv() {
+            batch.push(span);
+            // collect spans of connections closed around the same time into one request
+            while batch.len() < BATCH_SIZE {
+                match receiver.recv_timeout(Duration::from_secs(1)) {
+                    Ok(span) => batch.push(span),
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            self.encode(batch.as_slice());
+            if let Err(err) = self.post() {
+                log::warn!(
+                    "export {} spans to {} failed:{}",
+                    batch.len(),
+                    self.addr,
+                    err
+                );
+            }
+            batch.clear();
+        }
+    }
+
+    fn encode(&mut self, spans: &[Span]) {
+        self.body.clear();
+        let _ = write!(
+            self.body,
+            "{{\"resourceSpans\":[{{\"resource\":{{\"attributes\":[{}]}},\
+             \"scopeSpans\":[{{\"scope\":{{\"name\":\"trojan\"}},\"spans\":[",
+            string_attribute("service.name", &self.service)
+        );
+        for (i, span) in spans.iter().enumerate() {
+            if i > 0 {
+                self.body.push(',');
+            }
+            let user = span
+                .user
+                .as_deref()
+                .map(|user| format!(",{}", string_attribute("trojan.user", user)))
+                .unwrap_or_default();
+            let _ = write!(
+                self.body,
+                "{{\"traceId\":\"{:016x}{:016x}\",\"spanId\":\"{:016x}\",\"name\":\"connection\",\
+                 \"kind\":2,\"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\",\
+                 \"attributes\":[{},{},{},{},{}{}]}}",
+                self.ids.next(),
+                self.ids.next(),
+                self.ids.next(),
+                unix_nanos(span.start),
+                unix_nanos(span.end),
+                string_attribute("client.address", &span.client.ip().to_string()),
+                int_attribute("client.port", span.client.port() as u64),
+                string_attribute("trojan.target", &span.target),
+                int_attribute("trojan.bytes_read", span.bytes_read),
+                int_attribute("trojan.bytes_sent", span.bytes_sent),
+                user,
+            );
+        }
+        self.body.push_str("]}]}]}");
+    }
+
+    fn post(&self) -> std::io::Result<()> {
+        let timeout = Duration::from_secs(5);
+        let mut stream = TcpStream::connect_timeout(&self.addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        write!(
+            stream,
+            "POST /v1/traces HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.addr,
+            self.body.len(),
+            self.body
+        )?;
+        let mut status = [0u8; 12];
+        stream.read_exact(&mut status)?;
+        // "HTTP/1.1 200"
+        if status[9] != b'2' {
+            log::warn!(
+                "collector {} rejected spans, replied {}",
+                self.addr,
+                String::from_utf8_lossy(&status)
+            );
+        }
+        Ok(())
+    }
+}
+
+/// splitmix64, trace and span ids only have to be unique, not unpredictable
+pub struct IdGenerator {
+    state: u64,
+}
+
+impl IdGenerator {
+    pub fn new() -> IdGenerator {
+        IdGenerator {
+            state: unix_nanos(SystemTime::now()) as u64 ^ (std::process::id() as u64) << 32,
+        }
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or(0)
+}
+
+fn string_attribute(key: &str, value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    format!(
+        "{{\"key\":\"{}\",\"value\":{{\"stringValue\":\"{}\"}}}}",
+        key, escaped
+    )
+}
+
+/// 64 bit integers are strings in the json encoding of protobuf
+fn int_attribute(key: &str, value: u64) -> String {
+    format!(
+        "{{\"key\":\"{}\",\"value\":{{\"intValue\":\"{}\"}}}}",
+        key, value
+    )
+}
+
+#[cfg(all(test, feature = "test-support"))]
+mod client_tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+    use std::thread::spawn;
+    use std::time::Duration;
+
+    use crate::test_support::*;
+
+    #[test]
+    fn connection_exported_as_span() {
+        let collector = TcpListener::bind("127.0.0.1:0").unwrap();
+        let collector_addr = collector.local_addr().unwrap().to_string();
+        let (sender, receiver) = channel();
+        spawn(move || {
+            let (mut stream, _) = collector.accept().unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_millis(500)))
+                .unwrap();
+            let mut request = Vec::new();
+            let _ = stream.read_to_end(&mut request);
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+            sender.send(String::from_utf8(request).unwrap()).unwrap();
+        });
+        let server = start_server(&[
+            "--allow-self-connect",
+            "--otlp-addr",
+            collector_addr.as_str(),
+        ]);
+        let echo = start_echo();
+        let mut client = TrojanClient::connect(server, PASSWORD, &echo).unwrap();
+        let mut buffer = [0u8; 4];
+        client.write_all(b"ping").unwrap();
+        client.read_exact(&mut buffer).unwrap();
+        client.shutdown();
+        drop(client);
+
+        let request = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(request.starts_with("POST /v1/traces HTTP/1.1\r\n"));
+        assert!(request.contains("\"name\":\"connection\""));
+        assert!(request.contains(&format!(
+            "{{\"key\":\"trojan.target\",\"value\":{{\"stringValue\":\"{}\"}}}}",
+            echo
+        )));
+        assert!(request.contains("{\"key\":\"trojan.bytes_read\",\"value\":{\"intValue\":\"4\"}}"));
+        assert!(
+            request.contains("{\"key\":\"trojan.user\",\"value\":{\"stringValue\":\"default\"}}")
+        );
+        assert!(request
+            .contains("{\"key\":\"client.address\",\"value\":{\"stringValue\":\"127.0.0.1\"}}"));
+    }
+}
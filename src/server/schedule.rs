@@ -0,0 +1,129 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+
+use chrono::{DateTime, Datelike, Duration, TimeZone, Utc};
+
+use crate::config::{Opts, ResetSchedule};
+use crate::stats::Stats;
+
+/// Starts a new accounting period at every boundary of the reset schedule. the traffic of the
+/// ended period is logged and appended to the traffic log, one line per period.
+pub struct TrafficSchedule {
+    schedule: ResetSchedule,
+    next: DateTime<Utc>,
+    log_path: Option<String>,
+}
+
+impl TrafficSchedule {
+    pub fn new(opts: &Opts) -> Option<TrafficSchedule> {
+        let next = next_boundary(opts.traffic_reset, Utc::now())?;
+        log::info!("traffic counters will be reset at {}", next);
+        Some(TrafficSchedule {
+            schedule: opts.traffic_reset,
+            next,
+            log_path: opts.server_args().traffic_log.clone(),
+        })
+    }
+
+    /// reset the counters if a boundary is passed, returns true if they were
+    pub fn check(&mut self, now: DateTime<Utc>, stats: &Stats) -> bool {
+        if now < self.next {
+            return false;
+        }
+        let traffic = stats.take_traffic();
+        let record = format!(
+            "{} bytes_read {} bytes_sent {}\n",
+            self.next.to_rfc3339(),
+            traffic.bytes_read,
+            traffic.bytes_sent
+        );
+        log::warn!("traffic period ended, {}", record.trim_end());
+        if let Some(path) = &self.log_path {
+            if let Err(err) = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| file.write_all(record.as_bytes()))
+            {
+                log::error!("append traffic to {} failed:{}", path, err);
+            }
+        }
+        // a server suspended over several boundaries resets once
+        self.next = next_boundary(self.schedule, now).unwrap();
+        true
+    }
+}
+
+/// start of the next utc day, week or month after `now`, None if never reset
+fn next_boundary(schedule: ResetSchedule, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let today = now.date();
+    let next = match schedule {
+        ResetSchedule::Never => return None,
+        ResetSchedule::Daily => today.succ(),
+        ResetSchedule::Weekly => {
+            today + Duration::days(7 - today.weekday().num_days_from_monday() as i64)
+        }
+        ResetSchedule::Monthly if today.month() == 12 => Utc.ymd(today.year() + 1, 1, 1),
+        ResetSchedule::Monthly => Utc.ymd(today.year(), today.month() + 1, 1),
+    };
+    Some(next.and_hms(0, 0, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn boundaries() {
+        // a wednesday
+        let now = Utc.ymd(2020, 12, 30).and_hms(13, 5, 0);
+        assert_eq!(next_boundary(ResetSchedule::Never, now), None);
+        let next = |schedule| next_boundary(schedule, now).unwrap();
+        assert_eq!(
+            next(ResetSchedule::Daily),
+            Utc.ymd(2020, 12, 31).and_hms(0, 0, 0)
+        );
+        assert_eq!(
+            next(ResetSchedule::Weekly),
+            Utc.ymd(2021, 1, 4).and_hms(0, 0, 0)
+        );
+        assert_eq!(
+            next(ResetSchedule::Monthly),
+            Utc.ymd(2021, 1, 1).and_hms(0, 0, 0)
+        );
+        // exactly on a boundary, the next one is a whole period away
+        let now = Utc.ymd(2021, 1, 4).and_hms(0, 0, 0);
+        assert_eq!(
+            next_boundary(ResetSchedule::Weekly, now).unwrap(),
+            Utc.ymd(2021, 1, 11).and_hms(0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn period_logged_and_reset() {
+        let path = std::env::temp_dir().join(format!("trojan-traffic-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let now = Utc.ymd(2021, 1, 31).and_hms(8, 0, 0);
+        let mut schedule = TrafficSchedule {
+            schedule: ResetSchedule::Monthly,
+            next: next_boundary(ResetSchedule::Monthly, now).unwrap(),
+            log_path: Some(path.to_str().unwrap().to_string()),
+        };
+        let stats = Stats::default();
+        stats.add_read(5);
+        stats.add_sent(7);
+        assert!(!schedule.check(now, &stats));
+        assert!(schedule.check(Utc.ymd(2021, 2, 1).and_hms(0, 0, 1), &stats));
+        assert_eq!(stats.traffic().bytes_read, 0);
+        stats.add_read(1);
+        assert!(schedule.check(Utc.ymd(2021, 3, 1).and_hms(0, 0, 0), &stats));
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "2021-02-01T00:00:00+00:00 bytes_read 5 bytes_sent 7\n\
+             2021-03-01T00:00:00+00:00 bytes_read 1 bytes_sent 0\n"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
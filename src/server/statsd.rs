@@ -0,0 +1,194 @@
+use std::fmt::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::config::Opts;
+use crate::stats::{Stats, StatsSnapshot};
+
+/// Pushes server counters to a StatsD/DogStatsD endpoint.
+pub struct StatsdEmitter {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    prefix: String,
+    tags: String,
+    interval: Duration,
+    last_time: Instant,
+    last: StatsSnapshot,
+    buffer: String,
+}
+
+impl StatsdEmitter {
+    pub fn new(opts: &Opts) -> Option<StatsdEmitter> {
+        let addr = opts.statsd_addr?;
+        let bind_addr = if addr.is_ipv4() {
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)
+        } else {
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)
+        };
+        let socket = match UdpSocket::bind(bind_addr) {
+            Ok(socket) => socket,
+            Err(err) => {
+                log::error!("bind statsd socket failed:{}", err);
+                return None;
+            }
+        };
+        if let Err(err) = socket.set_nonblocking(true) {
+            log::error!("set statsd socket nonblocking failed:{}", err);
+            return None;
+        }
+        let args = opts.server_args();
+        let tags = if args.statsd_tags.is_empty() {
+            String::new()
+        } else {
+            format!("|#{}", args.statsd_tags.join(","))
+        };
+        log::info!("statsd metrics will be sent to {}", addr);
+        Some(StatsdEmitter {
+            socket,
+            addr,
+            prefix: args.statsd_prefix.clone(),
+            tags,
+            interval: opts.statsd_interval,
+            last_time: Instant::now(),
+            last: StatsSnapshot::default(),
+            buffer: String::new(),
+        })
+    }
+
+    pub fn check(&mut self, now: Instant, stats: &Stats, active: usize) {
+        if now - self.last_time < self.interval {
+            return;
+        }
+        self.last_time = now;
+        let current = stats.snapshot();
+        self.buffer.clear();
+        self.counter("connections", current.accepted - self.last.accepted);
+        self.counter("bytes_sent", current.bytes_sent - self.last.bytes_sent);
+        self.counter("bytes_read", current.bytes_read - self.last.bytes_read);
+        self.counter("errors", current.errors - self.last.errors);
+        self.counter(
+            "handshake_timeouts",
+            current.handshake_timeouts - self.last.handshake_timeouts,
+        );
+        self.counter("timeouts", current.timeouts - self.last.timeouts);
+        self.counter(
+            "accept_errors",
+            current.accept_errors - self.last.accept_errors,
+        );
+        self.counter(
+            "client_resets",
+            current.client_resets - self.last.client_resets,
+        );
+        self.counter(
+            "dead_accepts",
+            current.dead_accepts - self.last.dead_accepts,
+        );
+        self.counter("dns_lookups", current.dns_lookups - self.last.dns_lookups);
+        self.counter(
+            "udp_rate_drops",
+            current.udp_rate_drops - self.last.udp_rate_drops,
+        );
+        self.counter("polls", current.polls - self.last.polls);
+        self.counter(
+            "register_failures",
+            current.register_failures - self.last.register_failures,
+        );
+        self.gauge("active_connections", active as u64);
+        self.last = current;
+        if let Err(err) = self.socket.send_to(self.buffer.as_bytes(), self.addr) {
+            log::warn!("send metrics to statsd:{} failed:{}", self.addr, err);
+        }
+    }
+
+    fn counter(&mut self, name: &str, value: u64) {
+        self.metric(name, value, "c");
+    }
+
+    fn gauge(&mut self, name: &str, value: u64) {
+        self.metric(name, value, "g");
+    }
+
+    fn metric(&mut self, name: &str, value: u64, kind: &str) {
+        if !self.buffer.is_empty() {
+            self.buffer.push('\n');
+        }
+        let _ = write!(
+            self.buffer,
+            "{}.{}:{}|{}{}",
+            self.prefix, name, value, kind, self.tags
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::time::{Duration, Instant};
+
+    use super::StatsdEmitter;
+    use crate::stats::Stats;
+    use crate::test_support::{free_addr, server_opts};
+
+    #[test]
+    fn metrics_pushed() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let statsd_addr = receiver.local_addr().unwrap().to_string();
+        let args = [
+            "--statsd-addr",
+            statsd_addr.as_str(),
+            "--statsd-tags",
+            "env:test",
+        ];
+        let opts = server_opts(free_addr(), &args);
+        let mut emitter = StatsdEmitter::new(&opts).unwrap();
+        let stats = Stats::default();
+        stats.add_timeout();
+        stats.add_accept_error();
+        stats.add_accept_error();
+        emitter.check(Instant::now() + opts.statsd_interval, &stats, 3);
+
+        let mut buffer = [0u8; 4096];
+        let size = receiver.recv(&mut buffer).unwrap();
+        let datagram = std::str::from_utf8(&buffer[..size]).unwrap();
+        let metrics: Vec<(&str, &str, &str)> = datagram
+            .lines()
+            .map(|line| {
+                let (name, rest) = line.split_at(line.find(':').unwrap());
+                let mut fields = rest[1..].split('|');
+                let value = fields.next().unwrap();
+                let kind = fields.next().unwrap();
+                assert_eq!(fields.next(), Some("#env:test"));
+                (name, value, kind)
+            })
+            .collect();
+        let names: Vec<(&str, &str)> = metrics
+            .iter()
+            .map(|(name, _, kind)| (*name, *kind))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("trojan.connections", "c"),
+                ("trojan.bytes_sent", "c"),
+                ("trojan.bytes_read", "c"),
+                ("trojan.errors", "c"),
+                ("trojan.handshake_timeouts", "c"),
+                ("trojan.timeouts", "c"),
+                ("trojan.accept_errors", "c"),
+                ("trojan.client_resets", "c"),
+                ("trojan.dead_accepts", "c"),
+                ("trojan.dns_lookups", "c"),
+                ("trojan.udp_rate_drops", "c"),
+                ("trojan.polls", "c"),
+                ("trojan.register_failures", "c"),
+                ("trojan.active_connections", "g"),
+            ]
+        );
+        assert!(metrics.contains(&("trojan.timeouts", "1", "c")));
+        assert!(metrics.contains(&("trojan.accept_errors", "2", "c")));
+        assert!(metrics.contains(&("trojan.active_connections", "3", "g")));
+    }
+}
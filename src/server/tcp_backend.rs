@@ -1,13 +1,20 @@
-use std::net::Shutdown;
-use std::time::Duration;
+#[cfg(feature = "netem")]
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use bytes::buf::BufMutExt;
 use bytes::BytesMut;
 use mio::net::TcpStream;
 use mio::{Event, Poll, PollOpt, Ready, Token};
-use rustls::ServerSession;
+use rustls::{ClientConfig, ClientSession, ServerSession, Session};
+use webpki::DNSNameRef;
 
-use crate::config::Opts;
-use crate::proto::{MAX_BUFFER_SIZE, MAX_PACKET_SIZE};
+use crate::config::{Opts, RatioAction};
+use crate::proto;
+use crate::server::buffer_pool::BufferPool;
 use crate::server::tls_server::Backend;
 use crate::tcp_util;
 use crate::tls_conn::{ConnStatus, TlsConn};
@@ -19,36 +26,315 @@ pub struct TcpBackend {
     index: usize,
     token: Token,
     timeout: Duration,
+    /// last time bytes were read from or written to the target
+    last_active: Instant,
     send_buffer: BytesMut,
+    /// reading the client stops once send_buffer holds high_water bytes, and goes on once
+    /// it drains to low_water
+    high_water: usize,
+    low_water: usize,
+    client_paused: bool,
+    /// bytes of the PROXY protocol header at the front of send_buffer, not counted as sent
+    header_left: usize,
+    /// taken from buffers, given back while the target has nothing to read and when dropped
     recv_buffer: Vec<u8>,
+    buffers: Arc<BufferPool>,
+    bytes_read: u64,
+    bytes_sent: u64,
+    peer_addr: SocketAddr,
+    target_addr: SocketAddr,
+    ratio_flagged: bool,
+    over_soft_limit: bool,
+    /// start of the current transfer rate window and bytes transferred before it
+    rate_since: Instant,
+    rate_bytes: u64,
+    /// no event from the target yet, so connect has not finished
+    connecting: bool,
+    connect_deadline: Option<Instant>,
+    /// connect failed with an error or timed out
+    connect_failed: bool,
+    /// tls session with the target, send_buffer holds its records instead of plain data
+    tls: Option<ClientSession>,
+    /// reregister with poll failed
+    register_failed: bool,
+    #[cfg(feature = "netem")]
+    delayed: VecDeque<(Instant, Vec<u8>)>,
 }
 
 impl TcpBackend {
-    pub fn new(conn: TcpStream, index: usize, token: Token, timeout: Duration) -> TcpBackend {
+    pub fn new(
+        conn: TcpStream,
+        index: usize,
+        token: Token,
+        opts: &Opts,
+        peer_addr: SocketAddr,
+        target_addr: SocketAddr,
+        buffers: Arc<BufferPool>,
+    ) -> TcpBackend {
+        let connect_timeout = opts.server_args().target_connect_timeout;
         TcpBackend {
             conn,
-            timeout,
+            timeout: opts.tcp_idle_duration,
+            last_active: Instant::now(),
             status: ConnStatus::Established,
-            readiness: Ready::readable(),
+            readiness: Ready::readable() | Ready::writable(),
             send_buffer: BytesMut::new(),
-            recv_buffer: vec![0u8; MAX_PACKET_SIZE],
+            high_water: opts.send_buffer_high_water,
+            low_water: opts.tcp_low_water(),
+            client_paused: false,
+            header_left: 0,
+            recv_buffer: buffers.take(),
+            buffers,
             index,
             token,
+            bytes_read: 0,
+            bytes_sent: 0,
+            peer_addr,
+            target_addr,
+            ratio_flagged: false,
+            over_soft_limit: false,
+            rate_since: Instant::now(),
+            rate_bytes: 0,
+            connecting: true,
+            connect_deadline: if connect_timeout == 0 {
+                None
+            } else {
+                Some(Instant::now() + Duration::from_secs(connect_timeout))
+            },
+            connect_failed: false,
+            tls: None,
+            register_failed: false,
+            #[cfg(feature = "netem")]
+            delayed: VecDeque::new(),
         }
     }
-    fn do_read(&mut self, conn: &mut TlsConn<ServerSession>) {
-        if !tcp_util::tcp_read(self.index, &self.conn, &mut self.recv_buffer, conn) {
+
+    /// queue a PROXY protocol v2 header with the client address and alpn protocol, called
+    /// before any data is dispatched or tls started
+    pub fn send_proxy_header(&mut self, dst: &SocketAddr, alpn: Option<&[u8]>) {
+        let len = self.send_buffer.len();
+        proto::proxy_header(&mut self.send_buffer, &self.peer_addr, dst, alpn);
+        self.header_left = self.send_buffer.len() - len;
+    }
+
+    /// talk to the target over tls, called before any data is dispatched
+    pub fn start_tls(&mut self, config: &Arc<ClientConfig>, sni: DNSNameRef) {
+        log::debug!(
+            "connection:{} target {} is reached over tls",
+            self.index,
+            self.target_addr
+        );
+        self.tls = Some(ClientSession::new(config, sni));
+        self.seal(&[]);
+    }
+
+    /// encrypt data into send_buffer, along with any handshake records or alerts pending
+    fn seal(&mut self, data: &[u8]) {
+        let session = self.tls.as_mut().unwrap();
+        // without a buffer limit rustls takes all the data, holding it until handshake finished
+        let _ = session.write_all(data);
+        let mut writer = (&mut self.send_buffer).writer();
+        while session.wants_write() {
+            if session.write_tls(&mut writer).is_err() {
+                break;
+            }
+        }
+    }
+
+    /// read records from the target and pass the plain data to the client
+    fn do_read_tls(&mut self, conn: &mut TlsConn<ServerSession>, opts: &mut Opts) {
+        let session = self.tls.as_mut().unwrap();
+        let bytes_read = self.bytes_read;
+        loop {
+            // same as plain targets, stop reading while the client session is full
+            if !conn.writable() {
+                log::debug!(
+                    "connection:{} session is full, stop reading backend",
+                    self.index
+                );
+                break;
+            }
+            match session.read(&mut self.recv_buffer) {
+                Ok(0) => {}
+                Ok(size) => {
+                    self.bytes_read += size as u64;
+                    if !conn.write_session(&self.recv_buffer[..size]) {
+                        self.status = ConnStatus::Closing;
+                        break;
+                    }
+                    continue;
+                }
+                Err(err) => {
+                    log::warn!(
+                        "connection:{} read from target tls failed:{}",
+                        self.index,
+                        err
+                    );
+                    self.status = ConnStatus::Closing;
+                    break;
+                }
+            }
+            match session.read_tls(&mut self.conn) {
+                Ok(0) => {
+                    log::warn!("connection:{} meets end of file", self.index);
+                    self.status = ConnStatus::Closing;
+                    break;
+                }
+                Ok(_) => {
+                    if let Err(err) = session.process_new_packets() {
+                        log::warn!(
+                            "connection:{} tls with target {} failed:{}",
+                            self.index,
+                            self.target_addr,
+                            err
+                        );
+                        opts.stats.add_error();
+                        self.status = ConnStatus::Closing;
+                        break;
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    log::warn!("connection:{} read from backend failed:{}", self.index, err);
+                    self.status = ConnStatus::Closing;
+                    break;
+                }
+            }
+        }
+        opts.stats.add_read(self.bytes_read - bytes_read);
+        if self.bytes_read > bytes_read {
+            self.last_active = Instant::now();
+        }
+        self.send_to_client(conn);
+        // handshake records and alerts, or data held until the handshake finished
+        self.seal(&[]);
+        if !self.flush_tls() {
             self.status = ConnStatus::Closing;
         }
+    }
+
+    /// write sealed records to the target, they are counted as the plain data in them
+    fn flush_tls(&mut self) -> bool {
+        let mut wire_bytes = 0;
+        let ok = tcp_util::tcp_flush(
+            self.index,
+            &self.conn,
+            &mut self.send_buffer,
+            &mut wire_bytes,
+        );
+        if wire_bytes > 0 {
+            self.last_active = Instant::now();
+        }
+        ok
+    }
 
+    /// first event after connect, the socket error tells whether it succeeded
+    fn check_connect(&mut self, opts: &Opts) {
+        self.connecting = false;
+        let err = match self.conn.take_error() {
+            Ok(None) => return,
+            Ok(Some(err)) | Err(err) => err,
+        };
+        log::warn!(
+            "connection:{} from {} connect to target {} failed:{}",
+            self.index,
+            self.peer_addr,
+            self.target_addr,
+            err
+        );
+        opts.stats.add_error();
+        self.connect_failed = true;
+        self.status = ConnStatus::Closing;
+    }
+
+    fn do_read(&mut self, conn: &mut TlsConn<ServerSession>, opts: &mut Opts) {
+        if self.recv_buffer.is_empty() {
+            self.recv_buffer = self.buffers.take();
+        }
+        if self.tls.is_some() {
+            self.do_read_tls(conn, opts);
+            return;
+        }
+        let bytes_read = self.bytes_read;
+        if !tcp_util::tcp_read(
+            self.index,
+            &self.conn,
+            &mut self.recv_buffer,
+            conn,
+            &mut self.bytes_read,
+        ) {
+            self.status = ConnStatus::Closing;
+        }
+        opts.stats.add_read(self.bytes_read - bytes_read);
+        if self.bytes_read > bytes_read {
+            self.last_active = Instant::now();
+        }
+
+        self.send_to_client(conn);
+    }
+
+    /// send what was read to the client. reading stopped on a full session leaves data unread,
+    /// its edge is not reported again, so readable is registered again even if the session
+    /// drains right here. otherwise the read would block, everything read is passed on already
+    /// and the receive buffer goes back to the pool, idle connections hold none
+    fn send_to_client(&mut self, conn: &mut TlsConn<ServerSession>) {
+        let full = !conn.writable();
         conn.do_send();
+        if full {
+            self.readiness.remove(Ready::readable());
+        } else {
+            self.buffers.put(std::mem::take(&mut self.recv_buffer));
+        }
     }
 
-    fn do_send(&mut self, data: &[u8]) {
-        if !tcp_util::tcp_send(self.index, &self.conn, &mut self.send_buffer, data) {
+    fn do_send(&mut self, data: &[u8], opts: &mut Opts) {
+        let bytes_sent = self.bytes_sent;
+        // send immediately first, data queued behind a blocked write goes after it
+        let ok = if self.tls.is_some() {
+            self.seal(data);
+            self.bytes_sent += data.len() as u64;
+            self.flush_tls()
+        } else if self.send_buffer.is_empty() {
+            tcp_util::tcp_send(
+                self.index,
+                &self.conn,
+                &mut self.send_buffer,
+                data,
+                &mut self.bytes_sent,
+            )
+        } else {
+            self.send_buffer.extend_from_slice(data);
+            tcp_util::tcp_flush(
+                self.index,
+                &self.conn,
+                &mut self.send_buffer,
+                &mut self.bytes_sent,
+            )
+        };
+        if self.header_left > 0 && self.tls.is_none() {
+            let header = self
+                .header_left
+                .min((self.bytes_sent - bytes_sent) as usize);
+            self.header_left -= header;
+            self.bytes_sent -= header as u64;
+        }
+        opts.stats.add_sent(self.bytes_sent - bytes_sent);
+        // sealed data is counted before it is written, flush_tls tracks tls activity
+        if self.tls.is_none() && self.bytes_sent > bytes_sent {
+            self.last_active = Instant::now();
+        }
+        if !ok {
+            // a refused connect may show up on the first write, before any event
+            self.connect_failed = self.connecting;
             self.status = ConnStatus::Closing;
             return;
         }
+        self.check_ratio(opts);
+        if self.send_buffer.is_empty()
+            && self.send_buffer.capacity() > opts.server_args().send_buffer_retain
+        {
+            self.send_buffer = BytesMut::new();
+        }
 
         if let ConnStatus::Shutdown = self.status {
             if self.send_buffer.is_empty() {
@@ -58,6 +344,133 @@ impl TcpBackend {
         }
     }
 
+    /// flag connections uploading much more than downloading, like a tunnel used for flooding
+    fn check_ratio(&mut self, opts: &Opts) {
+        let args = opts.server_args();
+        if self.ratio_flagged
+            || args.upload_ratio_limit <= 0.0
+            || self.bytes_sent < args.upload_ratio_min_bytes
+        {
+            return;
+        }
+        let ratio = self.bytes_sent as f64 / self.bytes_read.max(1) as f64;
+        if ratio <= args.upload_ratio_limit {
+            return;
+        }
+        self.ratio_flagged = true;
+        log::warn!(
+            "connection:{} from {} to {} exceeds upload ratio limit, sent {} bytes, read {} bytes",
+            self.index,
+            self.peer_addr,
+            self.target_addr,
+            self.bytes_sent,
+            self.bytes_read
+        );
+        if opts.upload_ratio_action == RatioAction::Close {
+            self.status = ConnStatus::Closing;
+        }
+    }
+
+    /// close connections trickling data to stay just below the idle timeout, the rate of
+    /// both directions is measured over windows of min_rate_grace seconds
+    fn check_rate(&mut self, opts: &Opts) {
+        let args = opts.server_args();
+        if args.min_transfer_rate == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let elapsed = now - self.rate_since;
+        if elapsed < Duration::from_secs(args.min_rate_grace) {
+            return;
+        }
+        let total = self.bytes_read + self.bytes_sent;
+        let rate = (total - self.rate_bytes) as f64 / elapsed.as_secs_f64();
+        if rate < args.min_transfer_rate as f64 {
+            log::warn!(
+                "connection:{} from {} to {} below min transfer rate, {:.1} bytes/s in {:?}",
+                self.index,
+                self.peer_addr,
+                self.target_addr,
+                rate,
+                elapsed
+            );
+            self.status = ConnStatus::Closing;
+        } else {
+            self.rate_since = now;
+            self.rate_bytes = total;
+        }
+    }
+
+    /// writable events drain send_buffer through dispatch too, so reading the client goes on
+    /// as soon as the target took enough
+    fn check_water(&mut self) {
+        let depth = self.send_buffer.len();
+        if !self.client_paused && depth >= self.high_water {
+            log::debug!(
+                "connection:{} {} bytes queued for target, stop reading client",
+                self.index,
+                depth
+            );
+            self.client_paused = true;
+        } else if self.client_paused && depth <= self.low_water {
+            log::debug!(
+                "connection:{} {} bytes queued for target, read client again",
+                self.index,
+                depth
+            );
+            self.client_paused = false;
+        }
+    }
+
+    /// log once each time send_buffer grows over the soft limit, useful for tuning buffer sizes
+    fn check_soft_limit(&mut self, opts: &Opts) {
+        let limit = opts.server_args().send_buffer_warn;
+        if limit == 0 {
+            return;
+        }
+        let depth = self.send_buffer.len();
+        if depth > limit && !self.over_soft_limit {
+            log::warn!(
+                "connection:{} send buffer to {} reaches {} bytes, soft limit is {}",
+                self.index,
+                self.target_addr,
+                depth,
+                limit
+            );
+            self.over_soft_limit = true;
+        } else if depth <= limit && self.over_soft_limit {
+            log::info!(
+                "connection:{} send buffer drained to {} bytes",
+                self.index,
+                depth
+            );
+            self.over_soft_limit = false;
+        }
+    }
+
+    /// hold data back until its simulated latency passed, due data is moved into send_buffer
+    #[cfg(feature = "netem")]
+    fn delay<'a>(&mut self, buffer: &'a [u8], opts: &mut Opts) -> &'a [u8] {
+        if let Some(netem) = opts.netem.as_mut() {
+            let now = Instant::now();
+            if !buffer.is_empty() {
+                self.delayed
+                    .push_back((now + netem.latency(), buffer.to_vec()));
+                netem.schedule(self.token);
+            }
+            while let Some((time, _)) = self.delayed.front() {
+                if *time > now {
+                    break;
+                }
+                let (_, data) = self.delayed.pop_front().unwrap();
+                self.send_buffer.extend_from_slice(data.as_slice());
+            }
+            &[]
+        } else {
+            buffer
+        }
+    }
+
     fn setup(&mut self, poll: &Poll) {
         if let Err(err) = poll.reregister(&self.conn, self.token, self.readiness, PollOpt::edge()) {
             log::error!(
@@ -65,6 +478,7 @@ impl TcpBackend {
                 self.index,
                 err
             );
+            self.register_failed = true;
             self.status = ConnStatus::Closing;
         }
     }
@@ -72,31 +486,42 @@ impl TcpBackend {
 
 impl Backend for TcpBackend {
     fn ready(&mut self, event: &Event, opts: &mut Opts, conn: &mut TlsConn<ServerSession>) {
+        if self.connecting {
+            self.check_connect(opts);
+            if self.connect_failed {
+                return;
+            }
+        }
         if event.readiness().is_readable() {
-            self.do_read(conn);
+            self.do_read(conn, opts);
         }
         if event.readiness().is_writable() {
+            // flush what is queued, nothing queued is a no-op
             self.dispatch(&[], opts);
         }
+        self.check_rate(opts);
     }
 
-    fn dispatch(&mut self, buffer: &[u8], _: &mut Opts) {
-        // send immediately first
-        if self.send_buffer.is_empty() {
-            self.do_send(buffer);
-        } else {
-            self.send_buffer.extend_from_slice(buffer);
-            let buffer = self.send_buffer.split();
-            self.do_send(buffer.as_ref());
+    fn resume(&mut self, conn: &mut TlsConn<ServerSession>, opts: &mut Opts) {
+        // data already taken off the socket waits in the target session
+        if self.tls.is_some() {
+            self.do_read_tls(conn, opts);
         }
     }
 
+    fn dispatch(&mut self, buffer: &[u8], opts: &mut Opts) {
+        #[cfg(feature = "netem")]
+        let buffer = self.delay(buffer, opts);
+        self.do_send(buffer, opts);
+        self.check_water();
+        self.check_soft_limit(opts);
+        self.check_rate(opts);
+    }
+
     fn reregister(&mut self, poll: &Poll, readable: bool) {
         match self.status {
-            ConnStatus::Closing => {
-                let _ = poll.deregister(&self.conn);
-            }
-            ConnStatus::Closed => {}
+            // deregistered by check_close, which always follows
+            ConnStatus::Closing | ConnStatus::Closed => {}
             _ => {
                 let mut changed = false;
                 if !self.send_buffer.is_empty() && !self.readiness.is_writable() {
@@ -132,18 +557,64 @@ impl Backend for TcpBackend {
             let _ = poll.deregister(&self.conn);
             let _ = self.conn.shutdown(Shutdown::Both);
             self.status = ConnStatus::Closed;
+            log::info!(
+                "connection:{} tcp target closed, read {} bytes, sent {} bytes",
+                self.index,
+                self.bytes_read,
+                self.bytes_sent
+            );
+        }
+    }
+
+    fn timeout(&self, t1: Instant, t2: Instant) -> bool {
+        if self.unreachable() {
+            log::warn!(
+                "connection:{} from {} connect to target {} timed out",
+                self.index,
+                self.peer_addr,
+                self.target_addr
+            );
+            return true;
+        }
+        t2 - t1 > self.timeout
+    }
+
+    fn connect_deadline(&self) -> Option<Instant> {
+        if self.connecting {
+            self.connect_deadline
+        } else {
+            None
         }
     }
 
+    fn unreachable(&self) -> bool {
+        self.connect_failed
+            || matches!(self.connect_deadline(), Some(deadline) if deadline <= Instant::now())
+    }
+
     fn get_timeout(&self) -> Duration {
         self.timeout
     }
 
+    fn last_active(&self) -> Option<Instant> {
+        Some(self.last_active)
+    }
+
     fn status(&self) -> ConnStatus {
         self.status
     }
 
     fn shutdown(&mut self, poll: &Poll) {
+        if self.closed() {
+            return;
+        }
+        if !matches!(self.status, ConnStatus::Shutdown) {
+            if let Some(session) = self.tls.as_mut() {
+                session.send_close_notify();
+                self.seal(&[]);
+                let _ = self.flush_tls();
+            }
+        }
         if self.send_buffer.is_empty() {
             self.status = ConnStatus::Closing;
             self.check_close(poll);
@@ -156,7 +627,412 @@ impl Backend for TcpBackend {
         self.check_close(poll);
     }
 
+    fn reset(&mut self, poll: &Poll) {
+        if self.closed() {
+            return;
+        }
+        // no shutdown, the socket is closed with a reset when dropped
+        let _ = self.conn.set_linger(Some(Duration::from_secs(0)));
+        let _ = poll.deregister(&self.conn);
+        self.status = ConnStatus::Closed;
+        log::info!(
+            "connection:{} tcp target reset, read {} bytes, sent {} bytes, dropped {} bytes",
+            self.index,
+            self.bytes_read,
+            self.bytes_sent,
+            self.send_buffer.len()
+        );
+    }
+
     fn writable(&self) -> bool {
-        self.send_buffer.len() < MAX_BUFFER_SIZE
+        !self.client_paused
+    }
+
+    fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    fn target(&self) -> Option<SocketAddr> {
+        Some(self.target_addr)
+    }
+
+    fn traffic(&self) -> (u64, u64) {
+        (self.bytes_read, self.bytes_sent)
+    }
+
+    fn queue_depth(&self) -> usize {
+        self.send_buffer.len()
+    }
+
+    fn register_failed(&self) -> bool {
+        self.register_failed
+    }
+}
+
+/// closed, reset or abandoned for another address, the receive buffer is reused in any case
+impl Drop for TcpBackend {
+    fn drop(&mut self) {
+        self.buffers.put(std::mem::take(&mut self.recv_buffer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    use mio::net::TcpStream;
+    use mio::{Events, Poll, PollOpt, Ready, Token};
+
+    use super::TcpBackend;
+    use crate::server::buffer_pool::BufferPool;
+    use crate::server::tls_server::Backend;
+    use crate::test_support::*;
+    use crate::tls_conn::ConnStatus;
+
+    #[test]
+    fn empty_dispatch_is_noop() {
+        let mut opts = server_opts(free_addr(), &[]);
+        let target = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let conn = TcpStream::connect(&target_addr).unwrap();
+        let (_accepted, _) = target.accept().unwrap();
+        let poll = Poll::new().unwrap();
+        let token = Token(100);
+        let peer_addr = "127.0.0.1:1".parse().unwrap();
+        let buffers = BufferPool::new(opts.recv_buffer_size, 1);
+        let mut backend = TcpBackend::new(conn, 50, token, &opts, peer_addr, target_addr, buffers);
+        poll.register(&backend.conn, token, backend.readiness, PollOpt::edge())
+            .unwrap();
+        let mut events = Events::with_capacity(16);
+        poll.poll(&mut events, Some(Duration::from_secs(5)))
+            .unwrap();
+        assert!(events.iter().any(|event| event.readiness().is_writable()));
+        backend.connecting = false;
+
+        // first writable event with nothing queued drops writable interest once
+        backend.dispatch(&[], &mut opts);
+        backend.reregister(&poll, true);
+        assert_eq!(backend.readiness, Ready::readable());
+        // later ones leave the connection and its registration alone
+        for _ in 0..3 {
+            backend.dispatch(&[], &mut opts);
+            backend.reregister(&poll, true);
+            assert_eq!(backend.readiness, Ready::readable());
+        }
+        assert!(matches!(backend.status, ConnStatus::Established));
+        assert_eq!(backend.bytes_sent, 0);
+        assert!(backend.send_buffer.is_empty());
+        poll.poll(&mut events, Some(Duration::from_millis(200)))
+            .unwrap();
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn reset_returns_buffer() {
+        let opts = server_opts(free_addr(), &[]);
+        let target = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let conn = TcpStream::connect(&target_addr).unwrap();
+        let (_accepted, _) = target.accept().unwrap();
+        let poll = Poll::new().unwrap();
+        let peer_addr = "127.0.0.1:1".parse().unwrap();
+        let buffers = BufferPool::new(opts.recv_buffer_size, 1);
+        let mut backend = TcpBackend::new(
+            conn,
+            50,
+            Token(100),
+            &opts,
+            peer_addr,
+            target_addr,
+            buffers.clone(),
+        );
+        let ptr = backend.recv_buffer.as_ptr();
+        backend.reset(&poll);
+        drop(backend);
+        let reused = buffers.take();
+        assert_eq!(reused.as_ptr(), ptr);
+    }
+}
+
+#[cfg(all(test, feature = "test-support"))]
+mod client_tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+    use std::thread::{sleep, spawn};
+    use std::time::{Duration, Instant};
+
+    use crate::proto::MAX_PACKET_SIZE;
+    use crate::test_support::*;
+
+    const BURST: usize = MAX_PACKET_SIZE * 40 + 7;
+
+    fn pattern() -> Vec<u8> {
+        (0..BURST).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn download_burst_forwarded() {
+        let server = start_server(&["--allow-self-connect"]);
+        let burst = start_burst(BURST);
+        let mut client = TrojanClient::connect(server, PASSWORD, &burst).unwrap();
+        // target keeps the connection open, no eof wakes up the relay
+        let mut buffer = vec![0u8; BURST];
+        client.read_exact(buffer.as_mut_slice()).unwrap();
+        assert_eq!(buffer, pattern());
+    }
+
+    #[test]
+    fn upload_burst_forwarded() {
+        let server = start_server(&["--allow-self-connect"]);
+        let echo = start_echo();
+        let mut client = TrojanClient::connect(server, PASSWORD, &echo).unwrap();
+        client.write_all(pattern().as_slice()).unwrap();
+        let mut buffer = vec![0u8; BURST];
+        client.read_exact(buffer.as_mut_slice()).unwrap();
+        assert_eq!(buffer, pattern());
+    }
+
+    /// connections per second opening, echoing one byte and closing in a loop, with receive
+    /// buffers taken from a pool of `pool` idle ones
+    fn churn_rate(pool: &str) -> f64 {
+        const CONNECTIONS: u32 = 500;
+        let server = start_server(&[
+            "--allow-self-connect",
+            "--recv-buffer-size",
+            "262144",
+            "--recv-buffer-pool",
+            pool,
+        ]);
+        let echo = start_echo();
+        let start = Instant::now();
+        for _ in 0..CONNECTIONS {
+            let mut client = TrojanClient::connect(server, PASSWORD, &echo).unwrap();
+            let mut buffer = [0u8; 1];
+            client.write_all(b"x").unwrap();
+            client.read_exact(&mut buffer).unwrap();
+        }
+        CONNECTIONS as f64 / start.elapsed().as_secs_f64()
+    }
+
+    /// resident memory of the process in kB, the server and clients run in it
+    fn rss_kb() -> u64 {
+        let status = std::fs::read_to_string("/proc/self/status").unwrap();
+        let line = status
+            .lines()
+            .find(|line| line.starts_with("VmRSS:"))
+            .unwrap();
+        line.split_whitespace().nth(1).unwrap().parse().unwrap()
+    }
+
+    /// growth of the resident memory in kB while `connections` stay open, idle after each
+    /// echoed a burst filling its receive buffer
+    fn idle_growth(connections: usize) -> u64 {
+        let server = start_server(&["--allow-self-connect", "--recv-buffer-size", "262144"]);
+        let echo = start_echo();
+        let data = vec![1u8; 262144];
+        let before = rss_kb();
+        let clients: Vec<TrojanClient> = (0..connections)
+            .map(|_| {
+                let mut client = TrojanClient::connect(server, PASSWORD, &echo).unwrap();
+                client.write_all(data.as_slice()).unwrap();
+                let mut buffer = vec![0u8; data.len()];
+                client.read_exact(buffer.as_mut_slice()).unwrap();
+                client
+            })
+            .collect();
+        let grown = rss_kb().saturating_sub(before);
+        drop(clients);
+        grown
+    }
+
+    /// run with `cargo test --features test-support connection_churn -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn connection_churn() {
+        let unpooled = churn_rate("0");
+        let pooled = churn_rate("1024");
+        eprintln!(
+            "short-lived connections per second, unpooled {:.0}, pooled {:.0}",
+            unpooled, pooled
+        );
+        eprintln!(
+            "resident memory grown by {} kB with 200 idle connections",
+            idle_growth(200)
+        );
+    }
+
+    #[test]
+    fn proxy_protocol_header_sent() {
+        let admin_addr = free_addr().to_string();
+        let server = start_server(&[
+            "--allow-self-connect",
+            "--send-proxy-protocol",
+            "--admin-addr",
+            admin_addr.as_str(),
+        ]);
+        let echo = start_echo();
+        let mut client = TrojanClient::connect(server, PASSWORD, &echo).unwrap();
+        client.write_all(b"ping").unwrap();
+        // the echo target sends the header back along with the data
+        let mut buffer = [0u8; 32];
+        client.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer[..12], b"\r\n\r\n\0\r\nQUIT\n");
+        assert_eq!(&buffer[12..16], &[0x21, 0x11, 0, 12]);
+        let local_addr = client.local_addr().unwrap();
+        assert_eq!(&buffer[16..20], &[127, 0, 0, 1]);
+        assert_eq!(&buffer[20..24], &[127, 0, 0, 1]);
+        assert_eq!(&buffer[24..26], &local_addr.port().to_be_bytes());
+        assert_eq!(&buffer[26..28], &server.port().to_be_bytes());
+        assert_eq!(&buffer[28..], b"ping");
+        // the header is not counted as data from the client
+        assert!(admin(&admin_addr, "list").contains(" sent:4 "));
+    }
+
+    #[test]
+    fn small_buffers_forwarded() {
+        let server = start_server(&[
+            "--allow-self-connect",
+            "--recv-buffer-size",
+            "100",
+            "--send-buffer-high-water",
+            "1000",
+        ]);
+        let echo = start_echo();
+        let mut client = TrojanClient::connect(server, PASSWORD, &echo).unwrap();
+        client.write_all(pattern().as_slice()).unwrap();
+        let mut buffer = vec![0u8; BURST];
+        client.read_exact(buffer.as_mut_slice()).unwrap();
+        assert_eq!(buffer, pattern());
+    }
+
+    #[test]
+    fn slow_target_pauses_client() {
+        const SIZE: usize = 16 * 1024 * 1024;
+        let admin_addr = free_addr().to_string();
+        let server = start_server(&[
+            "--allow-self-connect",
+            "--admin-addr",
+            admin_addr.as_str(),
+            "--send-buffer-high-water",
+            "65536",
+        ]);
+        let target = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let (sender, receiver) = channel();
+        spawn(move || {
+            let (mut stream, _) = target.accept().unwrap();
+            receiver.recv().unwrap();
+            let mut buffer = vec![0u8; 65536];
+            let mut total = 0;
+            while total < SIZE {
+                let size = stream.read(buffer.as_mut_slice()).unwrap();
+                assert_ne!(size, 0);
+                total += size;
+            }
+            stream.write_all(b"done").unwrap();
+        });
+        let mut client = TrojanClient::connect(server, PASSWORD, &target_addr).unwrap();
+        let writer = spawn(move || {
+            client.write_all(vec![7u8; SIZE].as_slice()).unwrap();
+            client
+        });
+        sleep(Duration::from_secs(1));
+        // the target reads nothing yet, the client is not read past the high water
+        let list = admin(&admin_addr, "list");
+        let queue: usize = list
+            .split_whitespace()
+            .find_map(|field| field.strip_prefix("queue:"))
+            .unwrap()
+            .parse()
+            .unwrap();
+        // up to one read budget, as large as the high water, may come on top of it
+        assert!(queue < 2 * 65536, "{} bytes queued", queue);
+        // all of it arrives once the target reads, reading the client goes on as it drains
+        sender.send(()).unwrap();
+        let mut client = writer.join().unwrap();
+        let mut buffer = [0u8; 4];
+        client.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"done");
+    }
+
+    #[test]
+    fn tls_target_reached_with_sni() {
+        let (target, sni) = start_tls_echo();
+        let entry = format!("{}=target.test", target);
+        let server = start_server(&[
+            "--allow-self-connect",
+            "--target-tls",
+            entry.as_str(),
+            "--target-tls-ca",
+            TARGET_CA,
+        ]);
+        let mut client = TrojanClient::connect(server, PASSWORD, &target).unwrap();
+        let mut buffer = [0u8; 4];
+        client.write_all(b"ping").unwrap();
+        client.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"ping");
+        let sni = sni.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(sni.as_deref(), Some("target.test"));
+    }
+
+    #[test]
+    fn tls_target_not_verified_closed() {
+        let (target, _) = start_tls_echo();
+        let entry = format!("{}=other.test", target);
+        let server = start_server(&[
+            "--allow-self-connect",
+            "--target-tls",
+            entry.as_str(),
+            "--target-tls-ca",
+            TARGET_CA,
+        ]);
+        let mut client = TrojanClient::connect(server, PASSWORD, &target).unwrap();
+        client.write_all(b"ping").unwrap();
+        let mut buffer = [0u8; 4];
+        assert!(client.read_exact(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn slow_drip_closed() {
+        let server = start_server(&[
+            "--allow-self-connect",
+            "--min-transfer-rate",
+            "100",
+            "--min-rate-grace",
+            "1",
+        ]);
+        let echo = start_echo();
+        let mut client = TrojanClient::connect(server, PASSWORD, &echo).unwrap();
+        let start = Instant::now();
+        let mut buffer = [0u8; 1];
+        // one byte every 200ms, never idle but far below 100 bytes/s
+        loop {
+            if client.write_all(b"x").is_err() {
+                break;
+            }
+            match client.read(&mut buffer) {
+                Ok(1) => sleep(Duration::from_millis(200)),
+                _ => break,
+            }
+            assert!(start.elapsed() < Duration::from_secs(5), "not closed");
+        }
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn max_lifetime_closes_active() {
+        let server = start_server(&["--allow-self-connect", "--max-connection-lifetime", "1"]);
+        let echo = start_echo();
+        let mut client = TrojanClient::connect(server, PASSWORD, &echo).unwrap();
+        let start = Instant::now();
+        let mut buffer = [0u8; 4];
+        // busy all the time, still closed once its lifetime is over
+        while client.write_all(b"ping").is_ok() && client.read_exact(&mut buffer).is_ok() {
+            sleep(Duration::from_millis(50));
+            assert!(start.elapsed() < Duration::from_secs(5), "not closed");
+        }
+        assert!(start.elapsed() >= Duration::from_secs(1));
     }
 }
@@ -1,28 +1,120 @@
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fmt::Write;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
 
-use mio::net::TcpListener;
-use mio::{Event, Poll, Token};
+use mio::net::{TcpListener, TcpStream};
+use mio::{Event, Poll, PollOpt, Ready, Token};
 use rustls::{ServerConfig, ServerSession};
 
-use crate::config::Opts;
+use crate::config::{Opts, OverloadPolicy};
+use crate::server::buffer_pool::BufferPool;
 use crate::server::connection::Connection;
-use crate::server::{CHANNEL_CNT, CHANNEL_PROXY, MAX_INDEX, MIN_INDEX};
+use crate::server::otlp::{IdGenerator, SpanExporter};
+use crate::server::{Channel, Counter, LISTENER, MIN_INDEX};
+use crate::stats::Traffic;
 use crate::sys;
 use crate::tls_conn::{ConnStatus, TlsConn};
 
+/// Traffic of the connections alive at one moment, see `TlsServer::stats`
+#[derive(Clone, Default)]
+pub struct ServerStats {
+    pub bytes_read: u64,
+    pub bytes_sent: u64,
+    pub connections: Vec<ConnTraffic>,
+}
+
+#[derive(Clone)]
+pub struct ConnTraffic {
+    pub index: usize,
+    /// None before the request is read or for the fallback
+    pub user: Option<String>,
+    pub bytes_read: u64,
+    pub bytes_sent: u64,
+}
+
+impl ServerStats {
+    pub fn active(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// add the connections of another worker, still ordered by index
+    pub fn merge(&mut self, other: ServerStats) {
+        self.bytes_read += other.bytes_read;
+        self.bytes_sent += other.bytes_sent;
+        self.connections.extend(other.connections);
+        self.connections.sort_by_key(|conn| conn.index);
+    }
+
+    /// one json object, for logs or shipping elsewhere
+    pub fn to_json(&self) -> String {
+        let mut json = format!(
+            "{{\"bytes_read\":{},\"bytes_sent\":{},\"active\":{},\"connections\":[",
+            self.bytes_read,
+            self.bytes_sent,
+            self.active()
+        );
+        for (i, conn) in self.connections.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            // user names are checked to need no escaping
+            let user = match &conn.user {
+                Some(user) => format!("\"{}\"", user),
+                None => "null".to_string(),
+            };
+            let _ = write!(
+                json,
+                "{{\"index\":{},\"user\":{},\"bytes_read\":{},\"bytes_sent\":{}}}",
+                conn.index, user, conn.bytes_read, conn.bytes_sent
+            );
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
 pub struct TlsServer {
     listener: TcpListener,
     config: Arc<ServerConfig>,
     next_id: usize,
     conns: HashMap<usize, Connection>,
+    /// connections ordered by deadline, earliest first
+    deadlines: BinaryHeap<Reverse<(Instant, usize)>>,
+    /// listener is deregistered by admin, existing connections keep running
+    paused: bool,
+    /// accept stopped for good, the server exits once connections are drained
+    shutting_down: bool,
+    /// accept stopped for too many handshakes, continued by `accept_deferred`
+    deferred: bool,
+    /// accept failed for lack of file descriptors, retried by `accept_starved`
+    starved: bool,
+    /// accept stopped for the accept rate, continued by `accept_deferred`
+    throttled: bool,
+    /// connections refused since max connections were last reached, logged sparsely
+    refused: usize,
+    /// released when out of file descriptors, so clients in the backlog can still be refused
+    spare_fd: Option<File>,
+    /// held for good, the spare descriptor is made again by duplicating it
+    reserve: Option<File>,
+    /// each connection removed is exported as a span if set
+    spans: Option<SpanExporter>,
+    /// source of connection ids with random index
+    ids: IdGenerator,
+    /// receive buffers shared by tcp targets
+    buffers: Arc<BufferPool>,
 }
 
 pub trait Backend {
     fn ready(&mut self, event: &Event, opts: &mut Opts, conn: &mut TlsConn<ServerSession>);
     fn dispatch(&mut self, data: &[u8], opts: &mut Opts);
+    /// tls side is writable again, deliver any data held back by the backend
+    fn resume(&mut self, _conn: &mut TlsConn<ServerSession>, _opts: &mut Opts) {}
     fn reregister(&mut self, poll: &Poll, readable: bool);
     fn check_close(&mut self, poll: &Poll);
     fn closing(&self) -> bool {
@@ -42,106 +134,1019 @@ pub trait Backend {
     fn timeout(&self, t1: Instant, t2: Instant) -> bool {
         t2 - t1 > self.get_timeout()
     }
+    /// time the target has to finish connecting, None once connected or without limit
+    fn connect_deadline(&self) -> Option<Instant> {
+        None
+    }
+    /// connecting to the target failed or timed out
+    fn unreachable(&self) -> bool {
+        false
+    }
     fn get_timeout(&self) -> Duration;
+    /// last time data moved to or from the target, None if any event counts as activity
+    fn last_active(&self) -> Option<Instant> {
+        None
+    }
     fn status(&self) -> ConnStatus;
     fn shutdown(&mut self, poll: &Poll);
+    /// close at once dropping data not sent yet, a tcp target gets a reset
+    fn reset(&mut self, poll: &Poll);
     fn writable(&self) -> bool;
+    /// address of the client this backend works for
+    fn peer_addr(&self) -> SocketAddr;
+    /// target address, None if there is no single target, e.g. udp associate
+    fn target(&self) -> Option<SocketAddr> {
+        None
+    }
+    /// bytes read from and sent to target
+    fn traffic(&self) -> (u64, u64);
+    /// bytes waiting to be sent to target
+    fn queue_depth(&self) -> usize;
+    /// reregister with poll failed
+    fn register_failed(&self) -> bool {
+        false
+    }
 }
 
 impl TlsServer {
-    pub fn new(listener: TcpListener, config: Arc<ServerConfig>) -> TlsServer {
+    pub fn new(
+        listener: TcpListener,
+        reserve: Option<File>,
+        config: Arc<ServerConfig>,
+        buffers: Arc<BufferPool>,
+    ) -> TlsServer {
         TlsServer {
             listener,
             config,
-            next_id: 2,
+            next_id: MIN_INDEX,
             conns: HashMap::new(),
+            deadlines: BinaryHeap::new(),
+            paused: false,
+            shutting_down: false,
+            deferred: false,
+            starved: false,
+            throttled: false,
+            refused: 0,
+            spare_fd: reserve.as_ref().and_then(|file| file.try_clone().ok()),
+            reserve,
+            spans: None,
+            ids: IdGenerator::new(),
+            buffers,
+        }
+    }
+
+    pub fn set_span_exporter(&mut self, exporter: SpanExporter) {
+        self.spans.replace(exporter);
+    }
+
+    /// connections accepted from now on use `config`, existing ones keep the one they started with
+    pub fn reload_config(&mut self, config: Arc<ServerConfig>) {
+        self.config = config;
+    }
+
+    /// stop accepting new connections, returns false if already paused
+    pub fn pause(&mut self, poll: &Poll) -> bool {
+        if self.paused {
+            return false;
+        }
+        if let Err(err) = poll.deregister(&self.listener) {
+            log::error!("deregister listener failed:{}", err);
+            return false;
+        }
+        log::warn!("accept paused by admin");
+        self.paused = true;
+        true
+    }
+
+    /// accept new connections again, pending ones in the backlog are reported on register
+    pub fn resume(&mut self, poll: &Poll) -> bool {
+        if !self.paused || self.shutting_down {
+            return false;
+        }
+        if let Err(err) = poll.register(
+            &self.listener,
+            Token(LISTENER),
+            Ready::readable(),
+            PollOpt::edge(),
+        ) {
+            log::error!("register listener failed:{}", err);
+            return false;
+        }
+        log::warn!("accept resumed by admin");
+        self.paused = false;
+        true
+    }
+
+    /// stop accepting for good, connections keep running until they finish or the caller's drain
+    /// deadline passes. returns false if already shutting down
+    pub fn begin_shutdown(&mut self, poll: &Poll) -> bool {
+        if self.shutting_down {
+            return false;
+        }
+        if !self.paused {
+            if let Err(err) = poll.deregister(&self.listener) {
+                log::error!("deregister listener failed:{}", err);
+            }
+            self.paused = true;
         }
+        self.shutting_down = true;
+        log::warn!(
+            "shutting down, drain {} connections",
+            self.connection_count()
+        );
+        true
     }
 
-    pub fn accept(&mut self, poll: &Poll, opts: &Opts) {
+    pub fn shutting_down(&self) -> bool {
+        self.shutting_down
+    }
+
+    /// accept connections in the backlog, fails only if the listener is broken
+    pub fn accept(&mut self, poll: &Poll, opts: &mut Opts) -> std::io::Result<()> {
+        // events polled before pausing may still be in the batch
+        if self.paused {
+            return Ok(());
+        }
+        let limit = opts.server_args().max_handshakes;
+        let limits = opts.limits.clone();
+        let mut shed = false;
         loop {
+            let handshake = match limits.take(Counter::Handshakes, limit) {
+                Some(handshake) => handshake,
+                None => {
+                    if !self.deferred {
+                        log::warn!(
+                            "{} handshakes in progress, defer accepting",
+                            limits.count(Counter::Handshakes)
+                        );
+                        self.deferred = true;
+                    }
+                    break;
+                }
+            };
+            if !self.refill_accept_tokens(opts) {
+                if !self.throttled {
+                    log::warn!("accept rate limit reached, new connections wait in the backlog");
+                    self.throttled = true;
+                }
+                break;
+            }
             match self.listener.accept() {
                 Ok((stream, addr)) => {
+                    limits.take_accept_token();
+                    if self.starved {
+                        log::info!("file descriptors available, accept again");
+                        self.starved = false;
+                    }
                     log::debug!(
                         "get new connection, token:{}, address:{}",
                         self.next_id,
                         addr
                     );
-                    if let Err(err) = sys::set_mark(&stream, opts.marker) {
-                        log::error!("set mark failed:{}", err);
+                    if let Err(err) = check_alive(&stream) {
+                        setup_failed("accept", addr, &err, opts);
+                        continue;
+                    } else if let Err(err) = sys::set_mark(&stream, opts.marker) {
+                        setup_failed("set mark", addr, &err, opts);
                         continue;
                     } else if let Err(err) = stream.set_nodelay(true) {
-                        log::error!("set nodelay failed:{}", err);
+                        setup_failed("set nodelay", addr, &err, opts);
+                        continue;
+                    } else if let Err(err) = set_keepalive(&stream, opts) {
+                        setup_failed("set keepalive", addr, &err, opts);
+                        continue;
+                    } else if let Err(err) = sys::set_buffer_size(
+                        &stream,
+                        opts.server_args().socket_recv_buffer,
+                        opts.server_args().socket_send_buffer,
+                    ) {
+                        setup_failed("set buffer size", addr, &err, opts);
                         continue;
                     }
+                    let per_ip = opts.server_args().max_conns_per_ip;
+                    let client = match limits.take(Counter::Ip(addr.ip()), per_ip) {
+                        Some(client) => client,
+                        None => {
+                            // dropping the stream closes it, the loop goes on with other clients
+                            log::warn!(
+                                "{} connections from {}, refuse connection",
+                                per_ip,
+                                addr.ip()
+                            );
+                            continue;
+                        }
+                    };
+                    // shedding frees a slot only if this worker has a connection to close
+                    let max = opts.server_args().max_connections;
+                    let slot = match limits.take(Counter::Connections, max) {
+                        None if self.shed(poll, opts) => limits.take(Counter::Connections, max),
+                        slot => slot,
+                    };
+                    let slot = match slot {
+                        Some(slot) => slot,
+                        None => {
+                            self.refused += 1;
+                            if self.refused.is_power_of_two() {
+                                log::warn!(
+                                    "{} connections, refuse connection from {}, {} refused",
+                                    max,
+                                    addr,
+                                    self.refused
+                                );
+                            }
+                            continue;
+                        }
+                    };
+                    self.refused = 0;
+                    let index = match self.next_index(opts) {
+                        Some(index) => index,
+                        None => {
+                            log::warn!("no free connection index, refuse connection from {}", addr);
+                            continue;
+                        }
+                    };
                     let session = ServerSession::new(&self.config);
-                    let index = self.next_index();
                     let mut conn = Connection::new(
                         index,
-                        TlsConn::new(
-                            index,
-                            Token(index * CHANNEL_CNT + CHANNEL_PROXY),
-                            session,
-                            stream,
-                        ),
+                        addr,
+                        TlsConn::new(index, Channel::Proxy.token(index), session, stream),
+                        self.buffers.clone(),
                     );
                     if conn.setup(poll, opts) {
+                        handshake.keep();
+                        client.keep();
+                        slot.keep();
                         self.conns.insert(index, conn);
+                        self.schedule(index, opts);
+                        opts.stats.add_accepted();
                     } else {
-                        conn.close_now(poll);
+                        opts.stats.add_error();
+                        opts.stats.add_register_failure();
+                        conn.close_now(poll, opts);
                     }
                 }
                 Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
                     log::debug!("no more connection to be accepted");
                     break;
                 }
+                Err(err) if sys::is_fd_exhausted(&err) => {
+                    opts.stats.add_error();
+                    opts.stats.add_accept_error();
+                    if !self.starved {
+                        log::warn!("accept failed:{}, retry later", err);
+                        self.starved = true;
+                    }
+                    // free one descriptor if the overload policy allows and take the client
+                    if !shed && !self.conns.is_empty() && self.shed(poll, opts) {
+                        shed = true;
+                        continue;
+                    }
+                    self.refuse_backlog();
+                    break;
+                }
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        ErrorKind::ConnectionAborted
+                            | ErrorKind::ConnectionReset
+                            | ErrorKind::Interrupted
+                    ) =>
+                {
+                    log::debug!("accept failed:{}, skip connection", err);
+                    continue;
+                }
                 Err(err) => {
-                    log::error!("accept failed with error:{}, exit now", err);
-                    panic!(err)
+                    opts.stats.add_accept_error();
+                    return Err(err);
                 }
             }
         }
+        Ok(())
+    }
+
+    /// close clients in the backlog with the spare descriptor while out of them, so they are
+    /// refused at once instead of waiting on a listener that can not accept
+    fn refuse_backlog(&mut self) {
+        while self.spare_fd.take().is_some() {
+            let refused = self.listener.accept().map(|(_, addr)| addr);
+            self.spare_fd = self.reserve.as_ref().and_then(|file| file.try_clone().ok());
+            match refused {
+                Ok(addr) => {
+                    self.refused += 1;
+                    if self.refused.is_power_of_two() {
+                        log::warn!(
+                            "out of file descriptors, refuse connection from {}, {} refused",
+                            addr,
+                            self.refused
+                        );
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// connections left in the backlog by fd exhaustion are not reported again by the edge
+    /// triggered listener, try them again once in a while
+    pub fn accept_starved(&mut self, poll: &Poll, opts: &mut Opts) -> std::io::Result<()> {
+        if self.starved {
+            self.starved = false;
+            self.accept(poll, opts)?;
+        }
+        Ok(())
+    }
+
+    /// continue accepting once handshakes drop below the limit or accept tokens refill,
+    /// listener is edge triggered so connections left in the backlog are not reported again
+    pub fn accept_deferred(&mut self, poll: &Poll, opts: &mut Opts) -> std::io::Result<()> {
+        let handshakes = opts.limits.count(Counter::Handshakes);
+        if self.deferred && handshakes < opts.server_args().max_handshakes {
+            log::info!("{} handshakes in progress, accept again", handshakes);
+            self.deferred = false;
+            self.accept(poll, opts)?;
+        }
+        if self.throttled && self.refill_accept_tokens(opts) {
+            log::info!("below accept rate, accept again");
+            self.throttled = false;
+            self.accept(poll, opts)?;
+        }
+        Ok(())
+    }
+
+    /// refill the accept token bucket, false if no connection may be accepted now
+    fn refill_accept_tokens(&mut self, opts: &Opts) -> bool {
+        let args = opts.server_args();
+        opts.limits
+            .refill_accept_tokens(args.accept_rate, args.accept_burst)
+    }
+
+    /// time until the next token while accept is throttled, so poll wakes up for it
+    pub fn accept_wait(&self, opts: &Opts) -> Option<Duration> {
+        if !self.throttled {
+            return None;
+        }
+        Some(opts.limits.accept_wait(opts.server_args().accept_rate))
+    }
+
+    /// close a connection picked by overload policy to make room for a new one,
+    /// returns false if the policy refuses new connections instead
+    fn shed(&mut self, poll: &Poll, opts: &mut Opts) -> bool {
+        let index = match opts.overload_policy {
+            OverloadPolicy::Refuse => return false,
+            OverloadPolicy::Oldest => self
+                .conns
+                .iter()
+                .min_by_key(|(_, conn)| conn.accept_time())
+                .map(|(index, _)| *index),
+            OverloadPolicy::Idlest => self
+                .conns
+                .iter()
+                .min_by_key(|(_, conn)| conn.last_active_time())
+                .map(|(index, _)| *index),
+        };
+        if let Some(index) = index {
+            let mut conn = self.conns.remove(&index).unwrap();
+            log::warn!(
+                "connection:{} from {} closed for overload",
+                index,
+                conn.peer_addr()
+            );
+            self.forget(&conn, opts);
+            conn.close_now(poll, opts);
+        }
+        true
+    }
+
+    /// connection is removed from pool, give back what it took of the shared limits
+    fn forget(&mut self, conn: &Connection, opts: &mut Opts) {
+        log::info!(
+            "connection:{} of user {} closed, {}",
+            conn.index(),
+            conn.user().unwrap_or("-"),
+            conn.phases()
+        );
+        if conn.register_failed() {
+            opts.stats.add_register_failure();
+        }
+        if let Some(spans) = self.spans.as_mut() {
+            spans.export(conn.span());
+        }
+        if conn.tls_handshaking() {
+            opts.limits.release(Counter::Handshakes);
+        }
+        if conn.udp_associated() {
+            opts.limits.release(Counter::UdpSessions);
+        }
+        opts.limits.release(Counter::Ip(conn.peer_addr().ip()));
+        opts.limits.release(Counter::Connections);
+    }
+
+    pub fn connection_count(&self) -> usize {
+        self.conns.len()
+    }
+
+    /// one line for each connection, used by admin
+    pub fn status(&self, opts: &Opts) -> String {
+        let mut indexes: Vec<_> = self.conns.keys().collect();
+        indexes.sort();
+        let mut status = String::new();
+        for index in indexes {
+            let _ = writeln!(status, "{}", self.conns[index].status());
+        }
+        let _ = writeln!(status, "total {} connections", self.conns.len());
+        if self.shutting_down {
+            let _ = writeln!(status, "shutting down");
+        } else if self.paused {
+            let _ = writeln!(status, "accept paused");
+        }
+        if self.starved {
+            let _ = writeln!(status, "accept waiting for file descriptors");
+        }
+        if self.reserve.is_none() {
+            let _ = writeln!(
+                status,
+                "no file descriptor in reserve to refuse clients with"
+            );
+        }
+        if self.throttled {
+            let _ = writeln!(status, "accept throttled by accept rate");
+        }
+        if self.deferred {
+            let handshakes = opts.limits.count(Counter::Handshakes);
+            let _ = writeln!(status, "accept deferred, {} handshakes", handshakes);
+        }
+        status
+    }
+
+    /// one line for each connection with traffic, used by admin
+    pub fn list(&self) -> String {
+        let mut indexes: Vec<_> = self.conns.keys().collect();
+        indexes.sort();
+        let mut list = String::new();
+        for index in indexes {
+            let _ = writeln!(list, "{}", self.conns[index].detail());
+        }
+        list
+    }
+
+    /// traffic of each connection alive and their totals, ordered by index
+    pub fn stats(&self) -> ServerStats {
+        let mut stats = ServerStats::default();
+        for (index, conn) in &self.conns {
+            let (bytes_read, bytes_sent) = conn.traffic();
+            stats.bytes_read += bytes_read;
+            stats.bytes_sent += bytes_sent;
+            stats.connections.push(ConnTraffic {
+                index: *index,
+                user: conn.user().map(str::to_string),
+                bytes_read,
+                bytes_sent,
+            });
+        }
+        stats.connections.sort_by_key(|conn| conn.index);
+        stats
+    }
+
+    /// bytes relayed by all connections since the last `reset_traffic`
+    pub fn traffic_snapshot(&self, opts: &Opts) -> Traffic {
+        opts.stats.traffic()
+    }
+
+    /// start a new accounting period and return the totals of the one ended, use the returned
+    /// totals for billing instead of an earlier snapshot, which misses bytes relayed since then
+    pub fn reset_traffic(&mut self, opts: &Opts) -> Traffic {
+        let traffic = opts.stats.take_traffic();
+        log::info!(
+            "traffic reset, read {} bytes, sent {} bytes",
+            traffic.bytes_read,
+            traffic.bytes_sent
+        );
+        traffic
+    }
+
+    /// shut down both sides of every connection, data already buffered is still sent. a
+    /// connection stuck sending is closed by its idle timeout or by `close_all`
+    pub fn shutdown_all(&mut self, poll: &Poll, opts: &mut Opts) {
+        let indexes: Vec<_> = self.conns.keys().copied().collect();
+        for index in indexes {
+            let conn = self.conns.get_mut(&index).unwrap();
+            conn.close_now(poll, opts);
+            if conn.destroyed() {
+                let conn = self.conns.remove(&index).unwrap();
+                self.forget(&conn, opts);
+            }
+        }
+    }
+
+    /// close every connection at once, used when draining takes too long
+    pub fn close_all(&mut self, poll: &Poll, opts: &mut Opts) {
+        let indexes: Vec<_> = self.conns.keys().copied().collect();
+        for index in indexes {
+            let mut conn = self.conns.remove(&index).unwrap();
+            self.forget(&conn, opts);
+            conn.close_now(poll, opts);
+        }
+    }
+
+    /// close connection by admin request, returns false if not found
+    pub fn kill(&mut self, index: usize, poll: &Poll, opts: &mut Opts) -> bool {
+        if let Some(mut conn) = self.conns.remove(&index) {
+            log::warn!("connection:{} killed by admin", index);
+            self.forget(&conn, opts);
+            conn.close_now(poll, opts);
+            true
+        } else {
+            false
+        }
     }
 
-    fn next_index(&mut self) -> usize {
-        let index = self.next_id;
-        self.next_id += 1;
-        if self.next_id > MAX_INDEX {
-            self.next_id = MIN_INDEX;
+    /// an index not taken by a live connection, None if all of them are taken
+    fn next_index(&mut self, opts: &Opts) -> Option<usize> {
+        let (min_index, max_index) = (opts.min_index, opts.max_index);
+        if self.conns.len() > max_index - min_index {
+            return None;
         }
-        index
+        if opts.server_args().random_index {
+            let range = (max_index - min_index) as u64 + 1;
+            loop {
+                let index = min_index + (self.ids.next() % range) as usize;
+                if !self.conns.contains_key(&index) {
+                    return Some(index);
+                }
+            }
+        }
+        let conns = &self.conns;
+        let start = self.next_id.max(min_index);
+        let (index, next_id) = free_index(start, min_index, max_index, |index| {
+            conns.contains_key(&index)
+        });
+        self.next_id = next_id;
+        Some(index)
     }
 
-    fn token2index(&mut self, token: Token) -> usize {
-        token.0 / CHANNEL_CNT
+    fn token2index(&self, token: Token) -> usize {
+        Channel::of(token).0
+    }
+
+    pub fn handshaking(&self, token: Token) -> bool {
+        self.conns
+            .get(&self.token2index(token))
+            .map_or(false, |conn| conn.handshaking())
     }
 
     pub fn do_conn_event(&mut self, poll: &Poll, event: &Event, opts: &mut Opts) {
         let index = self.token2index(event.token());
         if self.conns.contains_key(&index) {
             let conn = self.conns.get_mut(&index).unwrap();
+            let handshaking = conn.tls_handshaking();
             conn.ready(poll, event, opts);
+            if handshaking && !conn.tls_handshaking() {
+                opts.limits.release(Counter::Handshakes);
+            }
             if conn.destroyed() {
-                self.conns.remove(&index);
+                let conn = self.conns.remove(&index).unwrap();
+                self.forget(&conn, opts);
                 log::debug!("connection:{} closed, remove from pool", index);
+            } else {
+                self.schedule(index, opts);
             }
         } else {
             log::error!("connection:{} not found", index);
         }
     }
 
-    pub fn check_timeout(&mut self, check_active_time: Instant, poll: &Poll) {
-        let mut list = Vec::new();
-        for (index, conn) in &mut self.conns {
-            if conn.timeout(check_active_time) {
-                list.push(*index);
+    /// put connection into the deadline heap if it is not there yet or its deadline moved
+    /// earlier, like a deadline from client
+    fn schedule(&mut self, index: usize, opts: &Opts) {
+        if let Some(conn) = self.conns.get_mut(&index) {
+            if let Some(deadline) = conn.deadline(opts) {
+                if !matches!(conn.scheduled(), Some(scheduled) if scheduled <= deadline) {
+                    conn.set_scheduled(Some(deadline));
+                    self.deadlines.push(Reverse((deadline, index)));
+                }
+            }
+        }
+    }
+
+    /// only connections with a passed deadline are examined. activity does not move a
+    /// connection in the heap, its deadline is recomputed and pushed again when popped.
+    /// entries of removed connections or outdated deadlines are skipped.
+    pub fn check_timeout(&mut self, now: Instant, poll: &Poll, opts: &mut Opts) {
+        let batch = opts.server_args().timeout_check_batch;
+        let mut closed = 0;
+        while let Some(Reverse((deadline, index))) = self.deadlines.peek().copied() {
+            if deadline >= now || (batch > 0 && closed >= batch) {
+                break;
+            }
+            self.deadlines.pop();
+            let conn = match self.conns.get_mut(&index) {
+                Some(conn) if conn.scheduled() == Some(deadline) => conn,
+                _ => continue,
+            };
+            conn.set_scheduled(None);
+            if conn.timeout(now, opts) {
                 log::warn!("connection:{} timeout, close now", index);
-                conn.close_now(poll)
+                opts.stats.add_timeout();
+                conn.close_now(poll, opts);
+                let conn = self.conns.remove(&index).unwrap();
+                self.forget(&conn, opts);
+                closed += 1;
+            } else {
+                self.schedule(index, opts);
             }
         }
+    }
+}
+
+/// scanners often reset right after connecting, such sockets are still returned by accept
+fn check_alive(stream: &TcpStream) -> std::io::Result<()> {
+    if let Some(err) = stream.take_error()? {
+        return Err(err);
+    }
+    stream.peer_addr().map(|_| ())
+}
+
+/// keepalive of a client or target connection as configured
+pub fn set_keepalive(stream: &TcpStream, opts: &Opts) -> std::io::Result<()> {
+    let args = opts.server_args();
+    if args.tcp_keepalive {
+        sys::set_keepalive(stream, args.keepalive_idle, args.keepalive_interval)
+    } else {
+        Ok(())
+    }
+}
+
+/// first index from `start` on that is not used, wrapping around to `min` after `max`,
+/// returned with the index to start from next time. some index must be free
+fn free_index(
+    mut start: usize,
+    min: usize,
+    max: usize,
+    used: impl Fn(usize) -> bool,
+) -> (usize, usize) {
+    loop {
+        let index = start;
+        start = if start >= max { min } else { start + 1 };
+        if !used(index) {
+            return (index, start);
+        }
+    }
+}
+
+/// log a failure setting up an accepted socket, quietly if the client is already gone
+fn setup_failed(what: &str, addr: SocketAddr, err: &std::io::Error, opts: &Opts) {
+    if matches!(
+        err.kind(),
+        ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::NotConnected
+    ) {
+        opts.stats.add_dead_accept();
+        if opts.server_args().log_dead_accepts {
+            log::warn!("connection from {} closed before setup:{}", addr, err);
+        } else {
+            log::debug!("connection from {} closed before setup:{}", addr, err);
+        }
+    } else {
+        log::error!("{} failed:{}", what, err);
+        opts.stats.add_error();
+        opts.stats.add_accept_error();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use socket2::Socket;
+
+    use super::{check_alive, free_index, MIN_INDEX};
+    use crate::server::MAX_INDEX;
+
+    #[test]
+    fn index_in_use_skipped_on_wrap() {
+        let live = [MAX_INDEX - 1, MAX_INDEX, MIN_INDEX, MIN_INDEX + 2];
+        let used = |index| live.contains(&index);
+        let (index, next) = free_index(MAX_INDEX - 2, MIN_INDEX, MAX_INDEX, used);
+        assert_eq!(index, MAX_INDEX - 2);
+        let (index, next) = free_index(next, MIN_INDEX, MAX_INDEX, used);
+        assert_eq!(index, MIN_INDEX + 1);
+        let (index, next) = free_index(next, MIN_INDEX, MAX_INDEX, used);
+        assert_eq!(index, MIN_INDEX + 3);
+        assert_eq!(next, MIN_INDEX + 4);
+        assert_eq!(
+            free_index(MAX_INDEX, MIN_INDEX, MAX_INDEX, |_| false),
+            (MAX_INDEX, MIN_INDEX)
+        );
+        // a worker wraps around to the start of its own range
+        assert_eq!(free_index(20, 10, 20, |_| false), (20, 10));
+    }
+
+    #[test]
+    fn reset_before_accept_detected() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = Socket::from(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+        client.set_linger(Some(Duration::from_secs(0))).unwrap();
+        drop(client);
+        sleep(Duration::from_millis(50));
+        let (stream, _) = listener.accept().unwrap();
+        let stream = mio::net::TcpStream::from_stream(stream).unwrap();
+        assert!(check_alive(&stream).is_err());
+
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let stream = mio::net::TcpStream::from_stream(stream).unwrap();
+        assert!(check_alive(&stream).is_ok());
+    }
+}
+
+#[cfg(all(test, feature = "test-support"))]
+mod client_tests {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread::{sleep, spawn};
+    use std::time::{Duration, Instant};
+
+    use super::MIN_INDEX;
+    use crate::server::MAX_INDEX;
+    use crate::test_support::*;
+
+    #[test]
+    fn drain_and_exit_after_max_served() {
+        // the probe connection of start_server is the first one served
+        let (server, exit) = start_server_exit(&["--allow-self-connect", "--max-served", "2"]);
+        let echo = start_echo();
+        let mut client = TrojanClient::connect(server, PASSWORD, &echo).unwrap();
+        assert!(echoed(&mut client, b"first"));
+        sleep(Duration::from_millis(1500));
+        // draining, the active connection keeps working
+        assert!(exit.try_recv().is_err());
+        assert!(echoed(&mut client, b"still working"));
+        client.shutdown();
+        let code = exit.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(code, 75);
+    }
+
+    #[test]
+    fn shutdown_drains_connections() {
+        let admin_addr = free_addr().to_string();
+        let (server, exit) =
+            start_server_exit(&["--allow-self-connect", "--admin-addr", admin_addr.as_str()]);
+        let echo = start_echo();
+        let mut client = TrojanClient::connect(server, PASSWORD, &echo).unwrap();
+        assert!(echoed(&mut client, b"first"));
+        assert_eq!(admin(&admin_addr, "shutdown"), "shutting down\n");
+        assert_eq!(admin(&admin_addr, "shutdown"), "already shutting down\n");
+        assert!(admin(&admin_addr, "status").contains("shutting down\n"));
+        sleep(Duration::from_millis(1500));
+        // accept stays stopped, the active connection keeps working
+        assert!(admin(&admin_addr, "resume").starts_with("accept not resumed"));
+        assert!(exit.try_recv().is_err());
+        assert!(echoed(&mut client, b"still working"));
+        client.shutdown();
+        let code = exit.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(code, 0);
+    }
+
+    #[test]
+    fn shutdown_timeout_closes_connections() {
+        let admin_addr = free_addr().to_string();
+        let (server, exit) = start_server_exit(&[
+            "--allow-self-connect",
+            "--admin-addr",
+            admin_addr.as_str(),
+            "--shutdown-timeout",
+            "1",
+        ]);
+        let echo = start_echo();
+        let mut client = TrojanClient::connect(server, PASSWORD, &echo).unwrap();
+        assert!(echoed(&mut client, b"first"));
+        admin(&admin_addr, "shutdown");
+        // the idle client is shut down at the deadline instead of holding the server forever
+        let code = exit.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(code, 0);
+        let mut buffer = [0u8; 8];
+        assert!(!matches!(client.read(&mut buffer), Ok(n) if n > 0));
+    }
+
+    #[test]
+    fn random_index_not_sequential() {
+        let admin_addr = free_addr().to_string();
+        let server = start_server(&[
+            "--allow-self-connect",
+            "--admin-addr",
+            admin_addr.as_str(),
+            "--random-index",
+        ]);
+        let echo = start_echo();
+        let mut clients: Vec<_> = (0..4)
+            .map(|_| TrojanClient::connect(server, PASSWORD, &echo).unwrap())
+            .collect();
+        for client in &mut clients {
+            assert!(echoed(client, b"ping"));
+        }
+        sleep(Duration::from_millis(100));
+        let indexes: Vec<usize> = admin(&admin_addr, "list")
+            .lines()
+            .map(|line| line.split(' ').next().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(indexes.len(), 4);
+        assert!(indexes.iter().all(|index| *index >= super::MIN_INDEX));
+        // listed in order, four random ids are practically never adjacent
+        assert!(indexes.windows(2).any(|pair| pair[1] - pair[0] > 1));
+    }
+
+    #[test]
+    fn workers_share_address() {
+        let admin_addr = free_addr().to_string();
+        let (server, exit) = start_server_exit(&[
+            "--allow-self-connect",
+            "--workers",
+            "2",
+            "--admin-addr",
+            admin_addr.as_str(),
+        ]);
+        let echo = start_echo();
+        let mut clients: Vec<TrojanClient> = (0..16)
+            .map(|_| TrojanClient::connect(server, PASSWORD, &echo).unwrap())
+            .collect();
+        for client in &mut clients {
+            assert!(echoed(client, b"ping"));
+        }
+        // the admin lists connections of every worker, each takes its own part of the indexes
+        let list = admin(&admin_addr, "list");
+        let indexes: Vec<usize> = list
+            .lines()
+            .map(|line| line.split(' ').next().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(indexes.len(), clients.len());
+        assert!(indexes.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(indexes.iter().any(|index| *index > MAX_INDEX / 2));
+        let status = admin(&admin_addr, "status");
+        assert!(status.ends_with(&format!("total {} connections\n", clients.len())));
+        let last = indexes.last().unwrap();
+        assert_eq!(
+            admin(&admin_addr, &format!("kill {}", last)),
+            format!("connection {} killed\n", last)
+        );
+        drop(clients);
+        admin(&admin_addr, "shutdown");
+        assert_eq!(exit.recv_timeout(Duration::from_secs(10)), Ok(0));
+    }
 
-        for index in list {
-            self.conns.remove(&index);
+    #[test]
+    fn workers_share_limits() {
+        let server = start_server(&[
+            "--allow-self-connect",
+            "--workers",
+            "2",
+            "--max-conns-per-ip",
+            "2",
+        ]);
+        let echo = start_echo();
+        sleep(Duration::from_millis(100));
+        // clients are spread over both workers, yet only two of them are served
+        let mut clients: Vec<TrojanClient> = (0..8)
+            .filter_map(|_| TrojanClient::connect(server, PASSWORD, &echo).ok())
+            .collect();
+        let served = clients
+            .iter_mut()
+            .map(|client| echoed(client, b"ping"))
+            .filter(|served| *served)
+            .count();
+        assert_eq!(served, 2);
+    }
+
+    #[test]
+    fn index_range_limits_connections() {
+        let max_index = (MIN_INDEX + 1).to_string();
+        let server = start_server(&["--allow-self-connect", "--max-index", max_index.as_str()]);
+        let echo = start_echo();
+        // let the server drop the probe connection of start_server first
+        sleep(Duration::from_millis(100));
+        let mut first = TrojanClient::connect(server, PASSWORD, &echo).unwrap();
+        let mut second = TrojanClient::connect(server, PASSWORD, &echo).unwrap();
+        assert!(echoed(&mut first, b"first"));
+        assert!(echoed(&mut second, b"second"));
+        if let Ok(mut third) = TrojanClient::connect(server, PASSWORD, &echo) {
+            assert!(!echoed(&mut third, b"third"));
         }
+        first.shutdown();
+        drop(first);
+        sleep(Duration::from_millis(100));
+        let mut fourth = TrojanClient::connect(server, PASSWORD, &echo).unwrap();
+        assert!(echoed(&mut fourth, b"fourth"));
+        assert!(echoed(&mut second, b"still second"));
+    }
+
+    #[test]
+    fn handshakes_over_limit_wait() {
+        let admin_addr = free_addr().to_string();
+        let server = start_server(&[
+            "--allow-self-connect",
+            "--admin-addr",
+            admin_addr.as_str(),
+            "--max-handshakes",
+            "1",
+            "--tls-handshake-timeout",
+            "1",
+        ]);
+        let echo = start_echo();
+        // never sends a client hello, holds the only handshake slot until it times out
+        let _stalled = TcpStream::connect(server).unwrap();
+        sleep(Duration::from_millis(100));
+        let client = spawn(move || {
+            let mut client = TrojanClient::connect(server, PASSWORD, &echo).unwrap();
+            client.write_all(b"waited").unwrap();
+            let mut buffer = [0u8; 6];
+            client.read_exact(&mut buffer).unwrap();
+            buffer
+        });
+        sleep(Duration::from_millis(200));
+        let status = admin(&admin_addr, "status");
+        assert!(status.ends_with("total 1 connections\naccept deferred, 1 handshakes\n"));
+        assert_eq!(&client.join().unwrap(), b"waited");
+    }
+
+    #[test]
+    fn accept_rate_limited() {
+        let server = start_server(&[
+            "--allow-self-connect",
+            "--accept-rate",
+            "2",
+            "--accept-burst",
+            "1",
+        ]);
+        let echo = start_echo();
+        let start = Instant::now();
+        // the bucket holds one token, the probe of start_server took it
+        for _ in 0..3 {
+            let mut client = TrojanClient::connect(server, PASSWORD, &echo).unwrap();
+            assert!(echoed(&mut client, b"ping"));
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(1000), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+    }
+
+    fn echoed(client: &mut TrojanClient, data: &[u8]) -> bool {
+        let mut buffer = vec![0u8; data.len()];
+        client.write_all(data).is_ok()
+            && client.read_exact(buffer.as_mut_slice()).is_ok()
+            && buffer == data
+    }
+
+    #[test]
+    fn overload_refuses_new() {
+        let server = start_server(&["--allow-self-connect", "--max-connections", "1"]);
+        let echo = start_echo();
+        // let the server drop the probe connection of start_server first
+        sleep(Duration::from_millis(100));
+        let mut first = TrojanClient::connect(server, PASSWORD, &echo).unwrap();
+        assert!(echoed(&mut first, b"first"));
+        // closed before tls handshake, the request may fail to be written already
+        if let Ok(mut second) = TrojanClient::connect(server, PASSWORD, &echo) {
+            assert!(!echoed(&mut second, b"second"));
+        }
+        assert!(echoed(&mut first, b"still first"));
+    }
+
+    #[test]
+    fn connections_per_ip_limited() {
+        let server = start_server(&["--allow-self-connect", "--max-conns-per-ip", "2"]);
+        let echo = start_echo();
+        sleep(Duration::from_millis(100));
+        let mut first = TrojanClient::connect(server, PASSWORD, &echo).unwrap();
+        let mut second = TrojanClient::connect(server, PASSWORD, &echo).unwrap();
+        assert!(echoed(&mut first, b"first"));
+        assert!(echoed(&mut second, b"second"));
+        if let Ok(mut third) = TrojanClient::connect(server, PASSWORD, &echo) {
+            assert!(!echoed(&mut third, b"third"));
+        }
+        // a closed connection makes room for the next one
+        first.shutdown();
+        drop(first);
+        sleep(Duration::from_millis(200));
+        let mut third = TrojanClient::connect(server, PASSWORD, &echo).unwrap();
+        assert!(echoed(&mut third, b"third"));
+        assert!(echoed(&mut second, b"still second"));
+    }
+
+    #[test]
+    fn overload_sheds_oldest() {
+        let server = start_server(&[
+            "--allow-self-connect",
+            "--max-connections",
+            "1",
+            "--overload-policy",
+            "oldest",
+        ]);
+        let echo = start_echo();
+        sleep(Duration::from_millis(100));
+        let mut first = TrojanClient::connect(server, PASSWORD, &echo).unwrap();
+        assert!(echoed(&mut first, b"first"));
+        let mut second = TrojanClient::connect(server, PASSWORD, &echo).unwrap();
+        assert!(echoed(&mut second, b"second"));
+        assert!(!echoed(&mut first, b"first again"));
     }
 }
@@ -1,19 +1,23 @@
-use std::net::SocketAddr;
-use std::time::Duration;
+use std::collections::{HashSet, VecDeque};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
 
 use bytes::BytesMut;
 use mio::net::UdpSocket;
 use mio::{Event, Poll, PollOpt, Ready, Token};
 use rustls::ServerSession;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 
-use crate::config::Opts;
-use crate::proto::{UdpAssociate, UdpParseResult, MAX_BUFFER_SIZE, MAX_PACKET_SIZE};
+use crate::config::{DropPolicy, Opts, ReplyFilter};
+use crate::proto::{UdpAssociate, UdpParseResult};
 use crate::server::tls_server::Backend;
 use crate::tls_conn::{ConnStatus, TlsConn};
 
 pub struct UdpBackend {
     socket: UdpSocket,
     send_buffer: BytesMut,
+    /// the client is not read while send_buffer holds this many bytes
+    high_water: usize,
     recv_body: Vec<u8>,
     recv_head: BytesMut,
     index: usize,
@@ -21,27 +25,142 @@ pub struct UdpBackend {
     status: ConnStatus,
     readiness: Ready,
     timeout: Duration,
-    bytes_read: usize,
-    bytes_sent: usize,
+    bytes_read: u64,
+    bytes_sent: u64,
     remote_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    queue: VecDeque<Vec<u8>>,
+    queue_size: usize,
+    drop_policy: DropPolicy,
+    dropped: usize,
+    dual_stack: bool,
+    reply_filter: ReplyFilter,
+    /// targets the client sent packets to, only kept if replies are filtered
+    destinations: HashSet<SocketAddr>,
+    /// token bucket of packets to targets, refilled at packet_rate up to packet_burst
+    packet_rate: f64,
+    packet_burst: f64,
+    packet_tokens: f64,
+    refill_time: Instant,
+    rate_dropped: usize,
+    /// reregister with poll failed
+    register_failed: bool,
+}
+
+/// bind a dual stack socket reaching both ipv4 and ipv6 targets,
+/// fallback to the family of `empty_addr` if ipv6 is not available.
+pub fn bind(opts: &Opts) -> std::io::Result<UdpSocket> {
+    let socket =
+        Socket::new(Domain::ipv6(), Type::dgram(), Some(Protocol::udp())).and_then(|socket| {
+            socket.set_only_v6(false)?;
+            let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0);
+            socket.bind(&SockAddr::from(addr))?;
+            Ok(socket)
+        });
+    match socket {
+        Ok(socket) => UdpSocket::from_socket(socket.into_udp_socket()),
+        Err(err) => {
+            log::warn!("bind dual stack udp socket failed:{}", err);
+            UdpSocket::bind(opts.empty_addr.as_ref().unwrap())
+        }
+    }
+}
+
+/// ipv4 peers of a dual stack socket show up as ipv4-mapped ipv6 address
+fn unmap(addr: SocketAddr) -> SocketAddr {
+    if let SocketAddr::V6(v6) = addr {
+        if v6.ip().segments()[..6] == [0, 0, 0, 0, 0, 0xffff] {
+            return SocketAddr::new(IpAddr::V4(v6.ip().to_ipv4().unwrap()), v6.port());
+        }
+    }
+    addr
 }
 
 impl UdpBackend {
-    pub fn new(socket: UdpSocket, index: usize, token: Token, timeout: Duration) -> UdpBackend {
+    pub fn new(
+        socket: UdpSocket,
+        index: usize,
+        peer_addr: SocketAddr,
+        token: Token,
+        opts: &Opts,
+    ) -> UdpBackend {
         let remote_addr = socket.local_addr().unwrap();
+        let dual_stack = remote_addr.is_ipv6();
+        let args = opts.server_args();
+        let packet_burst = if args.udp_packet_burst == 0 {
+            args.udp_packet_rate
+        } else {
+            args.udp_packet_burst
+        } as f64;
         UdpBackend {
             socket,
             send_buffer: Default::default(),
-            recv_body: vec![0u8; MAX_PACKET_SIZE],
+            high_water: opts.send_buffer_high_water,
+            // one more byte to detect datagrams larger than the limit
+            recv_body: vec![0u8; opts.udp_max_datagram + 1],
             recv_head: Default::default(),
             index,
             token,
             status: ConnStatus::Established,
             readiness: Ready::empty(),
-            timeout,
+            timeout: opts.udp_idle_duration,
             bytes_read: 0,
             bytes_sent: 0,
             remote_addr,
+            peer_addr,
+            queue: VecDeque::new(),
+            queue_size: opts.server_args().udp_queue_size,
+            drop_policy: opts.udp_drop_policy,
+            dropped: 0,
+            dual_stack,
+            reply_filter: opts.udp_reply_filter,
+            destinations: HashSet::new(),
+            packet_rate: opts.server_args().udp_packet_rate as f64,
+            packet_burst,
+            packet_tokens: packet_burst,
+            refill_time: Instant::now(),
+            rate_dropped: 0,
+            register_failed: false,
+        }
+    }
+
+    /// take a token for one packet to a target, false if the session is over its packet rate
+    fn take_token(&mut self) -> bool {
+        if self.packet_rate <= 0.0 {
+            return true;
+        }
+        let now = Instant::now();
+        let elapsed = (now - self.refill_time).as_secs_f64();
+        self.refill_time = now;
+        self.packet_tokens =
+            (self.packet_tokens + elapsed * self.packet_rate).min(self.packet_burst);
+        if self.packet_tokens < 1.0 {
+            return false;
+        }
+        self.packet_tokens -= 1.0;
+        true
+    }
+
+    /// ipv4 targets have to be mapped before sending through a dual stack socket
+    fn target_addr(&self, addr: &SocketAddr) -> SocketAddr {
+        match addr {
+            SocketAddr::V4(v4) if self.dual_stack => {
+                SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port())
+            }
+            _ => *addr,
+        }
+    }
+
+    /// whether a packet from `addr` belongs to this session, every reply carries its own
+    /// source address, so replies of concurrent targets are told apart by the client
+    fn accepts(&self, addr: &SocketAddr) -> bool {
+        match self.reply_filter {
+            ReplyFilter::Any => true,
+            ReplyFilter::Address => self
+                .destinations
+                .iter()
+                .any(|target| target.ip() == addr.ip()),
+            ReplyFilter::Endpoint => self.destinations.contains(addr),
         }
     }
 
@@ -49,12 +168,61 @@ impl UdpBackend {
         loop {
             match UdpAssociate::parse(buffer, opts) {
                 UdpParseResult::Packet(packet) => {
+                    #[cfg(feature = "netem")]
+                    {
+                        if opts.netem.as_mut().map_or(false, |netem| netem.lose()) {
+                            log::debug!("connection:{} simulate udp packet loss", self.index);
+                            buffer = &packet.payload[packet.length..];
+                            continue;
+                        }
+                    }
+                    if let Some(reason) = opts.link_local_error(&packet.address) {
+                        log::warn!(
+                            "connection:{} drop udp packet to {}, {}",
+                            self.index,
+                            packet.address,
+                            reason
+                        );
+                        self.dropped += 1;
+                        buffer = &packet.payload[packet.length..];
+                        continue;
+                    }
+                    if opts.block_self_connect && opts.is_self_connect(&packet.address) {
+                        log::warn!(
+                            "connection:{} from {} sends udp packet to self address {}, dropped",
+                            self.index,
+                            self.peer_addr,
+                            packet.address
+                        );
+                        self.dropped += 1;
+                        buffer = &packet.payload[packet.length..];
+                        continue;
+                    }
+                    if !self.take_token() {
+                        if self.rate_dropped == 0 {
+                            log::warn!(
+                                "connection:{} from {} exceeds udp packet rate, drop packets",
+                                self.index,
+                                self.peer_addr
+                            );
+                        }
+                        self.rate_dropped += 1;
+                        self.dropped += 1;
+                        opts.stats.add_udp_rate_drop();
+                        buffer = &packet.payload[packet.length..];
+                        continue;
+                    }
+                    if self.reply_filter != ReplyFilter::Any {
+                        self.destinations.insert(packet.address);
+                    }
+                    let target = self.target_addr(&packet.address);
                     match self
                         .socket
-                        .send_to(&packet.payload[..packet.length], &packet.address)
+                        .send_to(&packet.payload[..packet.length], &target)
                     {
                         Ok(size) => {
-                            self.bytes_sent += size;
+                            self.bytes_sent += size as u64;
+                            opts.stats.add_sent(size as u64);
                             if size != packet.length {
                                 log::error!(
                                     "connection:{} udp packet is truncated, {}：{}",
@@ -90,6 +258,17 @@ impl UdpBackend {
                         }
                     }
                 }
+                UdpParseResult::Oversized(packet) => {
+                    log::warn!(
+                        "connection:{} drop {} bytes udp packet to {}, larger than {}",
+                        self.index,
+                        packet.length,
+                        packet.address,
+                        opts.udp_max_datagram
+                    );
+                    self.dropped += 1;
+                    buffer = &packet.payload[packet.length..];
+                }
                 UdpParseResult::InvalidProtocol => {
                     log::error!("connection:{} got invalid udp protocol", self.index);
                     self.status = ConnStatus::Closing;
@@ -110,14 +289,36 @@ impl UdpBackend {
         }
     }
 
-    fn do_read(&mut self, conn: &mut TlsConn<ServerSession>) {
+    fn do_read(&mut self, conn: &mut TlsConn<ServerSession>, opts: &mut Opts) {
+        if !self.flush_queue(conn) {
+            conn.do_send();
+            return;
+        }
         loop {
             match self.socket.recv_from(self.recv_body.as_mut_slice()) {
                 Ok((size, addr)) => {
+                    let addr = unmap(addr);
+                    if !self.accepts(&addr) {
+                        log::debug!(
+                            "connection:{} drop udp packet from {}, not a target",
+                            self.index,
+                            addr
+                        );
+                        self.dropped += 1;
+                        continue;
+                    }
                     self.remote_addr = addr;
-                    self.bytes_read += size;
-                    if size == MAX_PACKET_SIZE {
-                        log::error!("received {} bytes udp data, packet fragmented", size);
+                    self.bytes_read += size as u64;
+                    opts.stats.add_read(size as u64);
+                    if size > opts.udp_max_datagram {
+                        log::warn!(
+                            "connection:{} drop udp packet from {}, larger than {}",
+                            self.index,
+                            addr,
+                            opts.udp_max_datagram
+                        );
+                        self.dropped += 1;
+                        continue;
                     }
                     log::debug!(
                         "connection:{} got {} bytes udp data from:{}",
@@ -125,8 +326,19 @@ impl UdpBackend {
                         size,
                         addr
                     );
+                    #[cfg(feature = "netem")]
+                    {
+                        if opts.netem.as_mut().map_or(false, |netem| netem.lose()) {
+                            log::debug!("connection:{} simulate udp packet loss", self.index);
+                            continue;
+                        }
+                    }
                     self.recv_head.clear();
                     UdpAssociate::generate(&mut self.recv_head, &addr, size as u16);
+                    if !self.queue.is_empty() || !conn.writable() {
+                        self.enqueue(size);
+                        continue;
+                    }
                     if !conn.write_session(self.recv_head.as_ref()) {
                         self.status = ConnStatus::Closing;
                         break;
@@ -150,6 +362,42 @@ impl UdpBackend {
         conn.do_send();
     }
 
+    /// queue a packet read from target while the tls side is busy, apply the drop policy if full
+    fn enqueue(&mut self, size: usize) {
+        if self.queue_size != 0 && self.queue.len() >= self.queue_size {
+            self.dropped += 1;
+            match self.drop_policy {
+                DropPolicy::Newest => {
+                    log::debug!("connection:{} udp queue is full, drop newest", self.index);
+                    return;
+                }
+                DropPolicy::Oldest => {
+                    log::debug!("connection:{} udp queue is full, drop oldest", self.index);
+                    let _ = self.queue.pop_front();
+                }
+            }
+        }
+        let mut packet = Vec::with_capacity(self.recv_head.len() + size);
+        packet.extend_from_slice(self.recv_head.as_ref());
+        packet.extend_from_slice(&self.recv_body.as_slice()[..size]);
+        self.queue.push_back(packet);
+    }
+
+    /// move queued packets into tls session as long as it is writable
+    fn flush_queue(&mut self, conn: &mut TlsConn<ServerSession>) -> bool {
+        while conn.writable() {
+            if let Some(packet) = self.queue.pop_front() {
+                if !conn.write_session(packet.as_slice()) {
+                    self.status = ConnStatus::Closing;
+                    return false;
+                }
+            } else {
+                break;
+            }
+        }
+        true
+    }
+
     fn setup(&mut self, poll: &Poll) {
         if let Err(err) = poll.reregister(&self.socket, self.token, self.readiness, PollOpt::edge())
         {
@@ -158,6 +406,7 @@ impl UdpBackend {
                 self.index,
                 err
             );
+            self.register_failed = true;
             self.status = ConnStatus::Closing;
         }
     }
@@ -166,7 +415,7 @@ impl UdpBackend {
 impl Backend for UdpBackend {
     fn ready(&mut self, event: &Event, opts: &mut Opts, conn: &mut TlsConn<ServerSession>) {
         if event.readiness().is_readable() {
-            self.do_read(conn);
+            self.do_read(conn, opts);
         }
         if event.readiness().is_writable() {
             self.dispatch(&[], opts);
@@ -184,11 +433,11 @@ impl Backend for UdpBackend {
     }
 
     fn reregister(&mut self, poll: &Poll, readable: bool) {
+        // packets are queued with limit, so keep reading to let the drop policy work
+        let readable = readable || self.queue_size != 0;
         match self.status {
-            ConnStatus::Closing => {
-                let _ = poll.deregister(&self.socket);
-            }
-            ConnStatus::Closed => {}
+            // deregistered by check_close, which always follows
+            ConnStatus::Closing | ConnStatus::Closed => {}
             _ => {
                 let mut changed = false;
                 if !self.send_buffer.is_empty() && !self.readiness.is_writable() {
@@ -224,15 +473,23 @@ impl Backend for UdpBackend {
             let _ = poll.deregister(&self.socket);
             self.status = ConnStatus::Closed;
             log::info!(
-                "connection:{} address:{} closed, read {} bytes, sent {} bytes",
+                "connection:{} address:{} closed, read {} bytes, sent {} bytes, dropped {} packets",
                 self.index,
                 self.remote_addr,
                 self.bytes_read,
-                self.bytes_sent
+                self.bytes_sent,
+                self.dropped
             );
         }
     }
 
+    fn resume(&mut self, conn: &mut TlsConn<ServerSession>, _opts: &mut Opts) {
+        if !self.queue.is_empty() {
+            self.flush_queue(conn);
+            conn.do_send();
+        }
+    }
+
     fn get_timeout(&self) -> Duration {
         self.timeout
     }
@@ -242,6 +499,9 @@ impl Backend for UdpBackend {
     }
 
     fn shutdown(&mut self, poll: &Poll) {
+        if self.closed() {
+            return;
+        }
         if self.send_buffer.is_empty() {
             self.status = ConnStatus::Closing;
             self.check_close(poll);
@@ -253,7 +513,166 @@ impl Backend for UdpBackend {
         self.check_close(poll);
     }
 
+    fn reset(&mut self, poll: &Poll) {
+        if self.closed() {
+            return;
+        }
+        self.send_buffer.clear();
+        self.status = ConnStatus::Closing;
+        self.check_close(poll);
+    }
+
     fn writable(&self) -> bool {
-        self.send_buffer.len() < MAX_BUFFER_SIZE
+        self.send_buffer.len() < self.high_water
+    }
+
+    fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    fn traffic(&self) -> (u64, u64) {
+        (self.bytes_read, self.bytes_sent)
+    }
+
+    fn queue_depth(&self) -> usize {
+        self.send_buffer.len()
+    }
+
+    fn register_failed(&self) -> bool {
+        self.register_failed
+    }
+}
+
+#[cfg(all(test, feature = "test-support"))]
+mod client_tests {
+    use std::collections::HashMap;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+    use std::thread::{sleep, spawn};
+    use std::time::Duration;
+
+    use crate::test_support::*;
+
+    /// replies from another port than the one packets are sent to
+    fn start_udp_reflector() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let other = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        spawn(move || {
+            let mut buffer = [0u8; 65536];
+            while let Ok((size, peer)) = socket.recv_from(&mut buffer) {
+                let _ = other.send_to(&buffer[..size], peer);
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn replies_of_concurrent_targets_routed() {
+        let server = start_server(&["--allow-self-connect", "--udp-reply-filter", "endpoint"]);
+        let targets: Vec<_> = (0..3).map(|_| start_udp_echo("127.0.0.1:0")).collect();
+        let mut client = TrojanClient::associate(server, PASSWORD).unwrap();
+        for target in &targets {
+            client
+                .send_to(target.to_string().as_bytes(), target)
+                .unwrap();
+        }
+        let mut replies = HashMap::new();
+        for _ in 0..targets.len() {
+            let (from, payload) = client.recv_from().unwrap();
+            replies.insert(from, String::from_utf8(payload).unwrap());
+        }
+        for target in &targets {
+            assert_eq!(replies[target], target.to_string());
+        }
+    }
+
+    #[test]
+    fn reply_from_other_port_filtered() {
+        let server = start_server(&["--allow-self-connect", "--udp-reply-filter", "endpoint"]);
+        let reflector = start_udp_reflector();
+        let echo = start_udp_echo("127.0.0.1:0");
+        let mut client = TrojanClient::associate(server, PASSWORD).unwrap();
+        client.send_to(b"reflected", &reflector).unwrap();
+        client.send_to(b"echoed", &echo).unwrap();
+        let (from, payload) = client.recv_from().unwrap();
+        assert_eq!(from, echo);
+        assert_eq!(payload.as_slice(), b"echoed");
+    }
+
+    #[test]
+    fn reply_from_other_port_passes_address_filter() {
+        let server = start_server(&["--allow-self-connect", "--udp-reply-filter", "address"]);
+        let reflector = start_udp_reflector();
+        let mut client = TrojanClient::associate(server, PASSWORD).unwrap();
+        client.send_to(b"reflected", &reflector).unwrap();
+        let (from, payload) = client.recv_from().unwrap();
+        assert_ne!(from, reflector);
+        assert_eq!(payload.as_slice(), b"reflected");
+    }
+
+    #[test]
+    fn ipv6_round_trip() {
+        let server = start_server(&["--allow-self-connect"]);
+        let echo = start_udp_echo("[::1]:0");
+        let mut client = TrojanClient::associate(server, PASSWORD).unwrap();
+        client.send_to(b"hello, ipv6", &echo).unwrap();
+        let (from, payload) = client.recv_from().unwrap();
+        assert_eq!(from, echo);
+        assert_eq!(payload.as_slice(), b"hello, ipv6");
+    }
+
+    #[test]
+    fn packets_over_rate_dropped() {
+        let server = start_server(&[
+            "--allow-self-connect",
+            "--udp-packet-rate",
+            "10",
+            "--udp-packet-burst",
+            "3",
+        ]);
+        let echo = start_udp_echo("127.0.0.1:0");
+        let mut client = TrojanClient::associate(server, PASSWORD).unwrap();
+        for i in 0..20u8 {
+            client.send_to(&[i], &echo).unwrap();
+        }
+        // refilled by then, so this one always passes
+        sleep(Duration::from_millis(500));
+        client.send_to(b"last", &echo).unwrap();
+        let mut passed = 0;
+        loop {
+            let (_, payload) = client.recv_from().unwrap();
+            if payload.as_slice() == b"last" {
+                break;
+            }
+            passed += 1;
+        }
+        assert_eq!(passed, 3);
+    }
+
+    #[test]
+    fn packets_to_self_dropped() {
+        let admin_addr = free_addr().to_string();
+        let server = start_server(&["--admin-addr", admin_addr.as_str()]);
+        let echo = start_udp_echo("127.0.0.1:0");
+        let mapped = SocketAddr::new(
+            IpAddr::V6(Ipv4Addr::LOCALHOST.to_ipv6_mapped()),
+            echo.port(),
+        );
+        let mut client = TrojanClient::associate(server, PASSWORD).unwrap();
+        client.send_to(b"loopback", &echo).unwrap();
+        client.send_to(b"mapped", &mapped).unwrap();
+        sleep(Duration::from_millis(100));
+        assert!(admin(&admin_addr, "list").contains(" sent:0 "));
+    }
+
+    #[test]
+    fn oversized_dropped() {
+        let server = start_server(&["--allow-self-connect"]);
+        let echo = start_udp_echo("127.0.0.1:0");
+        let mut client = TrojanClient::associate(server, PASSWORD).unwrap();
+        client.send_to(&[0u8; 2000], &echo).unwrap();
+        client.send_to(b"small", &echo).unwrap();
+        let (_, payload) = client.recv_from().unwrap();
+        assert_eq!(payload.as_slice(), b"small");
     }
 }
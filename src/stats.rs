@@ -0,0 +1,187 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// upper bounds in milliseconds of the tls handshake duration buckets
+pub const HANDSHAKE_BUCKETS: [u64; 10] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000];
+
+/// Server wide counters, updated from the event loop and read by the metric exporters.
+#[derive(Default)]
+pub struct Stats {
+    /// connections accepted by the listener
+    pub accepted: AtomicU64,
+    /// bytes sent to targets
+    pub bytes_sent: AtomicU64,
+    /// bytes read from targets
+    pub bytes_read: AtomicU64,
+    /// failures on accepting, resolving or connecting
+    pub errors: AtomicU64,
+    /// connections closed for not finishing tls handshake in time
+    pub handshake_timeouts: AtomicU64,
+    /// connections closed by a timeout or deadline, handshake timeouts included
+    pub timeouts: AtomicU64,
+    /// accept or setup of new connections failed, not counting ones closed by the client
+    pub accept_errors: AtomicU64,
+    /// tcp resets from clients with a target connected
+    pub client_resets: AtomicU64,
+    /// accepted connections closed by the client before setup
+    pub dead_accepts: AtomicU64,
+    /// target domains handed to the resolver, ip literals never are
+    pub dns_lookups: AtomicU64,
+    /// udp packets to targets dropped for exceeding the session packet rate
+    pub udp_rate_drops: AtomicU64,
+    /// wakeups of the event loop
+    pub polls: AtomicU64,
+    /// sockets failed to register or reregister with poll, a sign of fd or kernel memory shortage
+    pub register_failures: AtomicU64,
+    /// tls handshakes finished, by the first bucket of `HANDSHAKE_BUCKETS` they fit in, the
+    /// last slot for slower ones
+    pub handshake_buckets: [AtomicU64; HANDSHAKE_BUCKETS.len() + 1],
+    /// total tls handshake duration in microseconds
+    pub handshake_micros: AtomicU64,
+    /// bytes sent to targets since the last traffic reset
+    period_sent: AtomicU64,
+    /// bytes read from targets since the last traffic reset
+    period_read: AtomicU64,
+}
+
+/// Bytes relayed in an accounting period
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct Traffic {
+    pub bytes_read: u64,
+    pub bytes_sent: u64,
+}
+
+/// A point-in-time copy of `Stats`
+#[derive(Default, Clone, Copy)]
+pub struct StatsSnapshot {
+    pub accepted: u64,
+    pub bytes_sent: u64,
+    pub bytes_read: u64,
+    pub errors: u64,
+    pub handshake_timeouts: u64,
+    pub timeouts: u64,
+    pub accept_errors: u64,
+    pub client_resets: u64,
+    pub dead_accepts: u64,
+    pub dns_lookups: u64,
+    pub udp_rate_drops: u64,
+    pub polls: u64,
+    pub register_failures: u64,
+    pub handshake_buckets: [u64; HANDSHAKE_BUCKETS.len() + 1],
+    pub handshake_micros: u64,
+}
+
+impl Stats {
+    pub fn add_accepted(&self) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_sent(&self, size: u64) {
+        self.bytes_sent.fetch_add(size, Ordering::Relaxed);
+        self.period_sent.fetch_add(size, Ordering::Relaxed);
+    }
+
+    pub fn add_read(&self, size: u64) {
+        self.bytes_read.fetch_add(size, Ordering::Relaxed);
+        self.period_read.fetch_add(size, Ordering::Relaxed);
+    }
+
+    pub fn add_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_handshake_timeout(&self) {
+        self.handshake_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_timeout(&self) {
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_accept_error(&self) {
+        self.accept_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_client_reset(&self) {
+        self.client_resets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_dead_accept(&self) {
+        self.dead_accepts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_dns_lookup(&self) {
+        self.dns_lookups.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_udp_rate_drop(&self) {
+        self.udp_rate_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_poll(&self) {
+        self.polls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_register_failure(&self) {
+        self.register_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_handshake(&self, duration: Duration) {
+        let millis = duration.as_millis() as u64;
+        let bucket = HANDSHAKE_BUCKETS
+            .iter()
+            .position(|bound| millis <= *bound)
+            .unwrap_or(HANDSHAKE_BUCKETS.len());
+        self.handshake_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.handshake_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn traffic(&self) -> Traffic {
+        Traffic {
+            bytes_read: self.period_read.load(Ordering::Relaxed),
+            bytes_sent: self.period_sent.load(Ordering::Relaxed),
+        }
+    }
+
+    /// zero the period counters, returning what they held. each counter is swapped, so a byte
+    /// is counted either in the returned period or in the next one, never in both or neither.
+    pub fn take_traffic(&self) -> Traffic {
+        Traffic {
+            bytes_read: self.period_read.swap(0, Ordering::Relaxed),
+            bytes_sent: self.period_sent.swap(0, Ordering::Relaxed),
+        }
+    }
+
+    /// add counters of a period persisted before restart
+    pub fn restore_traffic(&self, traffic: Traffic) {
+        self.period_read
+            .fetch_add(traffic.bytes_read, Ordering::Relaxed);
+        self.period_sent
+            .fetch_add(traffic.bytes_sent, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let mut handshake_buckets = [0; HANDSHAKE_BUCKETS.len() + 1];
+        for (count, bucket) in handshake_buckets.iter_mut().zip(&self.handshake_buckets) {
+            *count = bucket.load(Ordering::Relaxed);
+        }
+        StatsSnapshot {
+            accepted: self.accepted.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            handshake_timeouts: self.handshake_timeouts.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            accept_errors: self.accept_errors.load(Ordering::Relaxed),
+            client_resets: self.client_resets.load(Ordering::Relaxed),
+            dead_accepts: self.dead_accepts.load(Ordering::Relaxed),
+            dns_lookups: self.dns_lookups.load(Ordering::Relaxed),
+            udp_rate_drops: self.udp_rate_drops.load(Ordering::Relaxed),
+            polls: self.polls.load(Ordering::Relaxed),
+            register_failures: self.register_failures.load(Ordering::Relaxed),
+            handshake_buckets,
+            handshake_micros: self.handshake_micros.load(Ordering::Relaxed),
+        }
+    }
+}
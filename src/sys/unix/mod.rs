@@ -1,8 +1,13 @@
 use mio::net::TcpStream;
 use std::convert::TryFrom;
 use std::io::{Error, ErrorKind, Result};
-use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
-use std::os::unix::io::AsRawFd;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use mio::unix::EventedFd;
+use mio::{Evented, Poll, PollOpt, Ready, Token};
 
 pub fn set_mark<T: AsRawFd>(socket: &T, mark: u8) -> Result<()> {
     let fd = socket.as_raw_fd();
@@ -23,6 +28,128 @@ pub fn set_mark<T: AsRawFd>(socket: &T, mark: u8) -> Result<()> {
     }
 }
 
+/// allow sockets bound to the same address and port to be used at the same time
+pub fn set_reuse_port<T: AsRawFd>(socket: &T) -> Result<()> {
+    let enable: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_REUSEPORT,
+            &enable as *const _ as *const _,
+            std::mem::size_of_val(&enable) as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// set SO_RCVBUF and SO_SNDBUF, a size of 0 keeps the system default
+pub fn set_buffer_size<T: AsRawFd>(socket: &T, recv: usize, send: usize) -> Result<()> {
+    let fd = socket.as_raw_fd();
+    for (opt, size) in &[(libc::SO_RCVBUF, recv), (libc::SO_SNDBUF, send)] {
+        if *size == 0 {
+            continue;
+        }
+        let size = *size as libc::c_int;
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                *opt,
+                &size as *const _ as *const _,
+                std::mem::size_of_val(&size) as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// enable SO_KEEPALIVE, probes start after `idle` without traffic and repeat every `interval`.
+/// the times are ignored where they can not be set, see `KEEPALIVE_TIMES`
+#[cfg_attr(
+    not(any(target_os = "linux", target_os = "android")),
+    allow(unused_variables, unused_mut)
+)]
+pub fn set_keepalive<T: AsRawFd>(socket: &T, idle: u32, interval: u32) -> Result<()> {
+    let mut opts = vec![(libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)];
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    opts.extend_from_slice(&[
+        (libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, idle as libc::c_int),
+        (
+            libc::IPPROTO_TCP,
+            libc::TCP_KEEPINTVL,
+            interval as libc::c_int,
+        ),
+    ]);
+    for (level, opt, value) in opts {
+        let value: libc::c_int = value;
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                opt,
+                &value as *const _ as *const _,
+                std::mem::size_of_val(&value) as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// whether keepalive idle time and probe interval can be set
+pub const KEEPALIVE_TIMES: bool = cfg!(any(target_os = "linux", target_os = "android"));
+
+/// signal asking the server to reload its certificate
+pub const RELOAD_SIGNAL: libc::c_int = libc::SIGHUP;
+
+/// set IP_TTL, and IPV6_UNICAST_HOPS too on an ipv6 socket, which may also carry ipv4-mapped
+/// traffic
+pub fn set_ttl<T: AsRawFd>(socket: &T, ipv6: bool, ttl: u8) -> Result<()> {
+    let fd = socket.as_raw_fd();
+    let ttl = ttl as libc::c_int;
+    let mut opts = vec![(libc::IPPROTO_IP, libc::IP_TTL)];
+    if ipv6 {
+        opts.push((libc::IPPROTO_IPV6, libc::IPV6_UNICAST_HOPS));
+    }
+    for (level, opt) in opts {
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                opt,
+                &ttl as *const _ as *const _,
+                std::mem::size_of_val(&ttl) as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// max SO_RCVBUF and SO_SNDBUF allowed for unprivileged sockets, None if unknown
+pub fn max_buffer_size() -> Option<(usize, usize)> {
+    let read = |name: &str| {
+        std::fs::read_to_string(format!("/proc/sys/net/core/{}", name))
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    };
+    Some((read("rmem_max")?, read("wmem_max")?))
+}
+
 pub fn set_socket_opts<T: AsRawFd>(v4: bool, is_udp: bool, socket: &T) -> Result<()> {
     let fd = socket.as_raw_fd();
 
@@ -191,3 +318,502 @@ fn sockaddr_to_std(saddr: &libc::sockaddr_storage) -> Result<SocketAddr> {
         }
     }
 }
+
+/// datagram socket connected to the service manager listening on `socket`, a leading '@'
+/// means an abstract namespace socket. connected once on startup, so states still reach the
+/// manager after a chroot hides the socket path
+pub fn notify_socket(socket: &str) -> Result<NotifySocket> {
+    use std::os::unix::io::FromRawFd;
+
+    let path = socket.as_bytes();
+    unsafe {
+        let mut addr: libc::sockaddr_un = std::mem::zeroed();
+        if path.is_empty() || path.len() >= addr.sun_path.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "invalid notify socket"));
+        }
+        addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        for (i, c) in path.iter().enumerate() {
+            addr.sun_path[i] = *c as libc::c_char;
+        }
+        if path[0] == b'@' {
+            addr.sun_path[0] = 0;
+        }
+        let len = std::mem::size_of::<libc::sa_family_t>() + path.len();
+
+        let fd = libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        let socket = NotifySocket::from_raw_fd(fd);
+        let ret = libc::connect(fd, &addr as *const _ as *const _, len as libc::socklen_t);
+        if ret < 0 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(socket)
+        }
+    }
+}
+
+/// send a state like `READY=1` to the service manager `socket` is connected to
+pub fn notify(socket: &NotifySocket, state: &str) -> Result<()> {
+    socket.send(state.as_bytes()).map(|_| ())
+}
+
+/// index of a network interface by name, None if there is no such interface
+pub fn if_index(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if index == 0 {
+        None
+    } else {
+        Some(index)
+    }
+}
+
+/// addresses of the local network interfaces
+pub fn interface_addrs() -> Result<Vec<IpAddr>> {
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    let mut addrs = Vec::new();
+    let mut next = ifaddrs;
+    while !next.is_null() {
+        let ifaddr = unsafe { &*next };
+        next = ifaddr.ifa_next;
+        if ifaddr.ifa_addr.is_null() {
+            continue;
+        }
+        match unsafe { (*ifaddr.ifa_addr).sa_family } as libc::c_int {
+            libc::AF_INET => {
+                let addr = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in) };
+                addrs.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                    addr.sin_addr.s_addr,
+                ))));
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in6) };
+                addrs.push(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)));
+            }
+            _ => {}
+        }
+    }
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    Ok(addrs)
+}
+
+/// the process or system ran out of file descriptors or socket memory, accept fails until
+/// some are released
+pub fn is_fd_exhausted(err: &Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::ENOBUFS) | Some(libc::ENOMEM)
+    )
+}
+
+/// socket states are sent to the service manager over
+pub type NotifySocket = UnixDatagram;
+
+/// a descriptor held in reserve, released to accept and close a client when none are left.
+/// opened before a chroot, which hides /dev/null, more are made with `File::try_clone`
+pub fn reserve_fd() -> Option<std::fs::File> {
+    std::fs::File::open("/dev/null").ok()
+}
+
+/// bind a unix stream socket at `path` with file permissions `mode`, and change its owner to
+/// `owner` like "user", "user:group" or ":group", names or numeric ids. a stale socket file left
+/// at `path` is removed first
+pub fn bind_unix(path: &str, mode: u32, owner: Option<&str>) -> Result<UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path),
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+    // nobody else may connect before the permissions are set
+    let mask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(mask) };
+    let listener = listener?;
+    if let Some(owner) = owner {
+        let (uid, gid) = lookup_owner(owner)?;
+        let name = std::ffi::CString::new(path)?;
+        if unsafe { libc::chown(name.as_ptr(), uid, gid) } < 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// uid and gid of "user[:group]", an omitted part is -1 which chown leaves unchanged
+fn lookup_owner(owner: &str) -> Result<(libc::uid_t, libc::gid_t)> {
+    let mut parts = owner.splitn(2, ':');
+    let user = parts.next().unwrap_or("");
+    let group = parts.next().unwrap_or("");
+    let uid = if user.is_empty() {
+        libc::uid_t::MAX
+    } else if let Ok(uid) = user.parse() {
+        uid
+    } else {
+        let name = std::ffi::CString::new(user)?;
+        let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+        if passwd.is_null() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("unknown user {}", user),
+            ));
+        }
+        unsafe { (*passwd).pw_uid }
+    };
+    let gid = if group.is_empty() {
+        libc::gid_t::MAX
+    } else if let Ok(gid) = group.parse() {
+        gid
+    } else {
+        let name = std::ffi::CString::new(group)?;
+        let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+        if entry.is_null() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("unknown group {}", group),
+            ));
+        }
+        unsafe { (*entry).gr_gid }
+    };
+    Ok((uid, gid))
+}
+
+/// uid and gid of "user[:group]", names or numeric ids, the group defaults to the primary group
+/// of the user. names are looked up before a chroot hides /etc/passwd
+pub fn lookup_user(owner: &str) -> Result<(u32, u32)> {
+    let (uid, gid) = lookup_owner(owner)?;
+    if uid == libc::uid_t::MAX {
+        return Err(Error::new(ErrorKind::InvalidInput, "no user to run as"));
+    }
+    let gid = if gid == libc::gid_t::MAX {
+        let passwd = unsafe { libc::getpwuid(uid) };
+        if passwd.is_null() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("no primary group of user {}", uid),
+            ));
+        }
+        unsafe { (*passwd).pw_gid }
+    } else {
+        gid
+    };
+    Ok((uid, gid))
+}
+
+/// switch the process to `uid` and `gid` from `lookup_user`. supplementary groups are dropped,
+/// so root privileges can not be taken back
+pub fn drop_privileges(uid: u32, gid: u32) -> Result<()> {
+    unsafe {
+        if libc::setgroups(1, &gid) < 0 || libc::setgid(gid) < 0 || libc::setuid(uid) < 0 {
+            return Err(Error::last_os_error());
+        }
+        if uid != 0 && libc::setuid(0) == 0 {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "root privileges still held after dropping",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// open directory `path`, files in it are found through the handle after a chroot too
+pub fn open_dir(path: &std::path::Path) -> Result<std::fs::File> {
+    std::fs::File::open(path)
+}
+
+/// whether the process may create and remove files in `dir` from `open_dir`
+pub fn dir_writable(dir: &std::fs::File) -> bool {
+    unsafe { libc::faccessat(dir.as_raw_fd(), b".\0".as_ptr() as *const _, libc::W_OK, 0) == 0 }
+}
+
+/// remove file `path` in `dir` from `open_dir`, only its file name is looked up
+pub fn remove_in(dir: &std::fs::File, path: &std::path::Path) -> Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    let name = path
+        .file_name()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "no file name"))?;
+    let name = std::ffi::CString::new(name.as_bytes())?;
+    if unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), 0) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// confine the process to `dir`, which becomes its root and working directory
+pub fn chroot(dir: &str) -> Result<()> {
+    let path = std::ffi::CString::new(dir)?;
+    if unsafe { libc::chroot(path.as_ptr()) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    std::env::set_current_dir("/")
+}
+
+/// move the calling thread into the network namespace at `path`, like /var/run/netns/name.
+/// sockets already open stay in the old one, threads started after follow the new one
+pub fn enter_netns(path: &str) -> Result<()> {
+    let file = std::fs::File::open(path)?;
+    if unsafe { libc::setns(file.as_raw_fd(), libc::CLONE_NEWNET) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// write end of the pipe signals are reported to, -1 if there is none
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    let fd = SIGNAL_PIPE.load(Ordering::Relaxed);
+    if fd >= 0 {
+        // only async signal safe calls here, a full pipe already has a wakeup pending
+        let byte = signal as u8;
+        unsafe { libc::write(fd, &byte as *const u8 as *const _, 1) };
+    }
+}
+
+/// Signals delivered to the process, readable in a mio poll through a self pipe.
+/// there is one per process, as the handlers are
+pub struct Signals {
+    read: RawFd,
+    write: RawFd,
+}
+
+impl Signals {
+    pub fn new(signals: &[libc::c_int]) -> Result<Signals> {
+        let mut fds = [0 as libc::c_int; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(Error::last_os_error());
+        }
+        let pipes = Signals {
+            read: fds[0],
+            write: fds[1],
+        };
+        if SIGNAL_PIPE
+            .compare_exchange(-1, pipes.write, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Err(Error::from_raw_os_error(libc::EBUSY));
+        }
+        for signal in signals {
+            let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+            action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            if unsafe { libc::sigaction(*signal, &action, std::ptr::null_mut()) } < 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+        Ok(pipes)
+    }
+
+    /// signals received since last call, in order of arrival
+    pub fn pending(&self) -> Vec<libc::c_int> {
+        let mut received = Vec::new();
+        let mut buffer = [0u8; 64];
+        loop {
+            let n = unsafe { libc::read(self.read, buffer.as_mut_ptr() as *mut _, buffer.len()) };
+            if n <= 0 {
+                break;
+            }
+            received.extend(buffer[..n as usize].iter().map(|byte| *byte as libc::c_int));
+        }
+        received
+    }
+}
+
+impl Evented for Signals {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<()> {
+        EventedFd(&self.read).register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<()> {
+        EventedFd(&self.read).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<()> {
+        EventedFd(&self.read).deregister(poll)
+    }
+}
+
+impl Drop for Signals {
+    fn drop(&mut self) {
+        let _ = SIGNAL_PIPE.compare_exchange(self.write, -1, Ordering::SeqCst, Ordering::SeqCst);
+        unsafe {
+            libc::close(self.read);
+            libc::close(self.write);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    use super::*;
+
+    fn get_buffer_size(socket: &UdpSocket, opt: libc::c_int) -> usize {
+        let mut size: libc::c_int = 0;
+        let mut len = std::mem::size_of_val(&size) as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                opt,
+                &mut size as *mut _ as *mut _,
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0);
+        size as usize
+    }
+
+    fn get_opt<T: AsRawFd>(socket: &T, level: libc::c_int, opt: libc::c_int) -> libc::c_int {
+        let mut ttl: libc::c_int = 0;
+        let mut len = std::mem::size_of_val(&ttl) as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                level,
+                opt,
+                &mut ttl as *mut _ as *mut _,
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0);
+        ttl
+    }
+
+    #[test]
+    fn keepalive() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        set_keepalive(&stream, 30, 5).unwrap();
+        let get = |level, opt| get_opt(&stream, level, opt);
+        assert_eq!(get(libc::SOL_SOCKET, libc::SO_KEEPALIVE), 1);
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(get(libc::IPPROTO_TCP, libc::TCP_KEEPIDLE), 30);
+            assert_eq!(get(libc::IPPROTO_TCP, libc::TCP_KEEPINTVL), 5);
+        }
+    }
+
+    #[test]
+    fn ttl() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        set_ttl(&socket, false, 7).unwrap();
+        assert_eq!(get_opt(&socket, libc::IPPROTO_IP, libc::IP_TTL), 7);
+        if let Ok(socket) = UdpSocket::bind("[::1]:0") {
+            set_ttl(&socket, true, 9).unwrap();
+            assert_eq!(get_opt(&socket, libc::IPPROTO_IP, libc::IP_TTL), 9);
+            assert_eq!(
+                get_opt(&socket, libc::IPPROTO_IPV6, libc::IPV6_UNICAST_HOPS),
+                9
+            );
+        }
+    }
+
+    #[test]
+    fn buffer_size() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let send = get_buffer_size(&socket, libc::SO_SNDBUF);
+        set_buffer_size(&socket, 65536, 0).unwrap();
+        // linux doubles the value for bookkeeping overhead
+        assert!(get_buffer_size(&socket, libc::SO_RCVBUF) >= 65536);
+        assert_eq!(get_buffer_size(&socket, libc::SO_SNDBUF), send);
+        if let Some((recv, send)) = max_buffer_size() {
+            assert!(recv > 0 && send > 0);
+        }
+    }
+
+    #[test]
+    fn unix_socket_mode_and_owner() {
+        let path = std::env::temp_dir().join(format!("trojan-test-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap();
+        let uid = unsafe { libc::getuid() };
+        let gid = unsafe { libc::getgid() };
+        let owner = format!("{}:{}", uid, gid);
+        let _listener = bind_unix(path, 0o640, Some(owner.as_str())).unwrap();
+        // the stale socket is replaced
+        let _listener = bind_unix(path, 0o600, None).unwrap();
+        let metadata = std::fs::metadata(path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o600);
+        assert_eq!(metadata.uid(), uid);
+        assert!(std::os::unix::net::UnixStream::connect(path).is_ok());
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(lookup_owner(":0").unwrap(), (libc::uid_t::MAX, 0));
+        assert!(lookup_owner("no-such-user-here").is_err());
+    }
+
+    #[test]
+    fn privileges_dropped() {
+        let err = lookup_user(":0").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(lookup_user("no-such-user-here").is_err());
+        assert_eq!(lookup_user("65534:65534").unwrap(), (65534, 65534));
+        if unsafe { libc::getuid() } != 0 {
+            return;
+        }
+        // the test process keeps root, privileges are dropped in a child
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            let dropped = drop_privileges(65534, 65534).is_ok()
+                && unsafe { libc::getuid() == 65534 && libc::getgid() == 65534 };
+            unsafe { libc::_exit(if dropped { 0 } else { 1 }) };
+        }
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+    }
+
+    #[test]
+    fn confined() {
+        assert!(chroot("/no/such/dir").is_err());
+        assert!(enter_netns("/no/such/netns").is_err());
+        if unsafe { libc::getuid() } != 0 {
+            return;
+        }
+        let dir = std::env::temp_dir().join(format!("trojan-test-{}-root", std::process::id()));
+        std::fs::create_dir_all(dir.join("marker")).unwrap();
+        // the test process keeps its root, it is changed in a child
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            let confined = enter_netns("/proc/self/ns/net").is_ok()
+                && chroot(dir.to_str().unwrap()).is_ok()
+                && std::path::Path::new("/marker").is_dir();
+            unsafe { libc::_exit(if confined { 0 } else { 1 }) };
+        }
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        std::fs::remove_dir_all(dir).unwrap();
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+    }
+
+    #[test]
+    fn signal_reported_to_poll() {
+        let signals = Signals::new(&[libc::SIGUSR2]).unwrap();
+        let poll = Poll::new().unwrap();
+        poll.register(&signals, Token(0), Ready::readable(), PollOpt::edge())
+            .unwrap();
+        assert!(signals.pending().is_empty());
+        unsafe { libc::raise(libc::SIGUSR2) };
+        let mut events = mio::Events::with_capacity(4);
+        poll.poll(&mut events, Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(events.iter().next().unwrap().token(), Token(0));
+        assert_eq!(signals.pending(), vec![libc::SIGUSR2]);
+    }
+}
@@ -6,6 +6,31 @@ pub fn set_mark<T: Any>(_socket: &T, _mark: u8) -> Result<()> {
     Ok(())
 }
 
+pub fn set_reuse_port<T: Any>(_socket: &T) -> Result<()> {
+    Ok(())
+}
+
+pub fn set_buffer_size<T: Any>(_socket: &T, _recv: usize, _send: usize) -> Result<()> {
+    Ok(())
+}
+
+pub fn set_ttl<T: Any>(_socket: &T, _ipv6: bool, _ttl: u8) -> Result<()> {
+    Ok(())
+}
+
+pub fn set_keepalive<T: Any>(_socket: &T, _idle: u32, _interval: u32) -> Result<()> {
+    Ok(())
+}
+
+pub const KEEPALIVE_TIMES: bool = false;
+
+/// never delivered, there is no SIGHUP
+pub const RELOAD_SIGNAL: i32 = 1;
+
+pub fn max_buffer_size() -> Option<(usize, usize)> {
+    None
+}
+
 pub fn set_socket_opts<T: Any>(_v4: bool, _is_udp: bool, _socket: &T) -> Result<()> {
     unimplemented!("proxy mode not supported in windows");
 }
@@ -20,3 +45,118 @@ pub fn recv_from_with_destination<T: Any>(
 ) -> Result<(usize, SocketAddr, SocketAddr)> {
     unimplemented!("proxy mode not supported in windows");
 }
+
+/// there is no service manager to notify
+pub struct NotifySocket;
+
+pub fn notify_socket(_socket: &str) -> Result<NotifySocket> {
+    Ok(NotifySocket)
+}
+
+pub fn notify(_socket: &NotifySocket, _state: &str) -> Result<()> {
+    Ok(())
+}
+
+pub fn if_index(_name: &str) -> Option<u32> {
+    None
+}
+
+/// not listed, a wildcard listen address is not matched against them
+pub fn interface_addrs() -> Result<Vec<std::net::IpAddr>> {
+    Ok(Vec::new())
+}
+
+pub fn reserve_fd() -> Option<std::fs::File> {
+    None
+}
+
+/// WSAEMFILE and WSAENOBUFS
+pub fn is_fd_exhausted(err: &std::io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(10024) | Some(10055))
+}
+
+pub fn chroot(_dir: &str) -> Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "chroot not supported in windows",
+    ))
+}
+
+pub fn enter_netns(_path: &str) -> Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "network namespace not supported in windows",
+    ))
+}
+
+pub fn lookup_user(_owner: &str) -> Result<(u32, u32)> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "users not supported in windows",
+    ))
+}
+
+pub fn drop_privileges(_uid: u32, _gid: u32) -> Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "dropping privileges not supported in windows",
+    ))
+}
+
+pub fn open_dir(path: &std::path::Path) -> Result<std::fs::File> {
+    use std::os::windows::fs::OpenOptionsExt;
+    // FILE_FLAG_BACKUP_SEMANTICS, needed to open a directory
+    std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(0x0200_0000)
+        .open(path)
+}
+
+pub fn dir_writable(_dir: &std::fs::File) -> bool {
+    true
+}
+
+pub fn remove_in(_dir: &std::fs::File, path: &std::path::Path) -> Result<()> {
+    std::fs::remove_file(path)
+}
+
+pub struct Signals;
+
+impl Signals {
+    pub fn new(_signals: &[i32]) -> Result<Signals> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "signals not supported in windows",
+        ))
+    }
+
+    pub fn pending(&self) -> Vec<i32> {
+        Vec::new()
+    }
+}
+
+impl mio::Evented for Signals {
+    fn register(
+        &self,
+        _poll: &mio::Poll,
+        _token: mio::Token,
+        _interest: mio::Ready,
+        _opts: mio::PollOpt,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn reregister(
+        &self,
+        _poll: &mio::Poll,
+        _token: mio::Token,
+        _interest: mio::Ready,
+        _opts: mio::PollOpt,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn deregister(&self, _poll: &mio::Poll) -> Result<()> {
+        Ok(())
+    }
+}
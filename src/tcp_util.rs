@@ -1,6 +1,6 @@
 use std::io::{Read, Write};
 
-use bytes::BytesMut;
+use bytes::{Buf, BytesMut};
 use mio::net::TcpStream;
 use rustls::Session;
 
@@ -11,11 +11,19 @@ pub fn tcp_read<T: Session>(
     mut conn: &TcpStream,
     recv_buf: &mut Vec<u8>,
     server_conn: &mut TlsConn<T>,
+    bytes_read: &mut u64,
 ) -> bool {
     loop {
+        // a fast backend never blocks, stop here and resume after session is drained,
+        // the caller removes readable interest and re-adds it once session becomes writable
+        if !server_conn.writable() {
+            log::debug!("connection:{} session is full, stop reading backend", index);
+            break;
+        }
         match conn.read(recv_buf.as_mut_slice()) {
             Ok(size) => {
                 log::debug!("connection:{} read {} bytes from backend", index, size);
+                *bytes_read += size as u64;
                 if size == 0 {
                     log::warn!("connection:{} meets end of file", index);
                     return false;
@@ -36,11 +44,44 @@ pub fn tcp_read<T: Session>(
     true
 }
 
+/// write from the front of `send_buffer`, bytes taken by the socket are advanced over in place,
+/// so partial writes neither split nor reallocate the buffer
+pub fn tcp_flush(
+    index: usize,
+    mut conn: &TcpStream,
+    send_buffer: &mut BytesMut,
+    bytes_sent: &mut u64,
+) -> bool {
+    while !send_buffer.is_empty() {
+        match conn.write(send_buffer.as_ref()) {
+            Ok(size) => {
+                send_buffer.advance(size);
+                *bytes_sent += size as u64;
+                log::debug!("connection:{} buffer write {} byte to backend", index, size);
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                log::debug!(
+                    "connection:{} buffer write blocked, remaining:{}",
+                    index,
+                    send_buffer.len()
+                );
+                break;
+            }
+            Err(err) => {
+                log::warn!("connection:{} send failed:{}", index, err);
+                return false;
+            }
+        }
+    }
+    true
+}
+
 pub fn tcp_send(
     index: usize,
     mut conn: &TcpStream,
     send_buffer: &mut BytesMut,
     mut data: &[u8],
+    bytes_sent: &mut u64,
 ) -> bool {
     loop {
         if data.is_empty() {
@@ -49,6 +90,7 @@ pub fn tcp_send(
         match conn.write(data) {
             Ok(size) => {
                 data = &data[size..];
+                *bytes_sent += size as u64;
                 log::debug!(
                     "connection:{} session write {} byte to backend",
                     index,
@@ -0,0 +1,253 @@
+//! Trojan client for the tests, it trusts the self-signed test certificate through the rustls
+//! `dangerous_configuration` feature turned on by `test-support`.
+use std::convert::TryFrom;
+use std::io::{ErrorKind, Read, Result, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::BytesMut;
+use crypto::digest::Digest;
+use crypto::sha2::Sha224;
+use rustls::{
+    Certificate, ClientConfig, ClientSession, RootCertStore, ServerCertVerified,
+    ServerCertVerifier, Session, StreamOwned, TLSError,
+};
+use socket2::Socket;
+use webpki::DNSNameRef;
+
+use crate::proto::{Sock5Address, UdpAssociate, CONNECT, DOMAIN, UDP_ASSOCIATE};
+
+struct NoVerifier;
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        _presented_certs: &[Certificate],
+        _dns_name: DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> std::result::Result<ServerCertVerified, TLSError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// tls config trusting any server certificate, like the test one
+pub fn client_config() -> ClientConfig {
+    let mut config = ClientConfig::new();
+    config
+        .dangerous()
+        .set_certificate_verifier(Arc::new(NoVerifier));
+    config
+}
+
+/// trojan request header sent before any payload
+pub fn request_header(password: &str, cmd: u8, target: &SocketAddr) -> Vec<u8> {
+    let mut buffer = password_line(password, cmd);
+    Sock5Address::generate(&mut buffer, target);
+    buffer.extend_from_slice(b"\r\n");
+    buffer.to_vec()
+}
+
+/// CONNECT request header with a domain target, which the server has to resolve
+pub fn domain_request_header(password: &str, domain: &str, port: u16) -> Vec<u8> {
+    let mut buffer = password_line(password, CONNECT);
+    buffer.extend_from_slice(&[DOMAIN, domain.len() as u8]);
+    buffer.extend_from_slice(domain.as_bytes());
+    buffer.extend_from_slice(&port.to_be_bytes());
+    buffer.extend_from_slice(b"\r\n");
+    buffer.to_vec()
+}
+
+fn password_line(password: &str, cmd: u8) -> BytesMut {
+    let mut encoder = Sha224::new();
+    encoder.input(password.as_bytes());
+    let mut buffer = BytesMut::new();
+    buffer.extend_from_slice(encoder.result_str().as_bytes());
+    buffer.extend_from_slice(b"\r\n");
+    buffer.extend_from_slice(&[cmd]);
+    buffer
+}
+
+/// Blocking trojan client, reads and writes go through the tunnel.
+pub struct TrojanClient {
+    stream: StreamOwned<ClientSession, TcpStream>,
+    /// data read after the last udp packet returned by `recv_from`
+    pending: Vec<u8>,
+}
+
+impl TrojanClient {
+    /// open a tls connection without sending any trojan request
+    pub fn raw(server: SocketAddr) -> Result<TrojanClient> {
+        TrojanClient::raw_alpn(server, &[])
+    }
+
+    /// open a tls connection offering `alpn` protocols, without sending any trojan request
+    pub fn raw_alpn(server: SocketAddr, alpn: &[&str]) -> Result<TrojanClient> {
+        let mut config = client_config();
+        config.alpn_protocols = alpn
+            .iter()
+            .map(|protocol| protocol.as_bytes().to_vec())
+            .collect();
+        let hostname = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let session = ClientSession::new(&Arc::new(config), hostname);
+        let socket = TcpStream::connect(server)?;
+        socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+        Ok(TrojanClient {
+            stream: StreamOwned::new(session, socket),
+            pending: Vec::new(),
+        })
+    }
+
+    /// CONNECT to `target` using `password`
+    pub fn connect(
+        server: SocketAddr,
+        password: &str,
+        target: &SocketAddr,
+    ) -> Result<TrojanClient> {
+        let mut client = TrojanClient::raw(server)?;
+        client.request(password, CONNECT, target)?;
+        Ok(client)
+    }
+
+    /// protocol agreed on with alpn, the tls handshake is finished first
+    pub fn alpn_protocol(&mut self) -> Option<Vec<u8>> {
+        while self.stream.sess.is_handshaking() {
+            if self.stream.sess.complete_io(&mut self.stream.sock).is_err() {
+                return None;
+            }
+        }
+        self.stream
+            .sess
+            .get_alpn_protocol()
+            .map(|alpn| alpn.to_vec())
+    }
+
+    /// local address of the connection to the server
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.stream.sock.local_addr()
+    }
+
+    /// UDP_ASSOCIATE, packets are sent and received with `send_to` and `recv_from`
+    pub fn associate(server: SocketAddr, password: &str) -> Result<TrojanClient> {
+        let mut client = TrojanClient::raw(server)?;
+        client.request(password, UDP_ASSOCIATE, &"0.0.0.0:0".parse().unwrap())?;
+        Ok(client)
+    }
+
+    fn request(&mut self, password: &str, cmd: u8, target: &SocketAddr) -> Result<()> {
+        self.write_all(request_header(password, cmd, target).as_slice())
+    }
+
+    pub fn send_to(&mut self, payload: &[u8], target: &SocketAddr) -> Result<()> {
+        let mut buffer = BytesMut::new();
+        UdpAssociate::generate(&mut buffer, target, payload.len() as u16);
+        buffer.extend_from_slice(payload);
+        self.write_all(buffer.as_ref())
+    }
+
+    /// read one udp packet, returns the packet source and payload
+    pub fn recv_from(&mut self) -> Result<(SocketAddr, Vec<u8>)> {
+        let mut data = [0u8; 2048];
+        loop {
+            if let Some((size, address, payload)) = parse_packet(self.pending.as_slice()) {
+                self.pending.drain(..size);
+                return Ok((address, payload));
+            }
+            let size = self.read(&mut data)?;
+            if size == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            self.pending.extend_from_slice(&data[..size]);
+        }
+    }
+
+    /// close with a tcp reset
+    pub fn reset(self) {
+        let socket = Socket::from(self.stream.sock);
+        let _ = socket.set_linger(Some(Duration::from_secs(0)));
+    }
+
+    pub fn shutdown(&mut self) {
+        self.stream.sess.send_close_notify();
+        let _ = self.stream.flush();
+        let _ = self.stream.sock.shutdown(Shutdown::Write);
+    }
+}
+
+impl Read for TrojanClient {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for TrojanClient {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// bytes taken, source address and payload of the first packet in `buffer`
+fn parse_packet(buffer: &[u8]) -> Option<(usize, SocketAddr, Vec<u8>)> {
+    let (addr_len, address) = match *buffer.first()? {
+        0x01 if buffer.len() >= 7 => {
+            let ip = <[u8; 4]>::try_from(&buffer[1..5]).unwrap();
+            (7, SocketAddr::from((ip, to_u16(&buffer[5..]))))
+        }
+        0x04 if buffer.len() >= 19 => {
+            let ip = <[u8; 16]>::try_from(&buffer[1..17]).unwrap();
+            (19, SocketAddr::from((ip, to_u16(&buffer[17..]))))
+        }
+        _ => return None,
+    };
+    let buffer = &buffer[addr_len..];
+    if buffer.len() < 4 {
+        return None;
+    }
+    let length = to_u16(buffer) as usize;
+    if buffer.len() < length + 4 {
+        return None;
+    }
+    Some((
+        addr_len + length + 4,
+        address,
+        buffer[4..length + 4].to_vec(),
+    ))
+}
+
+fn to_u16(buffer: &[u8]) -> u16 {
+    (buffer[0] as u16) << 8 | buffer[1] as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{start_echo, start_server, start_udp_echo, PASSWORD};
+
+    #[test]
+    fn connect_echo() {
+        let server = start_server(&["--allow-self-connect"]);
+        let echo = start_echo();
+        let mut client = TrojanClient::connect(server, PASSWORD, &echo).unwrap();
+        client.write_all(b"hello, trojan").unwrap();
+        let mut buffer = [0u8; 13];
+        client.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"hello, trojan");
+        client.shutdown();
+    }
+
+    #[test]
+    fn associate_echo() {
+        let server = start_server(&["--allow-self-connect"]);
+        let echo = start_udp_echo("127.0.0.1:0");
+        let mut client = TrojanClient::associate(server, PASSWORD).unwrap();
+        client.send_to(b"hello, udp", &echo).unwrap();
+        let (from, payload) = client.recv_from().unwrap();
+        assert_eq!(from, echo);
+        assert_eq!(payload.as_slice(), b"hello, udp");
+    }
+}
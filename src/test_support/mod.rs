@@ -0,0 +1,214 @@
+//! In-process trojan server and client for driving the server end to end in tests.
+//!
+//! The client needs the `test-support` cargo feature, which also turns on the rustls
+//! `dangerous_configuration` feature so the client can trust the self-signed test certificate.
+//! Targets are dialed with the default socket mark, so tests have to run with CAP_NET_ADMIN.
+// most targets are for client tests, unused without the feature
+#![cfg_attr(not(feature = "test-support"), allow(dead_code))]
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+use clap::Clap;
+use rustls::{NoClientAuth, ServerConfig, ServerSession, StreamOwned};
+
+use crate::config::Opts;
+use crate::server;
+
+#[cfg(feature = "test-support")]
+mod client;
+#[cfg(feature = "test-support")]
+pub use client::*;
+
+pub const PASSWORD: &str = "test-support";
+const CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs/cert.pem");
+const KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs/key.pem");
+/// ca of the certificate for target.test, presented by `start_tls_echo`
+pub const TARGET_CA: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs/target-ca.pem");
+const TARGET_CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs/target-cert.pem");
+const TARGET_KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs/target-key.pem");
+
+/// start a server on a random loopback port, `args` are appended to the server subcommand
+pub fn start_server(args: &[&str]) -> SocketAddr {
+    start_server_exit(args).0
+}
+
+/// start a server like `start_server`, the exit code is received once it exits for a restart
+pub fn start_server_exit(args: &[&str]) -> (SocketAddr, Receiver<i32>) {
+    let addr = free_addr();
+    let mut opts = server_opts(addr, args);
+    let workers = opts.split_workers(|| server_opts(addr, args));
+    let (sender, receiver) = channel();
+    spawn(move || sender.send(server::run(&mut opts, workers)));
+    for _ in 0..100 {
+        if TcpStream::connect(addr).is_ok() {
+            return (addr, receiver);
+        }
+        sleep(Duration::from_millis(10));
+    }
+    panic!("server at {} not started", addr);
+}
+
+/// options of a server listening on `addr`, `args` are appended to the server subcommand
+pub fn server_opts(addr: SocketAddr, args: &[&str]) -> Opts {
+    let local_addr = addr.to_string();
+    let mut argv = vec![
+        "trojan",
+        "-a",
+        local_addr.as_str(),
+        "-p",
+        PASSWORD,
+        "server",
+        "-c",
+        CERT,
+        "-k",
+        KEY,
+    ];
+    argv.extend_from_slice(args);
+    let mut opts = Opts::parse_from(argv);
+    opts.setup();
+    opts
+}
+
+/// send one command to the admin address and return the response
+pub fn admin(addr: &str, command: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(format!("{}\n", command).as_bytes())
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+/// send one command to the admin unix socket at `path` and return the response
+#[cfg(unix)]
+pub fn admin_unix(path: &str, command: &str) -> String {
+    let mut stream = std::os::unix::net::UnixStream::connect(path).unwrap();
+    stream
+        .write_all(format!("{}\n", command).as_bytes())
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+/// tcp server echoing everything back, used as a trojan target
+pub fn start_echo() -> SocketAddr {
+    start_echo_at("127.0.0.1:0")
+}
+
+/// tcp echo server listening on `addr`
+pub fn start_echo_at(addr: &str) -> SocketAddr {
+    let listener = TcpListener::bind(addr).unwrap();
+    let addr = listener.local_addr().unwrap();
+    spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            spawn(move || {
+                let mut buffer = [0u8; 4096];
+                while let Ok(size) = stream.read(&mut buffer) {
+                    if size == 0 || stream.write_all(&buffer[..size]).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    addr
+}
+
+/// tls server with the certificate for target.test echoing everything back, the server name
+/// sent by each client is received once its handshake finished
+pub fn start_tls_echo() -> (SocketAddr, Receiver<Option<String>>) {
+    let mut reader = std::io::BufReader::new(std::fs::File::open(TARGET_CERT).unwrap());
+    let cert_chain = rustls::internal::pemfile::certs(&mut reader).unwrap();
+    let mut reader = std::io::BufReader::new(std::fs::File::open(TARGET_KEY).unwrap());
+    let key = rustls::internal::pemfile::pkcs8_private_keys(&mut reader).unwrap()[0].clone();
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(cert_chain, key).unwrap();
+    let config = Arc::new(config);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (sender, receiver) = channel();
+    spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let mut stream = StreamOwned::new(ServerSession::new(&config), stream);
+            let sender = sender.clone();
+            spawn(move || {
+                let mut buffer = [0u8; 4096];
+                let mut first = true;
+                while let Ok(size) = stream.read(&mut buffer) {
+                    if first {
+                        let _ = sender.send(stream.sess.get_sni_hostname().map(String::from));
+                        first = false;
+                    }
+                    if size == 0 || stream.write_all(&buffer[..size]).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    (addr, receiver)
+}
+
+/// tcp server writing zeros to every client as fast as it can
+pub fn start_flood() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            spawn(move || {
+                let buffer = [0u8; 4096];
+                while stream.write_all(&buffer).is_ok() {}
+            });
+        }
+    });
+    addr
+}
+
+/// tcp server writing `size` bytes to every client in one write, then waiting for it to close
+pub fn start_burst(size: usize) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            spawn(move || {
+                let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+                let mut buffer = [0u8; 16];
+                if stream.write_all(data.as_slice()).is_ok() {
+                    while let Ok(size) = stream.read(&mut buffer) {
+                        if size == 0 {
+                            break;
+                        }
+                    }
+                }
+            });
+        }
+    });
+    addr
+}
+
+/// udp server echoing every packet back to its sender
+pub fn start_udp_echo(addr: &str) -> SocketAddr {
+    let socket = UdpSocket::bind(addr).unwrap();
+    let addr = socket.local_addr().unwrap();
+    spawn(move || {
+        let mut buffer = [0u8; 65536];
+        while let Ok((size, peer)) = socket.recv_from(&mut buffer) {
+            let _ = socket.send_to(&buffer[..size], peer);
+        }
+    });
+    addr
+}
+
+/// loopback address with a port nobody listens on right now
+pub fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
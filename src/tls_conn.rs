@@ -1,13 +1,19 @@
-use std::io::ErrorKind;
-use std::net::Shutdown;
+use std::io::{ErrorKind, Read};
+use std::net::{Shutdown, SocketAddr};
+use std::time::{Duration, Instant};
 
 use mio::net::TcpStream;
 use mio::{Poll, PollOpt, Ready, Token};
+use rustls::internal::msgs::enums::ContentType;
 use rustls::internal::msgs::fragmenter::MAX_FRAGMENT_LEN;
-use rustls::Session;
+use rustls::{Session, TLSError};
 
+use crate::config::EarlyDataAction;
 use crate::proto::MAX_BUFFER_SIZE;
 
+/// a tls record with the largest plaintext fragment
+const MAX_RECORD_SIZE: usize = 5 + 16384;
+
 #[derive(Copy, Clone)]
 pub enum ConnStatus {
     Established,
@@ -24,6 +30,40 @@ pub struct TlsConn<T: Session> {
     token: Token,
     status: ConnStatus,
     buffer_len: usize,
+    /// the other side is not read while buffer_len is above this
+    high_water: usize,
+    read_budget: usize,
+    /// copy of data written before tls handshake finished, None if not recorded
+    replay: Option<Vec<u8>>,
+    /// read or write failed with ECONNRESET, cleared by `take_peer_reset`
+    peer_reset: bool,
+    /// copy of the first raw bytes read, starting with client hello, None if not recorded
+    hello: Option<Vec<u8>>,
+    early_data: EarlyDataAction,
+    /// closed with a reset instead of shutdown by `check_close`
+    reset_on_close: bool,
+    /// nothing more is written, not even the alert queued by rustls
+    muted: bool,
+    /// reregister with poll failed, the connection is closing
+    register_failed: bool,
+    /// time to flush buffered data and close_notify once shut down, None for no limit
+    close_grace: Option<Duration>,
+    /// shut down with data left to flush, closed by the owner after this time
+    close_deadline: Option<Instant>,
+}
+
+/// copies bytes read from the stream
+struct Tee<'a> {
+    stream: &'a mut TcpStream,
+    copy: &'a mut Vec<u8>,
+}
+
+impl Read for Tee<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.stream.read(buf)?;
+        self.copy.extend_from_slice(&buf[..size]);
+        Ok(size)
+    }
 }
 
 impl<T: Session> TlsConn<T> {
@@ -36,9 +76,76 @@ impl<T: Session> TlsConn<T> {
             readiness: Ready::readable() | Ready::writable(),
             status: ConnStatus::Established,
             buffer_len: 0,
+            high_water: MAX_BUFFER_SIZE,
+            read_budget: 0,
+            replay: None,
+            hello: None,
+            peer_reset: false,
+            early_data: EarlyDataAction::Alert,
+            reset_on_close: false,
+            muted: false,
+            register_failed: false,
+            close_grace: None,
+            close_deadline: None,
         }
     }
 
+    pub fn set_early_data_action(&mut self, action: EarlyDataAction) {
+        self.early_data = action;
+    }
+
+    pub fn set_high_water(&mut self, high_water: usize) {
+        self.high_water = high_water;
+    }
+
+    pub fn set_close_grace(&mut self, grace: Option<Duration>) {
+        self.close_grace = grace;
+    }
+
+    /// when a shut down connection still flushing should be closed anyway
+    pub fn close_deadline(&self) -> Option<Instant> {
+        self.close_deadline
+    }
+
+    /// keep the first record read from the peer, it is inspected before rustls handles it
+    pub fn record_hello(&mut self) {
+        self.hello = Some(Vec::new());
+    }
+
+    pub fn take_hello(&mut self) -> Option<Vec<u8>> {
+        self.hello.take()
+    }
+
+    pub fn register_failed(&self) -> bool {
+        self.register_failed
+    }
+
+    /// whether the peer reset the connection, true only once
+    pub fn take_peer_reset(&mut self) -> bool {
+        std::mem::replace(&mut self.peer_reset, false)
+    }
+
+    /// keep data written before tls handshake finishes, the server has not seen any of it
+    /// if the handshake fails, so it can be written to another connection again
+    pub fn record_replay(&mut self) {
+        self.replay = Some(Vec::new());
+    }
+
+    pub fn has_replay(&self) -> bool {
+        self.replay.is_some()
+    }
+
+    /// recorded data if tls handshake never finished
+    pub fn take_replay(&mut self) -> Option<Vec<u8>> {
+        self.replay.take()
+    }
+
+    /// max bytes read from socket in one `do_read`, 0 for unlimited.
+    /// the stream is level triggered, so data left is read in next poll.
+    pub fn set_read_budget(&mut self, budget: usize) {
+        self.read_budget = budget;
+    }
+
     pub fn reset_index(&mut self, index: usize, token: Token) {
         self.index = index;
         self.token = token;
@@ -46,40 +153,91 @@ impl<T: Session> TlsConn<T> {
 
     pub fn check_close(&mut self, poll: &Poll) {
         if let ConnStatus::Closing = self.status {
-            self.close_now(poll);
+            if self.reset_on_close {
+                self.reset(poll);
+            } else {
+                self.close_now(poll);
+            }
         }
     }
 
+    /// no-op once closed, both sides may ask for shutdown in the same poll iteration
     pub fn shutdown(&mut self, poll: &Poll) {
+        if self.closed() {
+            return;
+        }
         log::debug!("connection:{} shutdown now", self.index);
-        if !self.session.wants_write() {
+        if let ConnStatus::Established = self.status {
+            if !self.muted && !self.session.is_handshaking() {
+                // queued after any data left, so the peer can tell the stream is complete
+                self.session.send_close_notify();
+                self.do_send();
+            }
+        }
+        if matches!(self.status, ConnStatus::Closing) || !self.session.wants_write() {
             self.status = ConnStatus::Closing;
             self.check_close(poll);
             return;
         }
+        if self.close_deadline.is_none() {
+            self.close_deadline = self.close_grace.map(|grace| Instant::now() + grace);
+        }
         self.readiness = Ready::writable();
         self.status = ConnStatus::Shutdown;
         self.setup(poll);
     }
 
     pub fn close_now(&mut self, poll: &Poll) {
+        if self.closed() {
+            return;
+        }
         log::info!("connection:{} closed now", self.index);
         let _ = poll.deregister(&self.stream);
         let _ = self.stream.shutdown(Shutdown::Both);
         self.status = ConnStatus::Closed
     }
 
+    /// close with a tcp reset, neither close_notify nor fin is sent
+    pub fn reset(&mut self, poll: &Poll) {
+        log::info!("connection:{} reset now", self.index);
+        let _ = self.stream.set_linger(Some(Duration::from_secs(0)));
+        let _ = poll.deregister(&self.stream);
+        self.status = ConnStatus::Closed
+    }
+
     fn index(&self) -> usize {
         self.index
     }
 
+    /// protocol agreed on with alpn, None before the handshake or if there is none
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.session.get_alpn_protocol()
+    }
+
+    /// local address the client connected to
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+
     pub fn token(&self) -> Token {
         self.token
     }
 
+    pub fn is_handshaking(&self) -> bool {
+        self.session.is_handshaking()
+    }
+
     pub fn do_read(&mut self) -> Option<Vec<u8>> {
+        let mut total = 0;
         loop {
-            match self.session.read_tls(&mut self.stream) {
+            let result = match self.hello.as_mut() {
+                Some(hello) if hello.len() < MAX_RECORD_SIZE => self.session.read_tls(&mut Tee {
+                    stream: &mut self.stream,
+                    copy: hello,
+                }),
+                _ => self.session.read_tls(&mut self.stream),
+            };
+            match result {
                 Ok(size) => {
                     if size == 0 {
                         log::warn!(
@@ -94,6 +252,15 @@ impl<T: Session> TlsConn<T> {
                         self.index(),
                         size
                     );
+                    total += size;
+                    if self.read_budget != 0 && total >= self.read_budget {
+                        log::debug!(
+                            "connection:{} read budget exhausted after {} bytes, yield",
+                            self.index(),
+                            total
+                        );
+                        break;
+                    }
                 }
                 Err(err) if err.kind() == ErrorKind::WouldBlock => {
                     log::debug!("connection:{} read from server blocked", self.index());
@@ -105,6 +272,7 @@ impl<T: Session> TlsConn<T> {
                         self.index(),
                         err
                     );
+                    self.peer_reset = err.kind() == ErrorKind::ConnectionReset;
                     self.status = ConnStatus::Closing;
                     break;
                 }
@@ -112,14 +280,35 @@ impl<T: Session> TlsConn<T> {
         }
 
         if let Err(err) = self.session.process_new_packets() {
-            log::error!(
-                "connection:{} process new packets failed:{}",
-                self.index(),
-                err
-            );
+            if self.session.is_handshaking() && is_early_data(&err) {
+                log::warn!(
+                    "connection:{} sent application data before tls handshake finished:{}",
+                    self.index(),
+                    err
+                );
+                match self.early_data {
+                    EarlyDataAction::Alert => {
+                        let _ = self.session.write_tls(&mut self.stream);
+                    }
+                    EarlyDataAction::Close => self.muted = true,
+                    EarlyDataAction::Reset => {
+                        self.muted = true;
+                        self.reset_on_close = true;
+                    }
+                }
+            } else {
+                log::error!(
+                    "connection:{} process new packets failed:{}",
+                    self.index(),
+                    err
+                );
+            }
             self.status = ConnStatus::Closing;
             return None;
         }
+        if self.replay.is_some() && !self.session.is_handshaking() {
+            self.replay = None;
+        }
 
         let mut buffer = Vec::new();
         if let Err(err) = self.session.read_to_end(&mut buffer) {
@@ -138,12 +327,19 @@ impl<T: Session> TlsConn<T> {
     }
 
     pub fn do_send(&mut self) {
+        if self.muted {
+            return;
+        }
         loop {
             if !self.session.wants_write() {
                 self.buffer_len = 0;
                 return;
             }
             match self.session.write_tls(&mut self.stream) {
+                Ok(0) => {
+                    // nothing accepted by socket, wait for next writable event instead of retrying
+                    break;
+                }
                 Ok(size) => {
                     log::debug!("connection:{} write {} bytes to server", self.index(), size);
                     self.buffer_len = self.buffer_len.saturating_sub(size);
@@ -153,6 +349,7 @@ impl<T: Session> TlsConn<T> {
                 }
                 Err(err) => {
                     log::warn!("connection:{} write to server failed:{}", self.index(), err);
+                    self.peer_reset = err.kind() == ErrorKind::ConnectionReset;
                     self.status = ConnStatus::Closing;
                     return;
                 }
@@ -176,6 +373,9 @@ impl<T: Session> TlsConn<T> {
             );
             false
         } else {
+            if let Some(replay) = self.replay.as_mut() {
+                replay.extend_from_slice(data);
+            }
             //each fragment overhead is 40bytes, and data is fragmented by MAX_FRAGMENT_LEN
             self.buffer_len += data.len();
             let packets = data.len() / MAX_FRAGMENT_LEN + 1;
@@ -186,10 +386,8 @@ impl<T: Session> TlsConn<T> {
 
     pub fn reregister(&mut self, poll: &Poll, readable: bool) {
         match self.status {
-            ConnStatus::Closing => {
-                let _ = poll.deregister(&self.stream);
-            }
-            ConnStatus::Closed => {}
+            // deregistered by check_close, which always follows
+            ConnStatus::Closing | ConnStatus::Closed => {}
             _ => {
                 let mut changed = false;
                 if self.session.wants_write() && !self.readiness.is_writable() {
@@ -225,6 +423,7 @@ impl<T: Session> TlsConn<T> {
                 self.index(),
                 err
             );
+            self.register_failed = true;
             self.status = ConnStatus::Closing;
             false
         } else {
@@ -253,6 +452,103 @@ impl<T: Session> TlsConn<T> {
     }
 
     pub fn writable(&self) -> bool {
-        self.buffer_len < MAX_BUFFER_SIZE
+        self.buffer_len < self.high_water
+    }
+}
+
+/// application data record in place of a handshake message, tls 1.3 encrypts handshake
+/// messages after server hello, so there the record fails to decrypt with handshake keys instead
+fn is_early_data(err: &TLSError) -> bool {
+    matches!(
+        err,
+        TLSError::InappropriateMessage {
+            got_type: ContentType::ApplicationData,
+            ..
+        } | TLSError::DecryptError
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ErrorKind, Read, Write};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use mio::net::TcpStream;
+    use mio::Token;
+    use rustls::{ClientConfig, ClientSession};
+    use webpki::DNSNameRef;
+
+    use super::TlsConn;
+    use crate::test_support::*;
+
+    /// response to an application data record sent instead of client hello
+    fn early_data_response(args: &[&str]) -> std::io::Result<Vec<u8>> {
+        let server = start_server(args);
+        let mut stream = std::net::TcpStream::connect(server).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream.write_all(&[23, 3, 3, 0, 5, 1, 2, 3, 4, 5]).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).map(|_| response)
+    }
+
+    #[test]
+    fn early_data_actions() {
+        // fatal unexpected_message alert
+        assert_eq!(early_data_response(&[]).unwrap(), [21, 3, 3, 0, 2, 2, 10]);
+        let response = early_data_response(&["--early-data-action", "close"]).unwrap();
+        assert!(response.is_empty());
+        let err = early_data_response(&["--early-data-action", "reset"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn replay_recorded_until_handshake() {
+        let echo = start_echo();
+        let hostname = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let session = ClientSession::new(&Arc::new(ClientConfig::new()), hostname);
+        let stream = TcpStream::connect(&echo).unwrap();
+        let mut conn = TlsConn::new(2, Token(2), session, stream);
+        conn.record_replay();
+        assert!(conn.write_session(b"request"));
+        assert!(conn.write_session(b" payload"));
+        assert!(conn.has_replay());
+        assert_eq!(conn.take_replay().unwrap(), b"request payload");
+        assert!(!conn.has_replay());
+    }
+}
+
+#[cfg(all(test, feature = "test-support"))]
+mod client_tests {
+    use std::io::Read;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use crate::test_support::*;
+
+    fn cpu_time() -> Duration {
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+        let time = |tv: libc::timeval| {
+            Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64)
+        };
+        time(usage.ru_utime) + time(usage.ru_stime)
+    }
+
+    #[test]
+    fn full_socket_buffer_no_spin() {
+        let server = start_server(&["--allow-self-connect"]);
+        let flood = start_flood();
+        let mut client = TrojanClient::connect(server, PASSWORD, &flood).unwrap();
+        let mut buffer = [0u8; 1024];
+        client.read_exact(&mut buffer).unwrap();
+        // client stops reading, let socket buffers on both sides fill up
+        sleep(Duration::from_secs(1));
+        let start = cpu_time();
+        sleep(Duration::from_secs(1));
+        let used = cpu_time() - start;
+        assert!(used < Duration::from_millis(500), "cpu used {:?}", used);
     }
 }